@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rsll16::bench::{dispatch_loop, memory_loop, stack_loop, standard_workload, Engine};
+use rsll16::cpu::Cpu;
+use rsll16::memory::Memory;
+
+/// Instructions executed per iteration
+const INSTRUCTIONS: usize = 100_000;
+
+fn engines() -> Vec<Engine> {
+    vec![
+        Engine::Step,
+        Engine::Run,
+        Engine::Cached,
+        #[cfg(feature = "jit")]
+        Engine::Jit,
+    ]
+}
+
+fn bench_workload(c: &mut Criterion, name: &str, workload: fn() -> Memory) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(INSTRUCTIONS as u64));
+    for engine in engines() {
+        group.bench_function(format!("{:?}", engine), |b| {
+            b.iter_batched_ref(
+                || Cpu::new(workload()),
+                |cpu| engine.execute(cpu, INSTRUCTIONS),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn dispatch(c: &mut Criterion) {
+    bench_workload(c, "dispatch", dispatch_loop);
+}
+
+fn stack(c: &mut Criterion) {
+    bench_workload(c, "stack", stack_loop);
+}
+
+fn memory(c: &mut Criterion) {
+    bench_workload(c, "memory", memory_loop);
+}
+
+fn standard(c: &mut Criterion) {
+    bench_workload(c, "standard", standard_workload);
+}
+
+criterion_group!(benches, dispatch, stack, memory, standard);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+#![no_main]
+
+//! Decodes and executes a single instruction made of arbitrary bytes and
+//! checks the instruction pointer moved by the documented length.
+
+use libfuzzer_sys::fuzz_target;
+use rsll16::cpu::{opcode_info, Cpu, Operand, Register};
+use rsll16::memory::Memory;
+
+/// Keeps the instruction away from the stack at the top of memory
+const CODE_START: usize = 0x0100;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Some(&opcode) = bytes.first() else {
+        return;
+    };
+    let mut memory = Memory::new(0x10000);
+    for (i, byte) in bytes.iter().take(0x100).enumerate() {
+        memory.set_byte(CODE_START + i, *byte);
+    }
+    let mut cpu = Cpu::new(memory);
+    cpu.set_register(Register::InstructionPointer, CODE_START as u16);
+
+    if cpu.step().is_err() {
+        return;
+    }
+    let ip = cpu.peek_register(Register::InstructionPointer) as usize;
+
+    let Some(info) = opcode_info(opcode) else {
+        // Unknown opcodes are skipped like a noop
+        assert_eq!(ip, CODE_START + 1);
+        return;
+    };
+    if info.instruction.may_branch() {
+        return;
+    }
+
+    // A register operand naming the instruction pointer is a jump too
+    let mut offset = 1;
+    for operand in info.operands {
+        // Bytes past the input are zero, which encodes the instruction pointer
+        let value = bytes.get(offset).copied().unwrap_or(0);
+        if *operand == Operand::Register && value == Register::InstructionPointer as u8 {
+            return;
+        }
+        offset += operand.size();
+    }
+    assert_eq!(ip, CODE_START + info.length(), "{:?}", info);
+});
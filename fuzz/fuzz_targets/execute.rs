@@ -0,0 +1,35 @@
+#![no_main]
+
+//! Runs arbitrary bytes as a program. The VM must report problems as
+//! faults rather than panic, and every engine must end up in the same state.
+
+use libfuzzer_sys::fuzz_target;
+use rsll16::bench::Engine;
+use rsll16::cpu::Cpu;
+use rsll16::memory::Memory;
+
+const FUEL: usize = 4096;
+
+fn load(bytes: &[u8]) -> Cpu {
+    let mut memory = Memory::new(0x10000);
+    for (i, byte) in bytes.iter().take(0x8000).enumerate() {
+        memory.set_byte(i, *byte);
+    }
+    Cpu::new(memory)
+}
+
+fuzz_target!(|bytes: &[u8]| {
+    let mut reference = load(bytes);
+    let expected = Engine::Step.execute(&mut reference, FUEL);
+
+    for engine in [Engine::Run, Engine::Cached] {
+        let mut cpu = load(bytes);
+        assert_eq!(engine.execute(&mut cpu, FUEL), expected, "{:?}", engine);
+        assert_eq!(
+            cpu.registers().collect::<Vec<_>>(),
+            reference.registers().collect::<Vec<_>>(),
+            "{:?}",
+            engine
+        );
+    }
+});
@@ -0,0 +1,541 @@
+use crate::cpu::{opcode_info, OpcodeInfo, Operand, Register};
+use std::collections::HashMap;
+
+/// An operand as written, with values left as text until every label is
+/// known
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Written {
+    /// A literal or branch target: `0x0001`, `42` or a symbol
+    Value(String),
+    Register(Register),
+    /// `[r1]`
+    Pointer(Register),
+    /// `[0x0800]`
+    Memory(String),
+    /// `[0x0800 + r1]`
+    Indexed(String, Register),
+    /// `[fp - 2]`, whether it's below the frame pointer and by how much
+    Frame(bool, String),
+}
+
+/// A line that puts bytes into the image
+#[derive(Debug)]
+enum Statement {
+    Instruction {
+        opcode: u8,
+        info: &'static OpcodeInfo,
+        operands: Vec<Written>,
+    },
+    /// `db 0x01, 0x02`
+    Data(Vec<String>),
+}
+
+/// An `.if` whose `.endif` hasn't come yet
+struct Conditional {
+    /// Where the `.if` is, for when the `.endif` never comes
+    line: usize,
+    /// Whether the lines of the current branch are assembled
+    taking: bool,
+    /// Whether a branch was taken already, or can't be because the
+    /// conditional is in a branch that isn't
+    taken: bool,
+    seen_else: bool,
+}
+
+/// Assembles programs written the way the disassembler writes them in
+/// `Syntax::Native`: sources first, memory operands in brackets. Each line
+/// holds an optional `label:` and an instruction or `db` with bytes, with
+/// comments from `;` on.
+///
+/// Conditional assembly picks lines for different machine profiles out of
+/// one source, like with or without a screen device:
+///
+/// ```text
+/// .define SCREEN_BASE 0x3000
+/// .if SCREEN
+///     mov 0x0041, r1
+///     mov r1, [SCREEN_BASE]
+/// .elif SERIAL == 2
+///     cal print
+/// .else
+///     nop
+/// .endif
+/// ```
+///
+/// - `.define NAME [VALUE]` names a value, 1 without one
+/// - `.if COND`, `.elif COND`, `.else` and `.endif` assemble the lines of
+///   the first branch whose condition holds, and nest
+/// - a condition is a value, `!VALUE`, or two values compared with `==` or
+///   `!=`, where names that aren't defined count as 0
+///
+/// Names defined before assembling, like from the command line, and with
+/// `.define` work wherever a value does, as do labels.
+#[derive(Debug, Default, Clone)]
+pub struct Assembler {
+    defines: HashMap<String, u16>,
+    origin: u16,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Defines `name` as if the source started with `.define`
+    pub fn define(mut self, name: &str, value: u16) -> Assembler {
+        self.defines.insert(name.to_string(), value);
+        self
+    }
+
+    /// Where the image gets loaded, which labels count from. 0 by default.
+    pub fn origin(mut self, origin: u16) -> Assembler {
+        self.origin = origin;
+        self
+    }
+
+    /// Assembles `source` into an image to load at the origin
+    pub fn assemble(&self, source: &str) -> Result<Vec<u8>, String> {
+        let mut symbols = self.defines.clone();
+        let lines = preprocess(source, &mut symbols)?;
+
+        // Instructions are as long whatever their operands' values are, so
+        // one pass places the labels and the next fills the values in
+        let mut statements = Vec::new();
+        let mut address = self.origin as usize;
+        for (line_number, line) in lines {
+            let error = |message: String| format!("Line {}: {}", line_number, message);
+            let mut statement = line;
+            if let Some((label, rest)) = line.split_once(':') {
+                let label = label.trim();
+                if !is_name(label) {
+                    return Err(error(format!("{} is not a label name", label)));
+                }
+                if address > u16::MAX as usize {
+                    return Err(error("the program doesn't fit in memory".to_string()));
+                }
+                if symbols.insert(label.to_string(), address as u16).is_some() {
+                    return Err(error(format!("{} is already defined", label)));
+                }
+                statement = rest.trim();
+            }
+            if statement.is_empty() {
+                continue;
+            }
+            let statement = parse_statement(statement).map_err(error)?;
+            address += match &statement {
+                Statement::Instruction { info, .. } => info.length(),
+                Statement::Data(bytes) => bytes.len(),
+            };
+            statements.push((line_number, statement));
+        }
+        if address > u16::MAX as usize + 1 {
+            return Err("The program doesn't fit in memory".to_string());
+        }
+
+        let mut image = Vec::new();
+        for (line_number, statement) in statements {
+            let error = |message: String| format!("Line {}: {}", line_number, message);
+            let value = |text: &str| resolve(text, &symbols).map_err(error);
+            match statement {
+                Statement::Instruction {
+                    opcode, operands, ..
+                } => {
+                    image.push(opcode);
+                    for operand in operands {
+                        match operand {
+                            Written::Value(text) | Written::Memory(text) => {
+                                image.extend(value(&text)?.to_be_bytes())
+                            }
+                            Written::Register(register) | Written::Pointer(register) => {
+                                image.push(register as u8)
+                            }
+                            Written::Indexed(text, register) => {
+                                image.extend(value(&text)?.to_be_bytes());
+                                image.push(register as u8);
+                            }
+                            Written::Frame(below, text) => {
+                                let offset = match below {
+                                    true => value(&text)?.wrapping_neg(),
+                                    false => value(&text)?,
+                                };
+                                image.extend(offset.to_be_bytes());
+                            }
+                        }
+                    }
+                }
+                Statement::Data(bytes) => {
+                    for text in bytes {
+                        let byte = value(&text)?;
+                        let byte = u8::try_from(byte)
+                            .map_err(|_| error(format!("value {:#x} is more than a byte", byte)))?;
+                        image.push(byte);
+                    }
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// The lines of `source` that conditional assembly keeps, numbered from 1,
+/// without comments and directives. Adds the `.define`s to `symbols`.
+fn preprocess<'a>(
+    source: &'a str,
+    symbols: &mut HashMap<String, u16>,
+) -> Result<Vec<(usize, &'a str)>, String> {
+    let mut lines = Vec::new();
+    let mut conditionals: Vec<Conditional> = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: String| format!("Line {}: {}", line_number, message);
+        let line = line.split(';').next().unwrap_or_default().trim();
+        let taking = conditionals.iter().all(|conditional| conditional.taking);
+        let Some(directive) = line.strip_prefix('.') else {
+            if taking && !line.is_empty() {
+                lines.push((line_number, line));
+            }
+            continue;
+        };
+        let (directive, argument) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        let argument = argument.trim();
+        match directive {
+            "if" => {
+                let holds = taking && condition(argument, symbols).map_err(error)?;
+                conditionals.push(Conditional {
+                    line: line_number,
+                    taking: holds,
+                    taken: !taking || holds,
+                    seen_else: false,
+                });
+            }
+            "elif" => {
+                let Some(conditional) = conditionals.last_mut() else {
+                    return Err(error(".elif without .if".to_string()));
+                };
+                if conditional.seen_else {
+                    return Err(error(".elif after .else".to_string()));
+                }
+                conditional.taking =
+                    !conditional.taken && condition(argument, symbols).map_err(error)?;
+                conditional.taken |= conditional.taking;
+            }
+            "else" => {
+                let Some(conditional) = conditionals.last_mut() else {
+                    return Err(error(".else without .if".to_string()));
+                };
+                if conditional.seen_else {
+                    return Err(error(".else after .else".to_string()));
+                }
+                conditional.seen_else = true;
+                conditional.taking = !conditional.taken;
+                conditional.taken = true;
+            }
+            "endif" => {
+                if conditionals.pop().is_none() {
+                    return Err(error(".endif without .if".to_string()));
+                }
+            }
+            _ if !taking => {}
+            "define" => {
+                let (name, value) = argument
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((argument, "1"));
+                if !is_name(name) {
+                    return Err(error(format!("{} is not a name to define", name)));
+                }
+                let value = resolve(value.trim(), symbols).map_err(error)?;
+                if symbols.insert(name.to_string(), value).is_some() {
+                    return Err(error(format!("{} is already defined", name)));
+                }
+            }
+            directive => return Err(error(format!("unknown directive .{}", directive))),
+        }
+    }
+    match conditionals.first() {
+        Some(conditional) => Err(format!("Line {}: .if without .endif", conditional.line)),
+        None => Ok(lines),
+    }
+}
+
+/// Whether the condition of an `.if` or `.elif` holds
+fn condition(text: &str, symbols: &HashMap<String, u16>) -> Result<bool, String> {
+    let value = |text: &str| match resolve(text.trim(), symbols) {
+        Err(_) if is_name(text.trim()) => Ok(0),
+        value => value,
+    };
+    if let Some((left, right)) = text.split_once("==") {
+        Ok(value(left)? == value(right)?)
+    } else if let Some((left, right)) = text.split_once("!=") {
+        Ok(value(left)? != value(right)?)
+    } else if let Some(negated) = text.strip_prefix('!') {
+        Ok(value(negated)? == 0)
+    } else if text.is_empty() {
+        Err("missing condition".to_string())
+    } else {
+        Ok(value(text)? != 0)
+    }
+}
+
+/// A number in hex with `0x` or in decimal, or a defined name or label
+fn resolve(text: &str, symbols: &HashMap<String, u16>) -> Result<u16, String> {
+    let number = match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse::<u16>(),
+    };
+    match (number, symbols.get(text)) {
+        (Ok(number), _) => Ok(number),
+        (Err(_), Some(value)) => Ok(*value),
+        (Err(_), None) if is_name(text) => Err(format!("{} is not defined", text)),
+        (Err(e), None) => Err(format!("{}: {}", text, e)),
+    }
+}
+
+/// Whether `text` can name a label or value
+fn is_name(text: &str) -> bool {
+    let mut characters = text.chars();
+    characters
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && characters.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_statement(text: &str) -> Result<Statement, String> {
+    let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let operands: Vec<&str> = match operands.trim() {
+        "" => Vec::new(),
+        operands => operands.split(',').map(str::trim).collect(),
+    };
+    if mnemonic == "db" {
+        if operands.is_empty() {
+            return Err("db needs at least one byte".to_string());
+        }
+        let bytes = operands.iter().map(|byte| byte.to_string()).collect();
+        return Ok(Statement::Data(bytes));
+    }
+
+    let operands = operands
+        .into_iter()
+        .map(parse_operand)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut known = false;
+    for opcode in 0..=u8::MAX {
+        let Some(info) = opcode_info(opcode).filter(|info| info.mnemonic == mnemonic) else {
+            continue;
+        };
+        known = true;
+        if fits(info, &operands) {
+            return Ok(Statement::Instruction {
+                opcode,
+                info,
+                operands,
+            });
+        }
+    }
+    match known {
+        true => Err(format!("{} doesn't take these operands", mnemonic)),
+        false => Err(format!("unknown instruction {}", mnemonic)),
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Written, String> {
+    let register = |text: &str| text.trim().parse::<Register>();
+    let value = |text: &str| match text.trim() {
+        "" => Err("missing value".to_string()),
+        value if register(value).is_ok() => Err(format!("{} is a register", value)),
+        value => Ok(value.to_string()),
+    };
+    let Some(inside) = text.strip_prefix('[') else {
+        return Ok(match register(text) {
+            Ok(register) => Written::Register(register),
+            Err(_) => Written::Value(value(text)?),
+        });
+    };
+    let inside = inside
+        .strip_suffix(']')
+        .ok_or(format!("{} is missing a ]", text))?;
+    if let Some((base, offset)) = inside.split_once('+') {
+        return match (register(base), register(offset)) {
+            (Ok(Register::FramePointer), Err(_)) => Ok(Written::Frame(false, value(offset)?)),
+            (Err(_), Ok(register)) => Ok(Written::Indexed(value(base)?, register)),
+            _ => Err(format!("{} is not a memory operand", text)),
+        };
+    }
+    if let Some((base, offset)) = inside.split_once('-') {
+        return match register(base) {
+            Ok(Register::FramePointer) => Ok(Written::Frame(true, value(offset)?)),
+            _ => Err(format!("{} is not a memory operand", text)),
+        };
+    }
+    Ok(match register(inside) {
+        Ok(register) => Written::Pointer(register),
+        Err(_) => Written::Memory(value(inside)?),
+    })
+}
+
+/// Whether `operands` are what the instruction takes. Bare values are
+/// literals, or targets of branches, and the rest of the addresses are in
+/// brackets.
+fn fits(info: &OpcodeInfo, operands: &[Written]) -> bool {
+    let branch = info.instruction.may_branch();
+    let mut kinds = info.operands.iter();
+    operands.iter().all(|operand| match operand {
+        Written::Value(_) => match kinds.next() {
+            Some(Operand::Literal) => true,
+            Some(Operand::Address) => branch,
+            _ => false,
+        },
+        Written::Register(_) => kinds.next() == Some(&Operand::Register),
+        Written::Pointer(_) => kinds.next() == Some(&Operand::RegisterPointer),
+        Written::Memory(_) => !branch && kinds.next() == Some(&Operand::Address),
+        Written::Indexed(..) => {
+            kinds.next() == Some(&Operand::Address)
+                && kinds.next() == Some(&Operand::OffsetRegister)
+        }
+        Written::Frame(..) => kinds.next() == Some(&Operand::FrameOffset),
+    }) && kinds.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Assembler;
+    use crate::cpu::{opcode_info, Instruction, Register};
+    use crate::disassembler::disassemble;
+
+    /// The image disassembled, one instruction a line
+    fn listing(image: &[u8], start: usize) -> Vec<String> {
+        disassemble(image, start)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    }
+
+    #[test]
+    fn reads_what_the_disassembler_writes() {
+        // Every built-in instruction, with operand bytes naming r1 to r8
+        let mut code = Vec::new();
+        for opcode in 0..=u8::MAX {
+            let Some(info) = opcode_info(opcode) else {
+                continue;
+            };
+            code.push(opcode);
+            code.extend((1..info.length()).map(|i| Register::Register1 as u8 + i as u8 % 8));
+        }
+        let source = listing(&code, 0).join("\n");
+
+        assert_eq!(Assembler::new().assemble(&source), Ok(code));
+    }
+
+    #[test]
+    fn places_labels() {
+        let source = "
+            start:
+                mov 0x0003, r1      ; count down from 3
+            loop: dec r1
+                jne 0x0000, loop
+                mov [table + r1], r2
+            table: db 0x01, 2
+        ";
+        let image = Assembler::new().origin(0x0100).assemble(source).unwrap();
+        assert_eq!(
+            listing(&image[..16], 0x0100),
+            [
+                "mov 0x0003, r1",
+                "dec r1",
+                "jne 0x0000, 0x0104",
+                "mov [0x0110 + r1], r2"
+            ]
+        );
+        assert_eq!(image[16..], [0x01, 0x02]);
+
+        let assemble = |source: &str| Assembler::new().assemble(source).unwrap_err();
+        assert_eq!(
+            assemble("start: jmp [start + r1]"),
+            "Line 1: jmp doesn't take these operands"
+        );
+        assert_eq!(
+            assemble("start: nop\ndb start, 0x100"),
+            "Line 2: value 0x100 is more than a byte"
+        );
+        assert_eq!(assemble("jmp end"), "Line 1: end is not defined");
+        assert_eq!(
+            assemble("mov r1, [r2 + r3]"),
+            "Line 1: [r2 + r3] is not a memory operand"
+        );
+        assert_eq!(assemble("a: nop\na: nop"), "Line 2: a is already defined");
+        assert_eq!(assemble("jump 0x0000"), "Line 1: unknown instruction jump");
+    }
+
+    #[test]
+    fn assembles_for_each_profile() {
+        let source = "
+            .define SCREEN_BASE 0x3000
+            .if SCREEN
+                mov 0x0041, r1
+                mov r1, [SCREEN_BASE]
+            .elif SERIAL == 2
+                .if !FAST
+                    nop
+                .endif
+                cal print
+            .else
+                .define SILENT
+            .endif
+            .if SILENT
+                hlt
+            .endif
+            print: ret
+        ";
+        let assemble = |assembler: Assembler| listing(&assembler.assemble(source).unwrap(), 0);
+
+        assert_eq!(
+            assemble(Assembler::new().define("SCREEN", 1)),
+            ["mov 0x0041, r1", "mov r1, [0x3000]", "ret"]
+        );
+        assert_eq!(
+            assemble(Assembler::new().define("SERIAL", 2)),
+            ["nop", "cal 0x0004", "ret"]
+        );
+        assert_eq!(
+            assemble(Assembler::new().define("SERIAL", 2).define("FAST", 1)),
+            ["cal 0x0003", "ret"]
+        );
+        assert_eq!(assemble(Assembler::new()), ["hlt", "ret"]);
+        assert_eq!(
+            Assembler::new().define("SCREEN", 0).assemble(source),
+            Ok(vec![Instruction::Halt as u8, Instruction::Ret as u8])
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_conditionals() {
+        let assemble = |source: &str| Assembler::new().assemble(source).unwrap_err();
+
+        assert_eq!(
+            assemble(".if A\n.if B\n.endif"),
+            "Line 1: .if without .endif"
+        );
+        assert_eq!(assemble("nop\n.endif"), "Line 2: .endif without .if");
+        assert_eq!(assemble(".elif A"), "Line 1: .elif without .if");
+        assert_eq!(
+            assemble(".if A\n.else\n.elif B\n.endif"),
+            "Line 3: .elif after .else"
+        );
+        assert_eq!(
+            assemble(".if A\n.else\n.else\n.endif"),
+            "Line 3: .else after .else"
+        );
+        assert_eq!(assemble(".if\n.endif"), "Line 1: missing condition");
+        assert_eq!(
+            assemble(".define A\n.define A 2"),
+            "Line 2: A is already defined"
+        );
+        // Branches that aren't taken may hold anything but conditionals
+        assert_eq!(
+            Assembler::new().assemble(".if A\n.include x\nfoo r1\n.endif"),
+            Ok(Vec::new())
+        );
+        assert_eq!(assemble(".include x"), "Line 1: unknown directive .include");
+    }
+}
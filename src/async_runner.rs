@@ -0,0 +1,222 @@
+use crate::cpu::{Cpu, StopReason};
+use crate::ring::{InputRing, OutputRing};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Number of instructions executed between two yields to the reactor
+pub const YIELD_INTERVAL: usize = 1024;
+
+/// Bytes `feed` reads at a time
+const FEED_CHUNK: usize = 256;
+
+impl Cpu {
+    /// Executes `n` instructions, yielding back to the async runtime every
+    /// `YIELD_INTERVAL` instructions so other tasks on the reactor can make
+    /// progress. Stops early at the first fault or guest panic, or once the
+    /// CPU halts.
+    pub async fn run_async(&mut self, n: usize) -> StopReason {
+        let mut remaining = n;
+        while remaining > 0 {
+            let chunk = remaining.min(YIELD_INTERVAL);
+            match self.run(chunk) {
+                StopReason::FuelExhausted => {}
+                reason @ (StopReason::Fault(_)
+                | StopReason::GuestPanic { .. }
+                | StopReason::Halted) => return reason,
+            }
+            remaining -= chunk;
+            tokio::task::yield_now().await;
+        }
+        StopReason::FuelExhausted
+    }
+}
+
+/// Copies `reader`, like a TCP stream or a serial port, into `ring` until
+/// it ends, waiting for the guest to make room whenever the ring is full.
+/// Returns how many bytes it copied.
+pub async fn feed(ring: &InputRing, mut reader: impl AsyncRead + Unpin) -> io::Result<u64> {
+    let mut buffer = [0; FEED_CHUNK];
+    let mut copied = 0;
+    loop {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            return Ok(copied);
+        }
+        ring.write_all(&buffer[..count]).await;
+        copied += count as u64;
+    }
+}
+
+/// Copies everything the guest publishes in `ring` to `writer`, waiting for
+/// the guest in between. Only returns once writing fails, so run it as a
+/// task of its own and drop it when the guest is done.
+pub async fn drain(ring: &OutputRing, mut writer: impl AsyncWrite + Unpin) -> io::Result<()> {
+    loop {
+        let bytes = ring.read_async().await;
+        writer.write_all(&bytes).await?;
+        writer.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{drain, feed};
+    use crate::cpu::{Cpu, Instruction, Register, StopReason};
+    use crate::mapper::Device;
+    use crate::memory::Memory;
+    use crate::ring::{InputRing, OutputRing, RING_HEADER_SIZE};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn yields_to_other_tasks() {
+        let memory = Memory::new(256 * 256);
+        let mut cpu = Cpu::new(memory);
+
+        let ticks = Rc::new(Cell::new(0));
+        let local = tokio::task::LocalSet::new();
+        let counter = Rc::clone(&ticks);
+        local
+            .run_until(async move {
+                let background = tokio::task::spawn_local(async move {
+                    loop {
+                        counter.set(counter.get() + 1);
+                        tokio::task::yield_now().await;
+                    }
+                });
+                assert_eq!(
+                    cpu.run_async(super::YIELD_INTERVAL * 4).await,
+                    StopReason::FuelExhausted
+                );
+                background.abort();
+
+                assert_eq!(
+                    cpu.peek_register(Register::InstructionPointer) as usize,
+                    super::YIELD_INTERVAL * 4
+                );
+            })
+            .await;
+
+        assert!(ticks.get() >= 3, "Other tasks ran while the VM was busy");
+    }
+
+    #[tokio::test]
+    async fn stops_once_halted() {
+        let mut memory = Memory::new(256 * 256);
+        memory.set_byte(0x0800, Instruction::Halt as u8);
+        let mut cpu = Cpu::new(memory);
+
+        assert_eq!(
+            cpu.run_async(super::YIELD_INTERVAL * 4).await,
+            StopReason::Halted
+        );
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x0801);
+    }
+
+    /// Where the ring tests map their ring
+    const RING: usize = 0x1800;
+
+    /// A machine with `ring` mapped at `RING`, running `code` from 0
+    fn ring_machine(ring: impl Device + 'static, code: &[u8]) -> Cpu {
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .device("ring", ring, RING, RING + RING_HEADER_SIZE + 7)
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        cpu
+    }
+
+    #[tokio::test]
+    async fn feeds_the_guest_as_it_makes_room() {
+        // start:
+        //   mov [head], r1
+        //   mov r1, [tail] ;; takes everything
+        //   jne 0xffff, start:
+        let [head_high, head_low] = (RING as u16).to_be_bytes();
+        let [tail_high, tail_low] = (RING as u16 + 2).to_be_bytes();
+        let code = [
+            Instruction::MovMemReg as u8,
+            head_high,
+            head_low,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            tail_high,
+            tail_low,
+            Instruction::JmpNotEq as u8,
+            0xff,
+            0xff,
+            0x00,
+            0x00,
+        ];
+        let ring = InputRing::new(8);
+        let mut cpu = ring_machine(ring.clone(), &code);
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                // Far more than fits in the ring at once
+                let input = vec![0x42; 100];
+                let host = ring.clone();
+                let feeder = tokio::task::spawn_local(async move {
+                    feed(&host, input.as_slice()).await.unwrap()
+                });
+                while !feeder.is_finished() {
+                    assert_eq!(
+                        cpu.run_async(super::YIELD_INTERVAL).await,
+                        StopReason::FuelExhausted
+                    );
+                }
+                assert_eq!(feeder.await.unwrap(), 100);
+                cpu.run_async(3).await;
+                assert!(ring.is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn drains_what_the_guest_publishes() {
+        // mov 0x6162, r1
+        // mov r1, [buffer]
+        // mov 0x0002, r1
+        // mov r1, [head]
+        // hlt
+        let mut code = Vec::new();
+        for (value, address) in [(0x6162, RING + RING_HEADER_SIZE), (2, RING)] {
+            let [value_high, value_low] = u16::to_be_bytes(value);
+            let [address_high, address_low] = (address as u16).to_be_bytes();
+            code.extend([
+                Instruction::MovLitReg as u8,
+                value_high,
+                value_low,
+                Register::Register1 as u8,
+                Instruction::MovRegMem as u8,
+                Register::Register1 as u8,
+                address_high,
+                address_low,
+            ]);
+        }
+        code.push(Instruction::Halt as u8);
+        let ring = OutputRing::new(8);
+        let mut cpu = ring_machine(ring.clone(), &code);
+
+        let (writer, mut reader) = tokio::io::duplex(64);
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let drainer = tokio::task::spawn_local(async move { drain(&ring, writer).await });
+                tokio::task::yield_now().await;
+                assert_eq!(cpu.run_async(100).await, StopReason::Halted);
+
+                let mut output = [0; 2];
+                reader.read_exact(&mut output).await.unwrap();
+                assert_eq!(&output, b"ab");
+                drainer.abort();
+            })
+            .await;
+    }
+}
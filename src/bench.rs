@@ -0,0 +1,267 @@
+use crate::cpu::{Cpu, Instruction, Register, StopReason};
+use crate::memory::Memory;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const MEMORY_SIZE: usize = 0x10000;
+
+const MOV_LIT_REG: u8 = Instruction::MovLitReg as u8;
+const MOV_REG_REG: u8 = Instruction::MovRegReg as u8;
+const MOV_REG_MEM: u8 = Instruction::MovRegMem as u8;
+const MOV_MEM_REG: u8 = Instruction::MovMemReg as u8;
+const ADD_REG_REG: u8 = Instruction::AddRegReg as u8;
+const JMP_NOT_EQ: u8 = Instruction::JmpNotEq as u8;
+const PSH_LIT: u8 = Instruction::PushLit as u8;
+const PSH_REG: u8 = Instruction::PushReg as u8;
+const POP: u8 = Instruction::Pop as u8;
+const CAL_LIT: u8 = Instruction::CalLit as u8;
+const RET: u8 = Instruction::Ret as u8;
+
+const ACC: u8 = Register::Accumulator as u8;
+const R1: u8 = Register::Register1 as u8;
+const R2: u8 = Register::Register2 as u8;
+const R3: u8 = Register::Register3 as u8;
+const R6: u8 = Register::Register6 as u8;
+
+/// Loads code at the given addresses into a full address space
+fn image(parts: &[(usize, &[u8])]) -> Memory {
+    let mut memory = Memory::new(MEMORY_SIZE);
+    for (start, code) in parts {
+        for (i, byte) in code.iter().enumerate() {
+            memory.set_byte(start + i, *byte);
+        }
+    }
+    memory
+}
+
+/// Register to register moves and additions in a tight loop
+#[rustfmt::skip]
+pub fn dispatch_loop() -> Memory {
+    image(&[(
+        0x0000,
+        &[
+            // start:
+            //   mov 0x0000, r1
+            // loop:
+            //   mov 0x0001, r2
+            //   mov r2, r3
+            //   add r1, r3
+            //   mov acc, r1
+            //   jne 0xffff, loop:
+            //   jne 0x0000, start:
+            MOV_LIT_REG, 0x00, 0x00, R1,
+            MOV_LIT_REG, 0x00, 0x01, R2,
+            MOV_REG_REG, R2, R3,
+            ADD_REG_REG, R1, R3,
+            MOV_REG_REG, ACC, R1,
+            JMP_NOT_EQ, 0xff, 0xff, 0x00, 0x04,
+            JMP_NOT_EQ, 0x00, 0x00, 0x00, 0x00,
+        ],
+    )])
+}
+
+/// Pushes and pops in a tight loop
+#[rustfmt::skip]
+pub fn stack_loop() -> Memory {
+    image(&[(
+        0x0000,
+        &[
+            // start:
+            //   psh 0x1234
+            //   psh r1
+            //   pop r2
+            //   pop r3
+            //   jne 0x0001, start:
+            PSH_LIT, 0x12, 0x34,
+            PSH_REG, R1,
+            POP, R2,
+            POP, R3,
+            JMP_NOT_EQ, 0x00, 0x01, 0x00, 0x00,
+        ],
+    )])
+}
+
+/// Loads and stores in a tight loop
+#[rustfmt::skip]
+pub fn memory_loop() -> Memory {
+    image(&[(
+        0x0000,
+        &[
+            // start:
+            //   mov [0x8000], r1
+            //   mov r1, [0x8002]
+            //   mov [0x8002], r2
+            //   mov r2, [0x8000]
+            //   jne 0x0001, start:
+            MOV_MEM_REG, 0x80, 0x00, R1,
+            MOV_REG_MEM, R1, 0x80, 0x02,
+            MOV_MEM_REG, 0x80, 0x02, R2,
+            MOV_REG_MEM, R2, 0x80, 0x00,
+            JMP_NOT_EQ, 0x00, 0x01, 0x00, 0x00,
+        ],
+    )])
+}
+
+/// A counting loop that calls a subroutine and goes through memory on every
+/// iteration, mixing all kinds of instructions
+#[rustfmt::skip]
+pub fn standard_workload() -> Memory {
+    image(&[
+        (
+            0x0000,
+            &[
+                // start:
+                //   mov 0x0000, r1
+                // loop:
+                //   psh r1
+                //   psh 0x0001
+                //   cal subroutine:
+                //   mov acc, [0x8000]
+                //   mov [0x8000], r3
+                //   mov 0x0001, r2
+                //   add r1, r2
+                //   mov acc, r1
+                //   jne 0xffff, loop:
+                //   jne 0x0000, start:
+                MOV_LIT_REG, 0x00, 0x00, R1,
+                PSH_REG, R1,
+                PSH_LIT, 0x00, 0x01,
+                CAL_LIT, 0x01, 0x00,
+                MOV_REG_MEM, ACC, 0x80, 0x00,
+                MOV_MEM_REG, 0x80, 0x00, R3,
+                MOV_LIT_REG, 0x00, 0x01, R2,
+                ADD_REG_REG, R1, R2,
+                MOV_REG_REG, ACC, R1,
+                JMP_NOT_EQ, 0xff, 0xff, 0x00, 0x04,
+                JMP_NOT_EQ, 0x00, 0x00, 0x00, 0x00,
+            ],
+        ),
+        (
+            0x0100,
+            &[
+                // subroutine:
+                //   mov 0x0003, r6
+                //   add r6, r6
+                //   ret
+                MOV_LIT_REG, 0x00, 0x03, R6,
+                ADD_REG_REG, R6, R6,
+                RET,
+            ],
+        ),
+    ])
+}
+
+/// Ways of executing guest code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Step,
+    Run,
+    Cached,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+impl Engine {
+    pub fn execute(self, cpu: &mut Cpu, instructions: usize) -> StopReason {
+        match self {
+            Engine::Step => match cpu.step_n(instructions) {
+                Ok(()) => cpu.stop_reason_without_fault(),
+                Err(fault) => cpu.stop_reason(fault),
+            },
+            Engine::Run => cpu.run(instructions),
+            Engine::Cached => cpu.run_cached(instructions),
+            #[cfg(feature = "jit")]
+            Engine::Jit => cpu.run_jit(instructions),
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Engine, String> {
+        match name {
+            "step" => Ok(Engine::Step),
+            "run" => Ok(Engine::Run),
+            "cached" => Ok(Engine::Cached),
+            #[cfg(feature = "jit")]
+            "jit" => Ok(Engine::Jit),
+            _ => Err(format!("Unknown engine: {}", name)),
+        }
+    }
+}
+
+/// Outcome of running a workload
+#[derive(Debug)]
+pub struct Measurement {
+    pub engine: Engine,
+    pub instructions: u64,
+    pub elapsed: Duration,
+    pub stop: StopReason,
+}
+
+impl Measurement {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?}: {} instructions in {:.3}s ({:.1}M instructions/s)",
+            self.engine,
+            self.instructions,
+            self.elapsed.as_secs_f64(),
+            self.instructions_per_second() / 1e6
+        )?;
+        match &self.stop {
+            StopReason::FuelExhausted | StopReason::Halted => {}
+            StopReason::Fault(fault) => write!(f, ", stopped by {}", fault)?,
+            StopReason::GuestPanic { message, code } => write!(
+                f,
+                ", stopped by a guest panic with code {:#06x}: {}",
+                code, message
+            )?,
+        }
+        Ok(())
+    }
+}
+
+/// Runs the standard workload for `instructions` instructions
+pub fn measure(engine: Engine, instructions: usize) -> Measurement {
+    let mut cpu = Cpu::new(standard_workload());
+    let start = Instant::now();
+    let stop = engine.execute(&mut cpu, instructions);
+    Measurement {
+        engine,
+        instructions: cpu.instruction_count(),
+        elapsed: start.elapsed(),
+        stop,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dispatch_loop, memory_loop, stack_loop, standard_workload, Engine};
+    use crate::cpu::{Cpu, StopReason};
+
+    #[test]
+    fn workloads_run_forever() {
+        for workload in [dispatch_loop, stack_loop, memory_loop, standard_workload] {
+            let mut cpu = Cpu::new(workload());
+            assert_eq!(
+                Engine::Run.execute(&mut cpu, 1_000_000),
+                StopReason::FuelExhausted
+            );
+        }
+    }
+
+    #[test]
+    fn parses_engine_names() {
+        assert_eq!("cached".parse(), Ok(Engine::Cached));
+        assert!("fast".parse::<Engine>().is_err());
+    }
+}
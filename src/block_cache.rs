@@ -0,0 +1,277 @@
+use crate::cpu::{Cpu, Fault, OpcodeInfo, Operands, Register, StopReason};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Longest run of instructions decoded into a single block
+const MAX_BLOCK_LENGTH: usize = 64;
+const ADDRESS_SPACE_SIZE: usize = 0x10000;
+
+/// An instruction whose operands have already been read and checked
+#[derive(Clone, Copy)]
+pub(crate) struct DecodedInstruction {
+    pub(crate) info: &'static OpcodeInfo,
+    pub(crate) operands: Operands,
+    /// Address of the following instruction
+    pub(crate) next: usize,
+}
+
+struct Block {
+    end: usize,
+    instructions: Arc<[DecodedInstruction]>,
+}
+
+/// Straight-line runs of decoded instructions keyed by their start address.
+/// Blocks end at the first instruction that may change control flow.
+pub(crate) struct BlockCache {
+    blocks: HashMap<usize, Block>,
+    /// One bit per byte that belongs to a cached block
+    code: Vec<u64>,
+    /// Bumped whenever blocks are dropped
+    pub(crate) generation: u64,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        BlockCache {
+            blocks: HashMap::new(),
+            code: vec![0; ADDRESS_SPACE_SIZE / 64],
+            generation: 0,
+        }
+    }
+}
+
+impl BlockCache {
+    fn insert(&mut self, start: usize, block: Block) {
+        self.mark(start, block.end);
+        self.blocks.insert(start, block);
+    }
+
+    fn mark(&mut self, start: usize, end: usize) {
+        for address in start..end.min(ADDRESS_SPACE_SIZE) {
+            self.code[address / 64] |= 1 << (address % 64);
+        }
+    }
+
+    fn is_code(&self, address: usize) -> bool {
+        address < ADDRESS_SPACE_SIZE && self.code[address / 64] & 1 << (address % 64) != 0
+    }
+
+    /// Drops every block that decodes a byte in `address..address + length`
+    pub(crate) fn invalidate(&mut self, address: usize, length: usize) {
+        let end = address + length;
+        if !(address..end).any(|address| self.is_code(address)) {
+            return;
+        }
+
+        self.blocks
+            .retain(|&start, block| block.end <= address || start >= end);
+        self.code.fill(0);
+        let ranges: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|(&start, block)| (start, block.end))
+            .collect();
+        for (start, end) in ranges {
+            self.mark(start, end);
+        }
+        self.generation += 1;
+    }
+
+    pub(crate) fn clear(&mut self) {
+        if !self.blocks.is_empty() {
+            self.blocks.clear();
+            self.code.fill(0);
+            self.generation += 1;
+        }
+    }
+}
+
+impl Cpu {
+    /// Runs like `run`, but decodes straight-line code once and replays the
+    /// decoded form on later visits. Writes the CPU makes into cached code
+    /// drop the affected blocks; writes by other bus masters are not seen.
+    pub fn run_cached(&mut self, fuel: usize) -> StopReason {
+        let mut executed = 0;
+        while executed < fuel && !self.halted {
+            match self.run_block(fuel - executed) {
+                Ok(count) => executed += count,
+                Err(fault) => return self.stop_reason(fault),
+            }
+        }
+        self.stop_reason_without_fault()
+    }
+
+    /// Executes at most `fuel` instructions of the block at the instruction
+    /// pointer, or a single instruction if there is none. Returns how many
+    /// instructions ran.
+    pub(crate) fn run_block(&mut self, fuel: usize) -> Result<usize, Fault> {
+        let ip = self.peek_register(Register::InstructionPointer) as usize;
+        let block = match self.has_pending_interrupts() {
+            false => self.cached_block(ip),
+            true => None,
+        };
+        let Some(instructions) = block else {
+            // Custom, unknown or faulting instructions and device interrupts
+            // go the slow way
+            self.step()?;
+            return Ok(1);
+        };
+
+        let generation = self.block_cache.generation;
+        let mut executed = 0;
+        for instruction in instructions.iter().take(fuel) {
+            executed += 1;
+
+            let length = instruction.info.length();
+            self.mark_executed(instruction.next - length, length);
+            #[cfg(feature = "instrument")]
+            self.notify_instruction();
+            self.set_register(Register::InstructionPointer, instruction.next as u16);
+            let result = (instruction.info.execute)(self, instruction.operands);
+            self.retire();
+            result?;
+
+            if self.peek_register(Register::InstructionPointer) as usize != instruction.next
+                || self.block_cache.generation != generation
+                || self.has_pending_interrupts()
+                || self.halted
+            {
+                break;
+            }
+        }
+        Ok(executed)
+    }
+
+    pub(crate) fn cached_block(&mut self, start: usize) -> Option<Arc<[DecodedInstruction]>> {
+        if let Some(block) = self.block_cache.blocks.get(&start) {
+            return Some(Arc::clone(&block.instructions));
+        }
+
+        let mut instructions = Vec::new();
+        let mut address = start;
+        while instructions.len() < MAX_BLOCK_LENGTH {
+            let Some((info, operands)) = self.decode_at(address) else {
+                break;
+            };
+            address += info.length();
+            instructions.push(DecodedInstruction {
+                info,
+                operands,
+                next: address,
+            });
+            if info.instruction.may_branch() {
+                break;
+            }
+        }
+
+        if instructions.is_empty() {
+            return None;
+        }
+
+        let instructions: Arc<[DecodedInstruction]> = instructions.into();
+        self.block_cache.insert(
+            start,
+            Block {
+                end: address,
+                instructions: Arc::clone(&instructions),
+            },
+        );
+        Some(instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Cpu, Instruction, Register, StopReason};
+    use crate::memory::Memory;
+
+    // start:
+    //   mov 0x0001, r2
+    //   add r1, r2
+    //   mov acc, r1
+    //   jne 0x0010, start:
+    fn counting_loop() -> Memory {
+        let mut memory = Memory::new(256);
+        let program = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x01,
+            Register::Register2 as u8,
+            Instruction::AddRegReg as u8,
+            Register::Register1 as u8,
+            Register::Register2 as u8,
+            Instruction::MovRegReg as u8,
+            Register::Accumulator as u8,
+            Register::Register1 as u8,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x10,
+            0x00,
+            0x00,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.set_byte(i, *byte);
+        }
+        memory
+    }
+
+    #[test]
+    fn run_cached_matches_step_n() {
+        let mut stepped = Cpu::new(counting_loop());
+        let mut cached = Cpu::new(counting_loop());
+
+        for fuel in [1, 3, 10, 25] {
+            stepped.step_n(fuel).unwrap();
+            assert_eq!(cached.run_cached(fuel), StopReason::FuelExhausted);
+            assert_eq!(
+                stepped.registers().collect::<Vec<_>>(),
+                cached.registers().collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(cached.instruction_count(), 39);
+    }
+
+    #[test]
+    fn drops_blocks_overwritten_by_the_program() {
+        // start:
+        //   mov 0x1111, r1
+        //   mov r2, start: + 1
+        //   jne 0x0001, start:
+        let mut memory = Memory::new(256);
+        let program = [
+            Instruction::MovLitReg as u8,
+            0x11,
+            0x11,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register2 as u8,
+            0x00,
+            0x01,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.set_byte(i, *byte);
+        }
+
+        let mut cpu = Cpu::new(memory);
+        cpu.set_register(Register::Register2, 0x2222);
+        cpu.run_cached(4);
+
+        assert_eq!(cpu.peek_register(Register::Register1), 0x2222);
+    }
+
+    #[test]
+    fn drops_blocks_when_memory_is_borrowed() {
+        let mut cpu = Cpu::new(counting_loop());
+        cpu.run_cached(4);
+        assert_eq!(cpu.peek_register(Register::Register1), 1);
+
+        cpu.memory_mut().set_word(1, 0x0005);
+        cpu.run_cached(4);
+        assert_eq!(cpu.peek_register(Register::Register1), 6);
+    }
+}
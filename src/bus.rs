@@ -0,0 +1,502 @@
+use crate::mapper::Device;
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+/// Bytes moved by a bus access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word,
+}
+
+impl Width {
+    /// Reads from `device` directly
+    pub fn read(self, device: &mut dyn Device, address: usize) -> u16 {
+        match self {
+            Width::Byte => device.get_byte(address) as u16,
+            Width::Word => device.get_word(address),
+        }
+    }
+
+    /// Writes to `device` directly, bytes taking the low byte of `value`
+    pub fn write(self, device: &mut dyn Device, address: usize, value: u16) {
+        match self {
+            Width::Byte => device.set_byte(address, value as u8),
+            Width::Word => device.set_word(address, value),
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+        }
+    }
+}
+
+/// An access that went through a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub address: usize,
+    pub width: Width,
+    pub value: u16,
+    pub write: bool,
+}
+
+/// Middleware for the memory bus. Every read and write goes through the
+/// layer on its way to `next`, the layers below and the bus they wrap, so
+/// the layer can look at it, change it or answer it itself. The defaults
+/// pass everything through.
+pub trait Layer: Send {
+    fn read(&mut self, next: &mut dyn Device, address: usize, width: Width) -> u16 {
+        width.read(next, address)
+    }
+
+    fn write(&mut self, next: &mut dyn Device, address: usize, width: Width, value: u16) {
+        width.write(next, address, value);
+    }
+
+    fn wait_states(&self, next: &dyn Device, address: usize, write: bool) -> u64 {
+        next.wait_states(address, write)
+    }
+}
+
+impl<L: Layer + ?Sized> Layer for Box<L> {
+    fn read(&mut self, next: &mut dyn Device, address: usize, width: Width) -> u16 {
+        (**self).read(next, address, width)
+    }
+
+    fn write(&mut self, next: &mut dyn Device, address: usize, width: Width, value: u16) {
+        (**self).write(next, address, width, value);
+    }
+
+    fn wait_states(&self, next: &dyn Device, address: usize, write: bool) -> u64 {
+        (**self).wait_states(next, address, write)
+    }
+}
+
+/// `device` with `layer` on top. Peeks and ticks go straight to the device,
+/// so debuggers see the bus as it is.
+pub struct Layered<D, L> {
+    device: D,
+    layer: L,
+}
+
+impl<D: Device, L: Layer> Layered<D, L> {
+    pub fn new(device: D, layer: L) -> Layered<D, L> {
+        Layered { device, layer }
+    }
+
+    /// Puts another layer on top of this one
+    pub fn layer<M: Layer>(self, layer: M) -> Layered<Self, M> {
+        Layered::new(self, layer)
+    }
+}
+
+impl<D: Device, L: Layer> Device for Layered<D, L> {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.layer.read(&mut self.device, address, Width::Byte) as u8
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.layer
+            .write(&mut self.device, address, Width::Byte, value as u16);
+    }
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        self.layer.read(&mut self.device, address, Width::Word)
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        self.layer
+            .write(&mut self.device, address, Width::Word, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.device.peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        self.device.byte_length()
+    }
+
+    fn is_mapped(&self, address: usize) -> bool {
+        self.device.is_mapped(address)
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        self.device.tick(cycles, interrupts);
+    }
+
+    fn wait_states(&self, address: usize, write: bool) -> u64 {
+        self.layer.wait_states(&self.device, address, write)
+    }
+}
+
+/// Records every access. Clones share the log, so keep one to read it.
+#[derive(Clone, Default)]
+pub struct LoggingLayer {
+    log: Arc<Mutex<Vec<BusAccess>>>,
+}
+
+impl LoggingLayer {
+    pub fn new() -> LoggingLayer {
+        LoggingLayer::default()
+    }
+
+    /// Hands over the accesses logged since the last call
+    pub fn take_log(&self) -> Vec<BusAccess> {
+        std::mem::take(&mut self.log.lock().unwrap())
+    }
+}
+
+impl Layer for LoggingLayer {
+    fn read(&mut self, next: &mut dyn Device, address: usize, width: Width) -> u16 {
+        let value = width.read(next, address);
+        self.log.lock().unwrap().push(BusAccess {
+            address,
+            width,
+            value,
+            write: false,
+        });
+        value
+    }
+
+    fn write(&mut self, next: &mut dyn Device, address: usize, width: Width, value: u16) {
+        width.write(next, address, value);
+        self.log.lock().unwrap().push(BusAccess {
+            address,
+            width,
+            value,
+            write: true,
+        });
+    }
+}
+
+/// Keeps accesses out of protected ranges: writes there are dropped and
+/// reads give 0. Ranges added later take precedence. Clones share the
+/// violations, so keep one to look at them.
+#[derive(Clone, Default)]
+pub struct PermissionLayer {
+    /// Ranges with whether they may be read and written, latest last
+    ranges: Vec<(RangeInclusive<usize>, bool, bool)>,
+    violations: Arc<Mutex<Vec<BusAccess>>>,
+}
+
+impl PermissionLayer {
+    /// Allows everything until ranges are protected
+    pub fn new() -> PermissionLayer {
+        PermissionLayer::default()
+    }
+
+    pub fn read_only(mut self, range: RangeInclusive<usize>) -> PermissionLayer {
+        self.ranges.push((range, true, false));
+        self
+    }
+
+    pub fn no_access(mut self, range: RangeInclusive<usize>) -> PermissionLayer {
+        self.ranges.push((range, false, false));
+        self
+    }
+
+    /// Lifts the protection of an earlier, bigger range
+    pub fn read_write(mut self, range: RangeInclusive<usize>) -> PermissionLayer {
+        self.ranges.push((range, true, true));
+        self
+    }
+
+    /// Hands over the accesses denied since the last call
+    pub fn take_violations(&self) -> Vec<BusAccess> {
+        std::mem::take(&mut self.violations.lock().unwrap())
+    }
+
+    /// Whether every byte of the access is allowed
+    fn allows(&self, address: usize, width: Width, write: bool) -> bool {
+        (address..address + width.bytes()).all(|address| {
+            match self
+                .ranges
+                .iter()
+                .rfind(|(range, _, _)| range.contains(&address))
+            {
+                Some((_, read, _)) if !write => *read,
+                Some((_, _, written)) => *written,
+                None => true,
+            }
+        })
+    }
+
+    fn deny(&self, address: usize, width: Width, value: u16, write: bool) {
+        self.violations.lock().unwrap().push(BusAccess {
+            address,
+            width,
+            value,
+            write,
+        });
+    }
+}
+
+impl Layer for PermissionLayer {
+    fn read(&mut self, next: &mut dyn Device, address: usize, width: Width) -> u16 {
+        if !self.allows(address, width, false) {
+            self.deny(address, width, 0, false);
+            return 0;
+        }
+        width.read(next, address)
+    }
+
+    fn write(&mut self, next: &mut dyn Device, address: usize, width: Width, value: u16) {
+        if !self.allows(address, width, true) {
+            self.deny(address, width, value, true);
+            return;
+        }
+        width.write(next, address, value);
+    }
+}
+
+/// Records accesses touching any watched byte. Unlike the watchpoints of
+/// `instrument` it sees all of the traffic, fetches included, and needs no
+/// feature. Clones share the hits, so keep one to look at them.
+#[derive(Clone, Default)]
+pub struct WatchLayer {
+    addresses: BTreeSet<usize>,
+    hits: Arc<Mutex<Vec<BusAccess>>>,
+}
+
+impl WatchLayer {
+    pub fn new(addresses: impl IntoIterator<Item = usize>) -> WatchLayer {
+        WatchLayer {
+            addresses: addresses.into_iter().collect(),
+            hits: Arc::default(),
+        }
+    }
+
+    /// Hands over the hits recorded since the last call
+    pub fn take_hits(&self) -> Vec<BusAccess> {
+        std::mem::take(&mut self.hits.lock().unwrap())
+    }
+
+    fn record(&self, access: BusAccess) {
+        let end = access.address + access.width.bytes();
+        if self.addresses.range(access.address..end).next().is_some() {
+            self.hits.lock().unwrap().push(access);
+        }
+    }
+}
+
+impl Layer for WatchLayer {
+    fn read(&mut self, next: &mut dyn Device, address: usize, width: Width) -> u16 {
+        let value = width.read(next, address);
+        self.record(BusAccess {
+            address,
+            width,
+            value,
+            write: false,
+        });
+        value
+    }
+
+    fn write(&mut self, next: &mut dyn Device, address: usize, width: Width, value: u16) {
+        width.write(next, address, value);
+        self.record(BusAccess {
+            address,
+            width,
+            value,
+            write: true,
+        });
+    }
+}
+
+/// Bytes in a cache line
+pub const CACHE_LINE_SIZE: usize = 8;
+
+#[derive(Default)]
+struct Cache {
+    /// Tag and bytes of each line, by index
+    lines: Vec<Option<(usize, [u8; CACHE_LINE_SIZE])>>,
+    hits: u64,
+    misses: u64,
+}
+
+/// A direct mapped, write through read cache, for slow devices: reads of
+/// cached lines stall for nothing and don't reach the device. Only put it
+/// over devices whose reads have no side effects. Clones share the cache.
+#[derive(Clone)]
+pub struct CacheLayer {
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl CacheLayer {
+    /// A cache of `lines` lines of `CACHE_LINE_SIZE` bytes, all empty
+    pub fn new(lines: usize) -> CacheLayer {
+        let cache = Cache {
+            lines: vec![None; lines.max(1)],
+            ..Cache::default()
+        };
+        CacheLayer {
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    /// Reads served from the cache and reads that went to the device
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        let cache = self.cache.lock().unwrap();
+        (cache.hits, cache.misses)
+    }
+}
+
+impl Cache {
+    fn slot(&self, address: usize) -> (usize, usize) {
+        let line = address / CACHE_LINE_SIZE;
+        (line % self.lines.len(), line)
+    }
+
+    fn cached_byte(&self, address: usize) -> Option<u8> {
+        let (index, tag) = self.slot(address);
+        match self.lines[index] {
+            Some((cached, bytes)) if cached == tag => Some(bytes[address % CACHE_LINE_SIZE]),
+            _ => None,
+        }
+    }
+
+    /// The byte at `address`, filling its line from `next` on a miss
+    fn byte(&mut self, next: &mut dyn Device, address: usize) -> u8 {
+        if let Some(byte) = self.cached_byte(address) {
+            self.hits += 1;
+            return byte;
+        }
+        self.misses += 1;
+        let (index, tag) = self.slot(address);
+        let start = tag * CACHE_LINE_SIZE;
+        let mut bytes = [0; CACHE_LINE_SIZE];
+        let length = next.byte_length();
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            if start + offset < length {
+                *byte = next.get_byte(start + offset);
+            }
+        }
+        self.lines[index] = Some((tag, bytes));
+        bytes[address % CACHE_LINE_SIZE]
+    }
+
+    fn update(&mut self, address: usize, value: u8) {
+        let (index, tag) = self.slot(address);
+        if let Some((cached, bytes)) = &mut self.lines[index] {
+            if *cached == tag {
+                bytes[address % CACHE_LINE_SIZE] = value;
+            }
+        }
+    }
+}
+
+impl Layer for CacheLayer {
+    fn read(&mut self, next: &mut dyn Device, address: usize, width: Width) -> u16 {
+        let mut cache = self.cache.lock().unwrap();
+        match width {
+            Width::Byte => cache.byte(next, address) as u16,
+            Width::Word => {
+                u16::from_be_bytes([cache.byte(next, address), cache.byte(next, address + 1)])
+            }
+        }
+    }
+
+    fn write(&mut self, next: &mut dyn Device, address: usize, width: Width, value: u16) {
+        width.write(next, address, value);
+        let mut cache = self.cache.lock().unwrap();
+        match width {
+            Width::Byte => cache.update(address, value as u8),
+            Width::Word => {
+                let [high, low] = value.to_be_bytes();
+                cache.update(address, high);
+                cache.update(address + 1, low);
+            }
+        }
+    }
+
+    fn wait_states(&self, next: &dyn Device, address: usize, write: bool) -> u64 {
+        let cache = self.cache.lock().unwrap();
+        let cached =
+            cache.cached_byte(address).is_some() && cache.cached_byte(address + 1).is_some();
+        match cached && !write {
+            true => 0,
+            false => next.wait_states(address, write),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BusAccess, CacheLayer, Layered, LoggingLayer, PermissionLayer, WatchLayer, Width};
+    use crate::mapper::{AddressSpace, Device, WaitStates};
+    use crate::memory::Memory;
+
+    #[test]
+    fn layers_stack_over_the_bus() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(256), 0x00, 0xff, false);
+        let log = LoggingLayer::new();
+        let watch = WatchLayer::new([0x41]);
+        let permissions = PermissionLayer::new()
+            .read_only(0x00..=0x3f)
+            .no_access(0x80..=0xff)
+            .read_write(0xf0..=0xff);
+        let mut bus = Layered::new(space, log.clone())
+            .layer(watch.clone())
+            .layer(permissions.clone());
+
+        bus.set_word(0x40, 0x4243);
+        bus.set_word(0x10, 0x1111);
+        assert_eq!(bus.get_word(0x40), 0x4243);
+        assert_eq!(bus.get_byte(0x80), 0, "No access");
+        bus.set_byte(0xf0, 0xf0);
+        assert_eq!(bus.get_byte(0xf0), 0xf0, "Allowed again");
+
+        assert_eq!(bus.peek(0x10, 2), [0, 0], "Read only");
+        let violations = permissions.take_violations();
+        assert_eq!(violations.len(), 2);
+        assert!(violations[0].write);
+        assert_eq!(violations[1].address, 0x80);
+
+        assert_eq!(
+            watch.take_hits(),
+            [
+                BusAccess {
+                    address: 0x40,
+                    width: Width::Word,
+                    value: 0x4243,
+                    write: true,
+                },
+                BusAccess {
+                    address: 0x40,
+                    width: Width::Word,
+                    value: 0x4243,
+                    write: false,
+                },
+            ]
+        );
+        assert_eq!(
+            log.take_log().len(),
+            4,
+            "Denied accesses stop above the log"
+        );
+    }
+
+    #[test]
+    fn cached_reads_skip_the_wait_states() {
+        let mut slow = WaitStates::new(Memory::new(64), 4, 4);
+        slow.set_word(0x10, 0x1234);
+        let cache = CacheLayer::new(2);
+        let mut bus = Layered::new(slow, cache.clone());
+
+        assert_eq!(bus.wait_states(0x10, false), 4);
+        assert_eq!(bus.get_word(0x10), 0x1234);
+        assert_eq!(bus.wait_states(0x10, false), 0);
+        assert_eq!(bus.wait_states(0x10, true), 4);
+        bus.set_word(0x12, 0x5678);
+        assert_eq!(bus.get_word(0x12), 0x5678, "Writes go through");
+        // 0x20 maps to the same line as 0x10 and evicts it
+        bus.get_byte(0x20);
+        assert_eq!(bus.get_word(0x10), 0x1234);
+        assert_eq!(cache.hits_and_misses(), (4, 3));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::isa::{self, Features, IsaError, ISA_VERSION};
+use crate::mapper::Device;
+use crate::memory::{BankedRom, Rom, BANK_SIZE};
+
+/// What every cartridge file starts with
+pub const MAGIC: &[u8; 4] = b"R16C";
+
+/// Bytes before the first bank
+const HEADER_LENGTH: usize = 8;
+
+/// How a cartridge's banks show up in the cartridge slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapper {
+    /// Up to two banks, back to back, always there
+    Flat = 0,
+    /// Bank 0 and a bank picked by writing its number, see `BankedRom`
+    Banked = 1,
+}
+
+impl TryFrom<u8> for Mapper {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Mapper::Flat),
+            1 => Ok(Mapper::Banked),
+            value => Err(format!("Unknown mapper {}", value)),
+        }
+    }
+}
+
+/// A game for the fantasy console. The file is a header, then the banks,
+/// `BANK_SIZE` bytes each:
+///
+/// ```text
+/// "R16C" <mapper> <bank count> <ISA version> <required features>
+/// ```
+///
+/// Cartridges from before versioning have 0 for both, and run anywhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cartridge {
+    pub mapper: Mapper,
+    pub banks: Vec<Vec<u8>>,
+    /// Version of the instruction set the game was built for
+    pub isa_version: u8,
+    /// Extensions the game can't run without, only the low 8 feature bits
+    /// fit in the header
+    pub requires: Features,
+}
+
+impl Cartridge {
+    /// A flat cartridge holding a raw image, padded with zeroes to whole
+    /// banks
+    pub fn from_image(image: &[u8]) -> Result<Cartridge, String> {
+        if image.len() > 2 * BANK_SIZE {
+            return Err(format!(
+                "An image of {} bytes needs a banked cartridge",
+                image.len()
+            ));
+        }
+        Ok(Cartridge {
+            mapper: Mapper::Flat,
+            banks: banks_of(image),
+            isa_version: ISA_VERSION,
+            requires: Features::NONE,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cartridge, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..4] != MAGIC {
+            return Err("Not a cartridge".to_string());
+        }
+        let mapper = Mapper::try_from(bytes[4])?;
+        let count = bytes[5] as usize;
+        let banks = &bytes[HEADER_LENGTH..];
+        if banks.len() != count * BANK_SIZE {
+            return Err(format!(
+                "Expected {} banks, found {} bytes of them",
+                count,
+                banks.len()
+            ));
+        }
+        let cartridge = Cartridge {
+            mapper,
+            banks: banks_of(banks),
+            isa_version: bytes[6],
+            requires: Features::from_bits(bytes[7] as u16),
+        };
+        cartridge.validate()?;
+        Ok(cartridge)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([
+            self.mapper as u8,
+            self.banks.len() as u8,
+            self.isa_version,
+            self.requires.bits() as u8,
+        ]);
+        for bank in &self.banks {
+            bytes.extend(bank);
+        }
+        bytes
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let most = match self.mapper {
+            Mapper::Flat => 2,
+            Mapper::Banked => u8::MAX as usize,
+        };
+        if self.banks.is_empty() || self.banks.len() > most {
+            return Err(format!(
+                "A {:?} cartridge holds 1 to {} banks, not {}",
+                self.mapper,
+                most,
+                self.banks.len()
+            ));
+        }
+        if self.banks.iter().any(|bank| bank.len() != BANK_SIZE) {
+            return Err(format!("Banks have to be {} bytes", BANK_SIZE));
+        }
+        if self.requires.bits() > u8::MAX as u16 {
+            return Err(format!(
+                "Cartridges can only require the first 8 features, not {}",
+                self.requires
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a machine with `features` can run the game
+    pub fn check(&self, features: Features) -> Result<(), IsaError> {
+        isa::check(features, self.isa_version, self.requires)
+    }
+
+    /// What goes in the cartridge slot
+    pub fn into_device(self) -> Box<dyn Device> {
+        match self.mapper {
+            Mapper::Flat => Box::new(Rom::new(self.banks.concat())),
+            Mapper::Banked => Box::new(BankedRom::new(self.banks)),
+        }
+    }
+}
+
+fn banks_of(bytes: &[u8]) -> Vec<Vec<u8>> {
+    bytes
+        .chunks(BANK_SIZE)
+        .map(|chunk| {
+            let mut bank = chunk.to_vec();
+            bank.resize(BANK_SIZE, 0);
+            bank
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cartridge, Mapper};
+    use crate::console::{Console, CARTRIDGE_START};
+    use crate::cpu::{Instruction, Register};
+    use crate::isa::{Features, IsaError, ISA_VERSION};
+    use crate::memory::BANK_SIZE;
+
+    #[test]
+    fn reads_what_it_writes() {
+        let cartridge = Cartridge {
+            mapper: Mapper::Banked,
+            banks: vec![vec![1; BANK_SIZE], vec![2; BANK_SIZE], vec![3; BANK_SIZE]],
+            isa_version: 1,
+            requires: Features::FLOATING_POINT,
+        };
+        let bytes = cartridge.to_bytes();
+        assert_eq!(&bytes[..8], b"R16C\x01\x03\x01\x04");
+        assert_eq!(Cartridge::from_bytes(&bytes), Ok(cartridge));
+
+        assert_eq!(
+            Cartridge::from_bytes(&bytes[..bytes.len() - 1]),
+            Err("Expected 3 banks, found 49151 bytes of them".to_string())
+        );
+        let mut flat = bytes.clone();
+        flat[4] = 0;
+        assert_eq!(
+            Cartridge::from_bytes(&flat),
+            Err("A Flat cartridge holds 1 to 2 banks, not 3".to_string())
+        );
+        assert_eq!(
+            Cartridge::from_image(&[0xaa; 3]).unwrap().banks[0][..4],
+            [0xaa, 0xaa, 0xaa, 0]
+        );
+    }
+
+    #[test]
+    fn checks_what_the_game_needs() {
+        let mut bytes = Cartridge::from_image(&[0; 4]).unwrap().to_bytes();
+        bytes[6] = 0;
+        let unversioned = Cartridge::from_bytes(&bytes).unwrap();
+        assert_eq!(unversioned.check(Features::NONE), Ok(()));
+
+        bytes[6] = ISA_VERSION + 1;
+        assert_eq!(
+            Cartridge::from_bytes(&bytes)
+                .unwrap()
+                .check(Features::BUILT_IN),
+            Err(IsaError::Version(ISA_VERSION + 1))
+        );
+        bytes[6] = ISA_VERSION;
+        bytes[7] = Features::FLOATING_POINT.bits() as u8;
+        let cartridge = Cartridge::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            cartridge.check(Features::BUILT_IN),
+            Err(IsaError::Missing(Features::FLOATING_POINT))
+        );
+        assert_eq!(
+            cartridge.check(Features::BUILT_IN | Features::FLOATING_POINT),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn switches_banks_from_the_guest() {
+        // Bank 0:
+        //   mov 0x0002, r1
+        //   mov r1, [0x0000] ;; select bank 2
+        //   mov [0x4000], r2
+        // Each other bank starts with its number.
+        let mut banks: Vec<Vec<u8>> = (0..3)
+            .map(|bank| {
+                let mut bank = vec![bank as u8; BANK_SIZE];
+                bank[1] = 0;
+                bank
+            })
+            .collect();
+        banks[0][..12].copy_from_slice(&[
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x02,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x00,
+            0x00,
+            Instruction::MovMemReg as u8,
+            0x40,
+            0x00,
+            Register::Register2 as u8,
+        ]);
+        let cartridge = Cartridge {
+            mapper: Mapper::Banked,
+            banks,
+            isa_version: ISA_VERSION,
+            requires: Features::NONE,
+        };
+        let mut console = Console::with_cartridge(cartridge.into_device()).unwrap();
+        assert_eq!(console.cpu.peek(CARTRIDGE_START + BANK_SIZE), 0x0100);
+
+        console.cpu.run(3);
+        assert_eq!(console.cpu.peek_register(Register::Register2), 0x0200);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::cpu::{Cpu, Fault};
+use crate::snapshot::Snapshot;
+use std::collections::VecDeque;
+use std::fmt::Display;
+
+/// Why the machine couldn't be taken to an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewindError {
+    /// The instruction is older than the oldest checkpoint kept, at the
+    /// instruction count given
+    TooFarBack(u64),
+    /// Running forward from the checkpoint faulted
+    Fault(Fault),
+}
+
+impl Display for RewindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RewindError::TooFarBack(oldest) => {
+                write!(f, "The oldest checkpoint is at instruction {}", oldest)
+            }
+            RewindError::Fault(fault) => write!(f, "Faulted replaying: {}", fault),
+        }
+    }
+}
+
+impl std::error::Error for RewindError {}
+
+/// Snapshots of a machine every `interval` instructions, keeping the last
+/// `capacity` of them. Going back to any instruction since the oldest one
+/// restores the checkpoint before it and runs forward from there, so the
+/// devices have to behave the same every run, like `replay` makes them.
+pub struct Checkpoints {
+    interval: u64,
+    capacity: usize,
+    /// Oldest first
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl Checkpoints {
+    /// Panics if `interval` or `capacity` is 0
+    pub fn new(interval: u64, capacity: usize) -> Checkpoints {
+        assert!(interval > 0, "Checkpoints need an interval");
+        assert!(capacity > 0, "Checkpoints need room for one snapshot");
+        Checkpoints {
+            interval,
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Takes a snapshot if `interval` instructions have gone by since the
+    /// last one, dropping the oldest one when full
+    pub fn record(&mut self, cpu: &mut Cpu) {
+        let due = match self.snapshots.back() {
+            Some(last) => cpu.instruction_count() >= last.instruction_count() + self.interval,
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(cpu.snapshot());
+    }
+
+    /// Steps `n` instructions, checkpointing on the way. Stops at the first
+    /// fault, which can then be rewound from.
+    pub fn step_n(&mut self, cpu: &mut Cpu, n: usize) -> Result<(), Fault> {
+        for _ in 0..n {
+            self.record(cpu);
+            cpu.step()?;
+        }
+        Ok(())
+    }
+
+    /// Instruction count of the oldest checkpoint kept
+    pub fn oldest(&self) -> Option<u64> {
+        self.snapshots.front().map(Snapshot::instruction_count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Takes the machine to just before the instruction `instruction` runs,
+    /// counting from power on. Checkpoints after it are dropped, since what
+    /// happens next may differ.
+    pub fn goto_instruction(&mut self, cpu: &mut Cpu, instruction: u64) -> Result<(), RewindError> {
+        if instruction < cpu.instruction_count() {
+            let Some(index) = self
+                .snapshots
+                .iter()
+                .rposition(|snapshot| snapshot.instruction_count() <= instruction)
+            else {
+                return Err(RewindError::TooFarBack(self.oldest().unwrap_or(0)));
+            };
+            self.snapshots.truncate(index + 1);
+            cpu.restore(&self.snapshots[index]);
+        }
+        let remaining = instruction - cpu.instruction_count();
+        self.step_n(cpu, remaining as usize)
+            .map_err(RewindError::Fault)
+    }
+
+    /// Undoes the last instruction
+    pub fn step_back(&mut self, cpu: &mut Cpu) -> Result<(), RewindError> {
+        let Some(instruction) = cpu.instruction_count().checked_sub(1) else {
+            return Err(RewindError::TooFarBack(0));
+        };
+        self.goto_instruction(cpu, instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoints, RewindError};
+    use crate::cpu::{Cpu, Fault, Instruction, Register};
+
+    /// Pushes r1 forever, counting it up, until the stack runs out
+    fn pusher() -> Cpu {
+        // start:
+        // mov 0x0001, r2
+        // add r1, r2
+        // mov acc, r1
+        // psh r1
+        // jne 0x0000, start  ;; always taken
+        let code = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x01,
+            Register::Register2 as u8,
+            Instruction::AddRegReg as u8,
+            Register::Register1 as u8,
+            Register::Register2 as u8,
+            Instruction::MovRegReg as u8,
+            Register::Accumulator as u8,
+            Register::Register1 as u8,
+            Instruction::PushReg as u8,
+            Register::Register1 as u8,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .stack_size(0x40)
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        cpu
+    }
+
+    #[test]
+    fn goes_back_to_any_instruction_kept() {
+        let mut cpu = pusher();
+        let mut checkpoints = Checkpoints::new(10, 4);
+        let mut hashes = Vec::new();
+        for _ in 0..60 {
+            hashes.push(cpu.state_hash());
+            checkpoints.step_n(&mut cpu, 1).unwrap();
+        }
+        assert_eq!(checkpoints.len(), 4, "The ring is full");
+        assert_eq!(checkpoints.oldest(), Some(20));
+
+        checkpoints.step_back(&mut cpu).unwrap();
+        assert_eq!(cpu.instruction_count(), 59);
+        assert_eq!(cpu.state_hash(), hashes[59]);
+
+        checkpoints.goto_instruction(&mut cpu, 25).unwrap();
+        assert_eq!(cpu.state_hash(), hashes[25]);
+        assert_eq!(checkpoints.len(), 1, "Later checkpoints are dropped");
+        checkpoints.goto_instruction(&mut cpu, 42).unwrap();
+        assert_eq!(cpu.state_hash(), hashes[42]);
+
+        assert_eq!(
+            checkpoints.goto_instruction(&mut cpu, 5),
+            Err(RewindError::TooFarBack(20))
+        );
+    }
+
+    #[test]
+    fn rewinds_from_a_crash() {
+        let mut cpu = pusher();
+        let mut checkpoints = Checkpoints::new(16, 8);
+        let fault = checkpoints.step_n(&mut cpu, 10_000).unwrap_err();
+        assert!(matches!(fault, Fault::StackOverflow { .. }));
+        let crashed_at = cpu.instruction_count();
+
+        checkpoints
+            .goto_instruction(&mut cpu, crashed_at - 3)
+            .unwrap();
+        assert_eq!(
+            checkpoints.step_n(&mut cpu, 3),
+            Err(fault),
+            "Replays into the same fault"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use crate::cpu::{Cpu, Instruction, Register};
+use crate::debug_info::DebugInfo;
+use crate::debugger::FaultAt;
+use crate::instrument::Observer;
+use crate::json::string;
+use std::sync::{Arc, Mutex};
+
+const CAL_LIT: u8 = Instruction::CalLit as u8;
+const CAL_REG: u8 = Instruction::CalReg as u8;
+const RET: u8 = Instruction::Ret as u8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TraceEvent {
+    /// A subroutine at `target` was called
+    Begin {
+        time: u64,
+        target: u16,
+    },
+    /// The innermost subroutine returned
+    End {
+        time: u64,
+    },
+    Interrupt {
+        time: u64,
+        value: u16,
+    },
+    Fault {
+        time: u64,
+        fault: FaultAt,
+    },
+}
+
+#[derive(Default)]
+struct Recording {
+    events: Vec<TraceEvent>,
+    /// Subroutines called and not returned from yet
+    open: usize,
+}
+
+/// Records a run in the Chrome trace event format, for Perfetto and
+/// `chrome://tracing`: a slice for every subroutine call, from `cal` to
+/// its `ret`, and instant events for interrupts and faults. Time is
+/// counted in instructions, one microsecond each. Attach it with
+/// `Cpu::attach_observer`; clones share the recording, so keep one to
+/// report faults and write it out.
+#[derive(Clone, Default)]
+pub struct ChromeTrace {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl ChromeTrace {
+    pub fn new() -> ChromeTrace {
+        ChromeTrace::default()
+    }
+
+    /// Marks the fault a run stopped with, which ends every open slice
+    pub fn fault(&self, cpu: &Cpu, fault: FaultAt) {
+        let time = cpu.instruction_count();
+        let mut recording = self.recording.lock().unwrap();
+        recording.events.push(TraceEvent::Fault { time, fault });
+        for _ in 0..recording.open {
+            recording.events.push(TraceEvent::End { time });
+        }
+        recording.open = 0;
+    }
+
+    /// The recording as a JSON trace. Slices are named by the scopes in
+    /// `debug_info` if there is one, by address otherwise.
+    pub fn to_json(&self, debug_info: Option<&DebugInfo>) -> String {
+        let recording = self.recording.lock().unwrap();
+        let events: Vec<String> = recording
+            .events
+            .iter()
+            .map(|event| match event {
+                TraceEvent::Begin { time, target } => {
+                    let name = match debug_info {
+                        Some(info) => info.symbolize(*target),
+                        None => format!("{:#06x}", target),
+                    };
+                    slice(&name, "B", *time, "")
+                }
+                TraceEvent::End { time } => slice("", "E", *time, ""),
+                TraceEvent::Interrupt { time, value } => slice(
+                    &format!("interrupt {:#06x}", value),
+                    "i",
+                    *time,
+                    ", \"s\": \"t\"",
+                ),
+                TraceEvent::Fault { time, fault } => {
+                    slice(&fault.to_string(), "i", *time, ", \"s\": \"g\"")
+                }
+            })
+            .collect();
+        format!(
+            "{{\"displayTimeUnit\": \"ns\", \"traceEvents\": [\n{}\n]}}\n",
+            events.join(",\n")
+        )
+    }
+}
+
+fn slice(name: &str, phase: &str, time: u64, extra: &str) -> String {
+    format!(
+        "{{\"name\": {}, \"ph\": \"{}\", \"ts\": {}, \"pid\": 1, \"tid\": 1{}}}",
+        string(name),
+        phase,
+        time,
+        extra
+    )
+}
+
+impl Observer for ChromeTrace {
+    fn instruction(&mut self, cpu: &Cpu, address: u16) {
+        let time = cpu.instruction_count();
+        let opcode = cpu.peek_code(address as usize, 1)[0];
+        let mut recording = self.recording.lock().unwrap();
+        match opcode {
+            CAL_LIT => {
+                let target = cpu.peek(address.wrapping_add(1) as usize);
+                recording.events.push(TraceEvent::Begin { time, target });
+                recording.open += 1;
+            }
+            CAL_REG => {
+                let operand = cpu.peek_code(address.wrapping_add(1) as usize, 1)[0];
+                if let Ok(register) = Register::try_from(operand) {
+                    let target = cpu.peek_register(register);
+                    recording.events.push(TraceEvent::Begin { time, target });
+                    recording.open += 1;
+                }
+            }
+            // Returns without a call, like out of a frame built by hand,
+            // have no slice to end
+            RET if recording.open > 0 => {
+                recording.events.push(TraceEvent::End { time: time + 1 });
+                recording.open -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn interrupt(&mut self, cpu: &Cpu, value: u16) {
+        let time = cpu.instruction_count();
+        let mut recording = self.recording.lock().unwrap();
+        recording.events.push(TraceEvent::Interrupt { time, value });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChromeTrace;
+    use crate::cpu::{Cpu, Instruction, Register, INTERRUPT_VECTOR_ADDRESS};
+    use crate::debug_info::DebugInfo;
+    use crate::debugger;
+    use crate::memory::Memory;
+
+    #[test]
+    fn slices_calls_and_marks_interrupts_and_faults() {
+        // psh 0x0000 ;; no arguments
+        // cal 0x0010
+        // int 0x0000
+        // mov 0x0001, <not a register>
+        // 0x0010:
+        //   mov 0x0001, r1
+        //   ret
+        // 0x0020: ;; interrupt 0
+        //   rti
+        let mut memory = Memory::new(0x1100);
+        memory.set_byte(0x00, Instruction::PushLit as u8);
+        memory.set_word(0x01, 0x0000);
+        memory.set_byte(0x03, Instruction::CalLit as u8);
+        memory.set_word(0x04, 0x0010);
+        memory.set_byte(0x06, Instruction::Int as u8);
+        memory.set_word(0x07, 0x0000);
+        memory.set_byte(0x09, Instruction::MovLitReg as u8);
+        memory.set_word(0x0a, 0x0001);
+        memory.set_byte(0x0c, 0xee);
+        memory.set_byte(0x20, Instruction::RetInt as u8);
+        memory.set_word(INTERRUPT_VECTOR_ADDRESS, 0x0020);
+        memory.set_byte(0x10, Instruction::MovLitReg as u8);
+        memory.set_word(0x11, 0x0001);
+        memory.set_byte(0x13, Register::Register1 as u8);
+        memory.set_byte(0x14, Instruction::Ret as u8);
+        let mut cpu = Cpu::new(memory);
+        let trace = ChromeTrace::new();
+        cpu.attach_observer(trace.clone());
+
+        let fault = loop {
+            if let Err(fault) = debugger::step(&mut cpu) {
+                break fault;
+            }
+        };
+        trace.fault(&cpu, fault);
+
+        let mut info = DebugInfo::default();
+        info.add_scope("set_one", 0x0010, 0x0015);
+        let json = trace.to_json(Some(&info));
+        let events: Vec<&str> = json.lines().collect();
+        assert_eq!(events.len(), 6, "{}", json);
+        assert_eq!(
+            events[1],
+            "{\"name\": \"set_one\", \"ph\": \"B\", \"ts\": 1, \"pid\": 1, \"tid\": 1},"
+        );
+        assert!(events[2].contains("\"ph\": \"E\", \"ts\": 4"), "{}", json);
+        assert!(
+            events[3].starts_with("{\"name\": \"interrupt 0x0000\", \"ph\": \"i\", \"ts\": 4"),
+            "{}",
+            json
+        );
+        assert!(events[4].contains("\"ph\": \"i\", \"ts\": 7"), "{}", json);
+    }
+}
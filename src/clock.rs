@@ -0,0 +1,31 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Number of instructions the CPU has executed, shareable with devices
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    instructions: Arc<AtomicU64>,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.instructions.load(Ordering::Relaxed)
+    }
+
+    pub fn advance(&self) {
+        self.advance_by(1);
+    }
+
+    pub fn advance_by(&self, instructions: u64) {
+        self.instructions.fetch_add(instructions, Ordering::Relaxed);
+    }
+
+    /// Sets the count back, or forward, when a snapshot is restored
+    pub(crate) fn set(&self, instructions: u64) {
+        self.instructions.store(instructions, Ordering::Relaxed);
+    }
+}
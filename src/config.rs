@@ -0,0 +1,1170 @@
+use crate::bus::{Layer, Layered};
+use crate::clock::Clock;
+use crate::counters::COUNTERS_SIZE;
+use crate::cpu::{
+    Cpu, DEFAULT_GENERAL_PURPOSE_REGISTERS, INTERRUPT_VECTOR_ADDRESS, REGISTER_WINDOW_SIZE,
+};
+use crate::device_schedule::{DeviceSchedule, ScheduledDevices};
+use crate::heap::Allocator;
+use crate::isa::{Features, ISA_INFO_SIZE, ISA_VERSION};
+use crate::mapper::{AddressSpace, Device};
+use crate::memory::{Memory, Rom};
+use crate::rng::RngDevice;
+use std::fmt::Display;
+
+/// Largest address space the 16 bit address bus can reach
+pub const ADDRESS_SPACE_SIZE: usize = 0x10000;
+
+/// Number of bytes in the interrupt vector, one word per interrupt
+pub const INTERRUPT_VECTOR_SIZE: usize = 16 * 2;
+
+/// A device mapped over RAM at `start..=end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceMapping {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// RAM handed out by an `Allocator` mapped at `allocator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapRegion {
+    pub start: usize,
+    pub size: usize,
+    pub allocator: usize,
+}
+
+/// Layout of a machine. RAM starts at address 0 and devices are mapped on
+/// top of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineConfig {
+    pub memory_size: usize,
+    /// Initial stack and frame pointer, the stack grows down from here
+    pub stack_top: usize,
+    /// Bytes the stack may take up, pushes past that fault. Without a size
+    /// the stack may grow down over anything below it.
+    pub stack_size: Option<usize>,
+    pub entry_point: u16,
+    /// Address of a word holding the start address. When set, the CPU starts
+    /// from there on every reset instead of from `entry_point`.
+    pub reset_vector: Option<usize>,
+    pub interrupt_vector: usize,
+    pub general_purpose_registers: usize,
+    pub devices: Vec<DeviceMapping>,
+    /// Where to map a 2 byte `RngDevice`, if anywhere
+    pub rng: Option<usize>,
+    /// Seeds everything random in the machine, so the same program, seed
+    /// and inputs always run the same way
+    pub seed: u64,
+    /// Most calls and interrupt handlers that may be active at once, more
+    /// fault. Without a limit only the stack size bounds them.
+    pub max_call_depth: Option<usize>,
+    /// Where guest programs get dynamic memory from, if anywhere
+    pub heap: Option<HeapRegion>,
+    /// Where to map the registers for guest loads and stores, if anywhere,
+    /// see `REGISTER_WINDOW_SIZE`
+    pub register_window: Option<usize>,
+    /// Where far calls and returns write the code bank they switch to, like
+    /// the bank register of a `BankedRom`
+    pub bank_select: Option<usize>,
+    /// Extensions the machine has on top of `Features::BUILT_IN`, like a
+    /// floating point coprocessor of custom instructions
+    pub features: Features,
+    /// Where to map the ISA version and feature word for guests to read,
+    /// if anywhere, see `ISA_INFO_SIZE`
+    pub isa_info: Option<usize>,
+    /// Where to map the cycle, instruction and interrupt counters for
+    /// guests to read, if anywhere, see `COUNTERS_SIZE`
+    pub counters: Option<usize>,
+    /// Whether word loads, stores and stack accesses at odd addresses
+    /// fault instead of going through
+    pub strict_alignment: bool,
+}
+
+impl MachineConfig {
+    /// Default layout for `memory_size` bytes of RAM, with the stack
+    /// starting at the last word.
+    pub fn new(memory_size: usize) -> MachineConfig {
+        MachineConfig {
+            memory_size,
+            stack_top: memory_size.saturating_sub(2),
+            stack_size: None,
+            entry_point: 0,
+            reset_vector: None,
+            interrupt_vector: INTERRUPT_VECTOR_ADDRESS,
+            general_purpose_registers: DEFAULT_GENERAL_PURPOSE_REGISTERS,
+            devices: Vec::new(),
+            rng: None,
+            seed: 0,
+            max_call_depth: None,
+            heap: None,
+            register_window: None,
+            bank_select: None,
+            features: Features::NONE,
+            isa_info: None,
+            counters: None,
+            strict_alignment: false,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.memory_size == 0 || self.memory_size > ADDRESS_SPACE_SIZE {
+            return Err(ConfigError::MemorySize(self.memory_size));
+        }
+        if self.general_purpose_registers == 0 || self.general_purpose_registers > 15 {
+            return Err(ConfigError::RegisterCount(self.general_purpose_registers));
+        }
+
+        let in_memory = |what: &'static str, start: usize, length: usize| {
+            if start + length > self.memory_size {
+                Err(ConfigError::OutOfMemory {
+                    what,
+                    address: start,
+                })
+            } else {
+                Ok(())
+            }
+        };
+        let aligned = |what: &'static str, address: usize| {
+            if !address.is_multiple_of(2) {
+                Err(ConfigError::Unaligned { what, address })
+            } else {
+                Ok(())
+            }
+        };
+
+        aligned("stack", self.stack_top)?;
+        in_memory("stack", self.stack_top, 2)?;
+        if let Some(size) = self.stack_size {
+            if size < 2 || !size.is_multiple_of(2) || size > self.stack_top + 2 {
+                return Err(ConfigError::StackSize(size));
+            }
+        }
+        match self.reset_vector {
+            Some(reset_vector) => {
+                aligned("reset vector", reset_vector)?;
+                if reset_vector + 2 > ADDRESS_SPACE_SIZE {
+                    return Err(ConfigError::OutOfMemory {
+                        what: "reset vector",
+                        address: reset_vector,
+                    });
+                }
+            }
+            None => in_memory("entry point", self.entry_point as usize, 1)?,
+        }
+        aligned("interrupt vector", self.interrupt_vector)?;
+        in_memory(
+            "interrupt vector",
+            self.interrupt_vector,
+            INTERRUPT_VECTOR_SIZE,
+        )?;
+
+        let mut regions = vec![
+            (
+                "stack".to_string(),
+                self.stack_top + 2 - self.stack_size.unwrap_or(2),
+                self.stack_top + 1,
+            ),
+            (
+                "interrupt vector".to_string(),
+                self.interrupt_vector,
+                self.interrupt_vector + INTERRUPT_VECTOR_SIZE - 1,
+            ),
+        ];
+        if let Some(rng) = self.rng {
+            if rng + 1 >= ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::OutOfMemory {
+                    what: "rng",
+                    address: rng,
+                });
+            }
+            regions.push(("rng".to_string(), rng, rng + 1));
+        }
+        if let Some(heap) = self.heap {
+            // Allocation fails with address 0, so no block may start there
+            if heap.start == 0 || heap.size < 2 || !heap.size.is_multiple_of(2) {
+                return Err(ConfigError::HeapRange(heap));
+            }
+            aligned("heap", heap.start)?;
+            in_memory("heap", heap.start, heap.size)?;
+            if heap.allocator + 3 >= ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::OutOfMemory {
+                    what: "allocator",
+                    address: heap.allocator,
+                });
+            }
+            regions.push(("heap".to_string(), heap.start, heap.start + heap.size - 1));
+            regions.push(("allocator".to_string(), heap.allocator, heap.allocator + 3));
+        }
+        if let Some(window) = self.register_window {
+            aligned("register window", window)?;
+            if window + REGISTER_WINDOW_SIZE > ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::OutOfMemory {
+                    what: "register window",
+                    address: window,
+                });
+            }
+            regions.push((
+                "register window".to_string(),
+                window,
+                window + REGISTER_WINDOW_SIZE - 1,
+            ));
+        }
+        if let Some(bank_select) = self.bank_select {
+            if bank_select + 2 > ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::OutOfMemory {
+                    what: "bank select",
+                    address: bank_select,
+                });
+            }
+        }
+        if let Some(isa_info) = self.isa_info {
+            aligned("ISA info", isa_info)?;
+            if isa_info + ISA_INFO_SIZE > ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::OutOfMemory {
+                    what: "ISA info",
+                    address: isa_info,
+                });
+            }
+            regions.push((
+                "ISA info".to_string(),
+                isa_info,
+                isa_info + ISA_INFO_SIZE - 1,
+            ));
+        }
+        if let Some(counters) = self.counters {
+            aligned("counters", counters)?;
+            if counters + COUNTERS_SIZE > ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::OutOfMemory {
+                    what: "counters",
+                    address: counters,
+                });
+            }
+            regions.push((
+                "counters".to_string(),
+                counters,
+                counters + COUNTERS_SIZE - 1,
+            ));
+        }
+        if let Some(reset_vector) = self.reset_vector {
+            if reset_vector < self.memory_size {
+                regions.push(("reset vector".to_string(), reset_vector, reset_vector + 1));
+            }
+        }
+        for device in &self.devices {
+            if device.start > device.end || device.end >= ADDRESS_SPACE_SIZE {
+                return Err(ConfigError::DeviceRange(device.clone()));
+            }
+            regions.push((device.name.clone(), device.start, device.end));
+        }
+
+        for (index, (name, start, end)) in regions.iter().enumerate() {
+            for (other, other_start, other_end) in &regions[index + 1..] {
+                if start <= other_end && other_start <= end {
+                    return Err(ConfigError::Overlap(name.clone(), other.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl MachineConfig {
+    /// Lowest address a push may write to
+    pub fn stack_limit(&self) -> usize {
+        match self.stack_size {
+            Some(size) => self.stack_top + 2 - size,
+            None => 0,
+        }
+    }
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig::new(ADDRESS_SPACE_SIZE)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    MemorySize(usize),
+    RegisterCount(usize),
+    StackSize(usize),
+    Unaligned { what: &'static str, address: usize },
+    OutOfMemory { what: &'static str, address: usize },
+    DeviceRange(DeviceMapping),
+    HeapRange(HeapRegion),
+    Overlap(String, String),
+    Quantum(usize),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::MemorySize(size) => write!(
+                f,
+                "Memory size {:#x} must be between 1 and {:#x} bytes",
+                size, ADDRESS_SPACE_SIZE
+            ),
+            ConfigError::RegisterCount(count) => write!(
+                f,
+                "A CPU needs 1 to 15 general purpose registers, got {}",
+                count
+            ),
+            ConfigError::StackSize(size) => write!(
+                f,
+                "Stack size {:#x} must be even, at least 2 and fit below the stack top",
+                size
+            ),
+            ConfigError::Unaligned { what, address } => {
+                write!(f, "The {} at {:#06x} is not word aligned", what, address)
+            }
+            ConfigError::OutOfMemory { what, address } => {
+                write!(f, "The {} at {:#06x} is outside of memory", what, address)
+            }
+            ConfigError::DeviceRange(device) => write!(
+                f,
+                "Device {} has an invalid range {:#06x}..={:#06x}",
+                device.name, device.start, device.end
+            ),
+            ConfigError::HeapRange(heap) => write!(
+                f,
+                "Heap at {:#06x} of {:#x} bytes must not start at 0 and be an even, nonzero size",
+                heap.start, heap.size
+            ),
+            ConfigError::Overlap(first, second) => write!(f, "{} overlaps {}", first, second),
+            ConfigError::Quantum(quantum) => write!(
+                f,
+                "A scheduling quantum must be at least 1 instruction, got {}",
+                quantum
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Builds a `Cpu` from a validated `MachineConfig`, mapping RAM and devices
+pub struct CpuBuilder {
+    config: MachineConfig,
+    devices: Vec<Box<dyn Device>>,
+    /// Separate instruction memory, for a Harvard machine
+    code: Option<Box<dyn Device>>,
+    /// Middleware over the bus, innermost first
+    layers: Vec<Box<dyn Layer>>,
+    clock: Clock,
+    /// Whether devices are ticked every instruction
+    deterministic: bool,
+}
+
+impl CpuBuilder {
+    pub fn new() -> CpuBuilder {
+        CpuBuilder {
+            config: MachineConfig::default(),
+            devices: Vec::new(),
+            code: None,
+            layers: Vec::new(),
+            clock: Clock::new(),
+            deterministic: false,
+        }
+    }
+
+    /// Replaces the layout, keeping any devices added so far
+    pub fn config(mut self, config: MachineConfig) -> CpuBuilder {
+        let devices = std::mem::take(&mut self.config.devices);
+        self.config = config;
+        self.config.devices.splice(0..0, devices);
+        self
+    }
+
+    /// Sets the RAM size and moves the stack to its last word
+    pub fn memory_size(mut self, memory_size: usize) -> CpuBuilder {
+        self.config.memory_size = memory_size;
+        self.config.stack_top = memory_size.saturating_sub(2);
+        self
+    }
+
+    pub fn stack_top(mut self, stack_top: usize) -> CpuBuilder {
+        self.config.stack_top = stack_top;
+        self
+    }
+
+    pub fn stack_size(mut self, stack_size: usize) -> CpuBuilder {
+        self.config.stack_size = Some(stack_size);
+        self
+    }
+
+    pub fn entry_point(mut self, entry_point: u16) -> CpuBuilder {
+        self.config.entry_point = entry_point;
+        self
+    }
+
+    pub fn reset_vector(mut self, reset_vector: usize) -> CpuBuilder {
+        self.config.reset_vector = Some(reset_vector);
+        self
+    }
+
+    /// Maps `rom` read-only at `start`. Combine with `reset_vector` pointing
+    /// into the ROM to boot from it.
+    pub fn boot_rom(mut self, rom: Rom, start: usize) -> CpuBuilder {
+        let end = start + rom.byte_length() - 1;
+        self.config.devices.push(DeviceMapping {
+            name: "boot rom".to_string(),
+            start,
+            end,
+        });
+        self.devices.push(Box::new(rom));
+        self
+    }
+
+    pub fn interrupt_vector(mut self, interrupt_vector: usize) -> CpuBuilder {
+        self.config.interrupt_vector = interrupt_vector;
+        self
+    }
+
+    pub fn general_purpose_registers(mut self, count: usize) -> CpuBuilder {
+        self.config.general_purpose_registers = count;
+        self
+    }
+
+    /// Maps an `RngDevice` at `start`, seeded with the machine's seed
+    pub fn rng(mut self, start: usize) -> CpuBuilder {
+        self.config.rng = Some(start);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> CpuBuilder {
+        self.config.seed = seed;
+        self
+    }
+
+    pub fn max_call_depth(mut self, max_call_depth: usize) -> CpuBuilder {
+        self.config.max_call_depth = Some(max_call_depth);
+        self
+    }
+
+    /// Hands out the `size` bytes of RAM from `start` through an
+    /// `Allocator` mapped at `allocator`
+    pub fn heap(mut self, start: usize, size: usize, allocator: usize) -> CpuBuilder {
+        self.config.heap = Some(HeapRegion {
+            start,
+            size,
+            allocator,
+        });
+        self
+    }
+
+    /// Maps the registers at `start`, so guest code reaches them with
+    /// loads and stores. `REGISTER_WINDOW_ADDRESS` keeps them out of the way.
+    pub fn register_window(mut self, start: usize) -> CpuBuilder {
+        self.config.register_window = Some(start);
+        self
+    }
+
+    /// Makes far calls and returns write the bank they switch to at
+    /// `address`, usually into a banked device mapped with `device`
+    pub fn bank_select(mut self, address: usize) -> CpuBuilder {
+        self.config.bank_select = Some(address);
+        self
+    }
+
+    /// Declares extensions the machine has on top of the built-in ones,
+    /// for guests and loaders to check. Providing them, like registering
+    /// the instructions of a coprocessor, is up to the caller.
+    pub fn features(mut self, features: Features) -> CpuBuilder {
+        self.config.features = features;
+        self
+    }
+
+    /// Maps the ISA version and feature word read only at `start`
+    pub fn isa_info(mut self, start: usize) -> CpuBuilder {
+        self.config.isa_info = Some(start);
+        self
+    }
+
+    /// Maps the performance counters read only at `start`, so guests can
+    /// time themselves. `COUNTERS_ADDRESS` keeps them out of the way.
+    pub fn counters(mut self, start: usize) -> CpuBuilder {
+        self.config.counters = Some(start);
+        self
+    }
+
+    /// Makes word loads, stores and stack accesses at odd addresses fault
+    /// with `Fault::Unaligned`, like on most real machines, so guest code
+    /// that relies on unaligned access shows up early. Instructions stay
+    /// byte packed, so operands are still fetched from anywhere.
+    pub fn strict_alignment(mut self) -> CpuBuilder {
+        self.config.strict_alignment = true;
+        self
+    }
+
+    /// Fetches instructions from `code` instead of the bus, which is left
+    /// to loads, stores and the stack: a Harvard machine rather than a Von
+    /// Neumann one. Load programs with `Cpu::code_memory_mut`.
+    pub fn harvard(mut self, code: impl Device + 'static) -> CpuBuilder {
+        self.code = Some(Box::new(code));
+        self
+    }
+
+    /// Wraps the bus, RAM and devices together, in `layer`. Layers added
+    /// later sit on top of the ones added earlier and see accesses first.
+    pub fn layer(mut self, layer: impl Layer + 'static) -> CpuBuilder {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn clock(mut self, clock: Clock) -> CpuBuilder {
+        self.clock = clock;
+        self
+    }
+
+    /// Maps `device` at `start..=end`, with addresses relative to `start`
+    pub fn device(
+        mut self,
+        name: &str,
+        device: impl Device + 'static,
+        start: usize,
+        end: usize,
+    ) -> CpuBuilder {
+        self.config.devices.push(DeviceMapping {
+            name: name.to_string(),
+            start,
+            end,
+        });
+        self.devices.push(Box::new(device));
+        self
+    }
+
+    /// Maps the input ports of a `ScheduledDevices` playing `schedule` at
+    /// `start..=end`, and ticks devices every instruction so its events land
+    /// exactly when scheduled
+    pub fn schedule(self, schedule: &DeviceSchedule, start: usize, end: usize) -> CpuBuilder {
+        let devices = ScheduledDevices::new(schedule, end + 1 - start);
+        let mut builder = self.device("schedule", devices, start, end);
+        builder.deterministic = true;
+        builder
+    }
+
+    /// Validates the layout, then maps RAM and the devices
+    pub fn build(self) -> Result<Cpu, ConfigError> {
+        self.config.validate()?;
+
+        let mut space = AddressSpace::new();
+        space.map(
+            Memory::new(self.config.memory_size),
+            0,
+            self.config.memory_size - 1,
+            false,
+        );
+        for (device, mapping) in self.devices.into_iter().zip(&self.config.devices) {
+            space.map(device, mapping.start, mapping.end, true);
+        }
+        if let Some(start) = self.config.rng {
+            space.map(RngDevice::new(self.config.seed), start, start + 1, true);
+        }
+        if let Some(heap) = self.config.heap {
+            let allocator = Allocator::new(heap.start as u16, heap.size as u16);
+            space.map(allocator, heap.allocator, heap.allocator + 3, true);
+        }
+        if let Some(start) = self.config.isa_info {
+            let features = Features::BUILT_IN | self.config.features;
+            let mut info = vec![0, ISA_VERSION];
+            info.extend(features.bits().to_be_bytes());
+            space.map(Rom::new(info), start, start + ISA_INFO_SIZE - 1, true);
+        }
+
+        let mut bus: Box<dyn Device> = Box::new(space);
+        for layer in self.layers {
+            bus = Box::new(Layered::new(bus, layer));
+        }
+
+        let mut cpu = Cpu::from_config(bus, &self.config, self.clock);
+        if let Some(code) = self.code {
+            cpu.set_code_space(code);
+        }
+        if self.deterministic {
+            cpu.tick_devices_every(1);
+        }
+        Ok(cpu)
+    }
+}
+
+impl Default for CpuBuilder {
+    fn default() -> Self {
+        CpuBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigError, DeviceMapping, HeapRegion, MachineConfig};
+    use crate::bus::{LoggingLayer, PermissionLayer};
+    use crate::cpu::{Cpu, Fault, Instruction, Register, StopReason, REGISTER_WINDOW_ADDRESS};
+    use crate::mapper::WaitStates;
+    use crate::memory::{BankedRom, Memory, Rom, BANK_SIZE};
+    use crate::timer::Timer;
+
+    #[test]
+    fn validates_layout() {
+        assert_eq!(MachineConfig::default().validate(), Ok(()));
+
+        let config = MachineConfig {
+            stack_top: 0x1001,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Unaligned {
+                what: "stack",
+                address: 0x1001
+            })
+        );
+
+        let mut config = MachineConfig::new(0x2000);
+        config.interrupt_vector = 0x1ff0;
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::OutOfMemory {
+                what: "interrupt vector",
+                address: 0x1ff0
+            })
+        );
+
+        let config = MachineConfig {
+            devices: vec![
+                DeviceMapping {
+                    name: "screen".to_string(),
+                    start: 0x3000,
+                    end: 0x30ff,
+                },
+                DeviceMapping {
+                    name: "keyboard".to_string(),
+                    start: 0x30f0,
+                    end: 0x30f1,
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Overlap(
+                "screen".to_string(),
+                "keyboard".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn boots_from_rom() {
+        // At 0xf000, in ROM:
+        //   mov 0x0017, r1
+        //   mov r1, #0000  ;; copy `psh 0x....` opcode into RAM
+        //   jne 0x0001, 0x0000
+        // At 0xf0fe, the reset vector pointing at 0xf000
+        let mut image = vec![0; 0x100];
+        image[0x00] = Instruction::MovLitReg as u8;
+        image[0x01..0x03].copy_from_slice(&0x1700u16.to_be_bytes());
+        image[0x03] = Register::Register1 as u8;
+        image[0x04] = Instruction::MovRegMem as u8;
+        image[0x05] = Register::Register1 as u8;
+        image[0x06..0x08].copy_from_slice(&0x0000u16.to_be_bytes());
+        image[0x08] = Instruction::JmpNotEq as u8;
+        image[0x09..0x0b].copy_from_slice(&0x0001u16.to_be_bytes());
+        image[0x0b..0x0d].copy_from_slice(&0x0000u16.to_be_bytes());
+        image[0xfe..0x100].copy_from_slice(&0xf000u16.to_be_bytes());
+
+        let mut cpu = Cpu::builder()
+            .memory_size(0xf000)
+            .boot_rom(Rom::new(image), 0xf000)
+            .reset_vector(0xf0fe)
+            .build()
+            .unwrap();
+
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0xf000);
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x0000);
+        assert_eq!(cpu.peek_tape(0)[0], Instruction::PushLit as u8);
+
+        cpu.memory_mut().set_word(0xf000, 0xffff);
+        assert_eq!(cpu.peek(0xf000), 0x1017, "ROM can't be written");
+
+        cpu.reset();
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0xf000);
+    }
+
+    #[test]
+    fn builds_configured_cpu() {
+        let cpu = Cpu::builder()
+            .memory_size(0x4000)
+            .entry_point(0x0100)
+            .general_purpose_registers(10)
+            .device("scratch", Memory::new(16), 0x3000, 0x300f)
+            .build()
+            .unwrap();
+
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x0100);
+        assert_eq!(cpu.peek_register(Register::StackPointer), 0x3ffe);
+        assert_eq!(cpu.general_purpose_registers().len(), 10);
+
+        let error = Cpu::builder()
+            .device("io", Memory::new(2), 0xfffe, 0xffff)
+            .build()
+            .err();
+        assert_eq!(
+            error,
+            Some(ConfigError::Overlap("stack".to_string(), "io".to_string()))
+        );
+    }
+
+    #[test]
+    fn seeds_the_rng() {
+        let reads = |seed| {
+            let mut cpu = Cpu::builder().rng(0x3000).seed(seed).build().unwrap();
+            (0..4)
+                .map(|_| cpu.memory_mut().get_word(0x3000))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(reads(5), reads(5));
+        assert_ne!(reads(5), reads(6));
+
+        let config = MachineConfig {
+            rng: Some(0x1010),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Overlap(
+                "interrupt vector".to_string(),
+                "rng".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn delivers_scheduled_input_at_its_instruction() {
+        let schedule = "3 input 0x0000 0x41".parse().unwrap();
+        let mut cpu = Cpu::builder()
+            .schedule(&schedule, 0x3000, 0x3001)
+            .build()
+            .unwrap();
+        // mov [0x3000], r1 ;; over and over
+        for address in (0..0x40).step_by(4) {
+            let memory = cpu.memory_mut();
+            memory.set_byte(address, Instruction::MovMemReg as u8);
+            memory.set_word(address + 1, 0x3000);
+            memory.set_byte(address + 3, Register::Register1 as u8);
+        }
+
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.peek_register(Register::Register1), 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_register(Register::Register1), 0x4100);
+    }
+
+    #[test]
+    fn places_sized_stack_below_devices() {
+        let mut cpu = Cpu::builder()
+            .stack_top(0xeffe)
+            .stack_size(4)
+            .device("screen", Memory::new(0x1000), 0xf000, 0xffff)
+            .build()
+            .unwrap();
+        assert_eq!(cpu.peek_register(Register::StackPointer), 0xeffe);
+
+        // psh 0x0001; psh 0x0002; psh 0x0003
+        let mut code = Vec::new();
+        for value in 1..=3u16 {
+            code.push(Instruction::PushLit as u8);
+            code.extend(value.to_be_bytes());
+        }
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        cpu.step_n(2).unwrap();
+        assert_eq!(cpu.step(), Err(Fault::StackOverflow { address: 0xeffa }));
+
+        let config = MachineConfig {
+            stack_top: 0x0ffe,
+            stack_size: Some(0x100),
+            devices: vec![DeviceMapping {
+                name: "screen".to_string(),
+                start: 0x0f80,
+                end: 0x0f8f,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Overlap(
+                "stack".to_string(),
+                "screen".to_string()
+            ))
+        );
+
+        for size in [0, 3, 0x1002] {
+            let config = MachineConfig {
+                stack_top: 0x0ffe,
+                stack_size: Some(size),
+                ..Default::default()
+            };
+            assert_eq!(config.validate(), Err(ConfigError::StackSize(size)));
+        }
+    }
+    #[test]
+    fn limits_call_depth() {
+        // recurse:
+        //   psh 0x0000
+        //   cal recurse:
+        let mut cpu = Cpu::builder().max_call_depth(4).build().unwrap();
+        let code = [
+            Instruction::PushLit as u8,
+            0,
+            0,
+            Instruction::CalLit as u8,
+            0,
+            0,
+        ];
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+
+        cpu.step_n(8).unwrap();
+        assert_eq!(cpu.call_depth(), 4);
+        cpu.step().unwrap();
+        let stack_pointer = cpu.peek_register(Register::StackPointer);
+        assert_eq!(
+            cpu.step(),
+            Err(Fault::CallDepthExceeded {
+                address: stack_pointer,
+                limit: 4
+            })
+        );
+        assert_eq!(
+            cpu.peek_register(Register::StackPointer),
+            stack_pointer,
+            "Nothing pushed"
+        );
+    }
+
+    #[test]
+    fn guests_allocate_from_the_heap() {
+        // mov 0x0020, r1
+        // mov r1, [0xff00]  ;; allocate 0x20 bytes
+        // mov [0xff00], r2
+        // mov r2, [0xff02]  ;; and free them
+        let mut cpu = Cpu::builder()
+            .memory_size(0xf000)
+            .heap(0x8000, 0x1000, 0xff00)
+            .build()
+            .unwrap();
+        let code = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x20,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0xff,
+            0x00,
+            Instruction::MovMemReg as u8,
+            0xff,
+            0x00,
+            Register::Register2 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register2 as u8,
+            0xff,
+            0x02,
+        ];
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.peek_register(Register::Register2), 0x8000);
+        cpu.step().unwrap();
+
+        let mut config = MachineConfig::new(0x2000);
+        config.heap = Some(HeapRegion {
+            start: 0x1e00,
+            size: 0x200,
+            allocator: 0xff00,
+        });
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Overlap(
+                "stack".to_string(),
+                "heap".to_string()
+            ))
+        );
+        config.heap = Some(HeapRegion {
+            start: 0,
+            size: 0x200,
+            allocator: 0xff00,
+        });
+        assert!(matches!(config.validate(), Err(ConfigError::HeapRange(_))));
+    }
+
+    #[test]
+    fn maps_registers_for_loads_and_stores() {
+        // mov 0x1234, r1
+        // mov r1, [0xffc6]  ;; r2
+        // mov [0xffd4], r3  ;; sp
+        // mov 0x0040, r4
+        // mov r4, [0xffc0]  ;; ip, a jump
+        let code = [
+            Instruction::MovLitReg as u8,
+            0x12,
+            0x34,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0xff,
+            0xc6,
+            Instruction::MovMemReg as u8,
+            0xff,
+            0xd4,
+            Register::Register3 as u8,
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x40,
+            Register::Register4 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register4 as u8,
+            0xff,
+            0xc0,
+        ];
+        let machine = || {
+            let mut cpu = Cpu::builder()
+                .memory_size(0x8000)
+                .register_window(REGISTER_WINDOW_ADDRESS)
+                .build()
+                .unwrap();
+            for (i, byte) in code.iter().enumerate() {
+                cpu.memory_mut().set_byte(i, *byte);
+            }
+            cpu
+        };
+
+        let mut cpu = machine();
+        cpu.step_n(5).unwrap();
+        assert_eq!(cpu.peek_register(Register::Register2), 0x1234);
+        assert_eq!(cpu.peek_register(Register::Register3), 0x7ffe);
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x0040);
+
+        let mut cached = machine();
+        assert_eq!(cached.run_cached(5), StopReason::FuelExhausted);
+        assert_eq!(
+            cached.registers().collect::<Vec<_>>(),
+            cpu.registers().collect::<Vec<_>>()
+        );
+
+        let mut run = machine();
+        assert_eq!(run.run(5), StopReason::FuelExhausted);
+        assert_eq!(
+            run.registers().collect::<Vec<_>>(),
+            cpu.registers().collect::<Vec<_>>()
+        );
+
+        let config = MachineConfig {
+            register_window: Some(0xfff0),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::OutOfMemory {
+                what: "register window",
+                address: 0xfff0
+            })
+        );
+    }
+
+    #[test]
+    fn far_calls_switch_code_banks() {
+        // Bank 0:
+        //   psh 0x0000
+        //   fcl 0x0002, 0x4000
+        //   mov 0x0001, r2
+        // At 0x4000 in banks 1 and 2:
+        //   mov 0x1111, r1 ;; or 0x2222 in bank 2
+        //   frt
+        let mut banks = vec![vec![0; BANK_SIZE]; 3];
+        banks[0][..11].copy_from_slice(&[
+            Instruction::PushLit as u8,
+            0x00,
+            0x00,
+            Instruction::FarCal as u8,
+            0x00,
+            0x02,
+            0x40,
+            0x00,
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x01,
+        ]);
+        banks[0][11] = Register::Register2 as u8;
+        for (bank, value) in [(1, 0x11), (2, 0x22)] {
+            banks[bank][..5].copy_from_slice(&[
+                Instruction::MovLitReg as u8,
+                value,
+                value,
+                Register::Register1 as u8,
+                Instruction::FarRet as u8,
+            ]);
+        }
+        let rom = BankedRom::new(banks);
+        let mut cpu = Cpu::builder()
+            .device("rom", rom, 0x0000, 2 * BANK_SIZE - 1)
+            .bank_select(0x7ffe)
+            .interrupt_vector(0x8000)
+            .build()
+            .unwrap();
+
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.code_bank(), 2);
+        assert_eq!(cpu.peek_register(Register::Register1), 0x2222);
+        cpu.step().unwrap();
+        assert_eq!(cpu.code_bank(), 0);
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x0008);
+        assert_eq!(cpu.peek_register(Register::StackPointer), 0xfffe);
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_register(Register::Register2), 0x0001);
+    }
+
+    #[test]
+    fn slow_devices_stall_the_cpu() {
+        // mov [0x8000], r1
+        // mov r1, [0x8002]
+        // mov r1, [0x0080]
+        let mut cpu = Cpu::builder()
+            .memory_size(0x8000)
+            .device(
+                "slow",
+                WaitStates::new(Memory::new(0x100), 3, 5),
+                0x8000,
+                0x80ff,
+            )
+            .device("timer", Timer::new(), 0x8100, 0x8103)
+            .build()
+            .unwrap();
+        cpu.tick_devices_every(1);
+        let code = [
+            Instruction::MovMemReg as u8,
+            0x80,
+            0x00,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x80,
+            0x02,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x00,
+            0x80,
+        ];
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        cpu.memory_mut().set_word(0x8100, 11);
+
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.instruction_count(), 3);
+        assert_eq!(cpu.cycle_count(), 3 + 3 + 5);
+        assert!(
+            cpu.has_pending_interrupts(),
+            "The timer counts the stalled cycles"
+        );
+    }
+
+    #[test]
+    fn harvard_machines_keep_code_apart_from_data() {
+        // mov 0xabcd, r1
+        // mov r1, [0x0000]
+        // mov [0x0000], r2
+        let code = [
+            Instruction::MovLitReg as u8,
+            0xab,
+            0xcd,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x00,
+            0x00,
+            Instruction::MovMemReg as u8,
+            0x00,
+            0x00,
+            Register::Register2 as u8,
+        ];
+        let program = || {
+            let mut memory = Memory::new(0x100);
+            for (i, byte) in code.iter().enumerate() {
+                memory.set_byte(i, *byte);
+            }
+            memory
+        };
+
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .harvard(program())
+            .build()
+            .unwrap();
+        assert!(cpu.is_harvard());
+        assert_eq!(cpu.run(3), StopReason::FuelExhausted);
+        assert_eq!(cpu.peek_register(Register::Register2), 0xabcd);
+        assert_eq!(cpu.peek(0), 0xabcd, "The store lands in data");
+        assert_eq!(cpu.peek_code(0, 4), &code[..4], "Code is untouched");
+
+        let mut cpu = Cpu::harvard(program(), Memory::new(0x100));
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.peek_register(Register::Register2), 0xabcd);
+        assert_eq!(cpu.peek_code(0, 4), &code[..4]);
+
+        let mut von_neumann = Cpu::new(program());
+        assert!(!von_neumann.is_harvard());
+        von_neumann.step_n(3).unwrap();
+        assert_eq!(
+            von_neumann.peek_code(0, 2),
+            [0xab, 0xcd],
+            "Stores reach the code"
+        );
+    }
+
+    #[test]
+    fn layers_wrap_the_bus() {
+        // mov 0xabcd, r1
+        // mov r1, [0x1000]
+        let code = [
+            Instruction::MovLitReg as u8,
+            0xab,
+            0xcd,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x10,
+            0x00,
+        ];
+        let log = LoggingLayer::new();
+        let permissions = PermissionLayer::new().read_only(0x1000..=0x10ff);
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .layer(log.clone())
+            .layer(permissions.clone())
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        log.take_log();
+
+        cpu.step_n(2).unwrap();
+        assert_eq!(cpu.peek(0x1000), 0);
+        assert_eq!(
+            permissions.take_violations().len(),
+            1,
+            "The store is denied"
+        );
+        assert!(
+            log.take_log().iter().all(|access| !access.write),
+            "The write stops above the log"
+        );
+    }
+}
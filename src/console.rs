@@ -0,0 +1,330 @@
+use crate::config::{ConfigError, CpuBuilder, MachineConfig};
+use crate::cpu::{Cpu, StopReason};
+use crate::mapper::Device;
+use crate::memory::Rom;
+use std::sync::{Arc, Mutex};
+
+/// The fantasy console's memory map. The cartridge ROM sits at the bottom,
+/// where the CPU starts, then work RAM with the interrupt vector and the
+/// stack, then the devices.
+pub const CARTRIDGE_START: usize = 0x0000;
+pub const CARTRIDGE_END: usize = 0x7fff;
+pub const INTERRUPT_VECTOR: usize = 0x8000;
+pub const STACK_TOP: usize = 0xdffe;
+pub const STACK_SIZE: usize = 0x1000;
+pub const VIDEO_START: usize = 0xe000;
+pub const GAMEPAD_START: usize = 0xf000;
+pub const SOUND_START: usize = 0xf010;
+pub const RNG_START: usize = 0xf020;
+
+/// Instructions per video frame
+pub const FRAME_CYCLES: u64 = 10_000;
+
+/// Tiles across and down the screen
+pub const COLUMNS: usize = 32;
+pub const ROWS: usize = 24;
+
+/// Bytes of tile map, then the frame counter word
+const VIDEO_LENGTH: usize = COLUMNS * ROWS + 2;
+
+/// Tile video, memory mapped: one byte per tile, row by row, then a word
+/// counting the frames shown so far, which guest code can wait on. Tiles
+/// are character codes, rendered as text. Clones share the screen, so keep
+/// one to look at it.
+#[derive(Clone)]
+pub struct TileVideo {
+    memory: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TileVideo {
+    pub fn new() -> TileVideo {
+        TileVideo {
+            memory: Arc::new(Mutex::new(vec![0; VIDEO_LENGTH])),
+        }
+    }
+
+    pub fn frame(&self) -> u16 {
+        let memory = self.memory.lock().unwrap();
+        u16::from_be_bytes([memory[COLUMNS * ROWS], memory[COLUMNS * ROWS + 1]])
+    }
+
+    /// The screen as `ROWS` lines of text. Tile 0 is blank and tiles that
+    /// aren't printable show as `?`.
+    pub fn render(&self) -> String {
+        let memory = self.memory.lock().unwrap();
+        let mut screen = String::with_capacity((COLUMNS + 1) * ROWS);
+        for row in memory[..COLUMNS * ROWS].chunks(COLUMNS) {
+            for tile in row {
+                screen.push(match tile {
+                    0 => ' ',
+                    tile if tile.is_ascii_graphic() || *tile == b' ' => *tile as char,
+                    _ => '?',
+                });
+            }
+            screen.push('\n');
+        }
+        screen
+    }
+}
+
+impl Default for TileVideo {
+    fn default() -> Self {
+        TileVideo::new()
+    }
+}
+
+impl Device for TileVideo {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        // The frame counter is read-only
+        if address < COLUMNS * ROWS {
+            self.memory.lock().unwrap()[address] = value;
+        }
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.memory.lock().unwrap()[address]
+    }
+
+    fn byte_length(&self) -> usize {
+        VIDEO_LENGTH
+    }
+
+    fn tick(&mut self, cycles: u64, _interrupts: &mut Vec<u16>) {
+        // Ticked once per frame
+        let frames = (cycles / FRAME_CYCLES).max(1) as u16;
+        let frame = self.frame().wrapping_add(frames).to_be_bytes();
+        let mut memory = self.memory.lock().unwrap();
+        memory[COLUMNS * ROWS..].copy_from_slice(&frame);
+    }
+}
+
+/// Gamepad buttons, as bits of the byte the gamepad reads as
+pub mod buttons {
+    pub const UP: u8 = 0x01;
+    pub const DOWN: u8 = 0x02;
+    pub const LEFT: u8 = 0x04;
+    pub const RIGHT: u8 = 0x08;
+    pub const A: u8 = 0x10;
+    pub const B: u8 = 0x20;
+    pub const START: u8 = 0x40;
+    pub const SELECT: u8 = 0x80;
+}
+
+/// A gamepad, memory mapped as 2 bytes: the buttons held down, see
+/// `buttons`, and a spare byte that reads as zero. Clones share the
+/// buttons, so the host keeps one to press them.
+#[derive(Clone, Default)]
+pub struct Gamepad {
+    buttons: Arc<Mutex<u8>>,
+}
+
+impl Gamepad {
+    pub fn new() -> Gamepad {
+        Gamepad::default()
+    }
+
+    pub fn set_buttons(&self, buttons: u8) {
+        *self.buttons.lock().unwrap() = buttons;
+    }
+}
+
+impl Device for Gamepad {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        match address {
+            0 => *self.buttons.lock().unwrap(),
+            _ => 0,
+        }
+    }
+
+    fn byte_length(&self) -> usize {
+        2
+    }
+}
+
+/// The tone the sound chip plays
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tone {
+    /// In Hz
+    pub frequency: u16,
+    /// 0 is silent
+    pub volume: u8,
+}
+
+/// A one voice sound chip, memory mapped as 4 bytes: the frequency word,
+/// then the volume and a spare byte. The host plays what `tone` says;
+/// clones share the tone.
+#[derive(Clone, Default)]
+pub struct Sound {
+    registers: Arc<Mutex<[u8; 4]>>,
+}
+
+impl Sound {
+    pub fn new() -> Sound {
+        Sound::default()
+    }
+
+    pub fn tone(&self) -> Tone {
+        let registers = self.registers.lock().unwrap();
+        Tone {
+            frequency: u16::from_be_bytes([registers[0], registers[1]]),
+            volume: registers[2],
+        }
+    }
+}
+
+impl Device for Sound {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.registers.lock().unwrap()[address] = value;
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.registers.lock().unwrap()[address]
+    }
+
+    fn byte_length(&self) -> usize {
+        4
+    }
+}
+
+/// A CPU laid out as the fantasy console, with the host's ends of its
+/// devices
+pub struct Console {
+    pub cpu: Cpu,
+    pub video: TileVideo,
+    pub gamepad: Gamepad,
+    pub sound: Sound,
+}
+
+impl Console {
+    /// Plugs in a cartridge of up to 32 KiB and powers on
+    pub fn new(cartridge: Rom) -> Result<Console, ConfigError> {
+        Console::with_cartridge(cartridge)
+    }
+
+    /// Like `new`, for anything that can sit in the cartridge slot, like
+    /// `Cartridge::into_device`
+    pub fn with_cartridge(cartridge: impl Device + 'static) -> Result<Console, ConfigError> {
+        let end = CARTRIDGE_START + cartridge.byte_length().max(1) - 1;
+        if end > CARTRIDGE_END {
+            return Err(ConfigError::Overlap(
+                "cartridge".to_string(),
+                "work ram".to_string(),
+            ));
+        }
+        let video = TileVideo::new();
+        let gamepad = Gamepad::new();
+        let sound = Sound::new();
+
+        let mut cpu = CpuBuilder::new()
+            .config(console_config())
+            .device("cartridge", cartridge, CARTRIDGE_START, end)
+            .device(
+                "video",
+                video.clone(),
+                VIDEO_START,
+                VIDEO_START + VIDEO_LENGTH - 1,
+            )
+            .device("gamepad", gamepad.clone(), GAMEPAD_START, GAMEPAD_START + 1)
+            .device("sound", sound.clone(), SOUND_START, SOUND_START + 3)
+            .build()?;
+        cpu.tick_devices_every(FRAME_CYCLES);
+
+        Ok(Console {
+            cpu,
+            video,
+            gamepad,
+            sound,
+        })
+    }
+
+    /// Runs until the next frame is shown
+    pub fn run_frame(&mut self) -> StopReason {
+        self.cpu.run(FRAME_CYCLES as usize)
+    }
+}
+
+/// The console's layout, without the devices, which `Console` maps
+pub fn console_config() -> MachineConfig {
+    MachineConfig {
+        stack_top: STACK_TOP,
+        stack_size: Some(STACK_SIZE),
+        entry_point: CARTRIDGE_START as u16,
+        interrupt_vector: INTERRUPT_VECTOR,
+        rng: Some(RNG_START),
+        ..MachineConfig::new(0x1_0000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{buttons, console_config, Console, Tone};
+    use super::{COLUMNS, FRAME_CYCLES, GAMEPAD_START, SOUND_START, VIDEO_START};
+    use crate::config::ConfigError;
+    use crate::cpu::{Instruction, Register, StopReason};
+    use crate::memory::{Memory, Rom};
+
+    #[test]
+    fn lays_out_the_console() {
+        assert_eq!(console_config().validate(), Ok(()));
+        assert_eq!(
+            Console::new(Rom::new(vec![0; 0x8001])).err(),
+            Some(ConfigError::Overlap(
+                "cartridge".to_string(),
+                "work ram".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn runs_a_cartridge() {
+        // loop:
+        //   mov [gamepad], r1
+        //   mov r1, [screen + 1]
+        //   mov r1, [sound]
+        //   jne 0xffff, loop ;; acc stays 0, so always
+        let mut rom = Memory::new(0x20);
+        rom.set_byte(0x00, Instruction::MovMemReg as u8);
+        rom.set_word(0x01, GAMEPAD_START as u16);
+        rom.set_byte(0x03, Register::Register1 as u8);
+        rom.set_byte(0x04, Instruction::MovRegMem as u8);
+        rom.set_byte(0x05, Register::Register1 as u8);
+        rom.set_word(0x06, VIDEO_START as u16 + COLUMNS as u16);
+        rom.set_byte(0x08, Instruction::MovRegMem as u8);
+        rom.set_byte(0x09, Register::Register1 as u8);
+        rom.set_word(0x0a, SOUND_START as u16);
+        rom.set_byte(0x0c, Instruction::JmpNotEq as u8);
+        rom.set_word(0x0d, 0xffff);
+        rom.set_word(0x0f, 0x0000);
+        let mut console = Console::new(Rom::new(rom.peek(0, 0x20))).unwrap();
+
+        console.gamepad.set_buttons(buttons::START | buttons::A);
+        assert_eq!(console.run_frame(), StopReason::FuelExhausted);
+        assert_eq!(console.video.frame(), 1);
+        assert_eq!(console.cpu.instruction_count(), FRAME_CYCLES);
+
+        // The buttons land in the first column of the second row
+        let screen = console.video.render();
+        assert_eq!(screen.lines().nth(1).unwrap().chars().next(), Some('P'));
+        assert_eq!(
+            console.sound.tone(),
+            Tone {
+                frequency: 0x5000,
+                volume: 0
+            }
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::cpu::{opcode_info, Instruction, MAX_INSTRUCTION_LENGTH};
+use crate::disassembler::{disassemble_one, Disassembly};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Why control goes from one block to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edge {
+    /// A branch whose condition held
+    Taken,
+    /// On to the next instruction, after a branch that wasn't taken, a call
+    /// that returned or because the next one starts a block
+    Fallthrough,
+    /// Into a subroutine
+    Call,
+}
+
+impl Edge {
+    fn label(self) -> &'static str {
+        match self {
+            Edge::Taken => "taken",
+            Edge::Fallthrough => "fallthrough",
+            Edge::Call => "call",
+        }
+    }
+}
+
+/// Straight-line instructions, only entered at the top
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub instructions: Vec<Disassembly>,
+    /// Blocks control may go to next, by start address
+    pub successors: Vec<(usize, Edge)>,
+}
+
+/// The basic blocks reachable from a set of entry points
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<usize, BasicBlock>,
+}
+
+/// Where an instruction sends control
+struct Flow {
+    /// The instruction after it runs next, or is where a call returns to
+    falls_through: bool,
+    target: Option<(usize, Edge)>,
+}
+
+fn flow(code: &[u8], base: usize, instruction: &Disassembly) -> Flow {
+    let offset = instruction.address - base;
+    let opcode = code[offset];
+    let target = || {
+        let bytes = [code[offset + 1], code[offset + 2]];
+        u16::from_be_bytes(bytes) as usize
+    };
+    let target_after_literal = || {
+        let bytes = [code[offset + 3], code[offset + 4]];
+        u16::from_be_bytes(bytes) as usize
+    };
+    match opcode_info(opcode).map(|info| info.instruction) {
+        // Cut off instructions and bytes that aren't code
+        _ if instruction.text.starts_with("db ") => Flow {
+            falls_through: false,
+            target: None,
+        },
+        Some(Instruction::JmpNotEq) => Flow {
+            falls_through: true,
+            target: Some((target_after_literal(), Edge::Taken)),
+        },
+        Some(Instruction::JmpLit) => Flow {
+            falls_through: false,
+            target: Some((target(), Edge::Taken)),
+        },
+        Some(
+            Instruction::JmpIfEq
+            | Instruction::JmpIfNe
+            | Instruction::JmpIfGt
+            | Instruction::JmpIfLt
+            | Instruction::JmpIfGe
+            | Instruction::JmpIfLe
+            | Instruction::JmpIfCarry
+            | Instruction::JmpIfNoCarry
+            | Instruction::JmpIfOverflow
+            | Instruction::JmpIfNoOverflow,
+        ) => Flow {
+            falls_through: true,
+            target: Some((target(), Edge::Taken)),
+        },
+        Some(Instruction::CalLit) => Flow {
+            falls_through: true,
+            target: Some((target(), Edge::Call)),
+        },
+        // Jumps through registers, returns, panics and halts go on somewhere
+        // unknown, or nowhere
+        Some(
+            Instruction::JmpReg
+            | Instruction::Ret
+            | Instruction::FarRet
+            | Instruction::RetInt
+            | Instruction::Panic
+            | Instruction::Halt,
+        ) => Flow {
+            falls_through: false,
+            target: None,
+        },
+        // Calls through registers, into other banks and interrupts go
+        // somewhere unknown, but come back
+        _ => Flow {
+            falls_through: true,
+            target: None,
+        },
+    }
+}
+
+/// Follows every path from `entries` through `code`, which is loaded at
+/// `base`. Targets outside of the code are left out, and paths end at bytes
+/// that don't decode.
+pub fn control_flow_graph(code: &[u8], base: usize, entries: &[usize]) -> ControlFlowGraph {
+    let end = base + code.len();
+    let decode = |address: usize| {
+        let offset = address - base;
+        let length = MAX_INSTRUCTION_LENGTH.min(code.len() - offset);
+        disassemble_one(&code[offset..offset + length], address)
+    };
+    let in_code = |address: usize| base <= address && address < end;
+
+    // Find every reachable instruction and where blocks have to start
+    let mut instructions = BTreeMap::new();
+    let mut leaders: BTreeSet<usize> = entries.iter().copied().filter(|a| in_code(*a)).collect();
+    let mut pending: Vec<usize> = leaders.iter().copied().collect();
+    while let Some(address) = pending.pop() {
+        if instructions.contains_key(&address) {
+            continue;
+        }
+        let instruction = decode(address);
+        let next = address + instruction.bytes.len();
+        let flow = flow(code, base, &instruction);
+        instructions.insert(address, instruction);
+
+        if let Some((target, _)) = flow.target {
+            if in_code(target) {
+                leaders.insert(target);
+                pending.push(target);
+            }
+        }
+        if flow.falls_through && in_code(next) {
+            if flow.target.is_some() {
+                leaders.insert(next);
+            }
+            pending.push(next);
+        }
+    }
+
+    // Cut the instructions into blocks at the leaders
+    let mut blocks = BTreeMap::new();
+    for &start in &leaders {
+        let mut block = BasicBlock {
+            start,
+            instructions: Vec::new(),
+            successors: Vec::new(),
+        };
+        let mut address = start;
+        while let Some(instruction) = instructions.get(&address) {
+            let next = address + instruction.bytes.len();
+            let flow = flow(code, base, instruction);
+            block.instructions.push(instruction.clone());
+
+            if let Some((target, edge)) = flow.target {
+                if in_code(target) {
+                    block.successors.push((target, edge));
+                }
+            }
+            let ends_block = flow.target.is_some() || !flow.falls_through;
+            if flow.falls_through && in_code(next) && (ends_block || leaders.contains(&next)) {
+                block.successors.push((next, Edge::Fallthrough));
+                break;
+            }
+            if ends_block {
+                break;
+            }
+            address = next;
+        }
+        blocks.insert(start, block);
+    }
+    ControlFlowGraph { blocks }
+}
+
+impl ControlFlowGraph {
+    /// The graph in Graphviz DOT, one box per block
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for instruction in &block.instructions {
+                let _ = write!(
+                    label,
+                    "{:#06x}  {}\\l",
+                    instruction.address,
+                    instruction.text.replace('"', "\\\"")
+                );
+            }
+            let _ = writeln!(dot, "    \"{:#06x}\" [label=\"{}\"];", block.start, label);
+        }
+        for block in self.blocks.values() {
+            for (target, edge) in &block.successors {
+                let style = match edge {
+                    Edge::Call => ", style=dashed",
+                    _ => "",
+                };
+                let _ = writeln!(
+                    dot,
+                    "    \"{:#06x}\" -> \"{:#06x}\" [label=\"{}\"{}];",
+                    block.start,
+                    target,
+                    edge.label(),
+                    style
+                );
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{control_flow_graph, Edge};
+    use crate::bench::standard_workload;
+
+    #[test]
+    fn splits_the_standard_workload_into_blocks() {
+        let code = standard_workload().peek(0, 0x200);
+        let graph = control_flow_graph(&code, 0, &[0]);
+
+        let starts: Vec<usize> = graph.blocks.keys().copied().collect();
+        assert_eq!(starts, [0x0000, 0x0004, 0x000c, 0x0023, 0x0028, 0x0100]);
+        assert_eq!(
+            graph.blocks[&0x0000].successors,
+            [(0x0004, Edge::Fallthrough)]
+        );
+        assert_eq!(
+            graph.blocks[&0x0004].successors,
+            [(0x0100, Edge::Call), (0x000c, Edge::Fallthrough)]
+        );
+        assert_eq!(
+            graph.blocks[&0x000c].successors,
+            [(0x0004, Edge::Taken), (0x0023, Edge::Fallthrough)]
+        );
+        assert_eq!(
+            graph.blocks[&0x0023].successors,
+            [(0x0000, Edge::Taken), (0x0028, Edge::Fallthrough)]
+        );
+        // The zeroes after the loop are noops running into the subroutine
+        assert_eq!(
+            graph.blocks[&0x0028].successors,
+            [(0x0100, Edge::Fallthrough)]
+        );
+        assert_eq!(graph.blocks[&0x0100].successors, []);
+        assert_eq!(graph.blocks[&0x0100].instructions.len(), 3);
+    }
+
+    #[test]
+    fn stops_at_bytes_that_are_not_code() {
+        // jne 0x0000, 0x0010 ;; into the middle of nowhere
+        // 0xee
+        let code = [0x15, 0x00, 0x00, 0x00, 0x10, 0xee];
+        let graph = control_flow_graph(&code, 0, &[0]);
+        assert_eq!(graph.blocks.len(), 2);
+        assert_eq!(graph.blocks[&0x0005].instructions[0].text, "db 0xee");
+        assert_eq!(graph.blocks[&0x0005].successors, []);
+    }
+}
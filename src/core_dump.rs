@@ -0,0 +1,338 @@
+use crate::clock::Clock;
+use crate::cpu::{Cpu, Fault, Register, GENERAL_PURPOSE_REGISTERS};
+use crate::debugger::{trace_line, FaultAt};
+use crate::memory::Memory;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Bytes per `memory` line of a core file
+const BYTES_PER_LINE: usize = 32;
+
+/// The last trace lines of a run, to explain how it got where it stopped
+#[derive(Debug, Clone, Default)]
+pub struct TraceHistory {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl TraceHistory {
+    pub fn new(capacity: usize) -> TraceHistory {
+        TraceHistory {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Remembers the `trace_line` of the instruction `cpu` is about to run
+    pub fn record(&mut self, cpu: &Cpu) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(trace_line(cpu));
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &String> {
+        self.lines.iter()
+    }
+}
+
+/// Everything needed to look at a CPU after it faulted, in a text form
+/// with one directive per line, numbers in hex:
+///
+/// ```text
+/// fault <address> <kind> <fault address> [values]...
+/// instructions <count>
+/// register <name> <value>
+/// state <stack top> <stack frame size> <in interrupt handler>
+/// memory <size>
+/// bytes <address> <bytes>...
+/// trace <trace line>
+/// ```
+///
+/// Memory that is all zero is left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreDump {
+    pub fault: FaultAt,
+    pub instructions: u64,
+    pub registers: Vec<(Register, u16)>,
+    pub stack_top: u16,
+    pub stack_frame_size: usize,
+    pub in_interrupt_handler: bool,
+    pub memory: Vec<u8>,
+    /// Oldest first
+    pub trace: Vec<String>,
+}
+
+impl CoreDump {
+    pub fn capture(cpu: &Cpu, fault: FaultAt, history: &TraceHistory) -> CoreDump {
+        CoreDump {
+            fault,
+            instructions: cpu.instruction_count(),
+            registers: cpu
+                .registers()
+                .map(|(register, _, value)| (register, value))
+                .collect(),
+            stack_top: cpu.stack_top,
+            stack_frame_size: cpu.stack_frame_size,
+            in_interrupt_handler: cpu.is_in_interrupt_handler,
+            memory: cpu.peek_memory(0, cpu.memory.byte_length()),
+            trace: history.lines().cloned().collect(),
+        }
+    }
+
+    /// A CPU in the state the dump was taken in, on plain memory, for
+    /// looking around post-mortem. Devices and their state are gone.
+    pub fn restore(&self) -> Cpu {
+        let mut memory = Memory::new(self.memory.len());
+        for (address, byte) in self.memory.iter().enumerate() {
+            memory.set_byte(address, *byte);
+        }
+        let clock = Clock::new();
+        clock.advance_by(self.instructions);
+
+        let general_purpose_registers = self
+            .registers
+            .iter()
+            .filter(|(register, _)| GENERAL_PURPOSE_REGISTERS.contains(register))
+            .count();
+        let mut cpu = Cpu::with_clock(memory, clock)
+            .with_general_purpose_registers(general_purpose_registers.max(1));
+        cpu.stack_top = self.stack_top;
+        cpu.stack_frame_size = self.stack_frame_size;
+        cpu.is_in_interrupt_handler = self.in_interrupt_handler;
+        for (register, value) in &self.registers {
+            cpu.set_register(*register, *value);
+        }
+        cpu
+    }
+}
+
+impl Display for CoreDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, address, values) = match self.fault.fault {
+            Fault::IllegalOperand { address, value } => {
+                ("illegal-operand", address, format!(" {:02x}", value))
+            }
+            Fault::FetchOutOfBounds { address } => ("fetch-out-of-bounds", address, String::new()),
+            Fault::StackOverflow { address } => ("stack-overflow", address, String::new()),
+            Fault::StackUnderflow { address } => ("stack-underflow", address, String::new()),
+            Fault::ReturnAddressCorrupted {
+                address,
+                expected,
+                found,
+            } => (
+                "return-address-corrupted",
+                address,
+                format!(" {:04x} {:04x}", expected, found),
+            ),
+            Fault::CallDepthExceeded { address, limit } => {
+                ("call-depth-exceeded", address, format!(" {:x}", limit))
+            }
+            Fault::Unaligned { address } => ("unaligned", address, String::new()),
+            Fault::MemoryOutOfBounds { address } => {
+                ("memory-out-of-bounds", address, String::new())
+            }
+            Fault::DivideByZero { address } => ("divide-by-zero", address, String::new()),
+            Fault::GuestPanic { message, code } => {
+                ("guest-panic", message, format!(" {:04x}", code))
+            }
+        };
+        writeln!(
+            f,
+            "fault {:04x} {} {:04x}{}",
+            self.fault.address, kind, address, values
+        )?;
+        writeln!(f, "instructions {:x}", self.instructions)?;
+        for (register, value) in &self.registers {
+            writeln!(f, "register {} {:04x}", register.name(), value)?;
+        }
+        writeln!(
+            f,
+            "state {:04x} {:x} {}",
+            self.stack_top, self.stack_frame_size, self.in_interrupt_handler as u8
+        )?;
+        writeln!(f, "memory {:x}", self.memory.len())?;
+        for (index, line) in self.memory.chunks(BYTES_PER_LINE).enumerate() {
+            if line.iter().all(|byte| *byte == 0) {
+                continue;
+            }
+            let bytes: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            writeln!(
+                f,
+                "bytes {:04x} {}",
+                index * BYTES_PER_LINE,
+                bytes.join(" ")
+            )?;
+        }
+        for line in &self.trace {
+            writeln!(f, "trace {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CoreDump {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dump = CoreDump {
+            fault: FaultAt {
+                fault: Fault::FetchOutOfBounds { address: 0 },
+                address: 0,
+            },
+            instructions: 0,
+            registers: Vec::new(),
+            stack_top: 0,
+            stack_frame_size: 0,
+            in_interrupt_handler: false,
+            memory: Vec::new(),
+            trace: Vec::new(),
+        };
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let Some((directive, rest)) = line.split_once(' ') else {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Err(format!("Line {}: missing field", line_number));
+            };
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let field = |index: usize| {
+                fields
+                    .get(index)
+                    .copied()
+                    .ok_or(format!("Line {}: missing field", line_number))
+            };
+            let hex = |index: usize| {
+                usize::from_str_radix(field(index)?, 16)
+                    .map_err(|e| format!("Line {}: {}", line_number, e))
+            };
+            match directive {
+                "fault" => {
+                    let address = hex(2)? as u16;
+                    let fault = match field(1)? {
+                        "illegal-operand" => Fault::IllegalOperand {
+                            address,
+                            value: hex(3)? as u8,
+                        },
+                        "fetch-out-of-bounds" => Fault::FetchOutOfBounds { address },
+                        "stack-overflow" => Fault::StackOverflow { address },
+                        "stack-underflow" => Fault::StackUnderflow { address },
+                        "unaligned" => Fault::Unaligned { address },
+                        "memory-out-of-bounds" => Fault::MemoryOutOfBounds { address },
+                        "divide-by-zero" => Fault::DivideByZero { address },
+                        "guest-panic" => Fault::GuestPanic {
+                            message: address,
+                            code: hex(3)? as u16,
+                        },
+                        "return-address-corrupted" => Fault::ReturnAddressCorrupted {
+                            address,
+                            expected: hex(3)? as u16,
+                            found: hex(4)? as u16,
+                        },
+                        "call-depth-exceeded" => Fault::CallDepthExceeded {
+                            address,
+                            limit: hex(3)?,
+                        },
+                        kind => {
+                            return Err(format!("Line {}: unknown fault {}", line_number, kind))
+                        }
+                    };
+                    dump.fault = FaultAt {
+                        fault,
+                        address: hex(0)? as u16,
+                    };
+                }
+                "instructions" => dump.instructions = hex(0)? as u64,
+                "register" => {
+                    let register = field(0)?
+                        .parse()
+                        .map_err(|e| format!("Line {}: {}", line_number, e))?;
+                    dump.registers.push((register, hex(1)? as u16));
+                }
+                "state" => {
+                    dump.stack_top = hex(0)? as u16;
+                    dump.stack_frame_size = hex(1)?;
+                    dump.in_interrupt_handler = hex(2)? != 0;
+                }
+                "memory" => dump.memory = vec![0; hex(0)?],
+                "bytes" => {
+                    let address = hex(0)?;
+                    for index in 1..fields.len() {
+                        *dump
+                            .memory
+                            .get_mut(address + index - 1)
+                            .ok_or(format!("Line {}: outside of memory", line_number))? =
+                            hex(index)? as u8;
+                    }
+                }
+                "trace" => dump.trace.push(rest.to_string()),
+                directive => {
+                    return Err(format!(
+                        "Line {}: unknown directive {}",
+                        line_number, directive
+                    ))
+                }
+            }
+        }
+        Ok(dump)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoreDump, TraceHistory};
+    use crate::cpu::{Cpu, Fault, Instruction, Register};
+    use crate::debugger;
+    use crate::memory::Memory;
+
+    #[test]
+    fn dumps_and_restores_a_faulted_cpu() {
+        // mov 0x1234, r1
+        // mov 0x0001, <not a register>
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x00, Instruction::MovLitReg as u8);
+        memory.set_word(0x01, 0x1234);
+        memory.set_byte(0x03, Register::Register1 as u8);
+        memory.set_byte(0x04, Instruction::MovLitReg as u8);
+        memory.set_word(0x05, 0x0001);
+        memory.set_byte(0x07, 0xee);
+        let mut cpu = Cpu::new(memory).with_general_purpose_registers(4);
+
+        let mut history = TraceHistory::new(1);
+        let fault = loop {
+            history.record(&cpu);
+            if let Err(fault) = debugger::step(&mut cpu) {
+                break fault;
+            }
+        };
+        assert_eq!(
+            fault.fault,
+            Fault::IllegalOperand {
+                address: 0x07,
+                value: 0xee
+            }
+        );
+
+        let dump = CoreDump::capture(&cpu, fault, &history);
+        assert_eq!(dump.trace.len(), 1, "Only the last instruction");
+        assert!(dump.trace[0].starts_with("0x0004"), "{}", dump.trace[0]);
+        let text = dump.to_string();
+        assert!(
+            text.starts_with("fault 0004 illegal-operand 0007 ee\n"),
+            "{}",
+            text
+        );
+
+        let parsed: CoreDump = text.parse().unwrap();
+        assert_eq!(parsed, dump);
+        let restored = parsed.restore();
+        assert_eq!(restored.state_hash(), cpu.state_hash());
+        assert_eq!(restored.instruction_count(), cpu.instruction_count());
+        assert_eq!(restored.general_purpose_registers().len(), 4);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::cpu::Cpu;
+
+/// Bytes `CpuBuilder::counters` maps: the cycle, instruction and interrupt
+/// counts, 64 bits each, most significant word first
+pub const COUNTERS_SIZE: usize = COUNTERS * COUNTER_BYTES;
+
+/// Where `COUNTERS_SIZE` bytes are out of the way, below the register
+/// window
+pub const COUNTERS_ADDRESS: usize = 0xffa0;
+
+pub(crate) const COUNTERS: usize = 3;
+
+const COUNTER_BYTES: usize = 8;
+
+impl Cpu {
+    /// Interrupts taken since power on, the ones masked off don't count
+    pub fn interrupt_count(&self) -> u64 {
+        self.interrupt_count
+    }
+
+    /// Whether any of the `length` bytes from `address` are in the counters
+    pub(crate) fn in_counters(&self, address: usize, length: usize) -> bool {
+        self.counters
+            .is_some_and(|start| address < start + COUNTERS_SIZE && start < address + length)
+    }
+
+    fn counter_value(&self, counter: usize) -> u64 {
+        match counter {
+            0 => self.cycle_count(),
+            1 => self.instruction_count(),
+            _ => self.interrupt_count,
+        }
+    }
+
+    /// The byte at `address` of the counters, or of memory outside them.
+    /// Reading the low word of a counter latches the rest of it, which
+    /// reads from the latch from then on, so reading low to high gives
+    /// one count even if it carries in between.
+    pub(crate) fn counter_byte(&mut self, address: usize) -> u8 {
+        match self.counters {
+            Some(start) if (start..start + COUNTERS_SIZE).contains(&address) => {
+                let counter = (address - start) / COUNTER_BYTES;
+                let offset = (address - start) % COUNTER_BYTES;
+                if offset >= COUNTER_BYTES - 2 {
+                    self.counter_latches[counter] = self.counter_value(counter);
+                }
+                self.counter_latches[counter].to_be_bytes()[offset]
+            }
+            _ => self.memory.get_byte(address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::COUNTERS_ADDRESS;
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::mapper::WaitStates;
+    use crate::memory::Memory;
+
+    #[test]
+    fn guests_time_themselves() {
+        let low = |counter: u16| COUNTERS_ADDRESS as u16 + counter * 8 + 6;
+        // mov [instructions low], r1
+        // mov r1, [0x3000]  ;; a slow device, 3 wait states
+        // mov [cycles low], r2
+        // mov [cycles low - 2], r3
+        // int 0x0001
+        // handler:
+        // mov [interrupts low], r4
+        // mov r4, [cycles low]  ;; read only
+        // mov [cycles low], r5
+        let mut code = Vec::new();
+        for (instruction, address, register) in [
+            (Instruction::MovMemReg, low(1), Register::Register1),
+            (Instruction::MovRegMem, 0x3000, Register::Register1),
+            (Instruction::MovMemReg, low(0), Register::Register2),
+            (Instruction::MovMemReg, low(0) - 2, Register::Register3),
+        ] {
+            let [high, low] = address.to_be_bytes();
+            code.extend(match instruction {
+                Instruction::MovRegMem => [instruction as u8, register as u8, high, low],
+                _ => [instruction as u8, high, low, register as u8],
+            });
+        }
+        code.extend([Instruction::Int as u8, 0x00, 0x01]);
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .counters(COUNTERS_ADDRESS)
+            .device(
+                "slow",
+                WaitStates::new(Memory::new(2), 0, 3),
+                0x3000,
+                0x3001,
+            )
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        let mut handler = Vec::new();
+        for (instruction, address, register) in [
+            (Instruction::MovMemReg, low(2), Register::Register4),
+            (Instruction::MovRegMem, low(0), Register::Register4),
+            (Instruction::MovMemReg, low(0), Register::Register5),
+        ] {
+            let [high, low] = address.to_be_bytes();
+            handler.extend(match instruction {
+                Instruction::MovRegMem => [instruction as u8, register as u8, high, low],
+                _ => [instruction as u8, high, low, register as u8],
+            });
+        }
+        for (i, byte) in handler.iter().enumerate() {
+            cpu.memory_mut().set_byte(0x0100 + i, *byte);
+        }
+        cpu.memory_mut().set_word(0x1002, 0x0100);
+
+        cpu.run(8);
+        assert_eq!(cpu.peek_register(Register::Register1), 0);
+        assert_eq!(
+            cpu.peek_register(Register::Register2),
+            5,
+            "Two instructions and the wait states"
+        );
+        assert_eq!(cpu.peek_register(Register::Register3), 0, "Latched");
+        assert_eq!(cpu.peek_register(Register::Register4), 1);
+        assert_eq!(cpu.interrupt_count(), 1);
+        assert_eq!(
+            cpu.peek_register(Register::Register5),
+            10,
+            "Writes are dropped"
+        );
+    }
+}
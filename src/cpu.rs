@@ -1,53 +1,526 @@
-use crate::memory::Memory;
-use std::fmt::Debug;
+use crate::block_cache::BlockCache;
+use crate::clock::Clock;
+use crate::config::{CpuBuilder, MachineConfig};
+use crate::counters::COUNTERS;
+use crate::extension::InstructionHandler;
+#[cfg(feature = "instrument")]
+use crate::instrument::{Metrics, Observer};
+use crate::isa::Features;
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::mapper::Device;
+use crate::self_modifying::ExecutedCode;
+use crate::snapshot::{DirtyPages, Page};
+use crate::time_slice;
+use crate::watchdog::Watchdog;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Width of registers, memory words and stack slots. Everything that
+/// depends on the word size follows this definition and `WORD_BYTES`, so
+/// that's where an 8 or 32 bit variant of the machine would start.
+pub type Word = u16;
+
+/// Bytes in a `Word`, the stride of the stack and of word operands
+pub const WORD_BYTES: usize = std::mem::size_of::<Word>();
+
+/// Where the table of interrupt handler addresses starts by default
+pub const INTERRUPT_VECTOR_ADDRESS: usize = 0x1000;
+
+/// General purpose registers in the order they are enabled
+pub const GENERAL_PURPOSE_REGISTERS: [Register; 15] = [
+    Register::Register1,
+    Register::Register2,
+    Register::Register3,
+    Register::Register4,
+    Register::Register5,
+    Register::Register6,
+    Register::Register7,
+    Register::Register8,
+    Register::Register9,
+    Register::Register10,
+    Register::Register11,
+    Register::Register12,
+    Register::Register13,
+    Register::Register14,
+    Register::Register15,
+];
+
+pub const DEFAULT_GENERAL_PURPOSE_REGISTERS: usize = 8;
+
+/// Slots in the register file, enough for every register the ISA encodes
+pub(crate) const REGISTER_FILE_SIZE: usize = Register::Flags as usize + 1;
+
+/// Bytes in the register window, a word per register in the order of
+/// their encodings
+pub const REGISTER_WINDOW_SIZE: usize = REGISTER_FILE_SIZE * WORD_BYTES;
+
+/// Where machines usually map the register window, at the top of memory
+pub const REGISTER_WINDOW_ADDRESS: usize = 0xffc0;
+
+/// Set in the flags register if the result was zero
+pub const ZERO_FLAG: Word = 1 << 0;
+/// Set in the flags register if an unsigned add carried out of the top bit
+/// or an unsigned subtract borrowed
+pub const CARRY_FLAG: Word = 1 << 1;
+/// Set in the flags register if the top bit of the result was set
+pub const SIGN_FLAG: Word = 1 << 2;
+/// Set in the flags register if the result doesn't fit in a signed word
+pub const OVERFLOW_FLAG: Word = 1 << 3;
 
 pub struct Cpu {
-    memory: Memory,
-    register: Memory,
-    register_names: [Register; 12],
-    stack_frame_size: usize,
+    pub(crate) memory: Box<dyn Device>,
+    /// Where instructions are fetched from on a Harvard machine, `memory`
+    /// holding only data. `None` fetches from `memory` too.
+    code: Option<Box<dyn Device>>,
+    pub(crate) register: [Word; REGISTER_FILE_SIZE],
+    register_names: Vec<Register>,
+    /// Bit `n` is set if the register encoded as `n` is enabled
+    enabled_registers: u32,
+    general_purpose_registers: usize,
+    pub(crate) stack_frame_size: usize,
+    pub(crate) clock: Clock,
+    interrupt_vector_address: usize,
+    pub(crate) is_in_interrupt_handler: bool,
+    /// Whether `Instruction::Halt` stopped the CPU, until the next reset
+    pub(crate) halted: bool,
+    entry_point: u16,
+    reset_vector: Option<usize>,
+    pub(crate) stack_top: u16,
+    /// Lowest address a push may write to
+    stack_limit: u16,
+    /// Return addresses of the active calls and interrupts, innermost last,
+    /// if they are checked on return
+    pub(crate) shadow_stack: Option<Vec<u16>>,
+    /// Shadow stacks of the stacks swapped out or saved, by the address of
+    /// the block that holds them
+    pub(crate) parked_shadow_stacks: HashMap<u16, Vec<u16>>,
+    /// Calls and interrupt handlers active
+    pub(crate) call_depth: usize,
+    max_call_depth: Option<usize>,
+    /// Where guest loads and stores reach the registers, if anywhere
+    register_window: Option<usize>,
+    /// Where guest loads reach the performance counters, if anywhere
+    pub(crate) counters: Option<usize>,
+    /// Counts as of the last read of their low word, one per counter
+    pub(crate) counter_latches: [u64; COUNTERS],
+    /// Interrupts taken since power on
+    pub(crate) interrupt_count: u64,
+    /// Whether word accesses at odd addresses fault
+    strict_alignment: bool,
+    /// Bank the code being run is in, as last selected by a far call or
+    /// return
+    pub(crate) code_bank: u16,
+    /// Where far calls and returns write the bank they switch to
+    bank_select: Option<usize>,
+    pub(crate) features: Features,
+    pub(crate) custom_instructions: HashMap<u8, Arc<dyn InstructionHandler>>,
+    pub(crate) watchdog: Option<Watchdog>,
+    pub(crate) events: Vec<Event>,
+    pub(crate) executed_code: Option<Box<ExecutedCode>>,
+    pub(crate) block_cache: BlockCache,
+    /// Pages written since the last snapshot
+    pub(crate) dirty_pages: DirtyPages,
+    /// Memory as of the last snapshot taken or restored, shared with it
+    pub(crate) snapshot_pages: Option<Vec<Page>>,
+    /// Cycles between two device ticks, if the CPU ticks devices at all
+    pub(crate) device_tick_interval: Option<u64>,
+    pub(crate) cycles_since_tick: u64,
+    /// Cycles the current instruction stalled for on slow devices
+    wait_states: u64,
+    /// Cycles stalled on slow devices since power on
+    pub(crate) stalled_cycles: u64,
+    /// Interrupts raised by devices, taken before the next instruction
+    pub(crate) pending_interrupts: Vec<u16>,
+    /// Cycle the pending interrupts were raised at, the start of the tick
+    /// that raised them since devices can't tell any closer
+    pub(crate) interrupts_raised_at: u64,
+    /// Instructions `run_for` executes between two looks at the time
+    pub(crate) time_slice_chunk: usize,
+    #[cfg(feature = "instrument")]
+    pub(crate) observers: Vec<Box<dyn Observer>>,
+    #[cfg(feature = "instrument")]
+    pub(crate) metrics: Option<Metrics>,
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Option<Box<Jit>>,
 }
 
 impl Cpu {
-    pub fn new(memory: Memory) -> Cpu {
-        let register_names = [
-            Register::InstructionPointer,
-            Register::Accumulator,
-            Register::Register1,
-            Register::Register2,
-            Register::Register3,
-            Register::Register4,
-            Register::Register5,
-            Register::Register6,
-            Register::Register7,
-            Register::Register8,
-            Register::StackPointer,
-            Register::FramePointer,
-        ];
+    pub fn new(memory: impl Device + 'static) -> Cpu {
+        Cpu::with_clock(memory, Clock::new())
+    }
 
-        let mut register = Memory::new(register_names.len() * 2);
+    /// Creates a CPU that counts instructions on a clock shared with devices
+    pub fn with_clock(memory: impl Device + 'static, clock: Clock) -> Cpu {
+        let config = MachineConfig::new(memory.byte_length());
+        Cpu::from_config(Box::new(memory), &config, clock)
+    }
 
-        let bottom_of_stack = memory.byte_length() - 1 - 1;
-        let stack_pointer_pointer = Register::StackPointer as usize * 2;
-        let frame_pointer_pointer = Register::FramePointer as usize * 2;
-        register.set_word(stack_pointer_pointer, bottom_of_stack as u16);
-        register.set_word(frame_pointer_pointer, bottom_of_stack as u16);
+    /// Creates a Harvard machine, fetching instructions from `code` while
+    /// loads, stores and the stack go to `data`
+    pub fn harvard(code: impl Device + 'static, data: impl Device + 'static) -> Cpu {
+        let mut cpu = Cpu::new(data);
+        cpu.set_code_space(Box::new(code));
+        cpu
+    }
 
-        Cpu {
+    /// Starts configuring a machine, see `MachineConfig` for the defaults
+    pub fn builder() -> CpuBuilder {
+        CpuBuilder::new()
+    }
+
+    pub(crate) fn from_config(
+        memory: Box<dyn Device>,
+        config: &MachineConfig,
+        clock: Clock,
+    ) -> Cpu {
+        let mut cpu = Cpu {
             memory,
-            register,
-            register_names,
+            code: None,
+            register: [0; REGISTER_FILE_SIZE],
+            register_names: Vec::new(),
+            enabled_registers: 0,
+            general_purpose_registers: DEFAULT_GENERAL_PURPOSE_REGISTERS,
             stack_frame_size: 0,
+            clock,
+            interrupt_vector_address: config.interrupt_vector,
+            is_in_interrupt_handler: false,
+            halted: false,
+            entry_point: config.entry_point,
+            reset_vector: config.reset_vector,
+            stack_top: config.stack_top as u16,
+            stack_limit: config.stack_limit() as u16,
+            shadow_stack: None,
+            parked_shadow_stacks: HashMap::new(),
+            call_depth: 0,
+            max_call_depth: config.max_call_depth,
+            register_window: config.register_window,
+            counters: config.counters,
+            counter_latches: [0; COUNTERS],
+            interrupt_count: 0,
+            strict_alignment: config.strict_alignment,
+            code_bank: 0,
+            bank_select: config.bank_select,
+            features: Features::BUILT_IN | config.features,
+            custom_instructions: HashMap::new(),
+            watchdog: None,
+            events: Vec::new(),
+            executed_code: None,
+            block_cache: BlockCache::default(),
+            dirty_pages: DirtyPages::default(),
+            snapshot_pages: None,
+            device_tick_interval: None,
+            cycles_since_tick: 0,
+            wait_states: 0,
+            stalled_cycles: 0,
+            pending_interrupts: Vec::new(),
+            interrupts_raised_at: 0,
+            time_slice_chunk: time_slice::INITIAL_CHUNK,
+            #[cfg(feature = "instrument")]
+            observers: Vec::new(),
+            #[cfg(feature = "instrument")]
+            metrics: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+        .with_general_purpose_registers(config.general_purpose_registers);
+        cpu.reset();
+        cpu
+    }
+
+    /// Puts the CPU back in its power-on state, starting from the address in
+    /// the reset vector if there is one. Memory is left as it is.
+    pub fn reset(&mut self) {
+        self.register = [0; REGISTER_FILE_SIZE];
+
+        let bottom_of_stack = self.stack_top;
+        self.set_register(Register::StackPointer, bottom_of_stack);
+        self.set_register(Register::FramePointer, bottom_of_stack);
+        let entry_point = match self.reset_vector {
+            Some(reset_vector) => self.memory.get_word(reset_vector),
+            None => self.entry_point,
+        };
+        self.set_register(Register::InstructionPointer, entry_point);
+        // All interrupts are enabled by default
+        self.set_register(Register::InterruptMask, 0xffff);
+
+        self.stack_frame_size = 0;
+        self.call_depth = 0;
+        self.is_in_interrupt_handler = false;
+        self.halted = false;
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            shadow_stack.clear();
+        }
+        self.parked_shadow_stacks.clear();
+        self.code_bank = 0;
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.kick();
+        }
+    }
+
+    /// Passes command line arguments to the program about to start, on the
+    /// stack: below the top of the stack sit the argument count, then the
+    /// address of a table of string addresses ending in 0, like C's `argv`.
+    /// The table and the NUL terminated strings it points to come right
+    /// after, still on the stack. Faults if they don't fit.
+    pub fn set_arguments(&mut self, arguments: &[&str]) -> Result<(), Fault> {
+        let table_size = (arguments.len() + 1) * WORD_BYTES;
+        let strings_size: usize = arguments.iter().map(|argument| argument.len() + 1).sum();
+        let size = (table_size + strings_size).next_multiple_of(WORD_BYTES);
+        let stack_pointer = self.get_register(Register::StackPointer) as usize;
+        // The first word pushed lands at the stack pointer, the last one
+        // starts the block
+        let Some(base) = (stack_pointer + WORD_BYTES).checked_sub(size) else {
+            return Err(Fault::StackOverflow {
+                address: stack_pointer as u16,
+            });
+        };
+
+        let mut block = Vec::with_capacity(size);
+        let mut string = base + table_size;
+        for argument in arguments {
+            block.extend_from_slice(&(string as Word).to_be_bytes());
+            string += argument.len() + 1;
+        }
+        block.extend_from_slice(&[0; WORD_BYTES]);
+        for argument in arguments {
+            block.extend_from_slice(argument.as_bytes());
+            block.push(0);
+        }
+        block.resize(size, 0);
+
+        for word in block.chunks(WORD_BYTES).rev() {
+            self.push(Word::from_be_bytes([word[0], word[1]]))?;
+        }
+        self.push(base as Word)?;
+        self.push(arguments.len() as Word)
+    }
+
+    /// Lets `watchdog` reset the CPU when guest code stops kicking it. Map
+    /// `Watchdog::registers` on the bus so guest code can reach it.
+    pub fn attach_watchdog(&mut self, watchdog: Watchdog) {
+        watchdog.kick();
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Calls and interrupt handlers that haven't returned yet. Returns out
+    /// of frames built by hand don't take it below zero.
+    pub fn call_depth(&self) -> usize {
+        self.call_depth
+    }
+
+    /// Bank the last far call or return switched to, 0 after a reset
+    pub fn code_bank(&self) -> u16 {
+        self.code_bank
+    }
+
+    /// Keeps a copy of every return address a call or interrupt saves on
+    /// the stack, and faults on return when the saved one was overwritten.
+    /// Returns out of frames built by hand aren't checked.
+    pub fn protect_return_addresses(&mut self) {
+        self.shadow_stack = Some(Vec::new());
+    }
+
+    /// Calls `Device::tick` on the memory every `cycles` instructions, so
+    /// devices see time pass in step with the program. Interrupts they raise
+    /// are taken before the next instruction. Like a watchdog, this makes
+    /// `run` and `run_jit` fall back to paths that see every instruction.
+    /// Of several CPUs on a shared bus, only one should tick it.
+    pub fn tick_devices_every(&mut self, cycles: u64) {
+        assert!(
+            cycles > 0,
+            "Devices have to be ticked every 1 or more cycles"
+        );
+        self.device_tick_interval = Some(cycles);
+        self.cycles_since_tick = 0;
+    }
+
+    /// Hands over the events raised since the last call
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Enables R1 up to R`count`, which also changes how many registers
+    /// subroutine calls save on the stack. Panics unless `count` is 1 to 15.
+    pub fn with_general_purpose_registers(mut self, count: usize) -> Cpu {
+        if count == 0 || count > GENERAL_PURPOSE_REGISTERS.len() {
+            panic!(
+                "A CPU needs 1 to {} general purpose registers, got {}",
+                GENERAL_PURPOSE_REGISTERS.len(),
+                count
+            );
+        }
+        self.register_names = Cpu::register_names(count);
+        self.enabled_registers = self
+            .register_names
+            .iter()
+            .fold(0, |mask, register| mask | 1 << *register as u8);
+        self.general_purpose_registers = count;
+        self
+    }
+
+    /// Every register of this CPU with its name and value, in display order
+    pub fn registers(&self) -> impl Iterator<Item = (Register, &'static str, u16)> + '_ {
+        self.register_names
+            .iter()
+            .map(|register| (*register, register.name(), self.get_register(*register)))
+    }
+
+    pub fn general_purpose_registers(&self) -> &[Register] {
+        &GENERAL_PURPOSE_REGISTERS[..self.general_purpose_registers]
+    }
+
+    /// Executes the next instruction, doing nothing once the CPU halted
+    pub fn step(&mut self) -> Result<(), Fault> {
+        if self.halted {
+            return Ok(());
+        }
+        let result = self
+            .take_pending_interrupts()
+            .and_then(|()| {
+                #[cfg(feature = "instrument")]
+                self.notify_instruction();
+                self.fetch()
+            })
+            .and_then(|opcode| match &DISPATCH_TABLE[opcode as usize] {
+                Some(info) => self.fetch_operands(info).and_then(|operands| {
+                    let next = self.get_register(Register::InstructionPointer);
+                    (info.execute)(self, operands)?;
+                    #[cfg(debug_assertions)]
+                    self.check_advance(info, operands, next);
+                    Ok(())
+                }),
+                None => self.execute_custom(opcode),
+            });
+        self.retire();
+        result
+    }
+
+    /// Panics if an instruction that can't have moved the instruction
+    /// pointer left it anywhere but `next`, right after the instruction,
+    /// since the stream would be out of step with the length table from
+    /// there on
+    #[cfg(debug_assertions)]
+    fn check_advance(&self, info: &OpcodeInfo, operands: Operands, next: Word) {
+        let moves_ip = info.instruction.may_branch()
+            || self.register_window.is_some()
+            || info.operands.iter().zip(operands).any(|(kind, operand)| {
+                *kind == Operand::Register && operand == Register::InstructionPointer as u16
+            });
+        let ip = self.get_register(Register::InstructionPointer);
+        debug_assert!(
+            moves_ip || ip == next,
+            "{:?} left the instruction pointer at {:#06x} instead of {:#06x}",
+            info.instruction,
+            ip,
+            next
+        );
+    }
+
+    /// Whether something has to see every instruction, which rules out the
+    /// fast paths
+    pub(crate) fn needs_every_instruction(&self) -> bool {
+        // Loads and stores through the register window read and write the
+        // registers the fast paths keep in locals
+        let needed = self.watchdog.is_some()
+            || self.executed_code.is_some()
+            || self.device_tick_interval.is_some()
+            || self.register_window.is_some();
+        #[cfg(feature = "instrument")]
+        let needed = needed || !self.observers.is_empty() || self.metrics.is_some();
+        needed
+    }
+
+    /// Bookkeeping after every instruction, however it was executed
+    pub(crate) fn retire(&mut self) {
+        self.clock.advance();
+        let wait_states = std::mem::take(&mut self.wait_states);
+        self.stalled_cycles += wait_states;
+
+        if let Some(interval) = self.device_tick_interval {
+            // Devices keep running while the CPU waits on them
+            self.cycles_since_tick += 1 + wait_states;
+            if self.cycles_since_tick >= interval {
+                let cycles = std::mem::take(&mut self.cycles_since_tick);
+                let was_pending = self.has_pending_interrupts();
+                self.memory.tick(cycles, &mut self.pending_interrupts);
+                if !was_pending {
+                    self.interrupts_raised_at = self.cycle_count() - cycles;
+                }
+            }
+        }
+
+        if self.watchdog.as_ref().is_some_and(Watchdog::expired) {
+            self.events.push(Event::WatchdogReset {
+                instruction: self.clock.now(),
+            });
+            self.reset();
+        }
+    }
+
+    /// The bus the CPU reads and writes, for instruction handlers and loaders.
+    /// Drops the block cache, since the caller may overwrite code, and makes
+    /// the next snapshot copy all of memory.
+    pub fn memory_mut(&mut self) -> &mut dyn Device {
+        self.block_cache.clear();
+        self.dirty_pages.mark_all();
+        self.memory.as_mut()
+    }
+
+    /// The bus instructions are fetched from, `memory_mut` unless this is a
+    /// Harvard machine. Drops the block cache like `memory_mut`.
+    pub fn code_memory_mut(&mut self) -> &mut dyn Device {
+        self.block_cache.clear();
+        self.code_mut()
+    }
+
+    /// Whether instructions and data live in separate address spaces
+    pub fn is_harvard(&self) -> bool {
+        self.code.is_some()
+    }
+
+    pub(crate) fn set_code_space(&mut self, code: Box<dyn Device>) {
+        self.code = Some(code);
+        self.block_cache.clear();
+        self.reset();
+    }
+
+    /// Bytes of memory instructions can be fetched from
+    pub(crate) fn code_length(&self) -> usize {
+        self.code().byte_length()
+    }
+
+    fn code(&self) -> &dyn Device {
+        self.code.as_deref().unwrap_or(self.memory.as_ref())
+    }
+
+    pub(crate) fn code_mut(&mut self) -> &mut dyn Device {
+        match &mut self.code {
+            Some(code) => code.as_mut(),
+            None => self.memory.as_mut(),
         }
     }
 
-    pub fn step(&mut self) {
-        let instruction = self.fetch();
-        self.execute(instruction.into());
+    pub fn instruction_count(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Cycles since power on, one per instruction plus the wait states of
+    /// the slow devices accessed
+    pub fn cycle_count(&self) -> u64 {
+        self.clock.now() + self.stalled_cycles
     }
 
     pub fn peek_tape(&self, address: usize) -> Vec<u8> {
-        self.memory.peek(address, 8)
+        self.code().peek(address, 8)
+    }
+
+    /// Reads `length` bytes of code from `address` on without side effects,
+    /// the same as `peek_memory` unless this is a Harvard machine
+    pub fn peek_code(&self, address: usize, length: usize) -> Vec<u8> {
+        self.code().peek(address, length)
     }
 
     pub fn peek_stack(&self) -> Vec<u8> {
@@ -57,92 +530,660 @@ impl Cpu {
     }
 
     pub fn peek(&self, address: usize) -> u16 {
-        self.memory.get_word(address)
+        let bytes = self.memory.peek(address, 2);
+        u16::from_be_bytes([bytes[0], bytes[1]])
+    }
+
+    /// Reads `length` bytes from `address` on without side effects
+    pub fn peek_memory(&self, address: usize, length: usize) -> Vec<u8> {
+        self.memory.peek(address, length)
     }
 
-    pub fn peek_register(&self, register: Register) -> u16 {
+    /// The NUL terminated string at `address`, cut off at the end of
+    /// memory, with anything that isn't UTF-8 replaced
+    pub fn peek_string(&self, address: usize) -> String {
+        let length = self.memory.byte_length().saturating_sub(address);
+        let bytes = self.peek_memory(address, length);
+        let end = bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    }
+
+    /// Why a run that ran into `fault` stopped, reading out the message of
+    /// a guest panic while it's still in memory
+    pub fn stop_reason(&self, fault: Fault) -> StopReason {
+        match fault {
+            Fault::GuestPanic { message, code } => StopReason::GuestPanic {
+                message: self.peek_string(message as usize),
+                code,
+            },
+            fault => StopReason::Fault(fault),
+        }
+    }
+
+    /// Whether the CPU executed `Instruction::Halt` since the last reset
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Why a run that didn't fault stopped
+    pub(crate) fn stop_reason_without_fault(&self) -> StopReason {
+        match self.halted {
+            true => StopReason::Halted,
+            false => StopReason::FuelExhausted,
+        }
+    }
+
+    pub fn peek_register(&self, register: Register) -> Word {
         self.get_register(register)
     }
 
-    pub fn step_n(&mut self, n: usize) {
+    /// Hash of everything the rest of the run depends on: the registers,
+    /// the call bookkeeping and every byte on the bus, read without side
+    /// effects. The same state hashes the same on every run and platform, so
+    /// runs can be checked against each other by comparing hashes.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for value in self.register {
+            hash = fnv1a(hash, &value.to_be_bytes());
+        }
+        hash = fnv1a(hash, &(self.stack_frame_size as u64).to_be_bytes());
+        hash = fnv1a(
+            hash,
+            &[self.is_in_interrupt_handler as u8, self.halted as u8],
+        );
+        if let Some(code) = &self.code {
+            hash = fnv1a(hash, &code.peek(0, code.byte_length()));
+        }
+        fnv1a(hash, &self.memory.peek(0, self.memory.byte_length()))
+    }
+
+    /// Executes `n` instructions, stopping early at the first fault
+    pub fn step_n(&mut self, n: usize) -> Result<(), Fault> {
         for _ in 0..n {
-            self.step();
+            self.step()?;
         }
+        Ok(())
+    }
+
+    /// Executes up to `fuel` instructions, like `step_n` but faster. The
+    /// instruction and stack pointers are kept in locals and only written
+    /// back to the register file when the loop exits or has to fall back to
+    /// `step` for an instruction it doesn't handle itself.
+    pub fn run(&mut self, fuel: usize) -> StopReason {
+        if self.needs_every_instruction() {
+            return match self.step_n(fuel) {
+                Ok(()) => self.stop_reason_without_fault(),
+                Err(fault) => self.stop_reason(fault),
+            };
+        }
+
+        let mut ip = self.get_register(Register::InstructionPointer);
+        let mut sp = self.get_register(Register::StackPointer);
+        let code_length = self.code().byte_length();
+        let mut executed = 0;
+
+        let reason = loop {
+            if self.halted {
+                break StopReason::Halted;
+            }
+            if executed == fuel {
+                break StopReason::FuelExhausted;
+            }
+            executed += 1;
+
+            let address = ip as usize;
+            // Decode every operand before changing any state, so that an
+            // illegal operand can fall back to `step` and fault from there.
+            // So does anything close enough to the end of memory, or to a
+            // gap in it, to be cut off.
+            let handled = address + MAX_INSTRUCTION_LENGTH <= code_length
+                && (address..address + MAX_INSTRUCTION_LENGTH)
+                    .all(|address| self.code().is_mapped(address))
+                && {
+                    let opcode = self.code_mut().get_byte(address);
+                    let length = INSTRUCTION_LENGTHS[opcode as usize] as Word;
+                    match opcode.into() {
+                        Instruction::Noop if opcode == Instruction::Noop as u8 => {
+                            ip = ip.wrapping_add(length);
+                            true
+                        }
+                        Instruction::MovLitReg => match self.uncached_register_at(address + 3) {
+                            Some(register) => {
+                                let value = self.code_mut().get_word(address + 1);
+                                ip = ip.wrapping_add(length);
+                                self.set_register(register, value);
+                                true
+                            }
+                            None => false,
+                        },
+                        Instruction::MovRegReg => match (
+                            self.uncached_register_at(address + 1),
+                            self.uncached_register_at(address + 2),
+                        ) {
+                            (Some(from), Some(to)) => {
+                                ip = ip.wrapping_add(length);
+                                self.set_register(to, self.get_register(from));
+                                true
+                            }
+                            _ => false,
+                        },
+                        Instruction::MovMemReg => match self.uncached_register_at(address + 3) {
+                            Some(register) => {
+                                let source = self.code_mut().get_word(address + 1);
+                                // Counters latch when read, which `step` sees to
+                                !self.in_counters(source as usize, 2)
+                                    && !self.is_misaligned(source as usize)
+                                    && self.in_bounds(source as usize, WORD_BYTES)
+                                    && {
+                                        ip = ip.wrapping_add(length);
+                                        let value = self.memory.get_word(source as usize);
+                                        self.set_register(register, value);
+                                        true
+                                    }
+                            }
+                            None => false,
+                        },
+                        Instruction::MovRegMem => match self.uncached_register_at(address + 1) {
+                            Some(register) => {
+                                let target = self.code_mut().get_word(address + 2);
+                                let value = self.get_register(register);
+                                !self.is_misaligned(target as usize)
+                                    && self.write_word(target as usize, value).is_ok()
+                                    && {
+                                        ip = ip.wrapping_add(length);
+                                        true
+                                    }
+                            }
+                            None => false,
+                        },
+                        Instruction::AddRegReg => match (
+                            self.uncached_register_at(address + 1),
+                            self.uncached_register_at(address + 2),
+                        ) {
+                            (Some(first), Some(second)) => {
+                                ip = ip.wrapping_add(length);
+                                let value = self.add_with_flags(
+                                    self.get_register(first),
+                                    self.get_register(second),
+                                );
+                                self.set_register(Register::Accumulator, value);
+                                true
+                            }
+                            _ => false,
+                        },
+                        Instruction::JmpNotEq => {
+                            let value = self.code_mut().get_word(address + 1);
+                            let target = self.code_mut().get_word(address + 3);
+                            ip = ip.wrapping_add(length);
+                            if value != self.get_register(Register::Accumulator) {
+                                ip = target;
+                            }
+                            true
+                        }
+                        Instruction::PushLit
+                            if self.can_push(sp) && !self.is_misaligned(sp as usize) =>
+                        {
+                            let value = self.code_mut().get_word(address + 1);
+                            self.write_word(sp as usize, value).is_ok() && {
+                                ip = ip.wrapping_add(length);
+                                sp -= 2;
+                                self.stack_frame_size += 2;
+                                true
+                            }
+                        }
+                        _ => false,
+                    }
+                };
+
+            if handled {
+                // Retired like any other, for the wait states of slow devices
+                self.retire();
+                continue;
+            }
+
+            // Anything else goes through the regular path
+            self.set_register(Register::InstructionPointer, ip);
+            self.set_register(Register::StackPointer, sp);
+            let result = self.step();
+            ip = self.get_register(Register::InstructionPointer);
+            sp = self.get_register(Register::StackPointer);
+            if let Err(fault) = result {
+                break self.stop_reason(fault);
+            }
+        };
+
+        self.set_register(Register::InstructionPointer, ip);
+        self.set_register(Register::StackPointer, sp);
+        reason
+    }
+
+    /// Handles the interrupts devices raised since the last instruction
+    fn take_pending_interrupts(&mut self) -> Result<(), Fault> {
+        if self.pending_interrupts.is_empty() {
+            return Ok(());
+        }
+        for value in std::mem::take(&mut self.pending_interrupts) {
+            #[cfg(feature = "instrument")]
+            if self.is_unmasked(value) {
+                let latency = self.cycle_count() - self.interrupts_raised_at;
+                self.count(|metrics| {
+                    metrics.interrupt_latency[value as usize & 0xf].record(latency)
+                });
+            }
+            self.handle_interrupt(value)?;
+        }
+        Ok(())
+    }
+
+    /// Pending device interrupts, which only `step` takes
+    pub(crate) fn has_pending_interrupts(&self) -> bool {
+        !self.pending_interrupts.is_empty()
+    }
+
+    fn is_unmasked(&self, value: u16) -> bool {
+        (1 << (value & 0xf)) & self.get_register(Register::InterruptMask) != 0
+    }
+
+    /// Jumps to the handler of interrupt `value` if it isn't masked. The
+    /// handler address is read from the interrupt vector. Faults if there's
+    /// no room on the stack to save the interrupted state.
+    pub fn handle_interrupt(&mut self, value: u16) -> Result<(), Fault> {
+        if !self.is_unmasked(value) {
+            return Ok(());
+        }
+        let interrupt_bit = value & 0xf;
+
+        let address_pointer = self.interrupt_vector_address + interrupt_bit as usize * 2;
+        if !(address_pointer..address_pointer + WORD_BYTES).all(|x| self.memory.is_mapped(x)) {
+            return Err(Fault::MemoryOutOfBounds {
+                address: address_pointer as u16,
+            });
+        }
+        let address = self.memory.get_word(address_pointer);
+
+        // Nested interrupts reuse the frame of the outer one
+        if !self.is_in_interrupt_handler {
+            // The interrupted code's flags, restored by `RetInt`
+            self.push(self.get_register(Register::Flags))?;
+            // Handlers take no arguments
+            self.push(0)?;
+            self.push_state()?;
+        }
+
+        self.is_in_interrupt_handler = true;
+        self.set_register(Register::InstructionPointer, address);
+        self.interrupt_count += 1;
+        #[cfg(feature = "instrument")]
+        {
+            self.count(|metrics| metrics.interrupts += 1);
+            self.notify(|observer, cpu| observer.interrupt(cpu, value));
+        }
+        Ok(())
     }
 }
 
 impl Cpu {
+    fn register_names(general_purpose_registers: usize) -> Vec<Register> {
+        let mut names = vec![Register::InstructionPointer, Register::Accumulator];
+        names.extend_from_slice(&GENERAL_PURPOSE_REGISTERS[..general_purpose_registers]);
+        names.extend_from_slice(&[
+            Register::StackPointer,
+            Register::FramePointer,
+            Register::InterruptMask,
+            Register::Flags,
+        ]);
+        names
+    }
+
     fn register_map(&self, name: Register) -> usize {
-        name as usize * 2
+        name as usize
     }
 
-    fn get_register(&self, name: Register) -> u16 {
+    fn get_register(&self, name: Register) -> Word {
         self.get_register_at(self.register_map(name))
     }
 
-    fn get_register_at(&self, index: usize) -> u16 {
-        self.register.get_word(index)
+    #[cfg(not(feature = "unchecked"))]
+    fn get_register_at(&self, index: usize) -> Word {
+        self.register[index]
+    }
+
+    #[cfg(feature = "unchecked")]
+    fn get_register_at(&self, index: usize) -> Word {
+        // SAFETY: indices come from `register_map`, and the register file
+        // has room for every register
+        unsafe { *self.register.get_unchecked(index) }
     }
 
-    fn set_register(&mut self, name: Register, value: u16) {
+    pub fn set_register(&mut self, name: Register, value: Word) {
         self.set_register_at(self.register_map(name), value);
     }
 
-    fn set_register_at(&mut self, index: usize, value: u16) {
-        self.register.set_word(index, value);
+    #[cfg(not(feature = "unchecked"))]
+    fn set_register_at(&mut self, index: usize, value: Word) {
+        self.register[index] = value;
     }
 
-    fn fetch(&mut self) -> u8 {
-        let next_instruction_addr = self.get_register(Register::InstructionPointer);
-        self.set_register(Register::InstructionPointer, next_instruction_addr + 1);
+    #[cfg(feature = "unchecked")]
+    fn set_register_at(&mut self, index: usize, value: Word) {
+        // SAFETY: as in `get_register_at`
+        unsafe { *self.register.get_unchecked_mut(index) = value }
+    }
 
-        self.memory.get_byte(next_instruction_addr as usize)
+    /// Reads the byte at the instruction pointer and moves past it. The
+    /// instruction pointer wraps around after 0xffff. Faults without moving
+    /// if the byte is past the end of memory.
+    pub fn fetch(&mut self) -> Result<u8, Fault> {
+        let address = self.fetch_address(1)?;
+        self.mark_executed(address as usize, 1);
+        self.set_register(Register::InstructionPointer, address.wrapping_add(1));
+        Ok(self.code_mut().get_byte(address as usize))
     }
 
-    fn fetch16(&mut self) -> u16 {
-        let next_instruction_addr = self.get_register(Register::InstructionPointer);
-        self.set_register(Register::InstructionPointer, next_instruction_addr + 2);
-        self.memory.get_word(next_instruction_addr as usize)
+    /// Reads the word at the instruction pointer and moves past it, like
+    /// `fetch`
+    pub fn fetch16(&mut self) -> Result<Word, Fault> {
+        let address = self.fetch_address(WORD_BYTES)?;
+        self.mark_executed(address as usize, WORD_BYTES);
+        self.set_register(
+            Register::InstructionPointer,
+            address.wrapping_add(WORD_BYTES as Word),
+        );
+        Ok(self.code_mut().get_word(address as usize))
     }
 
-    fn fetch_register_index(&mut self) -> usize {
-        let address = self.fetch();
-        address as usize % self.register_names.len()
+    /// The instruction pointer, if `length` bytes from there are in memory
+    fn fetch_address(&self, length: usize) -> Result<u16, Fault> {
+        let address = self.get_register(Register::InstructionPointer);
+        let start = address as usize;
+        if !(start..start + length).all(|address| self.code().is_mapped(address)) {
+            return Err(Fault::FetchOutOfBounds { address });
+        }
+        Ok(address)
     }
 
-    fn push(&mut self, value: u16) {
+    /// Reads a register operand, faulting if this CPU doesn't have it
+    pub fn fetch_register(&mut self) -> Result<Register, Fault> {
+        let address = self.get_register(Register::InstructionPointer);
+        let value = self.fetch()?;
+        self.decode_register(value)
+            .ok_or(Fault::IllegalOperand { address, value })
+    }
+
+    fn fetch_operands(&mut self, info: &OpcodeInfo) -> Result<Operands, Fault> {
+        let start = self.get_register(Register::InstructionPointer);
+        let mut operands = Operands::default();
+        for (operand, kind) in operands.iter_mut().zip(info.operands) {
+            *operand = match kind {
+                Operand::Literal | Operand::Address | Operand::FrameOffset => self.fetch16()?,
+                Operand::Register | Operand::RegisterPointer | Operand::OffsetRegister => {
+                    self.fetch_register()? as u16
+                }
+            };
+        }
+        // The opcode was fetched before `start`
+        debug_assert_eq!(
+            self.get_register(Register::InstructionPointer)
+                .wrapping_sub(start) as usize
+                + 1,
+            info.length(),
+            "Fetched the operands of {:?} out of step with its length",
+            info.instruction
+        );
+        Ok(operands)
+    }
+
+    /// Decodes the built-in instruction at `address` without moving the
+    /// instruction pointer. `None` for custom or unknown opcodes, illegal
+    /// register operands and instructions that run past the end of memory.
+    pub(crate) fn decode_at(&mut self, address: usize) -> Option<(&'static OpcodeInfo, Operands)> {
+        if !self.code().is_mapped(address) {
+            return None;
+        }
+        let info = opcode_info(self.code_mut().get_byte(address))?;
+        if !(address..address + info.length()).all(|address| self.code().is_mapped(address)) {
+            return None;
+        }
+
+        let mut operands = Operands::default();
+        let mut offset = address + 1;
+        for (operand, kind) in operands.iter_mut().zip(info.operands) {
+            *operand = match kind {
+                Operand::Literal | Operand::Address | Operand::FrameOffset => {
+                    self.code_mut().get_word(offset)
+                }
+                Operand::Register | Operand::RegisterPointer | Operand::OffsetRegister => {
+                    let value = self.code_mut().get_byte(offset);
+                    self.decode_register(value)? as u16
+                }
+            };
+            offset += kind.size();
+        }
+        Some((info, operands))
+    }
+
+    /// Decodes the register operand at `address` for `run`, which can't
+    /// handle the registers it keeps in locals.
+    fn uncached_register_at(&mut self, address: usize) -> Option<Register> {
+        let value = self.code_mut().get_byte(address);
+        match self.decode_register(value)? {
+            Register::InstructionPointer | Register::StackPointer => None,
+            register => Some(register),
+        }
+    }
+
+    fn decode_register(&self, value: u8) -> Option<Register> {
+        match Register::try_from(value) {
+            Ok(register) if self.enabled_registers & 1 << value != 0 => Some(register),
+            _ => None,
+        }
+    }
+
+    /// Whether any of the `length` bytes from `address` are in the
+    /// register window
+    pub(crate) fn in_register_window(&self, address: usize, length: usize) -> bool {
+        self.register_window
+            .is_some_and(|start| address < start + REGISTER_WINDOW_SIZE && start < address + length)
+    }
+
+    /// The byte at `address`, from the register window if it is in there.
+    /// Registers that are not enabled read as 0.
+    fn window_byte(&mut self, address: usize) -> u8 {
+        match self.register_window {
+            Some(start) if (start..start + REGISTER_WINDOW_SIZE).contains(&address) => {
+                let index = (address - start) / 2;
+                if self.enabled_registers & 1 << index == 0 {
+                    return 0;
+                }
+                self.get_register_at(index).to_be_bytes()[(address - start) % 2]
+            }
+            _ => self.memory.get_byte(address),
+        }
+    }
+
+    /// Writes `value` at `address`, to the register window if it is in
+    /// there. Writes to registers that are not enabled are dropped.
+    fn set_window_byte(&mut self, address: usize, value: u8) {
+        match self.register_window {
+            Some(start) if (start..start + REGISTER_WINDOW_SIZE).contains(&address) => {
+                let index = (address - start) / 2;
+                if self.enabled_registers & 1 << index == 0 {
+                    return;
+                }
+                let mut bytes = self.get_register_at(index).to_be_bytes();
+                bytes[(address - start) % 2] = value;
+                self.set_register_at(index, u16::from_be_bytes(bytes));
+            }
+            _ => {
+                self.memory.set_byte(address, value);
+                self.wrote_memory(address, 1);
+            }
+        }
+    }
+
+    /// Whether a word access at `address` would fault for being unaligned
+    pub(crate) fn is_misaligned(&self, address: usize) -> bool {
+        self.strict_alignment && !address.is_multiple_of(WORD_BYTES)
+    }
+
+    fn check_aligned(&self, address: usize) -> Result<(), Fault> {
+        match self.is_misaligned(address) {
+            true => Err(Fault::Unaligned {
+                address: address as u16,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether all `length` bytes from `address` can be accessed, through
+    /// the register window, the counters or memory
+    pub(crate) fn in_bounds(&self, address: usize, length: usize) -> bool {
+        (address..address + length).all(|address| {
+            self.in_register_window(address, 1)
+                || self.in_counters(address, 1)
+                || self.memory.is_mapped(address)
+        })
+    }
+
+    fn check_in_bounds(&self, address: usize, length: usize) -> Result<(), Fault> {
+        match self.in_bounds(address, length) {
+            true => Ok(()),
+            false => Err(Fault::MemoryOutOfBounds {
+                address: address as u16,
+            }),
+        }
+    }
+
+    /// Reads a word on behalf of the guest
+    fn read_word(&mut self, address: usize) -> Result<Word, Fault> {
+        self.check_in_bounds(address, WORD_BYTES)?;
+        self.wait_states += self.memory.wait_states(address, false);
+        let value = if self.in_register_window(address, 2) {
+            u16::from_be_bytes([self.window_byte(address), self.window_byte(address + 1)])
+        } else if self.in_counters(address, 2) {
+            u16::from_be_bytes([self.counter_byte(address), self.counter_byte(address + 1)])
+        } else {
+            self.memory.get_word(address)
+        };
+        #[cfg(feature = "instrument")]
+        {
+            self.count(|metrics| metrics.reads += 1);
+            self.notify(|observer, cpu| observer.read(cpu, address as u16, value));
+        }
+        Ok(value)
+    }
+
+    /// Writes a word on behalf of the guest, dropping cached decodes of it
+    pub(crate) fn write_word(&mut self, address: usize, value: Word) -> Result<(), Fault> {
+        self.check_in_bounds(address, WORD_BYTES)?;
+        self.wait_states += self.memory.wait_states(address, true);
+        if self.in_register_window(address, 2) {
+            let [high, low] = value.to_be_bytes();
+            self.set_window_byte(address, high);
+            self.set_window_byte(address + 1, low);
+        } else if self.in_counters(address, 2) {
+            // The counters are read only
+        } else {
+            self.memory.set_word(address, value);
+            self.wrote_memory(address, 2);
+        }
+        #[cfg(feature = "instrument")]
+        {
+            self.count(|metrics| metrics.writes += 1);
+            self.notify(|observer, cpu| observer.write(cpu, address as u16, value));
+        }
+        Ok(())
+    }
+
+    /// Marks the `length` bytes written at `address` for the next snapshot
+    /// and drops cached decodes of them, unless code lives apart from data
+    /// where no store can reach it
+    fn wrote_memory(&mut self, address: usize, length: usize) {
+        self.dirty_pages.mark(address, length);
+        if self.code.is_none() {
+            self.block_cache.invalidate(address, length);
+            self.check_code_write(address, length);
+        }
+    }
+
+    /// Whether a push with the stack pointer at `stack_pointer` stays in
+    /// the stack, leaving a stack pointer that doesn't wrap around
+    fn can_push(&self, stack_pointer: Word) -> bool {
+        stack_pointer >= WORD_BYTES as Word
+            && self.stack_limit <= stack_pointer
+            && stack_pointer <= self.stack_top
+    }
+
+    /// Whether there is a word to pop above `stack_pointer`
+    fn can_pop(&self, stack_pointer: Word) -> bool {
+        stack_pointer
+            .checked_add(WORD_BYTES as Word)
+            .is_some_and(|address| self.stack_limit <= address && address <= self.stack_top)
+    }
+
+    fn push(&mut self, value: Word) -> Result<(), Fault> {
         let stack_pointer = self.get_register(Register::StackPointer);
-        self.memory.set_word(stack_pointer as usize, value);
-        // stack grows up, 2 bytes at a time
-        self.set_register(Register::StackPointer, stack_pointer - 2);
-        self.stack_frame_size += 2;
+        if !self.can_push(stack_pointer) {
+            return Err(Fault::StackOverflow {
+                address: stack_pointer,
+            });
+        }
+        self.check_aligned(stack_pointer as usize)?;
+        self.write_word(stack_pointer as usize, value)?;
+        // stack grows up, a word at a time
+        self.set_register(Register::StackPointer, stack_pointer - WORD_BYTES as Word);
+        self.stack_frame_size += WORD_BYTES;
+        #[cfg(feature = "instrument")]
+        {
+            let depth = self.stack_top - (stack_pointer - WORD_BYTES as Word);
+            self.count(|metrics| metrics.max_stack_depth = metrics.max_stack_depth.max(depth));
+        }
+        Ok(())
     }
 
-    fn pop(&mut self) -> u16 {
-        let next_stack_pointer = self.get_register(Register::StackPointer) + 2;
+    pub(crate) fn pop(&mut self) -> Result<Word, Fault> {
+        let stack_pointer = self.get_register(Register::StackPointer);
+        if !self.can_pop(stack_pointer) {
+            return Err(Fault::StackUnderflow {
+                address: stack_pointer,
+            });
+        }
+        self.check_aligned(stack_pointer as usize)?;
+        let next_stack_pointer = stack_pointer + WORD_BYTES as Word;
+        let value = self.read_word(next_stack_pointer as usize)?;
 
-        // stack shrinks down, 2 bytes at a time
+        // stack shrinks down, a word at a time
         self.set_register(Register::StackPointer, next_stack_pointer);
-        self.stack_frame_size -= 2;
+        self.stack_frame_size = self.stack_frame_size.saturating_sub(WORD_BYTES);
 
-        self.memory.get_word(next_stack_pointer as usize)
+        Ok(value)
     }
 
-    fn push_state(&mut self) {
+    fn push_state(&mut self) -> Result<(), Fault> {
+        if let Some(limit) = self.max_call_depth {
+            if self.call_depth >= limit {
+                return Err(Fault::CallDepthExceeded {
+                    address: self.get_register(Register::StackPointer),
+                    limit,
+                });
+            }
+        }
         // Push general purpose registers
-        self.push(self.get_register(Register::Register1));
-        self.push(self.get_register(Register::Register2));
-        self.push(self.get_register(Register::Register3));
-        self.push(self.get_register(Register::Register4));
-        self.push(self.get_register(Register::Register5));
-        self.push(self.get_register(Register::Register6));
-        self.push(self.get_register(Register::Register7));
-        self.push(self.get_register(Register::Register8));
+        for register in &GENERAL_PURPOSE_REGISTERS[..self.general_purpose_registers] {
+            self.push(self.get_register(*register))?;
+        }
         // Push instruciton pointer, which will be the return address
-        self.push(self.get_register(Register::InstructionPointer));
-        // Push stack size and +2 for this push
-        let stack_size_to_save = self.stack_frame_size + 2;
-        self.push(stack_size_to_save as u16);
+        let return_address = self.get_register(Register::InstructionPointer);
+        self.push(return_address)?;
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            shadow_stack.push(return_address);
+        }
+        // Push stack size and a word for this push
+        let stack_size_to_save = self.stack_frame_size + WORD_BYTES;
+        self.push(stack_size_to_save as Word)?;
 
         // Save the current stack pointer to frame pointer
         self.set_register(
@@ -152,136 +1193,752 @@ impl Cpu {
 
         // Reset stack size to 0
         self.stack_frame_size = 0;
+        self.call_depth += 1;
+        Ok(())
     }
 
-    fn pop_state(&mut self) {
+    fn pop_state(&mut self) -> Result<(), Fault> {
         let stack_pointer_address = self.get_register(Register::FramePointer);
 
         // Rewind the stack pointer
         self.set_register(Register::StackPointer, stack_pointer_address);
 
         // Rewind stack size
-        self.stack_frame_size = 2; // This is needed for the following pop, incase frame size is 0.
-        let frame_size = self.pop();
-        self.stack_frame_size = frame_size as usize;
+        self.stack_frame_size = WORD_BYTES; // This is needed for the following pop, incase frame size is 0.
+        let frame_size = self.pop()?;
+        // The saved size counts the word that held it, which is gone now
+        self.stack_frame_size = (frame_size as usize).saturating_sub(WORD_BYTES);
 
         // Point the return address via instruction pointer
-        let register_value = self.pop();
+        let register_value = self.pop()?;
+        if let Some(expected) = self.shadow_stack.as_mut().and_then(Vec::pop) {
+            if register_value != expected {
+                return Err(Fault::ReturnAddressCorrupted {
+                    address: self.get_register(Register::StackPointer),
+                    expected,
+                    found: register_value,
+                });
+            }
+        }
         self.set_register(Register::InstructionPointer, register_value);
 
         // Rewind the general purpose registers
-        let register_value = self.pop();
-        self.set_register(Register::Register8, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register7, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register6, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register5, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register4, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register3, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register2, register_value);
-        let register_value = self.pop();
-        self.set_register(Register::Register1, register_value);
+        for register in GENERAL_PURPOSE_REGISTERS[..self.general_purpose_registers]
+            .iter()
+            .rev()
+        {
+            let register_value = self.pop()?;
+            self.set_register(*register, register_value);
+        }
 
         // Pop out argument list
-        let n_args = self.pop();
+        let n_args = self.pop()?;
         for _ in 0..n_args {
-            self.pop();
+            self.pop()?;
         }
 
         // Rewind frame pointer
-        let frame_pointer_address = stack_pointer_address + frame_size;
+        let frame_pointer_address = stack_pointer_address.wrapping_add(frame_size);
         self.set_register(Register::FramePointer, frame_pointer_address);
+        self.call_depth = self.call_depth.saturating_sub(1);
+        Ok(())
     }
 
-    fn execute(&mut self, instruction: Instruction) {
-        match instruction {
-            Instruction::MovLitReg => {
-                let value = self.fetch16();
-                let register = self.fetch();
-                self.set_register(register.into(), value);
-            }
-            Instruction::MovRegReg => {
-                let register_from = self.fetch();
-                let register_to = self.fetch();
-                let value = self.get_register(register_from.into());
-                self.set_register(register_to.into(), value);
-            }
-            Instruction::MovMemReg => {
-                let address = self.fetch16();
-                let register_to = self.fetch();
-                let value = self.memory.get_word(address as usize);
-                self.set_register(register_to.into(), value);
-            }
-            Instruction::MovRegMem => {
-                let register_from = self.fetch();
-                let address = self.fetch16();
-                let value = self.get_register(register_from.into());
-                self.memory.set_word(address as usize, value);
-            }
-            Instruction::AddRegReg => {
-                let register1 = self.fetch();
-                let register2 = self.fetch();
+    fn execute_custom(&mut self, opcode: u8) -> Result<(), Fault> {
+        match self.custom_instructions.get(&opcode) {
+            Some(handler) => Arc::clone(handler).execute(self),
+            // Unknown opcodes do nothing
+            None => Ok(()),
+        }
+    }
 
-                let value1 = self.register.get_word(register1 as usize * 2);
-                let value2 = self.register.get_word(register2 as usize * 2);
+    fn noop(&mut self, _operands: Operands) -> Result<(), Fault> {
+        Ok(())
+    }
 
-                self.set_register(Register::Accumulator, value1 + value2);
-            }
-            Instruction::JmpNotEq => {
-                let value = self.fetch16();
-                let address = self.fetch16();
+    fn mov_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        self.set_register(Register::from_operand(register), value);
+        Ok(())
+    }
 
-                let acc_value = self.get_register(Register::Accumulator);
+    fn mov_reg_reg(&mut self, [register_from, register_to, _]: Operands) -> Result<(), Fault> {
+        let value = self.get_register(Register::from_operand(register_from));
+        self.set_register(Register::from_operand(register_to), value);
+        Ok(())
+    }
 
-                if value != acc_value {
-                    self.set_register(Register::InstructionPointer, address);
-                }
-            }
-            Instruction::PushLit => {
-                let value = self.fetch16();
-                self.push(value);
-            }
-            Instruction::PushReg => {
-                let index = self.fetch_register_index();
-                let value = self.get_register_at(index);
-                self.push(value);
+    fn mov_mem_reg(&mut self, [address, register_to, _]: Operands) -> Result<(), Fault> {
+        self.check_aligned(address as usize)?;
+        let value = self.read_word(address as usize)?;
+        self.set_register(Register::from_operand(register_to), value);
+        Ok(())
+    }
+
+    fn mov_reg_ptr_reg(&mut self, [pointer, register_to, _]: Operands) -> Result<(), Fault> {
+        let address = self.get_register(Register::from_operand(pointer));
+        self.mov_mem_reg([address, register_to, 0])
+    }
+
+    fn mov_reg_reg_ptr(&mut self, [register_from, pointer, _]: Operands) -> Result<(), Fault> {
+        let address = self.get_register(Register::from_operand(pointer));
+        self.mov_reg_mem([register_from, address, 0])
+    }
+
+    fn mov_lit_off_reg(&mut self, [base, offset, register_to]: Operands) -> Result<(), Fault> {
+        let offset = self.get_register(Register::from_operand(offset));
+        self.mov_mem_reg([base.wrapping_add(offset), register_to, 0])
+    }
+
+    fn mov_reg_lit_off(&mut self, [register_from, base, offset]: Operands) -> Result<(), Fault> {
+        let offset = self.get_register(Register::from_operand(offset));
+        self.mov_reg_mem([register_from, base.wrapping_add(offset), 0])
+    }
+
+    fn mov_frame_reg(&mut self, [offset, register_to, _]: Operands) -> Result<(), Fault> {
+        // Adding the two's complement wraps around to a subtraction
+        let address = self
+            .get_register(Register::FramePointer)
+            .wrapping_add(offset);
+        self.mov_mem_reg([address, register_to, 0])
+    }
+
+    fn mov_reg_frame(&mut self, [register_from, offset, _]: Operands) -> Result<(), Fault> {
+        let address = self
+            .get_register(Register::FramePointer)
+            .wrapping_add(offset);
+        self.mov_reg_mem([register_from, address, 0])
+    }
+
+    fn swap_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let register1 = Register::from_operand(register1);
+        let register2 = Register::from_operand(register2);
+        let value1 = self.get_register(register1);
+        self.set_register(register1, self.get_register(register2));
+        self.set_register(register2, value1);
+        Ok(())
+    }
+
+    fn mov_reg_mem(&mut self, [register_from, address, _]: Operands) -> Result<(), Fault> {
+        self.check_aligned(address as usize)?;
+        let value = self.get_register(Register::from_operand(register_from));
+        self.write_word(address as usize, value)
+    }
+
+    /// Whether `flag`, one of `ZERO_FLAG` and the others, is set
+    fn flag(&self, flag: Word) -> bool {
+        self.get_register(Register::Flags) & flag != 0
+    }
+
+    /// Sets the flags for `result`, zero and sign following from its bits
+    fn set_flags(&mut self, result: Word, carry: bool, overflow: bool) {
+        let mut flags = 0;
+        if result == 0 {
+            flags |= ZERO_FLAG;
+        }
+        if carry {
+            flags |= CARRY_FLAG;
+        }
+        if result >> (Word::BITS - 1) == 1 {
+            flags |= SIGN_FLAG;
+        }
+        if overflow {
+            flags |= OVERFLOW_FLAG;
+        }
+        self.set_register(Register::Flags, flags);
+    }
+
+    /// Adds wrapping around, setting the flags
+    pub(crate) fn add_with_flags(&mut self, value1: Word, value2: Word) -> Word {
+        let (sum, carry) = value1.overflowing_add(value2);
+        // Both values have the same sign and the sum the other
+        let overflow = ((value1 ^ sum) & (value2 ^ sum)) >> (Word::BITS - 1) == 1;
+        self.set_flags(sum, carry, overflow);
+        sum
+    }
+
+    /// Subtracts `value2` from `value1` wrapping around, setting the flags
+    fn sub_with_flags(&mut self, value1: Word, value2: Word) -> Word {
+        let (difference, borrow) = value1.overflowing_sub(value2);
+        // The values have different signs and the difference that of `value2`
+        let overflow = ((value1 ^ value2) & (value1 ^ difference)) >> (Word::BITS - 1) == 1;
+        self.set_flags(difference, borrow, overflow);
+        difference
+    }
+
+    fn add_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let value1 = self.get_register(Register::from_operand(register1));
+        let value2 = self.get_register(Register::from_operand(register2));
+
+        let sum = self.add_with_flags(value1, value2);
+        self.set_register(Register::Accumulator, sum);
+        Ok(())
+    }
+
+    fn add_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        let register_value = self.get_register(Register::from_operand(register));
+
+        let sum = self.add_with_flags(register_value, value);
+        self.set_register(Register::Accumulator, sum);
+        Ok(())
+    }
+
+    fn sub_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        let register_value = self.get_register(Register::from_operand(register));
+
+        let difference = self.sub_with_flags(register_value, value);
+        self.set_register(Register::Accumulator, difference);
+        Ok(())
+    }
+
+    fn sub_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let value1 = self.get_register(Register::from_operand(register1));
+        let value2 = self.get_register(Register::from_operand(register2));
+
+        let difference = self.sub_with_flags(value1, value2);
+        self.set_register(Register::Accumulator, difference);
+        Ok(())
+    }
+
+    fn cmp_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        let register_value = self.get_register(Register::from_operand(register));
+        self.sub_with_flags(register_value, value);
+        Ok(())
+    }
+
+    fn cmp_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let value1 = self.get_register(Register::from_operand(register1));
+        let value2 = self.get_register(Register::from_operand(register2));
+        self.sub_with_flags(value1, value2);
+        Ok(())
+    }
+
+    fn mul_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let high_register = Register::from_operand(register1);
+        if !GENERAL_PURPOSE_REGISTERS.contains(&high_register) {
+            // The first operand is the second to last byte of the instruction
+            let ip = self.get_register(Register::InstructionPointer);
+            return Err(Fault::IllegalOperand {
+                address: ip.wrapping_sub(2),
+                value: register1 as u8,
+            });
+        }
+        let value1 = self.get_register(high_register);
+        let value2 = self.get_register(Register::from_operand(register2));
+
+        let product = value1 as u32 * value2 as u32;
+        let high = (product >> 16) as u16;
+        // Carry and overflow tell the product took the high word
+        self.set_flags(product as u16, high != 0, high != 0);
+        self.set_register(Register::Accumulator, product as u16);
+        self.set_register(high_register, high);
+        Ok(())
+    }
+
+    /// The values in two registers, faulting if the second, the divisor,
+    /// is zero
+    fn dividend_and_divisor(
+        &self,
+        [register1, register2, _]: Operands,
+    ) -> Result<(u16, u16), Fault> {
+        let dividend = self.get_register(Register::from_operand(register1));
+        let divisor = self.get_register(Register::from_operand(register2));
+        if divisor == 0 {
+            // The divisor's operand is the last byte of the instruction
+            let ip = self.get_register(Register::InstructionPointer);
+            return Err(Fault::DivideByZero {
+                address: ip.wrapping_sub(1),
+            });
+        }
+        Ok((dividend, divisor))
+    }
+
+    fn div_reg_reg(&mut self, operands: Operands) -> Result<(), Fault> {
+        let (dividend, divisor) = self.dividend_and_divisor(operands)?;
+        self.set_logic_result(dividend / divisor);
+        Ok(())
+    }
+
+    fn mod_reg_reg(&mut self, operands: Operands) -> Result<(), Fault> {
+        let (dividend, divisor) = self.dividend_and_divisor(operands)?;
+        self.set_logic_result(dividend % divisor);
+        Ok(())
+    }
+
+    /// Saves `result` to the accumulator with its flags, for instructions
+    /// that can't carry or overflow
+    fn set_logic_result(&mut self, result: Word) {
+        self.set_flags(result, false, false);
+        self.set_register(Register::Accumulator, result);
+    }
+
+    fn and_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        let register_value = self.get_register(Register::from_operand(register));
+        self.set_logic_result(register_value & value);
+        Ok(())
+    }
+
+    fn and_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let value1 = self.get_register(Register::from_operand(register1));
+        let value2 = self.get_register(Register::from_operand(register2));
+        self.set_logic_result(value1 & value2);
+        Ok(())
+    }
+
+    fn or_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        let register_value = self.get_register(Register::from_operand(register));
+        self.set_logic_result(register_value | value);
+        Ok(())
+    }
+
+    fn or_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let value1 = self.get_register(Register::from_operand(register1));
+        let value2 = self.get_register(Register::from_operand(register2));
+        self.set_logic_result(value1 | value2);
+        Ok(())
+    }
+
+    fn xor_lit_reg(&mut self, [value, register, _]: Operands) -> Result<(), Fault> {
+        let register_value = self.get_register(Register::from_operand(register));
+        self.set_logic_result(register_value ^ value);
+        Ok(())
+    }
+
+    fn xor_reg_reg(&mut self, [register1, register2, _]: Operands) -> Result<(), Fault> {
+        let value1 = self.get_register(Register::from_operand(register1));
+        let value2 = self.get_register(Register::from_operand(register2));
+        self.set_logic_result(value1 ^ value2);
+        Ok(())
+    }
+
+    fn not_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let value = self.get_register(Register::from_operand(register));
+        self.set_logic_result(!value);
+        Ok(())
+    }
+
+    fn inc_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let register = Register::from_operand(register);
+        let value = self.get_register(register).wrapping_add(1);
+        // The carry is left alone, for counting in loops over wide adds
+        let carry = self.flag(CARRY_FLAG);
+        self.set_flags(value, carry, value == 1 << (Word::BITS - 1));
+        self.set_register(register, value);
+        Ok(())
+    }
+
+    fn dec_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let register = Register::from_operand(register);
+        let value = self.get_register(register).wrapping_sub(1);
+        let carry = self.flag(CARRY_FLAG);
+        self.set_flags(value, carry, value == Word::MAX >> 1);
+        self.set_register(register, value);
+        Ok(())
+    }
+
+    fn jmp_not_eq(&mut self, [value, address, _]: Operands) -> Result<(), Fault> {
+        let acc_value = self.get_register(Register::Accumulator);
+
+        let taken = value != acc_value;
+        if taken {
+            self.set_register(Register::InstructionPointer, address);
+        }
+        #[cfg(feature = "instrument")]
+        self.count_branch(taken);
+        Ok(())
+    }
+
+    fn jmp_lit(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.set_register(Register::InstructionPointer, address);
+        Ok(())
+    }
+
+    fn jmp_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let address = self.get_register(Register::from_operand(register));
+        self.set_register(Register::InstructionPointer, address);
+        Ok(())
+    }
+
+    /// Jumps to `address` if `condition` holds
+    fn jmp_if(&mut self, condition: bool, address: Word) -> Result<(), Fault> {
+        if condition {
+            self.set_register(Register::InstructionPointer, address);
+        }
+        #[cfg(feature = "instrument")]
+        self.count_branch(condition);
+        Ok(())
+    }
+
+    fn jmp_if_eq(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(self.flag(ZERO_FLAG), address)
+    }
+
+    fn jmp_if_ne(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(!self.flag(ZERO_FLAG), address)
+    }
+
+    fn jmp_if_gt(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(
+            !self.flag(ZERO_FLAG) && self.flag(SIGN_FLAG) == self.flag(OVERFLOW_FLAG),
+            address,
+        )
+    }
+
+    fn jmp_if_lt(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(self.flag(SIGN_FLAG) != self.flag(OVERFLOW_FLAG), address)
+    }
+
+    fn jmp_if_ge(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(self.flag(SIGN_FLAG) == self.flag(OVERFLOW_FLAG), address)
+    }
+
+    fn jmp_if_le(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(
+            self.flag(ZERO_FLAG) || self.flag(SIGN_FLAG) != self.flag(OVERFLOW_FLAG),
+            address,
+        )
+    }
+
+    fn jmp_if_carry(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(self.flag(CARRY_FLAG), address)
+    }
+
+    fn jmp_if_no_carry(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(!self.flag(CARRY_FLAG), address)
+    }
+
+    fn jmp_if_overflow(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(self.flag(OVERFLOW_FLAG), address)
+    }
+
+    fn jmp_if_no_overflow(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.jmp_if(!self.flag(OVERFLOW_FLAG), address)
+    }
+
+    fn push_lit(&mut self, [value, _, _]: Operands) -> Result<(), Fault> {
+        self.push(value)
+    }
+
+    fn push_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let value = self.get_register(Register::from_operand(register));
+        self.push(value)
+    }
+
+    fn push_mem(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.check_aligned(address as usize)?;
+        let value = self.read_word(address as usize)?;
+        self.push(value)
+    }
+
+    fn pop_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let value = self.pop()?;
+        self.set_register(Register::from_operand(register), value);
+        Ok(())
+    }
+
+    fn pop_mem(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        // Checked first, so a bad store leaves the stack alone
+        self.check_aligned(address as usize)?;
+        self.check_in_bounds(address as usize, WORD_BYTES)?;
+        let value = self.pop()?;
+        self.write_word(address as usize, value)
+    }
+
+    fn push_flags(&mut self, _operands: Operands) -> Result<(), Fault> {
+        self.push(self.get_register(Register::Flags))
+    }
+
+    fn pop_flags(&mut self, _operands: Operands) -> Result<(), Fault> {
+        let value = self.pop()?;
+        self.set_register(Register::Flags, value);
+        Ok(())
+    }
+
+    fn swap_stack(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        let block = address as usize;
+        self.check_aligned(block)?;
+        // The whole block, so a fault leaves it and the stack untouched
+        self.check_in_bounds(block, 3 * WORD_BYTES)?;
+        let stack_pointer = self.read_word(block)?;
+        let frame_pointer = self.read_word(block + 2)?;
+        let frame_size = self.read_word(block + 4)?;
+        self.write_word(block, self.get_register(Register::StackPointer))?;
+        self.write_word(block + 2, self.get_register(Register::FramePointer))?;
+        self.write_word(block + 4, self.stack_frame_size as u16)?;
+
+        self.set_register(Register::StackPointer, stack_pointer);
+        self.set_register(Register::FramePointer, frame_pointer);
+        self.stack_frame_size = frame_size as usize;
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            let swapped_in = self.parked_shadow_stacks.remove(&address);
+            let swapped_out = std::mem::replace(shadow_stack, swapped_in.unwrap_or_default());
+            self.parked_shadow_stacks.insert(address, swapped_out);
+        }
+        Ok(())
+    }
+
+    /// Registers in a saved context, in the order they are saved
+    fn context_registers(&self) -> Vec<Register> {
+        let mut registers = vec![Register::Accumulator];
+        registers.extend_from_slice(self.general_purpose_registers());
+        registers.extend_from_slice(&[
+            Register::StackPointer,
+            Register::FramePointer,
+            Register::InterruptMask,
+            Register::Flags,
+        ]);
+        registers
+    }
+
+    fn save_context(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        let mut block = address as usize;
+        self.check_aligned(block)?;
+        let registers = self.context_registers();
+        self.check_in_bounds(block, (registers.len() + 1) * WORD_BYTES)?;
+        for register in registers {
+            self.write_word(block, self.get_register(register))?;
+            block += WORD_BYTES;
+        }
+        self.write_word(block, self.stack_frame_size as u16)?;
+        if let Some(shadow_stack) = &self.shadow_stack {
+            self.parked_shadow_stacks
+                .insert(address, shadow_stack.clone());
+        }
+        Ok(())
+    }
+
+    fn restore_context(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        let mut block = address as usize;
+        self.check_aligned(block)?;
+        let registers = self.context_registers();
+        self.check_in_bounds(block, (registers.len() + 1) * WORD_BYTES)?;
+        for register in registers {
+            let value = self.read_word(block)?;
+            self.set_register(register, value);
+            block += WORD_BYTES;
+        }
+        self.stack_frame_size = self.read_word(block)? as usize;
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            *shadow_stack = self
+                .parked_shadow_stacks
+                .get(&address)
+                .cloned()
+                .unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    fn cal_lit(&mut self, [address, _, _]: Operands) -> Result<(), Fault> {
+        self.push_state()?;
+        #[cfg(feature = "instrument")]
+        self.count(|metrics| metrics.calls += 1);
+        self.set_register(Register::InstructionPointer, address);
+        Ok(())
+    }
+
+    fn cal_reg(&mut self, [register, _, _]: Operands) -> Result<(), Fault> {
+        let address = self.get_register(Register::from_operand(register));
+        self.push_state()?;
+        #[cfg(feature = "instrument")]
+        self.count(|metrics| metrics.calls += 1);
+        self.set_register(Register::InstructionPointer, address);
+        Ok(())
+    }
+
+    fn ret(&mut self, _operands: Operands) -> Result<(), Fault> {
+        self.pop_state()
+    }
+
+    fn far_cal(&mut self, [bank, address, _]: Operands) -> Result<(), Fault> {
+        self.push_state()?;
+        self.push(self.code_bank)?;
+        #[cfg(feature = "instrument")]
+        self.count(|metrics| metrics.calls += 1);
+        self.select_code_bank(bank)?;
+        self.set_register(Register::InstructionPointer, address);
+        Ok(())
+    }
+
+    fn far_ret(&mut self, _operands: Operands) -> Result<(), Fault> {
+        let bank = self.read_word(self.get_register(Register::FramePointer) as usize)?;
+        self.pop_state()?;
+        self.select_code_bank(bank)
+    }
+
+    /// Switches code banks, writing the bank to the bank select address if
+    /// there is one
+    fn select_code_bank(&mut self, bank: u16) -> Result<(), Fault> {
+        self.code_bank = bank;
+        if let Some(address) = self.bank_select {
+            self.write_word(address, bank)?;
+        }
+        Ok(())
+    }
+
+    fn halt(&mut self, _operands: Operands) -> Result<(), Fault> {
+        self.halted = true;
+        Ok(())
+    }
+
+    fn ret_int(&mut self, _operands: Operands) -> Result<(), Fault> {
+        self.is_in_interrupt_handler = false;
+        self.pop_state()?;
+        let flags = self.pop()?;
+        self.set_register(Register::Flags, flags);
+        Ok(())
+    }
+
+    fn int(&mut self, [value, _, _]: Operands) -> Result<(), Fault> {
+        self.handle_interrupt(value)
+    }
+
+    fn panic(&mut self, [message, code, _]: Operands) -> Result<(), Fault> {
+        Err(Fault::GuestPanic { message, code })
+    }
+}
+
+impl Debug for Cpu {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CPU: {:#06x?}", self.register)
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Why `Cpu::run` returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// All the instructions it was given have been executed
+    FuelExhausted,
+    Fault(Fault),
+    /// The guest gave up with `Instruction::Panic`
+    GuestPanic {
+        message: String,
+        code: u16,
+    },
+    /// The guest stopped with `Instruction::Halt`
+    Halted,
+}
+
+/// Things that happened during execution that the host may want to know about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The watchdog expired and reset the CPU after `instruction`
+    WatchdogReset { instruction: u64 },
+    /// The `instruction`th instruction wrote over code at `address` that had
+    /// run since it was last written
+    SelfModifyingCode { address: u16, instruction: u64 },
+    /// `length` bytes of code from `address` were replaced by the host with
+    /// `Cpu::patch` after `instruction` instructions
+    Patched {
+        address: u16,
+        length: u16,
+        instruction: u64,
+    },
+}
+
+/// Conditions that stop the CPU from executing the current instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operand byte at `address` doesn't name a register of this CPU
+    IllegalOperand { address: u16, value: u8 },
+    /// The opcode or operand at `address` runs past the end of memory
+    FetchOutOfBounds { address: u16 },
+    /// A push with the stack pointer at `address`, outside of the stack
+    StackOverflow { address: u16 },
+    /// A pop with the stack pointer at `address`, with nothing above it
+    StackUnderflow { address: u16 },
+    /// The return address saved at `address` was overwritten, see
+    /// `Cpu::protect_return_addresses`
+    ReturnAddressCorrupted {
+        address: u16,
+        expected: u16,
+        found: u16,
+    },
+    /// A call or interrupt with the stack pointer at `address` would have
+    /// gone more than `limit` deep, see `MachineConfig::max_call_depth`
+    CallDepthExceeded { address: u16, limit: usize },
+    /// A word access at the odd `address`, see
+    /// `CpuBuilder::strict_alignment`
+    Unaligned { address: u16 },
+    /// The guest ran `Instruction::Panic` with the message at `message`
+    GuestPanic { message: u16, code: u16 },
+    /// A division by the register named by the operand at `address`,
+    /// which held zero
+    DivideByZero { address: u16 },
+    /// A word access at `address` that runs past the end of memory or
+    /// into an address nothing is mapped to
+    MemoryOutOfBounds { address: u16 },
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::IllegalOperand { address, value } => write!(
+                f,
+                "Illegal operand {:#04x} at address {:#06x}",
+                value, address
+            ),
+            Fault::StackOverflow { address } => {
+                write!(f, "Stack overflow at address {:#06x}", address)
             }
-            Instruction::Pop => {
-                let index = self.fetch_register_index();
-                let value = self.pop();
-                self.set_register_at(index, value);
+            Fault::StackUnderflow { address } => {
+                write!(f, "Stack underflow at address {:#06x}", address)
             }
-            Instruction::CalLit => {
-                let address = self.fetch16();
-                self.push_state();
-                self.set_register(Register::InstructionPointer, address);
+            Fault::ReturnAddressCorrupted {
+                address,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Return address at address {:#06x} overwritten, {:#06x} instead of {:#06x}",
+                address, found, expected
+            ),
+            Fault::CallDepthExceeded { address, limit } => write!(
+                f,
+                "Call depth limit of {} exceeded at address {:#06x}",
+                limit, address
+            ),
+            Fault::FetchOutOfBounds { address } => {
+                write!(
+                    f,
+                    "Instruction fetch out of bounds at address {:#06x}",
+                    address
+                )
             }
-            Instruction::CalReg => {
-                let register_index = self.fetch_register_index();
-                let address = self.get_register_at(register_index);
-                self.push_state();
-                self.set_register(Register::InstructionPointer, address);
+            Fault::Unaligned { address } => {
+                write!(f, "Unaligned word access at address {:#06x}", address)
             }
-            Instruction::Ret => {
-                self.pop_state();
+            Fault::MemoryOutOfBounds { address } => {
+                write!(f, "Memory access out of bounds at address {:#06x}", address)
             }
-            _ => {}
+            Fault::DivideByZero { address } => write!(
+                f,
+                "Division by zero, divisor named at address {:#06x}",
+                address
+            ),
+            Fault::GuestPanic { message, code } => write!(
+                f,
+                "Guest panicked with code {:#06x}, message at address {:#06x}",
+                code, message
+            ),
         }
     }
 }
 
-impl Debug for Cpu {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "CPU: {:?}", self.register)
-    }
-}
+impl std::error::Error for Fault {}
 
-#[derive(Debug, Clone, Copy)]
+/// A byte that doesn't encode any register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError(pub u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Register {
     InstructionPointer,
@@ -296,12 +1953,73 @@ pub enum Register {
     Register8,
     StackPointer,
     FramePointer,
-    None,
+    InterruptMask,
+    Register9,
+    Register10,
+    Register11,
+    Register12,
+    Register13,
+    Register14,
+    Register15,
+    /// Zero, carry, sign and overflow of the last arithmetic or logic
+    /// instruction, see `ZERO_FLAG` and the others. Saved on interrupts
+    /// and restored by `RetInt`, so handlers can't change the outcome of
+    /// the interrupted code's comparisons.
+    Flags,
 }
 
-impl From<u8> for Register {
-    fn from(value: u8) -> Self {
-        match value {
+impl Register {
+    /// Name of the register as written in assembly
+    pub fn name(&self) -> &'static str {
+        match self {
+            Register::InstructionPointer => "ip",
+            Register::Accumulator => "acc",
+            Register::Register1 => "r1",
+            Register::Register2 => "r2",
+            Register::Register3 => "r3",
+            Register::Register4 => "r4",
+            Register::Register5 => "r5",
+            Register::Register6 => "r6",
+            Register::Register7 => "r7",
+            Register::Register8 => "r8",
+            Register::StackPointer => "sp",
+            Register::FramePointer => "fp",
+            Register::InterruptMask => "im",
+            Register::Register9 => "r9",
+            Register::Register10 => "r10",
+            Register::Register11 => "r11",
+            Register::Register12 => "r12",
+            Register::Register13 => "r13",
+            Register::Register14 => "r14",
+            Register::Register15 => "r15",
+            Register::Flags => "flags",
+        }
+    }
+}
+
+impl FromStr for Register {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Register, String> {
+        (0..=u8::MAX)
+            .filter_map(|value| Register::try_from(value).ok())
+            .find(|register| register.name() == name)
+            .ok_or(format!("Unknown register: {}", name))
+    }
+}
+
+impl Register {
+    /// Converts a register operand that has already been decoded
+    fn from_operand(operand: u16) -> Register {
+        Register::try_from(operand as u8).expect("Register operand was decoded")
+    }
+}
+
+impl TryFrom<u8> for Register {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
             0 => Register::InstructionPointer,
             1 => Register::Accumulator,
             2 => Register::Register1,
@@ -314,8 +2032,17 @@ impl From<u8> for Register {
             9 => Register::Register8,
             10 => Register::StackPointer,
             11 => Register::FramePointer,
-            _ => Register::None,
-        }
+            12 => Register::InterruptMask,
+            13 => Register::Register9,
+            14 => Register::Register10,
+            15 => Register::Register11,
+            16 => Register::Register12,
+            17 => Register::Register13,
+            18 => Register::Register14,
+            19 => Register::Register15,
+            20 => Register::Flags,
+            _ => return Err(DecodeError(value)),
+        })
     }
 }
 
@@ -332,48 +2059,583 @@ pub enum Instruction {
     MovRegMem = 0x12,
     /// Move the value in a memory location to a register
     MovMemReg = 0x13,
-    /// Add the values in two registers and save it to the accumulator
+    /// Move the value in the memory location the first register points at
+    /// to the second register
+    MovRegPtrReg = 0x24,
+    /// Move the value in the first register to the memory location the
+    /// second register points at
+    MovRegRegPtr = 0x25,
+    /// Move the value in the memory location at the address plus the value
+    /// in the first register to the second register, for indexing arrays
+    /// and reaching into structs
+    MovLitOffReg = 0x26,
+    /// Move the value in the first register to the memory location at the
+    /// address plus the value in the second register
+    MovRegLitOff = 0x27,
+    /// Move the value in the memory location at the frame pointer plus a
+    /// signed offset to a register. Subroutines find their locals at and
+    /// below the frame pointer, and the saved frame and their arguments
+    /// above it.
+    MovFrameReg = 0x28,
+    /// Move the value in a register to the memory location at the frame
+    /// pointer plus a signed offset
+    MovRegFrame = 0x29,
+    /// Exchange the values in two registers
+    SwapRegReg = 0x2a,
+    /// Push the flags to the stack
+    PushFlags = 0x2b,
+    /// Pop the stack to the flags
+    PopFlags = 0x2c,
+    /// Add the values in two registers and save it to the accumulator,
+    /// wrapping around on overflow. Like every arithmetic and logic
+    /// instruction, sets the flags for the result.
     AddRegReg = 0x14,
-    /// Jump to a memory location if the value is not equal to accumulator
+    /// Jump to a memory location if the value is not equal to accumulator.
+    /// Deprecated, compare and use the jumps driven by the flags, like
+    /// `JmpIfNe`, instead. Kept for the programs that use it.
     JmpNotEq = 0x15,
+    /// Subtract a literal from the value in a register and save it to the
+    /// accumulator, wrapping around on underflow
+    SubLitReg = 0x16,
     /// Push a value to the stack
     PushLit = 0x17,
     /// Push the value in a register to the stack
     PushReg = 0x18,
+    /// Push the value in a memory location to the stack
+    PushMem = 0x19,
     /// Pop the stack to the given register
     Pop = 0x1a,
+    /// Pop the stack to a memory location
+    PopMem = 0x1b,
+    /// Exchange the stack pointer, frame pointer and frame size with the
+    /// three words at the address, to switch between coroutine stacks.
+    /// The stacks share the bounds of the machine's stack. By convention a
+    /// coroutine yields by calling a subroutine that swaps and returns, so
+    /// it returns into whichever coroutine yielded last; a new coroutine's
+    /// stack starts with a frame returning to its entry point.
+    SwapStack = 0x1c,
+    /// Save the context, the accumulator, r1 to rN, sp, fp, im, the flags
+    /// and the frame size, to the words at the address. The instruction pointer is
+    /// left out, see `RestoreContext`.
+    SaveContext = 0x1d,
+    /// Load a context saved by `SaveContext`. Execution goes on after the
+    /// instruction, so an interrupt handler that saves one context and
+    /// restores another returns into the other one.
+    RestoreContext = 0x1e,
+    /// Subtract the value in the second register from the value in the
+    /// first and save it to the accumulator, wrapping around on underflow
+    SubRegReg = 0x1f,
+    /// Add a literal to the value in a register and save it to the
+    /// accumulator, wrapping around on overflow
+    AddLitReg = 0x20,
+    /// Multiply the values in two registers into 32 bits, saving the low
+    /// word to the accumulator and the high word to the first register.
+    /// Faults unless the first register is a general purpose one.
+    MulRegReg = 0x21,
+    /// Divide the value in the first register by the value in the second,
+    /// unsigned, and save the quotient to the accumulator. Faults on a
+    /// zero divisor.
+    DivRegReg = 0x22,
+    /// Like `DivRegReg`, saving the remainder instead
+    ModRegReg = 0x23,
+    /// And a literal with the value in a register and save it to the
+    /// accumulator
+    AndLitReg = 0x2e,
+    /// And the values in two registers and save it to the accumulator
+    AndRegReg = 0x2f,
+    /// Or a literal with the value in a register and save it to the
+    /// accumulator
+    OrLitReg = 0x30,
+    /// Or the values in two registers and save it to the accumulator
+    OrRegReg = 0x31,
+    /// Exclusive or a literal with the value in a register and save it to
+    /// the accumulator
+    XorLitReg = 0x32,
+    /// Exclusive or the values in two registers and save it to the
+    /// accumulator
+    XorRegReg = 0x33,
+    /// Flip every bit of the value in a register and save it to the
+    /// accumulator
+    NotReg = 0x34,
+    /// Add one to the value in a register, in place, wrapping around. The
+    /// carry flag is left as it was.
+    IncReg = 0x35,
+    /// Subtract one from the value in a register, in place, wrapping around.
+    /// The carry flag is left as it was.
+    DecReg = 0x36,
+    /// Subtract a literal from the value in a register like `SubLitReg`,
+    /// setting the flags but dropping the result
+    CmpLitReg = 0x37,
+    /// Subtract the value in the second register from the value in the
+    /// first like `SubRegReg`, setting the flags but dropping the result
+    CmpRegReg = 0x38,
+    /// Jump to the memory location given by the literal
+    JmpLit = 0x40,
+    /// Jump to the memory location in the register
+    JmpReg = 0x41,
+    /// Jump to the memory location given by the literal if the zero flag is
+    /// set, as after comparing equal values
+    JmpIfEq = 0x42,
+    /// Like `JmpIfEq`, if the zero flag is clear
+    JmpIfNe = 0x43,
+    /// Like `JmpIfEq`, if the first value compared was greater, as signed
+    /// words
+    JmpIfGt = 0x44,
+    /// Like `JmpIfEq`, if the first value compared was less, as signed words
+    JmpIfLt = 0x45,
+    /// Like `JmpIfEq`, if the first value compared was greater or equal, as
+    /// signed words
+    JmpIfGe = 0x46,
+    /// Like `JmpIfEq`, if the first value compared was less or equal, as
+    /// signed words
+    JmpIfLe = 0x47,
+    /// Like `JmpIfEq`, if the carry flag is set. After a compare, the first
+    /// value was less, as unsigned words.
+    JmpIfCarry = 0x48,
+    /// Like `JmpIfEq`, if the carry flag is clear
+    JmpIfNoCarry = 0x49,
+    /// Like `JmpIfEq`, if the overflow flag is set
+    JmpIfOverflow = 0x4a,
+    /// Like `JmpIfEq`, if the overflow flag is clear
+    JmpIfNoOverflow = 0x4b,
     /// Call the subroutine at the literal
     CalLit = 0x5e,
     /// Call the subroutine at the register
     CalReg = 0x5f,
     /// Return from the subroutine
     Ret = 0x60,
+    /// Switch to the code bank given by the literal and call the subroutine
+    /// at the address there. The caller's bank is saved in the frame, at
+    /// the frame pointer.
+    FarCal = 0x61,
+    /// Return from a subroutine entered with `FarCal`, back to the caller's
+    /// bank
+    FarRet = 0x62,
+    /// Return from an interrupt handler, restoring the flags of the
+    /// interrupted code
+    RetInt = 0xfc,
+    /// Raise the software interrupt given by the literal
+    Int = 0xfd,
+    /// Stop with the NUL terminated message at the address and the code
+    /// given by the literal, 0 if there's nothing more to say, for failed
+    /// assertions and the like. See `StopReason::GuestPanic`.
+    Panic = 0xfe,
+    /// Stop the CPU for good, steps doing nothing until the next reset
+    Halt = 0xff,
+}
+
+impl Instruction {
+    /// Whether the instruction may continue anywhere but right after itself.
+    /// Moves and pops into the instruction pointer are not counted.
+    pub fn may_branch(self) -> bool {
+        matches!(
+            self,
+            Instruction::JmpNotEq
+                | Instruction::JmpLit
+                | Instruction::JmpReg
+                | Instruction::JmpIfEq
+                | Instruction::JmpIfNe
+                | Instruction::JmpIfGt
+                | Instruction::JmpIfLt
+                | Instruction::JmpIfGe
+                | Instruction::JmpIfLe
+                | Instruction::JmpIfCarry
+                | Instruction::JmpIfNoCarry
+                | Instruction::JmpIfOverflow
+                | Instruction::JmpIfNoOverflow
+                | Instruction::CalLit
+                | Instruction::CalReg
+                | Instruction::Ret
+                | Instruction::FarCal
+                | Instruction::FarRet
+                | Instruction::RetInt
+                | Instruction::Int
+        )
+    }
 }
 
 impl From<u8> for Instruction {
     fn from(value: u8) -> Self {
-        match value {
-            0x10 => Instruction::MovLitReg,
-            0x11 => Instruction::MovRegReg,
-            0x12 => Instruction::MovRegMem,
-            0x13 => Instruction::MovMemReg,
-            0x14 => Instruction::AddRegReg,
-            0x15 => Instruction::JmpNotEq,
-            0x17 => Instruction::PushLit,
-            0x18 => Instruction::PushReg,
-            0x1a => Instruction::Pop,
-            0x5e => Instruction::CalLit,
-            0x5f => Instruction::CalReg,
-            0x60 => Instruction::Ret,
-            _ => Instruction::Noop,
+        match &DISPATCH_TABLE[value as usize] {
+            Some(info) => info.instruction,
+            None => Instruction::Noop,
+        }
+    }
+}
+
+/// Decoded operand values in encoding order. Register operands hold the
+/// encoding of an enabled register.
+pub(crate) type Operands = [u16; 3];
+
+/// Kinds of operands that follow an opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// A 16 bit value
+    Literal,
+    /// A 16 bit memory address
+    Address,
+    /// A single byte naming a register
+    Register,
+    /// A single byte naming a register that holds a memory address
+    RegisterPointer,
+    /// A single byte naming a register whose value is added to the address
+    /// before it
+    OffsetRegister,
+    /// A signed 16 bit offset from the frame pointer to a memory location
+    FrameOffset,
+}
+
+impl Operand {
+    /// Number of bytes the operand takes in the instruction stream
+    pub const fn size(&self) -> usize {
+        match self {
+            Operand::Literal | Operand::Address | Operand::FrameOffset => 2,
+            Operand::Register | Operand::RegisterPointer | Operand::OffsetRegister => 1,
+        }
+    }
+}
+
+/// How an instruction is encoded and executed
+#[derive(Clone, Copy)]
+pub struct OpcodeInfo {
+    pub instruction: Instruction,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Operand],
+    pub(crate) execute: fn(&mut Cpu, Operands) -> Result<(), Fault>,
+}
+
+impl OpcodeInfo {
+    /// Encoded length including the opcode
+    pub const fn length(&self) -> usize {
+        let mut length = 1;
+        let mut i = 0;
+        while i < self.operands.len() {
+            length += self.operands[i].size();
+            i += 1;
+        }
+        length
+    }
+}
+
+impl Debug for OpcodeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpcodeInfo")
+            .field("instruction", &self.instruction)
+            .field("mnemonic", &self.mnemonic)
+            .field("operands", &self.operands)
+            .finish()
+    }
+}
+
+/// Looks up the built-in instruction encoded by `opcode`
+pub fn opcode_info(opcode: u8) -> Option<&'static OpcodeInfo> {
+    DISPATCH_TABLE[opcode as usize].as_ref()
+}
+
+const fn op(
+    instruction: Instruction,
+    mnemonic: &'static str,
+    operands: &'static [Operand],
+    execute: fn(&mut Cpu, Operands) -> Result<(), Fault>,
+) -> OpcodeInfo {
+    OpcodeInfo {
+        instruction,
+        mnemonic,
+        operands,
+        execute,
+    }
+}
+
+/// Every built-in instruction. Adding an instruction takes a variant in
+/// `Instruction`, a handler method on `Cpu` and an entry here.
+const INSTRUCTIONS: [OpcodeInfo; 62] = {
+    use Operand::{Address, FrameOffset, Literal, OffsetRegister, Register, RegisterPointer};
+    [
+        op(Instruction::Noop, "nop", &[], Cpu::noop),
+        op(
+            Instruction::MovLitReg,
+            "mov",
+            &[Literal, Register],
+            Cpu::mov_lit_reg,
+        ),
+        op(
+            Instruction::MovRegReg,
+            "mov",
+            &[Register, Register],
+            Cpu::mov_reg_reg,
+        ),
+        op(
+            Instruction::MovRegMem,
+            "mov",
+            &[Register, Address],
+            Cpu::mov_reg_mem,
+        ),
+        op(
+            Instruction::MovMemReg,
+            "mov",
+            &[Address, Register],
+            Cpu::mov_mem_reg,
+        ),
+        op(
+            Instruction::MovRegPtrReg,
+            "mov",
+            &[RegisterPointer, Register],
+            Cpu::mov_reg_ptr_reg,
+        ),
+        op(
+            Instruction::MovRegRegPtr,
+            "mov",
+            &[Register, RegisterPointer],
+            Cpu::mov_reg_reg_ptr,
+        ),
+        op(
+            Instruction::MovLitOffReg,
+            "mov",
+            &[Address, OffsetRegister, Register],
+            Cpu::mov_lit_off_reg,
+        ),
+        op(
+            Instruction::MovRegLitOff,
+            "mov",
+            &[Register, Address, OffsetRegister],
+            Cpu::mov_reg_lit_off,
+        ),
+        op(
+            Instruction::MovFrameReg,
+            "mov",
+            &[FrameOffset, Register],
+            Cpu::mov_frame_reg,
+        ),
+        op(
+            Instruction::MovRegFrame,
+            "mov",
+            &[Register, FrameOffset],
+            Cpu::mov_reg_frame,
+        ),
+        op(
+            Instruction::SwapRegReg,
+            "xch",
+            &[Register, Register],
+            Cpu::swap_reg_reg,
+        ),
+        op(
+            Instruction::AddRegReg,
+            "add",
+            &[Register, Register],
+            Cpu::add_reg_reg,
+        ),
+        op(
+            Instruction::JmpNotEq,
+            "jne",
+            &[Literal, Address],
+            Cpu::jmp_not_eq,
+        ),
+        op(
+            Instruction::SubLitReg,
+            "sub",
+            &[Literal, Register],
+            Cpu::sub_lit_reg,
+        ),
+        op(Instruction::PushLit, "psh", &[Literal], Cpu::push_lit),
+        op(Instruction::PushReg, "psh", &[Register], Cpu::push_reg),
+        op(Instruction::PushMem, "psh", &[Address], Cpu::push_mem),
+        op(Instruction::Pop, "pop", &[Register], Cpu::pop_reg),
+        op(Instruction::PopMem, "pop", &[Address], Cpu::pop_mem),
+        op(Instruction::PushFlags, "pshf", &[], Cpu::push_flags),
+        op(Instruction::PopFlags, "popf", &[], Cpu::pop_flags),
+        op(Instruction::SwapStack, "swp", &[Address], Cpu::swap_stack),
+        op(
+            Instruction::SaveContext,
+            "sav",
+            &[Address],
+            Cpu::save_context,
+        ),
+        op(
+            Instruction::RestoreContext,
+            "rst",
+            &[Address],
+            Cpu::restore_context,
+        ),
+        op(
+            Instruction::SubRegReg,
+            "sub",
+            &[Register, Register],
+            Cpu::sub_reg_reg,
+        ),
+        op(
+            Instruction::AddLitReg,
+            "add",
+            &[Literal, Register],
+            Cpu::add_lit_reg,
+        ),
+        op(
+            Instruction::MulRegReg,
+            "mul",
+            &[Register, Register],
+            Cpu::mul_reg_reg,
+        ),
+        op(
+            Instruction::DivRegReg,
+            "div",
+            &[Register, Register],
+            Cpu::div_reg_reg,
+        ),
+        op(
+            Instruction::ModRegReg,
+            "mod",
+            &[Register, Register],
+            Cpu::mod_reg_reg,
+        ),
+        op(
+            Instruction::AndLitReg,
+            "and",
+            &[Literal, Register],
+            Cpu::and_lit_reg,
+        ),
+        op(
+            Instruction::AndRegReg,
+            "and",
+            &[Register, Register],
+            Cpu::and_reg_reg,
+        ),
+        op(
+            Instruction::OrLitReg,
+            "or",
+            &[Literal, Register],
+            Cpu::or_lit_reg,
+        ),
+        op(
+            Instruction::OrRegReg,
+            "or",
+            &[Register, Register],
+            Cpu::or_reg_reg,
+        ),
+        op(
+            Instruction::XorLitReg,
+            "xor",
+            &[Literal, Register],
+            Cpu::xor_lit_reg,
+        ),
+        op(
+            Instruction::XorRegReg,
+            "xor",
+            &[Register, Register],
+            Cpu::xor_reg_reg,
+        ),
+        op(Instruction::NotReg, "not", &[Register], Cpu::not_reg),
+        op(Instruction::IncReg, "inc", &[Register], Cpu::inc_reg),
+        op(Instruction::DecReg, "dec", &[Register], Cpu::dec_reg),
+        op(
+            Instruction::CmpLitReg,
+            "cmp",
+            &[Literal, Register],
+            Cpu::cmp_lit_reg,
+        ),
+        op(
+            Instruction::CmpRegReg,
+            "cmp",
+            &[Register, Register],
+            Cpu::cmp_reg_reg,
+        ),
+        op(Instruction::JmpLit, "jmp", &[Address], Cpu::jmp_lit),
+        op(Instruction::JmpReg, "jmp", &[Register], Cpu::jmp_reg),
+        op(Instruction::JmpIfEq, "jeq", &[Address], Cpu::jmp_if_eq),
+        op(Instruction::JmpIfNe, "jne", &[Address], Cpu::jmp_if_ne),
+        op(Instruction::JmpIfGt, "jgt", &[Address], Cpu::jmp_if_gt),
+        op(Instruction::JmpIfLt, "jlt", &[Address], Cpu::jmp_if_lt),
+        op(Instruction::JmpIfGe, "jge", &[Address], Cpu::jmp_if_ge),
+        op(Instruction::JmpIfLe, "jle", &[Address], Cpu::jmp_if_le),
+        op(Instruction::JmpIfCarry, "jc", &[Address], Cpu::jmp_if_carry),
+        op(
+            Instruction::JmpIfNoCarry,
+            "jnc",
+            &[Address],
+            Cpu::jmp_if_no_carry,
+        ),
+        op(
+            Instruction::JmpIfOverflow,
+            "jo",
+            &[Address],
+            Cpu::jmp_if_overflow,
+        ),
+        op(
+            Instruction::JmpIfNoOverflow,
+            "jno",
+            &[Address],
+            Cpu::jmp_if_no_overflow,
+        ),
+        op(Instruction::CalLit, "cal", &[Address], Cpu::cal_lit),
+        op(Instruction::CalReg, "cal", &[Register], Cpu::cal_reg),
+        op(Instruction::Ret, "ret", &[], Cpu::ret),
+        op(
+            Instruction::FarCal,
+            "fcl",
+            &[Literal, Address],
+            Cpu::far_cal,
+        ),
+        op(Instruction::FarRet, "frt", &[], Cpu::far_ret),
+        op(Instruction::RetInt, "rti", &[], Cpu::ret_int),
+        op(Instruction::Int, "int", &[Literal], Cpu::int),
+        op(Instruction::Panic, "pnc", &[Address, Literal], Cpu::panic),
+        op(Instruction::Halt, "hlt", &[], Cpu::halt),
+    ]
+};
+
+/// Longest encoding of a built-in instruction
+pub const MAX_INSTRUCTION_LENGTH: usize = {
+    let mut longest = 0;
+    let mut i = 0;
+    while i < INSTRUCTIONS.len() {
+        if INSTRUCTIONS[i].length() > longest {
+            longest = INSTRUCTIONS[i].length();
         }
+        i += 1;
+    }
+    longest
+};
+
+/// Encoded length of every opcode, 0 for those that aren't built in
+static INSTRUCTION_LENGTHS: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < INSTRUCTIONS.len() {
+        table[INSTRUCTIONS[i].instruction as usize] = INSTRUCTIONS[i].length() as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Encoded length of the built-in instruction `opcode` starts, operands
+/// included, the same as `OpcodeInfo::length`
+pub fn instruction_length(opcode: u8) -> Option<usize> {
+    match INSTRUCTION_LENGTHS[opcode as usize] {
+        0 => None,
+        length => Some(length as usize),
     }
 }
 
+/// Built-in instructions indexed by opcode
+static DISPATCH_TABLE: [Option<OpcodeInfo>; 256] = {
+    let mut table = [None; 256];
+    let mut i = 0;
+    while i < INSTRUCTIONS.len() {
+        table[INSTRUCTIONS[i].instruction as usize] = Some(INSTRUCTIONS[i]);
+        i += 1;
+    }
+    table
+};
+
 #[cfg(test)]
 mod tests {
-    use super::{Cpu, Instruction, Register};
+    use super::{
+        Cpu, DecodeError, Fault, Instruction, Operand, Register, StopReason, CARRY_FLAG,
+        DEFAULT_GENERAL_PURPOSE_REGISTERS, GENERAL_PURPOSE_REGISTERS, OVERFLOW_FLAG, SIGN_FLAG,
+        ZERO_FLAG,
+    };
+    use crate::mapper::{AddressSpace, Device};
     use crate::memory::Memory;
+    use proptest::prelude::*;
+    use proptest::sample::select;
 
     fn assert_register_eq(cpu: &Cpu, register: &Register, value: u16, message: Option<&str>) {
         match message {
@@ -395,6 +2657,7 @@ mod tests {
     const TWO_BYTES: usize = 2;
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_push_state_and_pop_state() {
         let memory = Memory::new(256);
         let mut cpu = Cpu::new(memory);
@@ -409,9 +2672,9 @@ mod tests {
         cpu.set_register(Register::Register7, 0x7777);
         cpu.set_register(Register::Register8, 0x8888);
 
-        cpu.push(0x4242); // Push argument 1 for the subroutine
-        cpu.push(0x5252); // Push argument 2 for the subroutine
-        cpu.push(0x0002); // Push number of arguments we sent to subroutine
+        cpu.push(0x4242).unwrap(); // Push argument 1 for the subroutine
+        cpu.push(0x5252).unwrap(); // Push argument 2 for the subroutine
+        cpu.push(0x0002).unwrap(); // Push number of arguments we sent to subroutine
 
         let stack_pointer_offset =
             1 * TWO_BYTES // Offsetted 2 bytes by default to start the stack
@@ -425,7 +2688,7 @@ mod tests {
             Some("Stack pointer is pointing to the beginning"),
         );
 
-        cpu.push_state();
+        cpu.push_state().unwrap();
 
         let stack_pointer_offset =
             1 * TWO_BYTES // Offsetted 2 bytes by default to start the stack
@@ -460,7 +2723,7 @@ mod tests {
             "Pushed Register 4 to stack"
         );
 
-        cpu.pop_state();
+        cpu.pop_state().unwrap();
 
         let stack_pointer_offset =
             1 * TWO_BYTES // Offsetted 2 bytes by default to start the stack
@@ -478,6 +2741,43 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
+    fn saves_every_configured_register() {
+        let memory = Memory::new(256);
+        let mut cpu = Cpu::new(memory).with_general_purpose_registers(15);
+        let last_byte_pointer = cpu.memory.byte_length();
+
+        cpu.set_register(Register::Register1, 0x1111);
+        cpu.set_register(Register::Register15, 0xffff);
+        cpu.push(0x0000).unwrap(); // No arguments
+
+        cpu.push_state().unwrap();
+
+        let stack_pointer_offset =
+            1 * TWO_BYTES // Offsetted 2 bytes by default to start the stack
+          + 1 * TWO_BYTES // Number of arguments
+          + 15 * TWO_BYTES // General purpose registers
+          + 1 * TWO_BYTES // Instruction pointer
+          + 1 * TWO_BYTES // Stack size
+        ;
+
+        assert_register_eq(
+            &cpu,
+            &Register::StackPointer,
+            (last_byte_pointer - stack_pointer_offset) as u16,
+            Some("Stack pointer is moved by the saved frame"),
+        );
+
+        cpu.set_register(Register::Register15, 0x0000);
+        cpu.pop_state().unwrap();
+
+        assert_register_eq(&cpu, &Register::Register1, 0x1111, None);
+        assert_register_eq(&cpu, &Register::Register15, 0xffff, None);
+        assert_eq!(cpu.general_purpose_registers().len(), 15);
+    }
+
+    #[test]
+    #[allow(clippy::identity_op)]
     fn test_push_and_pop() {
         let memory = Memory::new(256);
         let mut cpu = Cpu::new(memory);
@@ -489,7 +2789,7 @@ mod tests {
             (last_byte_pointer - 2) as u16,
             Some("Offset for the stack is 2 bytes before the last index"),
         );
-        cpu.push(0x4243);
+        cpu.push(0x4243).unwrap();
         assert_eq!(cpu.memory.get_byte(last_byte_pointer - 1), 0x43);
         assert_eq!(cpu.memory.get_byte(last_byte_pointer - 2), 0x42);
         assert_eq!(cpu.stack_frame_size, 2, "Stack grew two bytes");
@@ -500,7 +2800,7 @@ mod tests {
             Some("Pointer points at the new empty address"),
         );
 
-        let value = cpu.pop();
+        let value = cpu.pop().unwrap();
         assert_eq!(value, 0x4243);
         assert_eq!(cpu.stack_frame_size, 0, "Stack shrank two bytes");
         assert_eq!(
@@ -522,12 +2822,727 @@ mod tests {
         let mut cpu = Cpu::new(memory);
 
         assert_register_eq(&cpu, &Register::InstructionPointer, 0, None);
-        cpu.step();
+        cpu.step().unwrap();
         assert_register_eq(&cpu, &Register::InstructionPointer, 1, None);
-        cpu.step();
+        cpu.step().unwrap();
         assert_register_eq(&cpu, &Register::InstructionPointer, 2, None);
     }
 
+    #[test]
+    fn ignores_masked_interrupts() {
+        let mut memory = Memory::new(256 * 256);
+        memory.set_word(super::INTERRUPT_VECTOR_ADDRESS + 2 * 2, 0x0300);
+
+        let mut cpu = Cpu::new(memory);
+        cpu.set_register(Register::InterruptMask, !(1 << 2));
+        cpu.handle_interrupt(2).unwrap();
+        assert_register_eq(&cpu, &Register::InstructionPointer, 0, None);
+
+        cpu.set_register(Register::InterruptMask, 0xffff);
+        cpu.handle_interrupt(2).unwrap();
+        assert_register_eq(&cpu, &Register::InstructionPointer, 0x0300, None);
+    }
+
+    #[test]
+    fn faults_on_illegal_register_operand() {
+        let mut memory = Memory::new(32);
+
+        // mov r9, r1 ;; r9 isn't enabled by default
+        memory.set_byte(0, Instruction::MovRegReg as u8);
+        memory.set_byte(1, Register::Register9 as u8);
+        memory.set_byte(2, Register::Register1 as u8);
+        // psh 0xee ;; not a register at all
+        memory.set_byte(3, Instruction::PushReg as u8);
+        memory.set_byte(4, 0xee);
+
+        let mut cpu = Cpu::new(memory);
+        assert_eq!(
+            cpu.step(),
+            Err(Fault::IllegalOperand {
+                address: 1,
+                value: Register::Register9 as u8
+            })
+        );
+
+        cpu.set_register(Register::InstructionPointer, 3);
+        assert_eq!(
+            cpu.step(),
+            Err(Fault::IllegalOperand {
+                address: 4,
+                value: 0xee
+            })
+        );
+        assert_eq!(Register::try_from(0xee), Err(DecodeError(0xee)));
+    }
+
+    #[test]
+    fn lists_registers_in_display_order() {
+        let memory = Memory::new(32);
+        let mut cpu = Cpu::new(memory).with_general_purpose_registers(9);
+        cpu.set_register(Register::Register9, 0x0909);
+
+        let names: Vec<&str> = cpu.registers().map(|(_, name, _)| name).collect();
+        assert_eq!(
+            names,
+            [
+                "ip", "acc", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "sp", "fp",
+                "im", "flags"
+            ]
+        );
+        assert!(cpu
+            .registers()
+            .any(|register| register == (Register::Register9, "r9", 0x0909)));
+    }
+
+    #[test]
+    fn dispatch_table_matches_opcodes() {
+        for opcode in 0..=255u8 {
+            if let Some(info) = super::opcode_info(opcode) {
+                assert_eq!(info.instruction as u8, opcode);
+            }
+        }
+        let info = super::opcode_info(Instruction::MovLitReg as u8).unwrap();
+        assert_eq!(info.mnemonic, "mov");
+        assert_eq!(info.length(), 4);
+        for opcode in 0..=255u8 {
+            assert_eq!(
+                super::instruction_length(opcode),
+                super::opcode_info(opcode).map(|info| info.length())
+            );
+        }
+        assert!(super::opcode_info(0x01).is_none());
+    }
+
+    #[test]
+    fn instructions_advance_by_their_length() {
+        for info in super::INSTRUCTIONS.iter() {
+            // Panics never finish
+            if info.instruction.may_branch() || matches!(info.instruction, Instruction::Panic) {
+                continue;
+            }
+            let mut cpu = Cpu::new(Memory::new(0x2000));
+            // Register operands name r1, set to 1 for divisions to go
+            // through, the rest point at zeroed memory, frame offsets at the
+            // top of the stack
+            let mut code = vec![info.instruction as u8];
+            for operand in info.operands {
+                match operand {
+                    Operand::Register | Operand::RegisterPointer | Operand::OffsetRegister => {
+                        code.push(Register::Register1 as u8)
+                    }
+                    Operand::Literal | Operand::Address => code.extend([0x01, 0x00]),
+                    Operand::FrameOffset => code.extend([0x00, 0x00]),
+                }
+            }
+            for (i, byte) in code.iter().enumerate() {
+                cpu.memory.set_byte(0x10 + i, *byte);
+            }
+            cpu.set_register(Register::InstructionPointer, 0x10);
+            cpu.set_register(Register::Register1, 1);
+            cpu.push(0).unwrap();
+
+            cpu.step().unwrap();
+            assert_eq!(
+                cpu.get_register(Register::InstructionPointer) as usize,
+                0x10 + info.length(),
+                "{:?}",
+                info.instruction
+            );
+        }
+    }
+
+    #[test]
+    fn run_matches_step_n() {
+        let program = || {
+            let mut memory = Memory::new(256 * 256);
+
+            // start:
+            //   mov 0x0001, r2
+            //   add r1, r2
+            //   mov acc, r1
+            //   mov r1, #0100
+            //   mov #0100, r3
+            //   psh 0x4242
+            //   psh r3
+            //   pop r4
+            //   jne 0x0005, start:
+            let mut i = 0;
+            memory.set_byte(i, Instruction::MovLitReg as u8);
+            memory.set_word(i + 1, 0x0001);
+            memory.set_byte(i + 3, Register::Register2 as u8);
+            i += 4;
+            memory.set_byte(i, Instruction::AddRegReg as u8);
+            memory.set_byte(i + 1, Register::Register1 as u8);
+            memory.set_byte(i + 2, Register::Register2 as u8);
+            i += 3;
+            memory.set_byte(i, Instruction::MovRegReg as u8);
+            memory.set_byte(i + 1, Register::Accumulator as u8);
+            memory.set_byte(i + 2, Register::Register1 as u8);
+            i += 3;
+            memory.set_byte(i, Instruction::MovRegMem as u8);
+            memory.set_byte(i + 1, Register::Register1 as u8);
+            memory.set_word(i + 2, 0x0100);
+            i += 4;
+            memory.set_byte(i, Instruction::MovMemReg as u8);
+            memory.set_word(i + 1, 0x0100);
+            memory.set_byte(i + 3, Register::Register3 as u8);
+            i += 4;
+            memory.set_byte(i, Instruction::PushLit as u8);
+            memory.set_word(i + 1, 0x4242);
+            i += 3;
+            memory.set_byte(i, Instruction::PushReg as u8);
+            memory.set_byte(i + 1, Register::Register3 as u8);
+            i += 2;
+            memory.set_byte(i, Instruction::Pop as u8);
+            memory.set_byte(i + 1, Register::Register4 as u8);
+            i += 2;
+            memory.set_byte(i, Instruction::JmpNotEq as u8);
+            memory.set_word(i + 1, 0x0005);
+            memory.set_word(i + 3, 0x0000);
+
+            memory
+        };
+
+        for fuel in [0, 1, 7, 9, 40] {
+            let mut stepped = Cpu::new(program());
+            stepped.step_n(fuel).unwrap();
+            let mut ran = Cpu::new(program());
+            assert_eq!(ran.run(fuel), super::StopReason::FuelExhausted);
+
+            assert_eq!(
+                ran.registers().collect::<Vec<_>>(),
+                stepped.registers().collect::<Vec<_>>(),
+                "Same registers after {} instructions",
+                fuel
+            );
+            assert_eq!(ran.peek_stack(), stepped.peek_stack());
+            assert_eq!(ran.peek(0x0100), stepped.peek(0x0100));
+            assert_eq!(ran.instruction_count(), fuel as u64);
+        }
+    }
+
+    #[test]
+    fn run_stops_at_fault() {
+        let mut memory = Memory::new(32);
+
+        // mov 0x1234, r1
+        // mov 0x1234, 0xee
+        memory.set_byte(0, Instruction::MovLitReg as u8);
+        memory.set_word(1, 0x1234);
+        memory.set_byte(3, Register::Register1 as u8);
+        memory.set_byte(4, Instruction::MovLitReg as u8);
+        memory.set_word(5, 0x1234);
+        memory.set_byte(7, 0xee);
+
+        let mut cpu = Cpu::new(memory);
+        assert_eq!(
+            cpu.run(10),
+            super::StopReason::Fault(Fault::IllegalOperand {
+                address: 7,
+                value: 0xee
+            })
+        );
+        assert_register_eq(&cpu, &Register::InstructionPointer, 8, None);
+        assert_eq!(cpu.instruction_count(), 2);
+    }
+
+    #[test]
+    fn runs_stop_at_halt() {
+        let program = || {
+            // mov 0x1234, r1
+            // hlt
+            // mov 0x5678, r1
+            let mut memory = Memory::new(32);
+            memory.set_byte(0, Instruction::MovLitReg as u8);
+            memory.set_word(1, 0x1234);
+            memory.set_byte(3, Register::Register1 as u8);
+            memory.set_byte(4, Instruction::Halt as u8);
+            memory.set_byte(5, Instruction::MovLitReg as u8);
+            memory.set_word(6, 0x5678);
+            memory.set_byte(8, Register::Register1 as u8);
+            memory
+        };
+
+        let run_with: [fn(&mut Cpu, usize) -> super::StopReason; 2] = [Cpu::run, Cpu::run_cached];
+        for run in run_with {
+            let mut cpu = Cpu::new(program());
+            assert_eq!(run(&mut cpu, 10), super::StopReason::Halted);
+            assert!(cpu.is_halted());
+            assert_register_eq(&cpu, &Register::InstructionPointer, 5, None);
+            assert_register_eq(&cpu, &Register::Register1, 0x1234, None);
+            assert_eq!(cpu.instruction_count(), 2);
+
+            cpu.step().unwrap();
+            assert_eq!(cpu.instruction_count(), 2, "Steps do nothing");
+            assert_eq!(run(&mut cpu, 10), super::StopReason::Halted);
+
+            cpu.reset();
+            assert!(!cpu.is_halted());
+            cpu.step().unwrap();
+            assert_eq!(cpu.instruction_count(), 3);
+        }
+    }
+
+    #[test]
+    fn fetching_past_the_end_of_memory_faults() {
+        // mov 0x1234, r1 cut off after the literal
+        let mut memory = Memory::new(32);
+        memory.set_byte(29, Instruction::MovLitReg as u8);
+        memory.set_word(30, 0x1234);
+
+        let mut stepped = Cpu::new(memory);
+        stepped.set_register(Register::InstructionPointer, 29);
+        assert_eq!(stepped.step(), Err(Fault::FetchOutOfBounds { address: 32 }));
+        assert_register_eq(&stepped, &Register::InstructionPointer, 32, None);
+        assert_eq!(stepped.step(), Err(Fault::FetchOutOfBounds { address: 32 }));
+
+        let mut memory = Memory::new(32);
+        memory.set_byte(29, Instruction::MovLitReg as u8);
+        memory.set_word(30, 0x1234);
+        let mut ran = Cpu::new(memory);
+        ran.set_register(Register::InstructionPointer, 29);
+        assert_eq!(
+            ran.run(10),
+            super::StopReason::Fault(Fault::FetchOutOfBounds { address: 32 })
+        );
+        assert_register_eq(&ran, &Register::InstructionPointer, 32, None);
+    }
+
+    #[test]
+    fn state_hash_covers_registers_and_memory() {
+        let machine = || {
+            let mut memory = Memory::new(256);
+            memory.set_byte(0, Instruction::PushLit as u8);
+            memory.set_word(1, 0x1234);
+            let mut cpu = Cpu::new(memory);
+            cpu.step().unwrap();
+            cpu
+        };
+        let hash = machine().state_hash();
+        assert_eq!(machine().state_hash(), hash);
+
+        let mut cpu = machine();
+        cpu.set_register(Register::Register8, 1);
+        assert_ne!(cpu.state_hash(), hash);
+
+        let mut cpu = machine();
+        cpu.memory_mut().set_byte(0xff, 1);
+        assert_ne!(cpu.state_hash(), hash);
+
+        let mut cpu = machine();
+        cpu.pop().unwrap();
+        cpu.push(0x1234).unwrap();
+        assert_eq!(cpu.state_hash(), hash, "Same state, however it came about");
+    }
+
+    #[test]
+    fn popping_an_empty_stack_underflows() {
+        // pop r1; ret
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::Pop as u8);
+        memory.set_byte(1, Register::Register1 as u8);
+        memory.set_byte(2, Instruction::Ret as u8);
+
+        let mut cpu = Cpu::new(memory);
+        assert_eq!(cpu.step(), Err(Fault::StackUnderflow { address: 0xfe }));
+        assert_register_eq(&cpu, &Register::StackPointer, 0xfe, None);
+
+        cpu.set_register(Register::InstructionPointer, 2);
+        assert_eq!(cpu.step(), Err(Fault::StackUnderflow { address: 0xfe }));
+    }
+
+    #[test]
+    fn strict_alignment_faults_on_odd_word_accesses() {
+        // mov [0x0081], r1; mov r1, [0x0080]; psh 0x1234
+        let code = [
+            Instruction::MovMemReg as u8,
+            0x00,
+            0x81,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x00,
+            0x80,
+            Instruction::PushLit as u8,
+            0x12,
+            0x34,
+        ];
+        let load = |strict: bool| {
+            let builder = Cpu::builder().memory_size(0x2000);
+            let mut cpu = match strict {
+                true => builder.strict_alignment(),
+                false => builder,
+            }
+            .build()
+            .unwrap();
+            for (i, byte) in code.iter().enumerate() {
+                cpu.memory_mut().set_byte(i, *byte);
+            }
+            cpu
+        };
+
+        let mut cpu = load(false);
+        assert_eq!(cpu.run(3), super::StopReason::FuelExhausted);
+
+        let mut cpu = load(true);
+        assert_eq!(
+            cpu.run(3),
+            super::StopReason::Fault(Fault::Unaligned { address: 0x0081 })
+        );
+        cpu.set_register(Register::InstructionPointer, 4);
+        cpu.step_n(1).unwrap();
+        cpu.set_register(Register::StackPointer, 0x00fd);
+        assert_eq!(cpu.step(), Err(Fault::Unaligned { address: 0x00fd }));
+        assert_register_eq(&cpu, &Register::StackPointer, 0x00fd, None);
+    }
+
+    #[test]
+    fn pushing_past_the_bottom_of_memory_overflows() {
+        // psh 0x1234; psh 0x1234
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x80, Instruction::PushLit as u8);
+        memory.set_word(0x81, 0x1234);
+        memory.set_byte(0x83, Instruction::PushLit as u8);
+        memory.set_word(0x84, 0x1234);
+
+        let mut cpu = Cpu::new(memory);
+        cpu.set_register(Register::InstructionPointer, 0x80);
+        cpu.set_register(Register::StackPointer, 0x0002);
+        assert_eq!(
+            cpu.run(2),
+            super::StopReason::Fault(Fault::StackOverflow { address: 0x0000 })
+        );
+        assert_register_eq(&cpu, &Register::StackPointer, 0x0000, None);
+        assert_eq!(cpu.peek(0x0002), 0x1234);
+    }
+
+    #[test]
+    fn instruction_pointer_wraps_around_at_the_top_of_memory() {
+        // 0xfffd: psh 0x1234
+        // 0x0000: mov 0x5678, r1
+        let mut memory = Memory::new(256 * 256);
+        memory.set_byte(0xfffd, Instruction::PushLit as u8);
+        memory.set_word(0xfffe, 0x1234);
+        memory.set_byte(0, Instruction::MovLitReg as u8);
+        memory.set_word(1, 0x5678);
+        memory.set_byte(3, Register::Register1 as u8);
+
+        let mut cpu = Cpu::builder()
+            .memory_size(256 * 256)
+            .stack_top(0x8000)
+            .build()
+            .unwrap();
+        for (address, byte) in memory.peek(0, 256 * 256).into_iter().enumerate() {
+            cpu.memory_mut().set_byte(address, byte);
+        }
+        cpu.set_register(Register::InstructionPointer, 0xfffd);
+        cpu.step().unwrap();
+        assert_register_eq(&cpu, &Register::InstructionPointer, 0, None);
+        cpu.step().unwrap();
+        assert_register_eq(&cpu, &Register::Register1, 0x5678, None);
+
+        // A word operand can't straddle the top
+        cpu.memory_mut()
+            .set_byte(0xfffe, Instruction::PushLit as u8);
+        cpu.set_register(Register::InstructionPointer, 0xfffe);
+        assert_eq!(cpu.step(), Err(Fault::FetchOutOfBounds { address: 0xffff }));
+    }
+
+    #[test]
+    fn accesses_out_of_bounds_fault() {
+        // swp 0xfffc, the third word of the block runs past the top
+        let mut memory = Memory::new(256 * 256);
+        memory.set_byte(0, Instruction::SwapStack as u8);
+        memory.set_word(1, 0xfffc);
+        let mut cpu = Cpu::new(memory);
+        let stack_pointer = cpu.get_register(Register::StackPointer);
+        assert_eq!(
+            cpu.step(),
+            Err(Fault::MemoryOutOfBounds { address: 0xfffc })
+        );
+        assert_register_eq(&cpu, &Register::StackPointer, stack_pointer, None);
+
+        // mov [0x0100], r1 with nothing mapped at 0x0100
+        let mut space = AddressSpace::new();
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::MovMemReg as u8);
+        memory.set_word(1, 0x0100);
+        memory.set_byte(3, Register::Register1 as u8);
+        space.map(memory, 0x0000, 0x00ff, false);
+        space.map(Memory::new(256), 0x0200, 0x02ff, false);
+        let mut cpu = Cpu::new(space);
+        assert_eq!(
+            cpu.step(),
+            Err(Fault::MemoryOutOfBounds { address: 0x0100 })
+        );
+    }
+
+    #[test]
+    fn words_straddle_regions() {
+        // mov 0x1234, r1
+        // mov r1, [0x004f]
+        // mov [0x004f], r2
+        // ...
+        // ;; at address 0x003e, the literal runs into the device at 0x0040
+        // mov 0xabcd, r3
+        let machine = || {
+            let mut space = AddressSpace::new();
+            space.map(Memory::new(256), 0x0000, 0x00ff, false);
+            space.map(Memory::new(16), 0x0040, 0x004f, true);
+            space.set_byte(0, Instruction::MovLitReg as u8);
+            space.set_word(1, 0x1234);
+            space.set_byte(3, Register::Register1 as u8);
+            space.set_byte(4, Instruction::MovRegMem as u8);
+            space.set_byte(5, Register::Register1 as u8);
+            space.set_word(6, 0x004f);
+            space.set_byte(8, Instruction::MovMemReg as u8);
+            space.set_word(9, 0x004f);
+            space.set_byte(11, Register::Register2 as u8);
+            space.set_byte(0x3e, Instruction::MovLitReg as u8);
+            space.set_word(0x3f, 0xabcd);
+            space.set_byte(0x41, Register::Register3 as u8);
+            Cpu::new(space)
+        };
+
+        let mut stepped = machine();
+        stepped.step_n(3).unwrap();
+        stepped.set_register(Register::InstructionPointer, 0x003e);
+        stepped.step().unwrap();
+
+        let mut run = machine();
+        assert_eq!(run.run(3), StopReason::FuelExhausted);
+        run.set_register(Register::InstructionPointer, 0x003e);
+        assert_eq!(run.run(1), StopReason::FuelExhausted);
+
+        for cpu in [stepped, run] {
+            assert_register_eq(&cpu, &Register::Register2, 0x1234, None);
+            assert_register_eq(&cpu, &Register::Register3, 0xabcd, None);
+            assert_eq!(cpu.peek_memory(0x004f, 2), [0x12, 0x34]);
+        }
+    }
+
+    #[test]
+    fn calls_leave_the_frame_unchanged() {
+        let mut memory = Memory::new(256 * 256);
+
+        // start:
+        //   psh 0x0000
+        //   cal 0x0100
+        //   jne 0x0001, start:
+        //
+        // ;; at address 0x0100
+        //   ret
+        memory.set_byte(0, Instruction::PushLit as u8);
+        memory.set_word(1, 0x0000);
+        memory.set_byte(3, Instruction::CalLit as u8);
+        memory.set_word(4, 0x0100);
+        memory.set_byte(6, Instruction::JmpNotEq as u8);
+        memory.set_word(7, 0x0001);
+        memory.set_word(9, 0x0000);
+        memory.set_byte(0x0100, Instruction::Ret as u8);
+
+        let mut cpu = Cpu::new(memory);
+        let frame_pointer = cpu.get_register(Register::FramePointer);
+        let stack_pointer = cpu.get_register(Register::StackPointer);
+        for _ in 0..10 {
+            cpu.step_n(4).unwrap();
+            assert_register_eq(&cpu, &Register::FramePointer, frame_pointer, None);
+            assert_register_eq(&cpu, &Register::StackPointer, stack_pointer, None);
+            assert_eq!(cpu.stack_frame_size, 0);
+        }
+    }
+
+    #[test]
+    fn protected_returns_catch_smashed_return_addresses() {
+        // psh 0x0000
+        // cal 0x0100
+        //
+        // ;; at address 0x0100
+        //   mov 0x0666, r1
+        //   mov r1, [0xffec] ;; where the call saved its return address
+        //   ret
+        let program = |saved_at: u16| {
+            let mut memory = Memory::new(256 * 256);
+            memory.set_byte(0, Instruction::PushLit as u8);
+            memory.set_word(1, 0x0000);
+            memory.set_byte(3, Instruction::CalLit as u8);
+            memory.set_word(4, 0x0100);
+            memory.set_byte(0x0100, Instruction::MovLitReg as u8);
+            memory.set_word(0x0101, 0x0666);
+            memory.set_byte(0x0103, Register::Register1 as u8);
+            memory.set_byte(0x0104, Instruction::MovRegMem as u8);
+            memory.set_byte(0x0105, Register::Register1 as u8);
+            memory.set_word(0x0106, saved_at);
+            memory.set_byte(0x0108, Instruction::Ret as u8);
+            memory
+        };
+
+        let mut cpu = Cpu::new(program(0xffec));
+        cpu.step_n(5).unwrap();
+        assert_eq!(cpu.get_register(Register::InstructionPointer), 0x0666);
+
+        let mut cpu = Cpu::new(program(0xffec));
+        cpu.protect_return_addresses();
+        assert_eq!(
+            cpu.step_n(5),
+            Err(Fault::ReturnAddressCorrupted {
+                address: 0xffec,
+                expected: 0x0006,
+                found: 0x0666
+            })
+        );
+
+        // Writes anywhere else are fine
+        let mut cpu = Cpu::new(program(0x0080));
+        cpu.protect_return_addresses();
+        assert_eq!(cpu.step_n(5), Ok(()));
+        assert_eq!(cpu.get_register(Register::InstructionPointer), 0x0006);
+    }
+
+    #[test]
+    fn swaps_between_coroutine_stacks() {
+        // psh 0x1111
+        // swp [0x0080]  ;; to the stack at 0x00be
+        // psh 0x2222
+        // swp [0x0080]  ;; and back
+        // pop r1
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x00, Instruction::PushLit as u8);
+        memory.set_word(0x01, 0x1111);
+        memory.set_byte(0x03, Instruction::SwapStack as u8);
+        memory.set_word(0x04, 0x0080);
+        memory.set_byte(0x06, Instruction::PushLit as u8);
+        memory.set_word(0x07, 0x2222);
+        memory.set_byte(0x09, Instruction::SwapStack as u8);
+        memory.set_word(0x0a, 0x0080);
+        memory.set_byte(0x0c, Instruction::Pop as u8);
+        memory.set_byte(0x0d, Register::Register1 as u8);
+        memory.set_word(0x80, 0x00be);
+        memory.set_word(0x82, 0x00be);
+        let mut cpu = Cpu::new(memory);
+        cpu.protect_return_addresses();
+
+        cpu.step_n(2).unwrap();
+        assert_eq!(cpu.get_register(Register::StackPointer), 0x00be);
+        assert_eq!(cpu.stack_frame_size, 0);
+        assert_eq!(
+            cpu.peek_memory(0x80, 6),
+            [0x00, 0xfc, 0x00, 0xfe, 0x00, 0x02]
+        );
+
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.get_register(Register::Register1), 0x1111);
+        assert_eq!(cpu.get_register(Register::StackPointer), 0x00fe);
+        assert_eq!(cpu.peek(0xbe), 0x2222);
+        assert_eq!(cpu.peek(0x80), 0x00bc, "The other stack is parked");
+    }
+
+    #[test]
+    fn passes_arguments_on_the_stack() {
+        let mut cpu = Cpu::new(Memory::new(256));
+        cpu.set_arguments(&["ab", "c"]).unwrap();
+
+        assert_eq!(cpu.peek_register(Register::StackPointer), 0x00ee);
+        assert_eq!(cpu.peek(0x00f0), 2, "argc");
+        assert_eq!(cpu.peek(0x00f2), 0x00f4, "argv");
+        assert_eq!(
+            cpu.peek_memory(0x00f4, 6),
+            [0x00, 0xfa, 0x00, 0xfd, 0x00, 0x00]
+        );
+        assert_eq!(cpu.peek_memory(0x00fa, 5), b"ab\0c\0");
+
+        let mut cpu = Cpu::new(Memory::new(256));
+        assert!(matches!(
+            cpu.set_arguments(&[&"x".repeat(300)]),
+            Err(Fault::StackOverflow { .. })
+        ));
+    }
+
+    #[test]
+    fn switches_tasks_from_an_interrupt_handler() {
+        // ;; task a
+        //   int 0x0000
+        // ;; at 0x0050, task b
+        //   mov 0xbbbb, r1
+        // ;; at 0x0100, interrupt 0
+        //   sav [0x0200]
+        //   rst [0x0240]
+        //   rti
+        let mut memory = Memory::new(0x1100);
+        memory.set_byte(0x0000, Instruction::Int as u8);
+        memory.set_word(0x0001, 0x0000);
+        memory.set_byte(0x0050, Instruction::MovLitReg as u8);
+        memory.set_word(0x0051, 0xbbbb);
+        memory.set_byte(0x0053, Register::Register1 as u8);
+        memory.set_byte(0x0100, Instruction::SaveContext as u8);
+        memory.set_word(0x0101, 0x0200);
+        memory.set_byte(0x0103, Instruction::RestoreContext as u8);
+        memory.set_word(0x0104, 0x0240);
+        memory.set_byte(0x0106, Instruction::RetInt as u8);
+        memory.set_word(super::INTERRUPT_VECTOR_ADDRESS, 0x0100);
+
+        // Task b's stack at 0x07fe holds the frame an interrupt would have
+        // left: the flags, no arguments, r1 to r8, the return address and
+        // the size
+        memory.set_word(0x07ea, 0x0050);
+        memory.set_word(0x07e8, 24);
+        // Its context: acc, r1 to r8, sp, fp, im, flags and the frame size
+        let context = [0x0000, 0, 0, 0, 0, 0, 0, 0, 0, 0x07e6, 0x07e6, 0xffff, 0, 0];
+        for (index, word) in context.iter().enumerate() {
+            memory.set_word(0x0240 + 2 * index, *word);
+        }
+        let mut cpu = Cpu::new(memory);
+        cpu.set_register(Register::Register1, 0xaaaa);
+
+        cpu.step_n(4).unwrap();
+        assert_eq!(cpu.get_register(Register::InstructionPointer), 0x0050);
+        assert_eq!(cpu.get_register(Register::StackPointer), 0x07fe);
+        assert_eq!(cpu.get_register(Register::FramePointer), 0x07fe);
+        assert!(!cpu.is_in_interrupt_handler);
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(Register::Register1), 0xbbbb);
+
+        // Task a's context is saved, with its frame on its own stack
+        assert_eq!(cpu.peek(0x0202), 0xaaaa);
+        let frame_pointer = cpu.peek(0x0212);
+        assert_eq!(cpu.peek(frame_pointer as usize + 4), 0x0003);
+    }
+
+    #[test]
+    fn interrupts_keep_the_flags_between_a_cmp_and_its_branch() {
+        //   cmp r1, r2
+        //   jeq 0x0040
+        // ;; at 0x0100, interrupt 0
+        //   add r1, r3
+        //   rti
+        let mut memory = Memory::new(0x1100);
+        memory.set_byte(0x0000, Instruction::CmpRegReg as u8);
+        memory.set_byte(0x0001, Register::Register1 as u8);
+        memory.set_byte(0x0002, Register::Register2 as u8);
+        memory.set_byte(0x0003, Instruction::JmpIfEq as u8);
+        memory.set_word(0x0004, 0x0040);
+        memory.set_byte(0x0100, Instruction::AddRegReg as u8);
+        memory.set_byte(0x0101, Register::Register1 as u8);
+        memory.set_byte(0x0102, Register::Register3 as u8);
+        memory.set_byte(0x0103, Instruction::RetInt as u8);
+        memory.set_word(super::INTERRUPT_VECTOR_ADDRESS, 0x0100);
+        let mut cpu = Cpu::new(memory);
+        cpu.set_register(Register::Register1, 0x1234);
+        cpu.set_register(Register::Register2, 0x1234);
+        cpu.set_register(Register::Register3, 0x0001);
+
+        cpu.step().unwrap();
+        // The interrupt lands between the two
+        cpu.handle_interrupt(0).unwrap();
+        cpu.step().unwrap();
+        assert!(!cpu.flag(super::ZERO_FLAG), "The handler's add clears zero");
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(Register::InstructionPointer), 0x0003);
+        assert!(cpu.flag(super::ZERO_FLAG));
+        cpu.step().unwrap();
+        assert_eq!(cpu.get_register(Register::InstructionPointer), 0x0040);
+    }
+
     #[test]
     fn test_move_lit_to_reg() {
         let mut memory = Memory::new(32);
@@ -543,7 +3558,7 @@ mod tests {
         memory.set_byte(i, Register::Register1 as u8);
 
         let mut cpu = Cpu::new(memory);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_register_eq(&cpu, &Register::Register1, 0x1234, None);
     }
@@ -566,7 +3581,7 @@ mod tests {
         memory.set_byte(0x1001, 0x43);
 
         let mut cpu = Cpu::new(memory);
-        cpu.step();
+        cpu.step().unwrap();
 
         assert_register_eq(&cpu, &Register::Register2, 0x4243, None);
     }
@@ -596,7 +3611,7 @@ mod tests {
         memory.set_byte(i, 0x00);
 
         let mut cpu = Cpu::new(memory);
-        cpu.step_n(2);
+        cpu.step_n(2).unwrap();
 
         assert_eq!(cpu.peek_tape(0x1000), [0x12, 0x34, 0, 0, 0, 0, 0, 0]);
     }
@@ -634,7 +3649,7 @@ mod tests {
         memory.set_byte(i, Register::Register2 as u8);
 
         let mut cpu = Cpu::new(memory);
-        cpu.step_n(3);
+        cpu.step_n(3).unwrap();
 
         assert_register_eq(&cpu, &Register::Register1, 0x1234, None);
         assert_register_eq(&cpu, &Register::Register2, 0xabcd, None);
@@ -703,7 +3718,7 @@ mod tests {
         memory.set_byte(i, 0x00);
 
         let mut cpu = Cpu::new(memory);
-        cpu.step_n(15);
+        cpu.step_n(15).unwrap();
 
         assert_register_eq(&cpu, &Register::Accumulator, 0x0003, None);
         assert_eq!(cpu.peek(0x0100), 0x0003);
@@ -839,12 +3854,12 @@ mod tests {
         memory.set_byte(i, Instruction::Ret as u8);
 
         let mut cpu = Cpu::new(memory);
-        cpu.step_n(12);
+        cpu.step_n(12).unwrap();
 
         assert_register_eq(&cpu, &Register::Register1, 0x0708, None);
         assert_register_eq(&cpu, &Register::Register8, 0x090a, None);
 
-        cpu.step_n(5);
+        cpu.step_n(5).unwrap();
 
         assert_register_eq(&cpu, &Register::Register1, 0x1234, None);
         assert_register_eq(&cpu, &Register::Register4, 0x5678, None);
@@ -854,4 +3869,159 @@ mod tests {
             [0x12, 0x34, 0x44, 0x44, 0x33, 0x33, 0x22, 0x22, 0x11, 0x11]
         );
     }
+
+    /// Executes `add first, second` with the registers holding the given values
+    fn add(first: (Register, u16), second: (Register, u16)) -> Cpu {
+        reg_reg(Instruction::AddRegReg, first, second)
+    }
+
+    /// Executes `instruction first, second` with the registers holding the
+    /// given values
+    fn reg_reg(instruction: Instruction, first: (Register, u16), second: (Register, u16)) -> Cpu {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, instruction as u8);
+        memory.set_byte(1, first.0 as u8);
+        memory.set_byte(2, second.0 as u8);
+
+        let mut cpu = Cpu::new(memory);
+        cpu.set_register(first.0, first.1);
+        cpu.set_register(second.0, second.1);
+        cpu.step().unwrap();
+        cpu
+    }
+
+    #[test]
+    fn add_wraps_around() {
+        for (a, b, sum) in [
+            (0xffff, 0x0001, 0x0000),
+            (0x0001, 0xffff, 0x0000),
+            (0x8000, 0x8000, 0x0000),
+            (0xffff, 0xffff, 0xfffe),
+            (0x7fff, 0x0001, 0x8000),
+        ] {
+            let (x, y) = (Register::Register1, Register::Register2);
+            let cpu = add((x, a), (y, b));
+            assert_eq!(
+                cpu.get_register(Register::Accumulator),
+                sum,
+                "{:#06x} + {:#06x}",
+                a,
+                b
+            );
+
+            let mut memory = Memory::new(256);
+            memory.set_byte(0, Instruction::AddRegReg as u8);
+            memory.set_byte(1, x as u8);
+            memory.set_byte(2, y as u8);
+            let mut cpu = Cpu::new(memory);
+            cpu.set_register(x, a);
+            cpu.set_register(y, b);
+            cpu.run(1);
+            assert_eq!(cpu.get_register(Register::Accumulator), sum);
+        }
+    }
+
+    fn distinct_registers() -> impl Strategy<Value = (Register, Register)> {
+        let registers = GENERAL_PURPOSE_REGISTERS[..DEFAULT_GENERAL_PURPOSE_REGISTERS].to_vec();
+        (select(registers.clone()), select(registers)).prop_filter("Same register", |(a, b)| a != b)
+    }
+
+    proptest! {
+        #[test]
+        fn add_is_commutative(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let forward = add((x, a), (y, b));
+            let backward = add((x, b), (y, a));
+
+            prop_assert_eq!(forward.get_register(Register::Accumulator), a.wrapping_add(b));
+            prop_assert_eq!(backward.get_register(Register::Accumulator), a.wrapping_add(b));
+        }
+
+        #[test]
+        fn add_has_zero_as_identity(a in any::<u16>(), (x, y) in distinct_registers()) {
+            let cpu = add((x, a), (y, 0));
+            prop_assert_eq!(cpu.get_register(Register::Accumulator), a);
+        }
+
+        #[test]
+        fn add_only_writes_the_accumulator(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let cpu = add((x, a), (y, b));
+
+            prop_assert_eq!(cpu.get_register(x), a);
+            prop_assert_eq!(cpu.get_register(y), b);
+            for register in &GENERAL_PURPOSE_REGISTERS {
+                if *register != x && *register != y {
+                    prop_assert_eq!(cpu.get_register(*register), 0);
+                }
+            }
+        }
+
+        #[test]
+        fn add_is_associative(a in any::<u16>(), b in any::<u16>(), c in any::<u16>()) {
+            let (x, y) = (Register::Register1, Register::Register2);
+            let left = add((x, add((x, a), (y, b)).get_register(Register::Accumulator)), (y, c));
+            let right = add((x, a), (y, add((x, b), (y, c)).get_register(Register::Accumulator)));
+
+            prop_assert_eq!(
+                left.get_register(Register::Accumulator),
+                right.get_register(Register::Accumulator)
+            );
+        }
+
+        #[test]
+        fn sub_undoes_add(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let sum = add((x, a), (y, b)).get_register(Register::Accumulator);
+            let cpu = reg_reg(Instruction::SubRegReg, (x, sum), (y, b));
+            prop_assert_eq!(cpu.get_register(Register::Accumulator), a);
+        }
+
+        #[test]
+        fn add_sets_the_flags(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let cpu = add((x, a), (y, b));
+            let sum = a.wrapping_add(b);
+
+            prop_assert_eq!(cpu.flag(ZERO_FLAG), sum == 0);
+            prop_assert_eq!(cpu.flag(CARRY_FLAG), a as u32 + b as u32 > 0xffff);
+            prop_assert_eq!(cpu.flag(SIGN_FLAG), (sum as i16) < 0);
+            prop_assert_eq!(cpu.flag(OVERFLOW_FLAG), (a as i16).checked_add(b as i16).is_none());
+        }
+
+        #[test]
+        fn sub_sets_the_flags(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let cpu = reg_reg(Instruction::SubRegReg, (x, a), (y, b));
+            let difference = a.wrapping_sub(b);
+
+            prop_assert_eq!(cpu.get_register(Register::Accumulator), difference);
+            prop_assert_eq!(cpu.flag(ZERO_FLAG), a == b);
+            prop_assert_eq!(cpu.flag(CARRY_FLAG), a < b, "Borrows");
+            prop_assert_eq!(cpu.flag(SIGN_FLAG), (difference as i16) < 0);
+            prop_assert_eq!(cpu.flag(OVERFLOW_FLAG), (a as i16).checked_sub(b as i16).is_none());
+        }
+
+        #[test]
+        fn cmp_is_sub_without_the_result(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let compared = reg_reg(Instruction::CmpRegReg, (x, a), (y, b));
+            let subtracted = reg_reg(Instruction::SubRegReg, (x, a), (y, b));
+
+            prop_assert_eq!(
+                compared.get_register(Register::Flags),
+                subtracted.get_register(Register::Flags)
+            );
+            prop_assert_eq!(compared.get_register(Register::Accumulator), 0);
+            prop_assert_eq!(compared.get_register(x), a);
+            prop_assert_eq!(compared.get_register(y), b);
+        }
+
+        #[test]
+        fn cmp_orders_like_the_branches(a in any::<u16>(), b in any::<u16>(), (x, y) in distinct_registers()) {
+            let cpu = reg_reg(Instruction::CmpRegReg, (x, a), (y, b));
+
+            // Unsigned below is the borrow, signed less is sign differing
+            // from overflow
+            prop_assert_eq!(cpu.flag(CARRY_FLAG), a < b);
+            prop_assert_eq!(
+                cpu.flag(SIGN_FLAG) != cpu.flag(OVERFLOW_FLAG),
+                (a as i16) < (b as i16)
+            );
+        }
+    }
 }
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Where a piece of code came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+}
+
+impl Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A named range of code, like a subroutine, from `start` up to `end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// A named piece of data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataLabel {
+    pub name: String,
+    pub address: u16,
+    /// In bytes
+    pub size: u16,
+}
+
+/// What an assembler knows about the program it wrote, kept next to the
+/// image so the debugger can talk about source instead of addresses. The
+/// text form has one directive per line, addresses in hex:
+///
+/// ```text
+/// file <index> <path>
+/// line <address> <file index> <line>
+/// scope <start> <end> <name>
+/// data <address> <size> <name>
+/// ```
+///
+/// `line` marks where the code of a source line starts. `#` starts a
+/// comment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    files: Vec<String>,
+    /// Source file index and line, by the address their code starts at
+    lines: BTreeMap<u16, (usize, u32)>,
+    scopes: Vec<Scope>,
+    data: Vec<DataLabel>,
+}
+
+impl DebugInfo {
+    /// Registers a source file, returning the index lines refer to it by
+    pub fn add_file(&mut self, path: &str) -> usize {
+        match self.files.iter().position(|file| file == path) {
+            Some(index) => index,
+            None => {
+                self.files.push(path.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Notes that the code of `line` in file `file` starts at `address`
+    pub fn add_line(&mut self, address: u16, file: usize, line: u32) {
+        self.lines.insert(address, (file, line));
+    }
+
+    pub fn add_scope(&mut self, name: &str, start: u16, end: u16) {
+        self.scopes.push(Scope {
+            name: name.to_string(),
+            start,
+            end,
+        });
+    }
+
+    pub fn add_data(&mut self, name: &str, address: u16, size: u16) {
+        self.data.push(DataLabel {
+            name: name.to_string(),
+            address,
+            size,
+        });
+    }
+
+    /// The source line whose code `address` is part of. Data has none.
+    pub fn location(&self, address: u16) -> Option<SourceLocation<'_>> {
+        if self.data_label(address).is_some() {
+            return None;
+        }
+        let (_, &(file, line)) = self.lines.range(..=address).next_back()?;
+        Some(SourceLocation {
+            file: &self.files[file],
+            line,
+        })
+    }
+
+    /// Whether the code of a source line starts at `address`
+    pub fn is_line_start(&self, address: u16) -> bool {
+        self.lines.contains_key(&address)
+    }
+
+    /// Where the code of a source line starts, for setting breakpoints on
+    /// it. `file` may be just the end of the path.
+    pub fn address_of(&self, file: &str, line: u32) -> Option<u16> {
+        self.lines
+            .iter()
+            .find(|(_, &(index, at))| at == line && self.files[index].ends_with(file))
+            .map(|(&address, _)| address)
+    }
+
+    /// The innermost scope around `address`
+    pub fn scope(&self, address: u16) -> Option<&Scope> {
+        self.scopes
+            .iter()
+            .filter(|scope| scope.start <= address && address < scope.end)
+            .min_by_key(|scope| scope.end - scope.start)
+    }
+
+    /// Where the code at `address` ended up in a rebuilt program described
+    /// by `rebuilt`: the same offset into the scope of the same name, or
+    /// failing that, for the start of a source line, the start of the same
+    /// line
+    pub fn relocate(&self, address: u16, rebuilt: &DebugInfo) -> Option<u16> {
+        let moved = self.scope(address).and_then(|scope| {
+            let offset = address - scope.start;
+            rebuilt
+                .scopes
+                .iter()
+                .find(|other| other.name == scope.name && offset < other.end - other.start)
+                .map(|other| other.start + offset)
+        });
+        moved.or_else(|| {
+            if !self.is_line_start(address) {
+                return None;
+            }
+            let location = self.location(address)?;
+            rebuilt.address_of(location.file, location.line)
+        })
+    }
+
+    pub fn scope_named(&self, name: &str) -> Option<&Scope> {
+        self.scopes.iter().find(|scope| scope.name == name)
+    }
+
+    /// The data label `address` falls in
+    pub fn data_label(&self, address: u16) -> Option<&DataLabel> {
+        self.data
+            .iter()
+            .find(|data| data.address <= address && address - data.address < data.size)
+    }
+
+    /// `address` as a person would like to read it, like
+    /// `my_subroutine+0x4 (main.asm:12)`
+    pub fn symbolize(&self, address: u16) -> String {
+        let name = match (self.scope(address), self.data_label(address)) {
+            (Some(scope), _) => offset_from(&scope.name, address - scope.start),
+            (None, Some(data)) => offset_from(&data.name, address - data.address),
+            (None, None) => format!("{:#06x}", address),
+        };
+        match self.location(address) {
+            Some(location) => format!("{} ({})", name, location),
+            None => name,
+        }
+    }
+}
+
+fn offset_from(name: &str, offset: u16) -> String {
+    match offset {
+        0 => name.to_string(),
+        offset => format!("{}+{:#x}", name, offset),
+    }
+}
+
+impl Display for DebugInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, file) in self.files.iter().enumerate() {
+            writeln!(f, "file {} {}", index, file)?;
+        }
+        for (address, (file, line)) in &self.lines {
+            writeln!(f, "line {:04x} {} {}", address, file, line)?;
+        }
+        for scope in &self.scopes {
+            writeln!(
+                f,
+                "scope {:04x} {:04x} {}",
+                scope.start, scope.end, scope.name
+            )?;
+        }
+        for data in &self.data {
+            writeln!(f, "data {:04x} {:x} {}", data.address, data.size, data.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DebugInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut info = DebugInfo::default();
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let mut field = || {
+                fields
+                    .next()
+                    .ok_or(format!("Line {}: missing field", line_number))
+            };
+            let hex = |field: &str| {
+                u16::from_str_radix(field.trim_start_matches("0x"), 16)
+                    .map_err(|e| format!("Line {}: {}", line_number, e))
+            };
+            let decimal = |field: &str| {
+                field
+                    .parse::<u32>()
+                    .map_err(|e| format!("Line {}: {}", line_number, e))
+            };
+            let Ok(directive) = field() else {
+                continue;
+            };
+            match directive {
+                "file" => {
+                    let index = decimal(field()?)? as usize;
+                    if index != info.files.len() {
+                        return Err(format!("Line {}: files out of order", line_number));
+                    }
+                    info.files.push(field()?.to_string());
+                }
+                "line" => {
+                    let address = hex(field()?)?;
+                    let file = decimal(field()?)? as usize;
+                    if file >= info.files.len() {
+                        return Err(format!("Line {}: unknown file {}", line_number, file));
+                    }
+                    info.add_line(address, file, decimal(field()?)?);
+                }
+                "scope" => {
+                    let start = hex(field()?)?;
+                    let end = hex(field()?)?;
+                    info.add_scope(field()?, start, end);
+                }
+                "data" => {
+                    let address = hex(field()?)?;
+                    let size = hex(field()?)?;
+                    info.add_data(field()?, address, size);
+                }
+                directive => {
+                    return Err(format!(
+                        "Line {}: unknown directive {}",
+                        line_number, directive
+                    ))
+                }
+            }
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugInfo, SourceLocation};
+
+    /// What an assembler would write for the standard workload
+    const WORKLOAD: &str = "\
+file 0 examples/workload.asm
+line 0000 0 3   # mov 0x0000, r1
+line 0004 0 5   # loop: psh r1
+line 0006 0 6
+line 0009 0 7   # cal add_one
+line 000c 0 8
+line 0100 0 20  # add_one: mov ...
+scope 0000 0028 main
+scope 0004 0028 loop
+scope 0100 0108 add_one
+data 8000 2 counter
+";
+
+    #[test]
+    fn maps_addresses_to_source() {
+        let info: DebugInfo = WORKLOAD.parse().unwrap();
+        let file = "examples/workload.asm";
+        assert_eq!(
+            info.location(0x0007),
+            Some(SourceLocation { file, line: 6 })
+        );
+        assert_eq!(
+            info.location(0x0100),
+            Some(SourceLocation { file, line: 20 })
+        );
+        assert_eq!(info.address_of("workload.asm", 7), Some(0x0009));
+        assert_eq!(info.address_of("workload.asm", 4), None);
+
+        assert_eq!(info.scope(0x0006).unwrap().name, "loop", "Innermost");
+        assert_eq!(info.scope(0x0002).unwrap().name, "main");
+        assert_eq!(info.data_label(0x8001).unwrap().name, "counter");
+        assert_eq!(info.data_label(0x8002), None);
+
+        assert_eq!(info.symbolize(0x0009), "loop+0x5 (examples/workload.asm:7)");
+        assert_eq!(info.symbolize(0x8001), "counter+0x1");
+    }
+
+    #[test]
+    fn relocates_into_rebuilt_programs() {
+        let info: DebugInfo = WORKLOAD.parse().unwrap();
+        // Four more bytes before the loop, and add_one renamed
+        let rebuilt: DebugInfo = "\
+file 0 examples/workload.asm
+line 0000 0 3
+line 0008 0 5
+line 0120 0 20
+scope 0000 002c main
+scope 0008 002c loop
+scope 0120 0128 increment
+"
+        .parse()
+        .unwrap();
+        assert_eq!(info.relocate(0x0009, &rebuilt), Some(0x000d), "By scope");
+        assert_eq!(info.relocate(0x0100, &rebuilt), Some(0x0120), "By line");
+        assert_eq!(info.relocate(0x0104, &rebuilt), None);
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let info: DebugInfo = WORKLOAD.parse().unwrap();
+        let again: DebugInfo = info.to_string().parse().unwrap();
+        assert_eq!(again, info);
+    }
+
+    #[test]
+    fn rejects_lines_of_unknown_files() {
+        assert_eq!(
+            "line 0000 0 1".parse::<DebugInfo>(),
+            Err("Line 1: unknown file 0".to_string())
+        );
+    }
+}
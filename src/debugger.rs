@@ -0,0 +1,469 @@
+use crate::cpu::{Cpu, Fault, Register, MAX_INSTRUCTION_LENGTH};
+use crate::debug_info::DebugInfo;
+use crate::disassembler::{disassemble, disassemble_one, Disassembly};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Every register, one per line
+pub fn register_pane(cpu: &Cpu) -> String {
+    cpu.registers()
+        .map(|(_, name, value)| format!("0x{:04x}  :: {}\n", value, name))
+        .collect()
+}
+
+/// The bytes at the instruction pointer and the instruction they decode to
+pub fn tape_pane(cpu: &Cpu) -> String {
+    let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
+    let tape = cpu.peek_tape(instruction_pointer as usize);
+    let instruction = disassemble_one(&tape, instruction_pointer as usize);
+    format!(
+        "Tape 0x{:04x} :: {} ::: {}\n",
+        instruction_pointer,
+        hex_bytes(&tape),
+        instruction.text
+    )
+}
+
+/// Everything from the stack pointer to the end of memory
+pub fn stack_pane(cpu: &Cpu) -> String {
+    format!(
+        "Stack 0x{:04x} :: {}\n",
+        cpu.peek_register(Register::StackPointer),
+        hex_bytes(&cpu.peek_stack())
+    )
+}
+
+/// The counters since `Cpu::enable_metrics`, interrupt latencies included
+#[cfg(feature = "instrument")]
+pub fn metrics_pane(cpu: &Cpu) -> String {
+    cpu.metrics().to_string()
+}
+
+/// The instruction about to execute followed by the registers it sees
+pub fn trace_line(cpu: &Cpu) -> String {
+    let instruction_pointer = cpu.peek_register(Register::InstructionPointer) as usize;
+    let instruction = disassemble_one(&cpu.peek_tape(instruction_pointer), instruction_pointer);
+    let registers: Vec<String> = cpu
+        .registers()
+        .filter(|(register, _, _)| *register != Register::InstructionPointer)
+        .map(|(_, name, value)| format!("{}={:04x}", name, value))
+        .collect();
+    format!("{:<48}{}", instruction.to_string(), registers.join(" "))
+}
+
+/// `trace_line`, followed by the symbol and source line of the instruction
+pub fn source_trace_line(cpu: &Cpu, info: &DebugInfo) -> String {
+    let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
+    format!(
+        "{}  ; {}",
+        trace_line(cpu),
+        info.symbolize(instruction_pointer)
+    )
+}
+
+/// Return addresses of the calls the CPU is in, innermost first
+pub fn backtrace(cpu: &Cpu) -> Vec<u16> {
+    let stack_top = cpu.stack_top as usize;
+    let mut frame = cpu.peek_register(Register::FramePointer) as usize;
+    let mut addresses = Vec::new();
+    // Below the frame pointer are the saved frame size, then the return
+    // address. A corrupted frame size could send this anywhere, so it only
+    // ever moves up the stack.
+    while frame + 4 <= stack_top {
+        let size = cpu.peek(frame + 2) as usize;
+        addresses.push(cpu.peek(frame + 4));
+        if size == 0 {
+            break;
+        }
+        frame += size;
+    }
+    addresses
+}
+
+/// A fault, with where the instruction that raised it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultAt {
+    pub fault: Fault,
+    pub address: u16,
+}
+
+impl Display for FaultAt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fault.fmt(f)
+    }
+}
+
+/// `Cpu::step`, remembering where a faulting instruction started
+pub fn step(cpu: &mut Cpu) -> Result<(), FaultAt> {
+    let address = cpu.peek_register(Register::InstructionPointer);
+    cpu.step().map_err(|fault| FaultAt { fault, address })
+}
+
+/// Puts the instruction pointer back at the instruction that raised
+/// `fault`, so a debugger suspends on it rather than past it. Anything else
+/// the instruction did before faulting stays done.
+pub fn rewind(cpu: &mut Cpu, fault: FaultAt) {
+    cpu.set_register(Register::InstructionPointer, fault.address);
+}
+
+/// Steps until a fault, until the CPU halts or until `stop` is set, like
+/// by a Ctrl-C handler, and clears it. Returns how many instructions ran.
+pub fn run_until_stopped(cpu: &mut Cpu, stop: &AtomicBool) -> Result<u64, FaultAt> {
+    let mut executed = 0;
+    while !cpu.is_halted() && !stop.swap(false, Ordering::Relaxed) {
+        step(cpu)?;
+        executed += 1;
+    }
+    Ok(executed)
+}
+
+/// What went wrong, where in the source and how the program got there
+pub fn fault_report(cpu: &Cpu, fault: FaultAt, info: &DebugInfo) -> String {
+    let mut report = format!("{}\n    at {}\n", fault, info.symbolize(fault.address));
+    for return_address in backtrace(cpu) {
+        // The byte before the return address is still part of the call
+        let call = return_address.wrapping_sub(1);
+        report += &format!("    called from {}\n", info.symbolize(call));
+    }
+    report
+}
+
+/// Instructions `crash_report` shows before and after the faulting one
+const CRASH_CONTEXT: usize = 3;
+
+/// Everything needed to triage a fault in one place: the cause, the code
+/// around the faulting instruction, the registers, marking the ones that
+/// differ from `previous`, and the innermost stack frame taken apart.
+/// `previous` are register values from a little earlier, like from before
+/// the last few instructions.
+pub fn crash_report(cpu: &Cpu, fault: FaultAt, previous: &[(Register, u16)]) -> String {
+    let mut report = format!("Crash: {}\n\nCode:\n", fault);
+    for line in code_around(cpu, fault.address) {
+        let marker = if line.address == fault.address as usize {
+            "=>"
+        } else {
+            "  "
+        };
+        report += &format!("{} {}\n", marker, line);
+    }
+
+    report += "\nRegisters, * changed:\n";
+    for (register, name, value) in cpu.registers() {
+        let changed = previous
+            .iter()
+            .any(|(before, old)| *before == register && *old != value);
+        let marker = if changed { "*" } else { " " };
+        report += &format!("{} {:<4}0x{:04x}\n", marker, name, value);
+    }
+
+    report += "\n";
+    report += &frame_pane(cpu);
+    report
+}
+
+/// The instructions around `address`. What comes before an instruction is
+/// ambiguous, so this takes the longest run of instructions that decodes
+/// straight into it.
+fn code_around(cpu: &Cpu, address: u16) -> Vec<Disassembly> {
+    let address = address as usize;
+    let code_length = cpu.code_length();
+    let before = (1..=CRASH_CONTEXT * MAX_INSTRUCTION_LENGTH)
+        .rev()
+        .filter(|distance| *distance <= address)
+        .map(|distance| {
+            let start = address - distance;
+            disassemble(&cpu.peek_code(start, distance), start)
+        })
+        .find(|lines| {
+            let last = lines.last().unwrap();
+            last.address + last.bytes.len() == address && !lines.iter().any(is_data)
+        })
+        .unwrap_or_default();
+
+    let skip = before.len().saturating_sub(CRASH_CONTEXT);
+    let mut lines: Vec<Disassembly> = before.into_iter().skip(skip).collect();
+    let mut next = address;
+    for _ in 0..=CRASH_CONTEXT {
+        if next >= code_length {
+            break;
+        }
+        let line = disassemble_one(&cpu.peek_tape(next), next);
+        next += line.bytes.len().max(1);
+        lines.push(line);
+    }
+    lines
+}
+
+fn is_data(line: &Disassembly) -> bool {
+    line.text.starts_with("db ")
+}
+
+/// The words pushed since the innermost call, then what the call saved and
+/// its arguments. Without a call, just the words on the stack.
+pub fn frame_pane(cpu: &Cpu) -> String {
+    let stack_top = cpu.stack_top as usize;
+    let stack_pointer = cpu.peek_register(Register::StackPointer) as usize;
+    let frame = cpu.peek_register(Register::FramePointer) as usize;
+    let words = |from: usize, to: usize| -> String {
+        (from..=to)
+            .step_by(2)
+            .map(|address| format!("0x{:04x}", cpu.peek(address)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    if frame + 4 > stack_top {
+        return match stack_pointer + 2 > stack_top {
+            true => "Stack: empty\n".to_string(),
+            false => format!("Stack: {}\n", words(stack_pointer + 2, stack_top)),
+        };
+    }
+    let mut pane = format!("Frame at 0x{:04x}:\n", frame);
+    if stack_pointer < frame {
+        pane += &format!("  pushed      {}\n", words(stack_pointer + 2, frame));
+    }
+    pane += &format!("  return to   0x{:04x}\n", cpu.peek(frame + 4));
+    let mut address = frame + 6;
+    for register in cpu.general_purpose_registers().iter().rev() {
+        pane += &format!(
+            "  saved {:<6}0x{:04x}\n",
+            register.name(),
+            cpu.peek(address)
+        );
+        address += 2;
+    }
+    let arguments = cpu.peek(address) as usize;
+    let last = address + 2 * arguments;
+    if last <= stack_top && arguments > 0 {
+        pane += &format!("  arguments   {}\n", words(address + 2, last));
+    }
+    pane
+}
+
+/// Lines of `source`, the text of the file the instruction pointer is in,
+/// around the one being executed, which is marked with `=>`
+pub fn source_pane(cpu: &Cpu, info: &DebugInfo, source: &str) -> String {
+    let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
+    let Some(location) = info.location(instruction_pointer) else {
+        return format!("No source for 0x{:04x}\n", instruction_pointer);
+    };
+    let current = location.line as usize;
+    let mut pane = format!("{}\n", location);
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        if number + SOURCE_CONTEXT < current || number > current + SOURCE_CONTEXT {
+            continue;
+        }
+        let marker = if number == current { "=>" } else { "  " };
+        pane += &format!("{} {:>4}  {}\n", marker, number, text);
+    }
+    pane
+}
+
+/// Lines `source_pane` shows on either side of the current one
+const SOURCE_CONTEXT: usize = 3;
+
+/// Steps until the instruction pointer reaches the start of a source line,
+/// going into calls. Gives up after `fuel` instructions.
+pub fn step_line(cpu: &mut Cpu, info: &DebugInfo, fuel: usize) -> Result<(), FaultAt> {
+    step_until_line(cpu, info, fuel, |_| true)
+}
+
+/// Like `step_line`, but runs calls to the end
+pub fn next_line(cpu: &mut Cpu, info: &DebugInfo, fuel: usize) -> Result<(), FaultAt> {
+    // The stack grows down, so callees have lower frame pointers
+    let frame = cpu.peek_register(Register::FramePointer);
+    step_until_line(cpu, info, fuel, |cpu| {
+        cpu.peek_register(Register::FramePointer) >= frame
+    })
+}
+
+fn step_until_line(
+    cpu: &mut Cpu,
+    info: &DebugInfo,
+    fuel: usize,
+    in_frame: impl Fn(&Cpu) -> bool,
+) -> Result<(), FaultAt> {
+    for _ in 0..fuel {
+        step(cpu)?;
+        let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
+        if info.is_line_start(instruction_pointer) && in_frame(cpu) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("0x{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        backtrace, crash_report, fault_report, next_line, rewind, run_until_stopped, source_pane,
+        step, step_line, FaultAt,
+    };
+    use crate::bench::standard_workload;
+    use crate::cpu::{Cpu, Fault, Instruction, Register};
+    use crate::debug_info::DebugInfo;
+    use crate::memory::Memory;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn runs_until_stopped() {
+        let mut cpu = Cpu::new(standard_workload());
+        let stop = AtomicBool::new(true);
+        assert_eq!(run_until_stopped(&mut cpu, &stop), Ok(0));
+        assert!(!stop.load(Ordering::Relaxed), "Cleared for the next run");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopper = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                stop.store(true, Ordering::Relaxed);
+            })
+        };
+        let executed = run_until_stopped(&mut cpu, &stop).unwrap();
+        stopper.join().unwrap();
+        assert!(executed > 0);
+        assert_eq!(cpu.instruction_count(), executed);
+    }
+
+    #[test]
+    fn runs_until_halted() {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x10, Instruction::Halt as u8);
+        let mut cpu = Cpu::new(memory);
+        let stop = AtomicBool::new(false);
+        assert_eq!(run_until_stopped(&mut cpu, &stop), Ok(0x11));
+        assert!(cpu.is_halted());
+    }
+
+    fn workload_info() -> DebugInfo {
+        let mut info = DebugInfo::default();
+        let file = info.add_file("workload.asm");
+        for (address, line) in [
+            (0x0000, 1),
+            (0x0004, 2),
+            (0x0009, 3),
+            (0x000c, 4),
+            (0x0100, 10),
+        ] {
+            info.add_line(address, file, line);
+        }
+        info
+    }
+
+    fn ip(cpu: &Cpu) -> u16 {
+        cpu.peek_register(Register::InstructionPointer)
+    }
+
+    #[test]
+    fn steps_by_source_line() {
+        let info = workload_info();
+        let mut cpu = Cpu::new(standard_workload());
+        step_line(&mut cpu, &info, 100).unwrap();
+        assert_eq!(ip(&cpu), 0x0004);
+        step_line(&mut cpu, &info, 100).unwrap();
+        assert_eq!(ip(&cpu), 0x0009, "Both pushes of line 2");
+
+        let mut over = Cpu::new(standard_workload());
+        over.step_n(4).unwrap();
+        step_line(&mut cpu, &info, 100).unwrap();
+        assert_eq!(ip(&cpu), 0x0100, "Into the subroutine");
+        next_line(&mut over, &info, 100).unwrap();
+        assert_eq!(ip(&over), 0x000c, "Over the subroutine");
+    }
+
+    #[test]
+    fn shows_the_source_around_the_current_line() {
+        let info = workload_info();
+        let mut cpu = Cpu::new(standard_workload());
+        cpu.step().unwrap();
+        let source = "start:\nloop: psh r1\n  psh 1\n  cal add_one\n  mov acc, [counter]\n6\n7\n";
+        assert_eq!(
+            source_pane(&cpu, &info, source),
+            "workload.asm:2\n\
+             \x20     1  start:\n\
+             =>    2  loop: psh r1\n\
+             \x20     3    psh 1\n\
+             \x20     4    cal add_one\n\
+             \x20     5    mov acc, [counter]\n"
+        );
+    }
+
+    #[test]
+    fn reports_faults_with_the_calls_that_led_there() {
+        let mut info = workload_info();
+        info.add_scope("main", 0x0000, 0x0028);
+        info.add_scope("add_one", 0x0100, 0x0108);
+        let mut cpu = Cpu::new(standard_workload());
+        cpu.step_n(4).unwrap();
+        assert_eq!(ip(&cpu), 0x0100);
+        assert_eq!(backtrace(&cpu), [0x000c]);
+
+        let fault = FaultAt {
+            fault: Fault::IllegalOperand {
+                address: 0x0102,
+                value: 0xee,
+            },
+            address: 0x0100,
+        };
+        assert_eq!(
+            fault_report(&cpu, fault, &info),
+            "Illegal operand 0xee at address 0x0102\n\
+             \x20   at add_one (workload.asm:10)\n\
+             \x20   called from main+0xb (workload.asm:3)\n"
+        );
+    }
+
+    #[test]
+    fn rewinds_to_the_faulting_instruction() {
+        // nop; mov 0x1234, <no such register>
+        let mut memory = Memory::new(0x100);
+        memory.set_byte(1, Instruction::MovLitReg as u8);
+        memory.set_word(2, 0x1234);
+        memory.set_byte(4, 0xee);
+        let mut cpu = Cpu::new(memory);
+        step(&mut cpu).unwrap();
+
+        let fault = step(&mut cpu).unwrap_err();
+        assert_eq!(fault.address, 0x0001);
+        assert_ne!(ip(&cpu), 0x0001);
+        rewind(&mut cpu, fault);
+        assert_eq!(ip(&cpu), 0x0001);
+        assert_eq!(step(&mut cpu), Err(fault), "Faults again from there");
+    }
+
+    #[test]
+    fn assembles_a_crash_report() {
+        let mut cpu = Cpu::new(standard_workload());
+        cpu.step_n(3).unwrap();
+        let previous: Vec<_> = cpu.registers().map(|(r, _, value)| (r, value)).collect();
+        cpu.step().unwrap();
+
+        let fault = FaultAt {
+            fault: Fault::StackOverflow { address: 0x00ee },
+            address: 0x0100,
+        };
+        let report = crash_report(&cpu, fault, &previous);
+        assert!(report.starts_with("Crash: Stack overflow"), "{}", report);
+        assert!(report.contains("   0x00ff  00 "), "{}", report);
+        assert!(report.contains("=> 0x0100  10 00 03 07     mov 0x0003, r6\n"));
+        assert!(report.contains("   0x0107  60              ret\n"));
+        assert!(report.contains("* sp  0xffe6\n"), "{}", report);
+        assert!(report.contains("  r1  0x0000\n"), "{}", report);
+        assert!(report.contains("  return to   0x000c\n"));
+        assert!(report.contains("  saved r8    0x0000\n"));
+        assert!(report.ends_with("  arguments   0x0000\n"), "{}", report);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::mapper::Device;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// A byte arrives at an input port, like a key press or a byte on a
+    /// serial line. It stays readable until the next one arrives.
+    Input {
+        address: usize,
+        value: u8,
+    },
+    Interrupt(u16),
+}
+
+/// A device event, delivered once `instruction` instructions have executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledEvent {
+    pub instruction: u64,
+    pub event: DeviceEvent,
+}
+
+/// Everything the outside world does to a machine, fixed in advance, one
+/// event per line in its text form:
+///
+/// ```text
+/// <instruction> input <address> <value>
+/// <instruction> interrupt <value>
+/// ```
+///
+/// `#` starts a comment. Events are delivered in instruction order, events
+/// at the same instruction in the order they are listed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceSchedule {
+    events: Vec<ScheduledEvent>,
+}
+
+impl DeviceSchedule {
+    pub fn new() -> DeviceSchedule {
+        DeviceSchedule::default()
+    }
+
+    pub fn push(&mut self, instruction: u64, event: DeviceEvent) {
+        let at = self
+            .events
+            .partition_point(|scheduled| scheduled.instruction <= instruction);
+        self.events
+            .insert(at, ScheduledEvent { instruction, event });
+    }
+
+    pub fn events(&self) -> &[ScheduledEvent] {
+        &self.events
+    }
+}
+
+impl Display for DeviceSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for scheduled in &self.events {
+            match scheduled.event {
+                DeviceEvent::Input { address, value } => writeln!(
+                    f,
+                    "{} input {:#06x} {:#04x}",
+                    scheduled.instruction, address, value
+                )?,
+                DeviceEvent::Interrupt(value) => {
+                    writeln!(f, "{} interrupt {:#06x}", scheduled.instruction, value)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DeviceSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse_hex(field: Option<&str>, line: usize) -> Result<usize, String> {
+            let field = field.ok_or(format!("Line {}: missing field", line))?;
+            usize::from_str_radix(field.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("Line {}: {}", line, e))
+        }
+
+        let mut schedule = DeviceSchedule::new();
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(instruction) = fields.next() else {
+                continue;
+            };
+            let instruction = instruction
+                .parse()
+                .map_err(|e| format!("Line {}: {}", line_number, e))?;
+            let event = match fields.next() {
+                Some("input") => DeviceEvent::Input {
+                    address: parse_hex(fields.next(), line_number)?,
+                    value: parse_hex(fields.next(), line_number)? as u8,
+                },
+                Some("interrupt") => {
+                    DeviceEvent::Interrupt(parse_hex(fields.next(), line_number)? as u16)
+                }
+                Some(kind) => return Err(format!("Line {}: unknown event {}", line_number, kind)),
+                None => return Err(format!("Line {}: missing field", line_number)),
+            };
+            schedule.push(instruction, event);
+        }
+        Ok(schedule)
+    }
+}
+
+/// Plays a `DeviceSchedule` back as a block of input ports, and raises its
+/// interrupts. It keeps time with `tick`, so events only land at their
+/// instruction when devices are ticked every cycle; `CpuBuilder::schedule`
+/// sets that up. Reads don't change anything, so runs are reproducible
+/// however often the guest polls.
+pub struct ScheduledDevices {
+    pending: VecDeque<ScheduledEvent>,
+    ports: Vec<u8>,
+    now: u64,
+}
+
+impl ScheduledDevices {
+    /// `byte_length` bytes of input ports, all zero until their first input
+    pub fn new(schedule: &DeviceSchedule, byte_length: usize) -> ScheduledDevices {
+        let mut devices = ScheduledDevices {
+            pending: schedule.events().iter().copied().collect(),
+            ports: vec![0; byte_length],
+            now: 0,
+        };
+        devices.deliver(&mut Vec::new());
+        devices
+    }
+
+    /// Events still waiting for their instruction
+    pub fn remaining(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn deliver(&mut self, interrupts: &mut Vec<u16>) {
+        while let Some(scheduled) = self.pending.front() {
+            if scheduled.instruction > self.now {
+                break;
+            }
+            match scheduled.event {
+                DeviceEvent::Input { address, value } => {
+                    if let Some(port) = self.ports.get_mut(address) {
+                        *port = value;
+                    }
+                }
+                DeviceEvent::Interrupt(value) => interrupts.push(value),
+            }
+            self.pending.pop_front();
+        }
+    }
+}
+
+impl Device for ScheduledDevices {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.ports[address]
+    }
+
+    fn byte_length(&self) -> usize {
+        self.ports.len()
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        self.now += cycles;
+        self.deliver(interrupts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceEvent, DeviceSchedule, ScheduledDevices};
+    use crate::mapper::Device;
+
+    const SCHEDULE: &str = "\
+# Two keys, then the timer
+3 input 0x0000 0x41
+5 interrupt 0x0002
+5 input 0x0000 0x42
+";
+
+    #[test]
+    fn reads_what_it_writes() {
+        let schedule: DeviceSchedule = SCHEDULE.parse().unwrap();
+        assert_eq!(schedule.events().len(), 3);
+        assert_eq!(
+            schedule.events()[1].event,
+            DeviceEvent::Interrupt(2),
+            "Same instruction keeps the listed order"
+        );
+        assert_eq!(schedule.to_string().parse::<DeviceSchedule>(), Ok(schedule));
+        assert_eq!(
+            "1 output 0 0".parse::<DeviceSchedule>(),
+            Err("Line 1: unknown event output".to_string())
+        );
+    }
+
+    #[test]
+    fn delivers_events_at_their_instruction() {
+        let schedule: DeviceSchedule = SCHEDULE.parse().unwrap();
+        let mut devices = ScheduledDevices::new(&schedule, 2);
+        let mut interrupts = Vec::new();
+
+        devices.tick(2, &mut interrupts);
+        assert_eq!(devices.get_byte(0), 0);
+        devices.tick(1, &mut interrupts);
+        assert_eq!(devices.get_byte(0), 0x41);
+        assert_eq!(devices.get_byte(0), 0x41, "Reads don't consume");
+        assert_eq!(interrupts, []);
+
+        devices.tick(2, &mut interrupts);
+        assert_eq!(devices.get_byte(0), 0x42);
+        assert_eq!(interrupts, [2]);
+        assert_eq!(devices.remaining(), 0);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::cpu::{Cpu, Register};
+use crate::memory::Memory;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// State the reference VM reached after one step
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceStep {
+    pub registers: Vec<(Register, u16)>,
+    /// Bytes whose value changed, with their new value
+    pub writes: Vec<(usize, u8)>,
+}
+
+/// A run of the Low Level JavaScript VM, as written by
+/// `tools/record-trace.js`. The text form has one directive per line:
+///
+/// ```text
+/// memory <size>
+/// load <address> <bytes>...
+/// step <register>=<value>...
+/// write <address> <byte>
+/// ```
+///
+/// `write` lines belong to the `step` above them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub memory_size: usize,
+    pub image: Vec<(usize, Vec<u8>)>,
+    pub steps: Vec<TraceStep>,
+}
+
+impl FromStr for Trace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse_hex(field: Option<&str>, line: usize) -> Result<usize, String> {
+            let field = field.ok_or(format!("Line {}: missing field", line))?;
+            usize::from_str_radix(field.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("Line {}: {}", line, e))
+        }
+
+        let mut trace = Trace::default();
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                None => {}
+                Some("memory") => {
+                    trace.memory_size = fields
+                        .next()
+                        .ok_or(format!("Line {}: missing field", line_number))?
+                        .parse()
+                        .map_err(|e| format!("Line {}: {}", line_number, e))?;
+                }
+                Some("load") => {
+                    let address = parse_hex(fields.next(), line_number)?;
+                    let bytes = fields
+                        .map(|field| parse_hex(Some(field), line_number).map(|byte| byte as u8))
+                        .collect::<Result<_, _>>()?;
+                    trace.image.push((address, bytes));
+                }
+                Some("step") => {
+                    let mut step = TraceStep::default();
+                    for field in fields {
+                        let (name, value) = field
+                            .split_once('=')
+                            .ok_or(format!("Line {}: expected name=value", line_number))?;
+                        let register = name
+                            .parse()
+                            .map_err(|e| format!("Line {}: {}", line_number, e))?;
+                        let value = parse_hex(Some(value), line_number)?;
+                        step.registers.push((register, value as u16));
+                    }
+                    trace.steps.push(step);
+                }
+                Some("write") => {
+                    let address = parse_hex(fields.next(), line_number)?;
+                    let value = parse_hex(fields.next(), line_number)?;
+                    trace
+                        .steps
+                        .last_mut()
+                        .ok_or(format!("Line {}: write before the first step", line_number))?
+                        .writes
+                        .push((address, value as u8));
+                }
+                Some(directive) => {
+                    return Err(format!(
+                        "Line {}: unknown directive {}",
+                        line_number, directive
+                    ))
+                }
+            }
+        }
+        Ok(trace)
+    }
+}
+
+/// First point where this VM disagrees with the trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Zero based index of the step
+    pub step: usize,
+    pub message: String,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Step {}: {}", self.step, self.message)
+    }
+}
+
+impl Trace {
+    /// Loads the trace's image into a fresh CPU, steps it alongside the
+    /// trace and returns the number of steps that matched
+    pub fn compare(&self) -> Result<usize, Divergence> {
+        let mut memory = Memory::new(self.memory_size);
+        for (address, bytes) in &self.image {
+            for (i, byte) in bytes.iter().enumerate() {
+                memory.set_byte(address + i, *byte);
+            }
+        }
+        let mut cpu = Cpu::new(memory);
+
+        for (step, expected) in self.steps.iter().enumerate() {
+            let divergence = |message| Divergence { step, message };
+            let before = cpu.peek_memory(0, self.memory_size);
+            cpu.step()
+                .map_err(|fault| divergence(format!("faulted: {}", fault)))?;
+
+            for (register, value) in &expected.registers {
+                let actual = cpu.peek_register(*register);
+                if actual != *value {
+                    return Err(divergence(format!(
+                        "{} is {:#06x}, expected {:#06x}",
+                        register.name(),
+                        actual,
+                        value
+                    )));
+                }
+            }
+
+            let after = cpu.peek_memory(0, self.memory_size);
+            let writes: Vec<(usize, u8)> = (0..self.memory_size)
+                .filter(|&address| before[address] != after[address])
+                .map(|address| (address, after[address]))
+                .collect();
+            let mut expected_writes = expected.writes.clone();
+            expected_writes.sort();
+            if writes != expected_writes {
+                return Err(divergence(format!(
+                    "wrote {}, expected {}",
+                    format_writes(&writes),
+                    format_writes(&expected_writes)
+                )));
+            }
+        }
+        Ok(self.steps.len())
+    }
+}
+
+fn format_writes(writes: &[(usize, u8)]) -> String {
+    if writes.is_empty() {
+        return "nothing".to_string();
+    }
+    writes
+        .iter()
+        .map(|(address, value)| format!("{:#04x} to {:#06x}", value, address))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Divergence, Trace};
+
+    // mov 0x1234, r1
+    // mov r1, [0x0010]
+    const TRACE: &str = "
+        memory 32
+        load 0x0000 0x10 0x12 0x34 0x02 0x12 0x02 0x00 0x10
+        step ip=0x0004 r1=0x1234
+        step ip=0x0008 r1=0x1234
+        write 0x0010 0x12
+        write 0x0011 0x34
+    ";
+
+    #[test]
+    fn matching_trace_compares_every_step() {
+        let trace: Trace = TRACE.parse().unwrap();
+        assert_eq!(trace.compare(), Ok(2));
+    }
+
+    #[test]
+    fn reports_the_first_divergence() {
+        let trace: Trace = TRACE.replace("write 0x0011 0x34", "").parse().unwrap();
+        assert_eq!(
+            trace.compare(),
+            Err(Divergence {
+                step: 1,
+                message: "wrote 0x12 to 0x0010, 0x34 to 0x0011, expected 0x12 to 0x0010"
+                    .to_string()
+            })
+        );
+
+        let trace: Trace = TRACE.replace("r1=0x1234\n", "r1=0x4321\n").parse().unwrap();
+        assert_eq!(trace.compare().unwrap_err().step, 0);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::cpu::{opcode_info, Instruction, Operand, Register, MAX_INSTRUCTION_LENGTH};
+use crate::json;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// One instruction, or a byte that doesn't start one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub address: usize,
+    pub bytes: Vec<u8>,
+    /// The instruction in `Syntax::Native`
+    pub text: String,
+    /// `db` for bytes that don't start an instruction
+    pub mnemonic: &'static str,
+    /// In encoding order, or the bytes of a `db`
+    pub operands: Vec<DecodedOperand>,
+}
+
+/// An operand as it was encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedOperand {
+    Literal(u16),
+    /// A memory operand
+    Memory(u16),
+    /// Where a branch goes
+    Target(u16),
+    Register(Register),
+    /// A register holding a memory address
+    RegisterPointer(Register),
+    /// A memory operand at the address plus the value in the register
+    Indexed(u16, Register),
+    /// A memory operand at the frame pointer plus the offset
+    Frame(i16),
+    /// A register operand naming no register
+    IllegalRegister(u8),
+    /// A byte of data
+    Byte(u8),
+}
+
+/// How to write instructions out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Operands in encoding order, sources first: `mov 0x0001, r1`
+    #[default]
+    Native,
+    /// Destination first: `mov r1, 0x0001`
+    Intel,
+    /// Sources first, with `$` before literals and `%` before registers:
+    /// `mov $0x0001, %r1`
+    Att,
+}
+
+impl FromStr for Syntax {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Syntax, String> {
+        match name {
+            "native" => Ok(Syntax::Native),
+            "intel" => Ok(Syntax::Intel),
+            "att" => Ok(Syntax::Att),
+            _ => Err(format!("Unknown syntax: {}", name)),
+        }
+    }
+}
+
+impl DecodedOperand {
+    fn format(&self, syntax: Syntax) -> String {
+        match (self, syntax) {
+            (DecodedOperand::Literal(value), Syntax::Att) => format!("${:#06x}", value),
+            (DecodedOperand::Literal(value) | DecodedOperand::Target(value), _) => {
+                format!("{:#06x}", value)
+            }
+            // AT&T memory operands are bare addresses
+            (DecodedOperand::Memory(address), Syntax::Att) => format!("{:#06x}", address),
+            (DecodedOperand::Memory(address), _) => format!("[{:#06x}]", address),
+            (DecodedOperand::Register(register), Syntax::Att) => format!("%{}", register.name()),
+            (DecodedOperand::Register(register), _) => register.name().to_string(),
+            (DecodedOperand::RegisterPointer(register), Syntax::Att) => {
+                format!("(%{})", register.name())
+            }
+            (DecodedOperand::RegisterPointer(register), _) => format!("[{}]", register.name()),
+            (DecodedOperand::Indexed(address, register), Syntax::Att) => {
+                format!("{:#06x}(%{})", address, register.name())
+            }
+            (DecodedOperand::Indexed(address, register), _) => {
+                format!("[{:#06x} + {}]", address, register.name())
+            }
+            (DecodedOperand::Frame(offset), Syntax::Att) => format!("{}(%fp)", offset),
+            (DecodedOperand::Frame(offset), _) if *offset < 0 => {
+                format!("[fp - {}]", offset.unsigned_abs())
+            }
+            (DecodedOperand::Frame(offset), _) => format!("[fp + {}]", offset),
+            (DecodedOperand::IllegalRegister(value), _) => format!("<{:#04x}>", value),
+            (DecodedOperand::Byte(byte), _) => format!("{:#04x}", byte),
+        }
+    }
+
+    fn to_json(self) -> String {
+        let (kind, value) = match self {
+            DecodedOperand::Literal(value) => ("literal", value.to_string()),
+            DecodedOperand::Memory(address) => ("memory", address.to_string()),
+            DecodedOperand::Target(address) => ("target", address.to_string()),
+            DecodedOperand::Register(register) => ("register", json::string(register.name())),
+            DecodedOperand::RegisterPointer(register) => {
+                ("register_pointer", json::string(register.name()))
+            }
+            DecodedOperand::Frame(offset) => ("frame", offset.to_string()),
+            DecodedOperand::Indexed(address, register) => (
+                "indexed",
+                format!(
+                    "{{\"address\": {}, \"register\": {}}}",
+                    address,
+                    json::string(register.name())
+                ),
+            ),
+            DecodedOperand::IllegalRegister(value) => ("illegal_register", value.to_string()),
+            DecodedOperand::Byte(byte) => ("byte", byte.to_string()),
+        };
+        format!("{{\"kind\": \"{}\", \"value\": {}}}", kind, value)
+    }
+}
+
+impl Disassembly {
+    /// The instruction in `syntax`, without the address and bytes
+    pub fn format(&self, syntax: Syntax) -> String {
+        if self.mnemonic == "db" {
+            let directive = match syntax {
+                Syntax::Att => ".byte",
+                _ => "db",
+            };
+            return self
+                .operands
+                .iter()
+                .map(|byte| format!("{} {}", directive, byte.format(syntax)))
+                .collect::<Vec<_>>()
+                .join("; ");
+        }
+
+        let mut operands: Vec<String> = self
+            .operands
+            .iter()
+            .map(|operand| operand.format(syntax))
+            .collect();
+        // Moves encode the destination last
+        let moves = opcode_info(self.bytes[0]).is_some_and(|info| {
+            matches!(
+                info.instruction,
+                Instruction::MovLitReg
+                    | Instruction::MovRegReg
+                    | Instruction::MovRegMem
+                    | Instruction::MovMemReg
+                    | Instruction::MovRegPtrReg
+                    | Instruction::MovRegRegPtr
+                    | Instruction::MovLitOffReg
+                    | Instruction::MovRegLitOff
+                    | Instruction::MovFrameReg
+                    | Instruction::MovRegFrame
+            )
+        });
+        if syntax == Syntax::Intel && moves {
+            operands.reverse();
+        }
+        if operands.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{} {}", self.mnemonic, operands.join(", "))
+        }
+    }
+
+    /// The instruction as a JSON object, for tools building on the
+    /// disassembler: its address, bytes, mnemonic and operands, each with
+    /// its kind and value
+    pub fn to_json(&self) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|o| o.to_json()).collect();
+        format!(
+            "{{\"address\": {}, \"bytes\": {}, \"mnemonic\": {}, \"operands\": [{}]}}",
+            self.address,
+            json::bytes(&self.bytes),
+            json::string(self.mnemonic),
+            operands.join(", ")
+        )
+    }
+
+    /// Like `Display`, in `syntax`
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "{:#06x}  {:<width$}  {}",
+            self.address,
+            bytes.join(" "),
+            self.format(syntax),
+            width = MAX_INSTRUCTION_LENGTH * 3 - 1
+        )
+    }
+}
+
+impl Display for Disassembly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.listing(Syntax::Native))
+    }
+}
+
+/// Decodes the instruction at the start of `code`, which was read from
+/// `address`. Unknown opcodes and cut off instructions come out as `db`.
+pub fn disassemble_one(code: &[u8], address: usize) -> Disassembly {
+    let decoded = |mnemonic: &'static str, length: usize, operands: Vec<DecodedOperand>| {
+        let mut line = Disassembly {
+            address,
+            bytes: code[..length].to_vec(),
+            text: String::new(),
+            mnemonic,
+            operands,
+        };
+        line.text = line.format(Syntax::Native);
+        line
+    };
+    let data = |length: usize| {
+        let bytes = code[..length]
+            .iter()
+            .map(|byte| DecodedOperand::Byte(*byte));
+        decoded("db", length, bytes.collect())
+    };
+
+    let Some(&opcode) = code.first() else {
+        return data(0);
+    };
+    let Some(info) = opcode_info(opcode) else {
+        return data(1);
+    };
+    if code.len() < info.length() {
+        return data(code.len());
+    }
+
+    let mut offset = 1;
+    let mut operands = Vec::new();
+    for operand in info.operands {
+        let word = || u16::from_be_bytes([code[offset], code[offset + 1]]);
+        let decoded_operand = match operand {
+            Operand::Literal => DecodedOperand::Literal(word()),
+            // Branch targets are plain addresses, the rest are memory operands
+            Operand::Address if info.instruction.may_branch() => DecodedOperand::Target(word()),
+            Operand::Address => DecodedOperand::Memory(word()),
+            Operand::FrameOffset => DecodedOperand::Frame(word() as i16),
+            Operand::Register => match Register::try_from(code[offset]) {
+                Ok(register) => DecodedOperand::Register(register),
+                Err(_) => DecodedOperand::IllegalRegister(code[offset]),
+            },
+            Operand::RegisterPointer => match Register::try_from(code[offset]) {
+                Ok(register) => DecodedOperand::RegisterPointer(register),
+                Err(_) => DecodedOperand::IllegalRegister(code[offset]),
+            },
+            // Folded into the memory operand before it
+            Operand::OffsetRegister => match (operands.pop(), Register::try_from(code[offset])) {
+                (Some(DecodedOperand::Memory(address)), Ok(register)) => {
+                    DecodedOperand::Indexed(address, register)
+                }
+                (previous, _) => {
+                    operands.extend(previous);
+                    DecodedOperand::IllegalRegister(code[offset])
+                }
+            },
+        };
+        operands.push(decoded_operand);
+        offset += operand.size();
+    }
+    decoded(info.mnemonic, offset, operands)
+}
+
+/// Decodes `code`, which was read from `start`, one instruction after another
+pub fn disassemble(code: &[u8], start: usize) -> Vec<Disassembly> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let end = code.len().min(offset + MAX_INSTRUCTION_LENGTH);
+        let line = disassemble_one(&code[offset..end], start + offset);
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// `lines` as a JSON array of `Disassembly::to_json` objects, one a line
+pub fn to_json(lines: &[Disassembly]) -> String {
+    let lines: Vec<String> = lines
+        .iter()
+        .map(|line| format!("  {}", line.to_json()))
+        .collect();
+    format!("[\n{}\n]", lines.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, to_json, Syntax};
+    use crate::cpu::{Instruction, Register};
+
+    #[test]
+    fn writes_every_syntax() {
+        let code = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x01,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x08,
+            0x00,
+            Instruction::MovRegPtrReg as u8,
+            Register::Register1 as u8,
+            Register::Register2 as u8,
+            Instruction::MovLitOffReg as u8,
+            0x08,
+            0x00,
+            Register::Register1 as u8,
+            Register::Register2 as u8,
+            Instruction::MovRegFrame as u8,
+            Register::Register1 as u8,
+            0xff,
+            0xfe,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x02,
+            0x00,
+            0x00,
+            0xee,
+        ];
+        let lines = disassemble(&code, 0x10);
+        let text = |syntax| {
+            lines
+                .iter()
+                .map(|line| line.format(syntax))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            text(Syntax::Native),
+            [
+                "mov 0x0001, r1",
+                "mov r1, [0x0800]",
+                "mov [r1], r2",
+                "mov [0x0800 + r1], r2",
+                "mov r1, [fp - 2]",
+                "jne 0x0002, 0x0000",
+                "db 0xee"
+            ]
+        );
+        assert_eq!(
+            text(Syntax::Intel),
+            [
+                "mov r1, 0x0001",
+                "mov [0x0800], r1",
+                "mov r2, [r1]",
+                "mov r2, [0x0800 + r1]",
+                "mov [fp - 2], r1",
+                "jne 0x0002, 0x0000",
+                "db 0xee"
+            ]
+        );
+        assert_eq!(
+            text(Syntax::Att),
+            [
+                "mov $0x0001, %r1",
+                "mov %r1, 0x0800",
+                "mov (%r1), %r2",
+                "mov 0x0800(%r1), %r2",
+                "mov %r1, -2(%fp)",
+                "jne $0x0002, 0x0000",
+                ".byte 0xee"
+            ]
+        );
+        assert_eq!(lines[0].text, lines[0].format(Syntax::Native));
+        assert_eq!("att".parse(), Ok(Syntax::Att));
+    }
+
+    #[test]
+    fn writes_json() {
+        let code = [Instruction::MovRegMem as u8, 0x02, 0x08, 0x00, 0xee];
+        assert_eq!(
+            to_json(&disassemble(&code, 0)),
+            "[\n  {\"address\": 0, \"bytes\": [18, 2, 8, 0], \"mnemonic\": \"mov\", \
+             \"operands\": [{\"kind\": \"register\", \"value\": \"r1\"}, \
+             {\"kind\": \"memory\", \"value\": 2048}]},\n  \
+             {\"address\": 4, \"bytes\": [238], \"mnemonic\": \"db\", \
+             \"operands\": [{\"kind\": \"byte\", \"value\": 238}]}\n]"
+        );
+    }
+}
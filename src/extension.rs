@@ -0,0 +1,128 @@
+use crate::cpu::{Cpu, Fault, Instruction};
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// Executes a custom instruction. The opcode has already been fetched, so the
+/// handler starts at the first operand byte and uses `Cpu::fetch`,
+/// `Cpu::fetch16` and `Cpu::fetch_register` to read the rest, passing on
+/// their faults.
+pub trait InstructionHandler: Send + Sync {
+    fn execute(&self, cpu: &mut Cpu) -> Result<(), Fault>;
+}
+
+impl<F> InstructionHandler for F
+where
+    F: Fn(&mut Cpu) -> Result<(), Fault> + Send + Sync,
+{
+    fn execute(&self, cpu: &mut Cpu) -> Result<(), Fault> {
+        self(cpu)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtensionError {
+    /// The opcode belongs to a built-in instruction
+    Reserved(u8),
+    /// Another handler is already registered for the opcode
+    Taken(u8),
+}
+
+impl Display for ExtensionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtensionError::Reserved(opcode) => {
+                write!(f, "Opcode {:#04x} is a built-in instruction", opcode)
+            }
+            ExtensionError::Taken(opcode) => {
+                write!(f, "Opcode {:#04x} already has a handler", opcode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtensionError {}
+
+impl Cpu {
+    /// Routes `opcode`, which must not be used by the built-in instruction
+    /// set, to `handler`.
+    pub fn register_instruction(
+        &mut self,
+        opcode: u8,
+        handler: impl InstructionHandler + 'static,
+    ) -> Result<(), ExtensionError> {
+        if opcode == Instruction::Noop as u8 || !matches!(opcode.into(), Instruction::Noop) {
+            return Err(ExtensionError::Reserved(opcode));
+        }
+        if self.custom_instructions.contains_key(&opcode) {
+            return Err(ExtensionError::Taken(opcode));
+        }
+        self.custom_instructions.insert(opcode, Arc::new(handler));
+        Ok(())
+    }
+
+    pub fn unregister_instruction(&mut self, opcode: u8) {
+        self.custom_instructions.remove(&opcode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExtensionError;
+    use crate::cpu::{Cpu, Fault, Instruction, Register};
+    use crate::memory::Memory;
+
+    const SWAP: u8 = 0xa0;
+
+    #[test]
+    fn executes_registered_instruction() {
+        let mut memory = Memory::new(32);
+
+        // mov 0x1234, r1
+        // swp r1 ;; swap the bytes of r1
+        let mut i = 0;
+        memory.set_byte(i, Instruction::MovLitReg as u8);
+        i += 1;
+        memory.set_byte(i, 0x12);
+        i += 1;
+        memory.set_byte(i, 0x34);
+        i += 1;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_byte(i, SWAP);
+        i += 1;
+        memory.set_byte(i, Register::Register1 as u8);
+
+        let mut cpu = Cpu::new(memory);
+        cpu.register_instruction(SWAP, |cpu: &mut Cpu| -> Result<(), Fault> {
+            let register = cpu.fetch_register()?;
+            let value = cpu.peek_register(register);
+            cpu.set_register(register, value.swap_bytes());
+            Ok(())
+        })
+        .unwrap();
+        cpu.step_n(2).unwrap();
+
+        assert_eq!(cpu.peek_register(Register::Register1), 0x3412);
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 6);
+    }
+
+    #[test]
+    fn refuses_built_in_and_taken_opcodes() {
+        let mut cpu = Cpu::new(Memory::new(32));
+        let noop = |_: &mut Cpu| -> Result<(), Fault> { Ok(()) };
+
+        assert_eq!(
+            cpu.register_instruction(Instruction::MovLitReg as u8, noop),
+            Err(ExtensionError::Reserved(0x10))
+        );
+        assert_eq!(
+            cpu.register_instruction(0x00, noop),
+            Err(ExtensionError::Reserved(0x00))
+        );
+        assert_eq!(cpu.register_instruction(SWAP, noop), Ok(()));
+        assert_eq!(
+            cpu.register_instruction(SWAP, noop),
+            Err(ExtensionError::Taken(SWAP))
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cpu::{Cpu, Register};
+use crate::debugger::{self, FaultAt};
+use std::fmt::Write;
+
+const LAMP_ON: char = '●';
+const LAMP_OFF: char = '○';
+const SWITCH_UP: char = '▲';
+const SWITCH_DOWN: char = '▼';
+
+/// An Altair style front panel: lamps for the address and data bus, the
+/// registers and the flags, and a row of toggle switches to examine and
+/// deposit memory with. While the CPU is stopped the address lamps show
+/// the panel's address and the data lamps the byte there; stepping moves
+/// them to the instruction pointer, like the fetch of the next cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrontPanel {
+    switches: u16,
+    address: u16,
+}
+
+impl FrontPanel {
+    pub fn new() -> FrontPanel {
+        FrontPanel::default()
+    }
+
+    /// Flips the toggle switches to `switches`, bit 15 leftmost
+    pub fn set_switches(&mut self, switches: u16) {
+        self.switches = switches;
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    /// Shows the byte at the address on the switches, and runs from there
+    pub fn examine(&mut self, cpu: &mut Cpu) {
+        self.address = self.switches;
+        cpu.set_register(Register::InstructionPointer, self.address);
+    }
+
+    pub fn examine_next(&mut self, cpu: &mut Cpu) {
+        self.address = self.address.wrapping_add(1);
+        cpu.set_register(Register::InstructionPointer, self.address);
+    }
+
+    /// Writes the low byte of the switches to the address shown
+    pub fn deposit(&self, cpu: &mut Cpu) {
+        cpu.memory_mut()
+            .set_byte(self.address as usize, self.switches as u8);
+    }
+
+    pub fn deposit_next(&mut self, cpu: &mut Cpu) {
+        self.examine_next(cpu);
+        self.deposit(cpu);
+    }
+
+    /// Runs one instruction
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<(), FaultAt> {
+        let result = debugger::step(cpu);
+        self.address = cpu.peek_register(Register::InstructionPointer);
+        result
+    }
+
+    /// The panel as rows of lamps, one row per bus, register and flag, and
+    /// the switches at the bottom
+    pub fn render(&self, cpu: &Cpu) -> String {
+        let mut panel = String::new();
+        let data = cpu.peek_memory(self.address as usize, 1)[0];
+        let _ = writeln!(
+            panel,
+            "{:<9}{}  {:#06x}",
+            "ADDRESS",
+            row(self.address, 16, LAMP_ON, LAMP_OFF),
+            self.address
+        );
+        let _ = writeln!(
+            panel,
+            "{:<9}{:>19}  {:#04x}",
+            "DATA",
+            row(data as u16, 8, LAMP_ON, LAMP_OFF),
+            data
+        );
+        for (_, name, value) in cpu.registers() {
+            let _ = writeln!(
+                panel,
+                "{:<9}{}  {:#06x}",
+                name.to_uppercase(),
+                row(value, 16, LAMP_ON, LAMP_OFF),
+                value
+            );
+        }
+        let _ = writeln!(
+            panel,
+            "{:<9}{}",
+            "INT",
+            row(cpu.is_in_interrupt_handler as u16, 1, LAMP_ON, LAMP_OFF)
+        );
+        let _ = writeln!(
+            panel,
+            "{:<9}{}  {:#06x}",
+            "SWITCHES",
+            row(self.switches, 16, SWITCH_UP, SWITCH_DOWN),
+            self.switches
+        );
+        panel
+    }
+}
+
+/// The low `bits` of `value` as lamps or switches, most significant first,
+/// in groups of 4
+fn row(value: u16, bits: u32, on: char, off: char) -> String {
+    let mut row = String::new();
+    for bit in (0..bits).rev() {
+        row.push(if value >> bit & 1 == 1 { on } else { off });
+        if bit % 4 == 0 && bit > 0 {
+            row.push(' ');
+        }
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrontPanel;
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+
+    #[test]
+    fn deposits_a_program_and_runs_it() {
+        // mov 0x1234, r1, toggled in at 0x0010
+        let program = [
+            Instruction::MovLitReg as u8,
+            0x12,
+            0x34,
+            Register::Register1 as u8,
+        ];
+        let mut cpu = Cpu::new(Memory::new(256));
+        let mut panel = FrontPanel::new();
+
+        panel.set_switches(0x0010);
+        panel.examine(&mut cpu);
+        for (index, byte) in program.iter().enumerate() {
+            panel.set_switches(*byte as u16);
+            match index {
+                0 => panel.deposit(&mut cpu),
+                _ => panel.deposit_next(&mut cpu),
+            }
+        }
+        assert_eq!(cpu.peek_memory(0x10, 4), program);
+
+        panel.set_switches(0x0010);
+        panel.examine(&mut cpu);
+        let stopped = panel.render(&cpu);
+        assert!(
+            stopped.starts_with("ADDRESS  ○○○○ ○○○○ ○○○● ○○○○  0x0010\n"),
+            "{}",
+            stopped
+        );
+        assert!(
+            stopped.contains("DATA               ○○○● ○○○○  0x10\n"),
+            "{}",
+            stopped
+        );
+
+        assert_eq!(panel.step(&mut cpu), Ok(()));
+        assert_eq!(panel.address(), 0x0014);
+        let running = panel.render(&cpu);
+        assert!(
+            running.contains("R1       ○○○● ○○●○ ○○●● ○●○○  0x1234\n"),
+            "{}",
+            running
+        );
+        assert!(
+            running.ends_with("SWITCHES ▼▼▼▼ ▼▼▼▼ ▼▼▼▲ ▼▼▼▼  0x0010\n"),
+            "{}",
+            running
+        );
+    }
+}
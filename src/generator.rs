@@ -0,0 +1,204 @@
+use crate::cpu::{Instruction, Register};
+use crate::mapper::Device;
+use crate::rng::Rng;
+
+/// Programs read and write words in `DATA_START..DATA_START + DATA_SIZE`
+pub const DATA_START: usize = 0x8000;
+pub const DATA_SIZE: usize = 0x100;
+
+const SUBROUTINE_START: usize = 0x1000;
+const SUBROUTINE_SIZE: usize = 0x400;
+const SUBROUTINES: usize = 8;
+const MAIN_LENGTH: usize = 64;
+const SUBROUTINE_LENGTH: usize = 24;
+
+const REGISTERS: [Register; 8] = [
+    Register::Register1,
+    Register::Register2,
+    Register::Register3,
+    Register::Register4,
+    Register::Register5,
+    Register::Register6,
+    Register::Register7,
+    Register::Register8,
+];
+
+/// A generated program, as code to load at a few addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub segments: Vec<(usize, Vec<u8>)>,
+}
+
+impl Program {
+    pub fn load(&self, memory: &mut dyn Device) {
+        for (start, code) in &self.segments {
+            for (i, byte) in code.iter().enumerate() {
+                memory.set_byte(start + i, *byte);
+            }
+        }
+    }
+}
+
+/// Generates a random but well formed program that runs forever. The main
+/// loop at address 0 calls subroutines with balanced pushes and pops,
+/// subroutines only call the ones after them and memory accesses stay in the
+/// data region.
+/// The same seed always gives the same program.
+pub fn generate(seed: u64) -> Program {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+    };
+
+    let mut segments = vec![(0, generator.body(0, MAIN_LENGTH, 0))];
+    for index in 0..SUBROUTINES {
+        let start = subroutine_address(index);
+        segments.push((start, generator.body(start, SUBROUTINE_LENGTH, index + 1)));
+    }
+    Program { segments }
+}
+
+fn subroutine_address(index: usize) -> usize {
+    SUBROUTINE_START + index * SUBROUTINE_SIZE
+}
+
+struct Generator {
+    rng: Rng,
+}
+
+impl Generator {
+    fn next(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn below(&mut self, limit: u64) -> u64 {
+        self.rng.below(limit)
+    }
+
+    fn register(&mut self) -> u8 {
+        REGISTERS[self.below(REGISTERS.len() as u64) as usize] as u8
+    }
+
+    fn literal(&mut self) -> [u8; 2] {
+        (self.next() as u16).to_be_bytes()
+    }
+
+    fn data_address(&mut self) -> [u8; 2] {
+        let offset = self.below(DATA_SIZE as u64 / 2) as usize * 2;
+        ((DATA_START + offset) as u16).to_be_bytes()
+    }
+
+    /// Code at `start` with about `length` instructions. Subroutines may call
+    /// the ones from `first_callee` on. The main loop (`start == 0`) pops
+    /// what it pushed and jumps back to the start, subroutines return.
+    fn body(&mut self, start: usize, length: usize, first_callee: usize) -> Vec<u8> {
+        let mut code = Vec::new();
+        let mut pushed = 0;
+        for _ in 0..length {
+            match self.below(11) {
+                0 => code.push(Instruction::Noop as u8),
+                1 => {
+                    let [high, low] = self.literal();
+                    code.extend([Instruction::MovLitReg as u8, high, low, self.register()]);
+                }
+                2 => code.extend([
+                    Instruction::MovRegReg as u8,
+                    self.register(),
+                    self.register(),
+                ]),
+                3 => {
+                    let [high, low] = self.data_address();
+                    code.extend([Instruction::MovRegMem as u8, self.register(), high, low]);
+                }
+                4 => {
+                    let [high, low] = self.data_address();
+                    code.extend([Instruction::MovMemReg as u8, high, low, self.register()]);
+                }
+                5 => code.extend([
+                    Instruction::AddRegReg as u8,
+                    self.register(),
+                    self.register(),
+                ]),
+                6 => {
+                    let [high, low] = self.literal();
+                    code.extend([Instruction::PushLit as u8, high, low]);
+                    pushed += 1;
+                }
+                7 => {
+                    code.extend([Instruction::PushReg as u8, self.register()]);
+                    pushed += 1;
+                }
+                8 if pushed > 0 => {
+                    code.extend([Instruction::Pop as u8, self.register()]);
+                    pushed -= 1;
+                }
+                9 if first_callee < SUBROUTINES => {
+                    // Arguments, their count, then the call
+                    let arguments = self.below(3);
+                    for _ in 0..arguments {
+                        code.extend([Instruction::PushReg as u8, self.register()]);
+                    }
+                    code.extend([Instruction::PushLit as u8, 0, arguments as u8]);
+                    let callee =
+                        first_callee + self.below((SUBROUTINES - first_callee) as u64) as usize;
+                    let [high, low] = (subroutine_address(callee) as u16).to_be_bytes();
+                    code.extend([Instruction::CalLit as u8, high, low]);
+                }
+                _ => {
+                    // Skip over the next instruction, unless the accumulator
+                    // happens to hold the literal
+                    let [high, low] = self.literal();
+                    let [target_high, target_low] = ((start + code.len() + 9) as u16).to_be_bytes();
+                    code.extend([
+                        Instruction::JmpNotEq as u8,
+                        high,
+                        low,
+                        target_high,
+                        target_low,
+                    ]);
+                    let [high, low] = self.literal();
+                    code.extend([Instruction::MovLitReg as u8, high, low, self.register()]);
+                }
+            }
+        }
+
+        if start != 0 {
+            code.push(Instruction::Ret as u8);
+            return code;
+        }
+        for _ in 0..pushed {
+            code.extend([Instruction::Pop as u8, self.register()]);
+        }
+        // The accumulator can't hold both literals, so one of these jumps
+        code.extend([Instruction::JmpNotEq as u8, 0x00, 0x00, 0x00, 0x00]);
+        code.extend([Instruction::JmpNotEq as u8, 0x00, 0x01, 0x00, 0x00]);
+        code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, SUBROUTINE_SIZE};
+    use crate::cpu::{Cpu, StopReason};
+    use crate::memory::Memory;
+
+    #[test]
+    fn same_seed_gives_the_same_program() {
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+    }
+
+    #[test]
+    fn programs_fit_their_slots_and_run() {
+        for seed in 0..32 {
+            let program = generate(seed);
+            for (_, code) in &program.segments {
+                assert!(code.len() <= SUBROUTINE_SIZE);
+            }
+
+            let mut memory = Memory::new(0x10000);
+            program.load(&mut memory);
+            let mut cpu = Cpu::new(memory);
+            assert_eq!(cpu.run(10_000), StopReason::FuelExhausted);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use crate::console::{buttons, Console, FRAME_CYCLES};
+use crate::cpu::{Register, StopReason};
+use crate::debugger;
+use crate::disassembler;
+use eframe::egui;
+use std::collections::BTreeSet;
+
+/// Bytes per row of the memory editor
+const HEX_COLUMNS: usize = 16;
+
+/// Rows of the memory editor
+const HEX_ROWS: usize = 16;
+
+/// Bytes the disassembly decodes from the instruction pointer on
+const DISASSEMBLY_BYTES: usize = 96;
+
+/// The console's address space
+const ADDRESS_SPACE: usize = 0x1_0000;
+
+/// A graphical debugger for the fantasy console. Only built with the `gui`
+/// feature.
+///
+/// - the registers
+/// - a hex editor over 256 bytes of memory from any address
+/// - the disassembly from the instruction pointer on, where clicking a line
+///   sets or clears a breakpoint
+/// - the screen, with the arrow keys, Z, X, Enter and Space as the gamepad
+///
+/// Running stops at breakpoints, faults, guest panics and once the CPU
+/// halts.
+pub struct Frontend {
+    console: Console,
+    breakpoints: BTreeSet<u16>,
+    running: bool,
+    /// Set by `resume` and single steps so the CPU can run off the
+    /// breakpoint it stopped at
+    resuming: bool,
+    /// Why the CPU last stopped
+    status: String,
+    /// Where the memory editor starts
+    memory_start: u16,
+}
+
+impl Frontend {
+    /// Starts out paused
+    pub fn new(console: Console) -> Frontend {
+        Frontend {
+            console,
+            breakpoints: BTreeSet::new(),
+            running: false,
+            resuming: false,
+            status: "Paused".to_string(),
+            memory_start: 0,
+        }
+    }
+
+    pub fn toggle_breakpoint(&mut self, address: u16) {
+        if !self.breakpoints.remove(&address) {
+            self.breakpoints.insert(address);
+        }
+    }
+
+    /// Runs a frame at a time from the next frame on
+    pub fn resume(&mut self) {
+        self.running = true;
+        self.resuming = true;
+        self.status = "Running".to_string();
+    }
+
+    /// Executes the next instruction, even if there's a breakpoint on it
+    pub fn step(&mut self) {
+        self.resuming = true;
+        self.advance(1);
+    }
+
+    /// Executes up to `instructions` instructions, pausing at a breakpoint
+    /// unless resuming from it, at a fault, which the instruction pointer is
+    /// put back on, or once the CPU halts
+    fn advance(&mut self, instructions: u64) {
+        let cpu = &mut self.console.cpu;
+        for _ in 0..instructions {
+            let address = cpu.peek_register(Register::InstructionPointer);
+            let resuming = std::mem::take(&mut self.resuming);
+            let reason = if cpu.is_halted() {
+                StopReason::Halted
+            } else if !resuming && self.breakpoints.contains(&address) {
+                self.running = false;
+                self.status = format!("Breakpoint at {:#06x}", address);
+                return;
+            } else {
+                match debugger::step(cpu) {
+                    Ok(()) => continue,
+                    Err(fault) => {
+                        debugger::rewind(cpu, fault);
+                        cpu.stop_reason(fault.fault)
+                    }
+                }
+            };
+            self.stop(reason);
+            return;
+        }
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        self.running = false;
+        self.status = match reason {
+            StopReason::FuelExhausted => "Paused".to_string(),
+            StopReason::Fault(fault) => fault.to_string(),
+            StopReason::GuestPanic { message, code } => {
+                format!("Guest panicked with code {:#06x}: {}", code, message)
+            }
+            StopReason::Halted => "Halted".to_string(),
+        };
+    }
+
+    fn controls(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if self.running {
+                if ui.button("Pause").clicked() {
+                    self.stop(StopReason::FuelExhausted);
+                }
+            } else {
+                if ui.button("Run").clicked() {
+                    self.resume();
+                }
+                if ui.button("Step").clicked() {
+                    self.step();
+                }
+            }
+            if ui.button("Reset").clicked() {
+                self.console.cpu.reset();
+                self.stop(StopReason::FuelExhausted);
+            }
+            ui.separator();
+            ui.label(&self.status);
+        });
+    }
+
+    fn registers(&self, ui: &mut egui::Ui) {
+        egui::Grid::new("registers").striped(true).show(ui, |ui| {
+            for (_, name, value) in self.console.cpu.registers() {
+                ui.monospace(name);
+                ui.monospace(format!("{:04x}", value));
+                ui.end_row();
+            }
+        });
+    }
+
+    fn disassembly(&mut self, ui: &mut egui::Ui) {
+        let ip = self.console.cpu.peek_register(Register::InstructionPointer);
+        let length = DISASSEMBLY_BYTES.min(ADDRESS_SPACE - ip as usize);
+        let code = self.console.cpu.peek_memory(ip as usize, length);
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for line in disassembler::disassemble(&code, ip as usize) {
+                let address = line.address as u16;
+                let marker = match self.breakpoints.contains(&address) {
+                    true => '●',
+                    false => ' ',
+                };
+                let text = format!("{} {:04x}  {}", marker, address, line.text);
+                let label =
+                    ui.selectable_label(address == ip, egui::RichText::new(text).monospace());
+                if label.clicked() {
+                    self.toggle_breakpoint(address);
+                }
+            }
+        });
+    }
+
+    fn memory(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Address");
+            ui.add(egui::DragValue::new(&mut self.memory_start).hexadecimal(4, false, false));
+        });
+        let start = self.memory_start as usize & !(HEX_COLUMNS - 1);
+        let length = (HEX_COLUMNS * HEX_ROWS).min(ADDRESS_SPACE - start);
+        let bytes = self.console.cpu.peek_memory(start, length);
+        let mut edits = Vec::new();
+        egui::Grid::new("memory").show(ui, |ui| {
+            for (row, chunk) in bytes.chunks(HEX_COLUMNS).enumerate() {
+                let row_start = start + row * HEX_COLUMNS;
+                ui.monospace(format!("{:04x}", row_start));
+                for (column, byte) in chunk.iter().enumerate() {
+                    let mut value = *byte;
+                    let field = egui::DragValue::new(&mut value).hexadecimal(2, false, false);
+                    if ui.add(field).changed() {
+                        edits.push((row_start + column, value));
+                    }
+                }
+                let text: String = chunk
+                    .iter()
+                    .map(|byte| match byte.is_ascii_graphic() {
+                        true => *byte as char,
+                        false => '.',
+                    })
+                    .collect();
+                ui.monospace(text);
+                ui.end_row();
+            }
+        });
+        for (address, value) in edits {
+            self.console.cpu.memory_mut().set_byte(address, value);
+        }
+    }
+
+    fn screen(&self, ui: &mut egui::Ui) {
+        ui.label(format!("Frame {}", self.console.video.frame()));
+        let screen = egui::RichText::new(self.console.video.render())
+            .monospace()
+            .size(16.0);
+        ui.label(screen);
+    }
+}
+
+/// The gamepad buttons held down on the keyboard
+fn gamepad_buttons(input: &egui::InputState) -> u8 {
+    [
+        (egui::Key::ArrowUp, buttons::UP),
+        (egui::Key::ArrowDown, buttons::DOWN),
+        (egui::Key::ArrowLeft, buttons::LEFT),
+        (egui::Key::ArrowRight, buttons::RIGHT),
+        (egui::Key::Z, buttons::A),
+        (egui::Key::X, buttons::B),
+        (egui::Key::Enter, buttons::START),
+        (egui::Key::Space, buttons::SELECT),
+    ]
+    .iter()
+    .filter(|(key, _)| input.key_down(*key))
+    .fold(0, |held, (_, button)| held | button)
+}
+
+impl eframe::App for Frontend {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.console.gamepad.set_buttons(ctx.input(gamepad_buttons));
+        if self.running {
+            self.advance(FRAME_CYCLES);
+            ctx.request_repaint();
+        }
+
+        egui::TopBottomPanel::top("controls").show(ctx, |ui| self.controls(ui));
+        egui::SidePanel::left("registers").show(ctx, |ui| self.registers(ui));
+        egui::SidePanel::right("disassembly").show(ctx, |ui| self.disassembly(ui));
+        egui::TopBottomPanel::bottom("memory").show(ctx, |ui| self.memory(ui));
+        egui::CentralPanel::default().show(ctx, |ui| self.screen(ui));
+    }
+}
+
+/// Opens the debugger in a window and returns once it's closed
+pub fn run(console: Console) -> Result<(), String> {
+    eframe::run_native(
+        "rsll16",
+        eframe::NativeOptions::default(),
+        Box::new(|_| Ok(Box::new(Frontend::new(console)))),
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Frontend;
+    use crate::console::Console;
+    use crate::cpu::{Instruction, Register};
+    use crate::memory::{Memory, Rom};
+
+    fn frontend() -> Frontend {
+        // mov 0x0001, r1
+        // mov 0x0002, r1
+        // hlt
+        let mut rom = Memory::new(0x10);
+        rom.set_byte(0x00, Instruction::MovLitReg as u8);
+        rom.set_word(0x01, 0x0001);
+        rom.set_byte(0x03, Register::Register1 as u8);
+        rom.set_byte(0x04, Instruction::MovLitReg as u8);
+        rom.set_word(0x05, 0x0002);
+        rom.set_byte(0x07, Register::Register1 as u8);
+        rom.set_byte(0x08, Instruction::Halt as u8);
+        Frontend::new(Console::new(Rom::new(rom.peek(0, 0x10))).unwrap())
+    }
+
+    #[test]
+    fn runs_to_breakpoints() {
+        let mut frontend = frontend();
+        frontend.toggle_breakpoint(0x0004);
+        frontend.resume();
+
+        frontend.advance(100);
+        assert!(!frontend.running);
+        assert_eq!(frontend.status, "Breakpoint at 0x0004");
+        let cpu = &frontend.console.cpu;
+        assert_eq!(cpu.peek_register(Register::Register1), 0x0001);
+
+        // Resuming runs off the breakpoint it stopped at
+        frontend.resume();
+        frontend.advance(100);
+        assert_eq!(frontend.status, "Halted");
+        assert_eq!(frontend.console.cpu.peek_register(Register::Register1), 2);
+    }
+
+    #[test]
+    fn stops_at_breakpoints_on_frame_boundaries() {
+        let mut frontend = frontend();
+        frontend.toggle_breakpoint(0x0004);
+        frontend.resume();
+
+        // The first frame ends right before the breakpoint
+        frontend.advance(1);
+        assert!(frontend.running);
+        frontend.advance(100);
+        assert!(!frontend.running);
+        assert_eq!(frontend.status, "Breakpoint at 0x0004");
+        let cpu = &frontend.console.cpu;
+        assert_eq!(cpu.peek_register(Register::Register1), 0x0001);
+
+        // Single steps go past it
+        frontend.step();
+        assert_eq!(frontend.console.cpu.peek_register(Register::Register1), 2);
+    }
+
+    #[test]
+    fn toggles_breakpoints() {
+        let mut frontend = frontend();
+        frontend.toggle_breakpoint(0x0004);
+        frontend.toggle_breakpoint(0x0004);
+        frontend.resume();
+
+        frontend.advance(100);
+        assert_eq!(frontend.status, "Halted");
+    }
+}
@@ -0,0 +1,442 @@
+use crate::cpu::{Cpu, Fault, Register};
+use crate::debug_info::DebugInfo;
+use crate::patch::PatchError;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Control requests shared between the handle and the VM thread
+struct Control {
+    paused: bool,
+    pending_steps: usize,
+    busy: bool,
+    stopped: bool,
+    fault: Option<Fault>,
+    breakpoints: BTreeSet<u16>,
+    /// Set by `resume` so the VM can run off the breakpoint it stopped at
+    resuming: bool,
+}
+
+struct Shared {
+    cpu: Mutex<Cpu>,
+    control: Mutex<Control>,
+    wakeup: Condvar,
+}
+
+/// Drives a `Cpu` on its own thread and lets other threads pause, resume,
+/// single-step and inspect it. The VM pauses by itself on faults,
+/// breakpoints and once the CPU halts.
+pub struct CpuHandle {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CpuHandle {
+    /// Moves the CPU to a new thread. The CPU starts paused.
+    pub fn spawn(cpu: Cpu) -> CpuHandle {
+        let shared = Arc::new(Shared {
+            cpu: Mutex::new(cpu),
+            control: Mutex::new(Control {
+                paused: true,
+                pending_steps: 0,
+                busy: false,
+                stopped: false,
+                fault: None,
+                breakpoints: BTreeSet::new(),
+                resuming: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+
+        let runner = Arc::clone(&shared);
+        let thread = thread::spawn(move || run(runner));
+
+        CpuHandle {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops execution. Returns once the VM thread is no longer stepping.
+    pub fn pause(&self) {
+        let mut control = self.shared.control.lock().unwrap();
+        control.paused = true;
+        while control.busy {
+            control = self.shared.wakeup.wait(control).unwrap();
+        }
+    }
+
+    pub fn resume(&self) {
+        let mut control = self.shared.control.lock().unwrap();
+        control.paused = false;
+        control.fault = None;
+        control.resuming = true;
+        self.shared.wakeup.notify_all();
+    }
+
+    /// Executes a single instruction while paused. Returns once it is done.
+    pub fn step(&self) {
+        let mut control = self.shared.control.lock().unwrap();
+        if !control.paused {
+            return;
+        }
+        control.pending_steps += 1;
+        control.fault = None;
+        self.shared.wakeup.notify_all();
+        while control.pending_steps > 0 || control.busy {
+            control = self.shared.wakeup.wait(control).unwrap();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shared.control.lock().unwrap().paused
+    }
+
+    /// The fault that paused the VM, cleared by the next `resume` or `step`
+    pub fn fault(&self) -> Option<Fault> {
+        self.shared.control.lock().unwrap().fault
+    }
+
+    /// Runs `f` against the CPU in between two instructions.
+    pub fn inspect<T>(&self, f: impl FnOnce(&Cpu) -> T) -> T {
+        let cpu = self.shared.cpu.lock().unwrap();
+        f(&cpu)
+    }
+
+    /// Runs `f` against the CPU in between two instructions, letting it
+    /// change the machine.
+    pub fn modify<T>(&self, f: impl FnOnce(&mut Cpu) -> T) -> T {
+        let mut cpu = self.shared.cpu.lock().unwrap();
+        f(&mut cpu)
+    }
+
+    pub fn peek_register(&self, register: Register) -> u16 {
+        self.inspect(|cpu| cpu.peek_register(register))
+    }
+
+    /// Pauses the VM before it runs the instruction at `address`. Single
+    /// steps go past breakpoints.
+    pub fn add_breakpoint(&self, address: u16) {
+        self.shared
+            .control
+            .lock()
+            .unwrap()
+            .breakpoints
+            .insert(address);
+    }
+
+    pub fn remove_breakpoint(&self, address: u16) {
+        self.shared
+            .control
+            .lock()
+            .unwrap()
+            .breakpoints
+            .remove(&address);
+    }
+
+    pub fn breakpoints(&self) -> Vec<u16> {
+        let control = self.shared.control.lock().unwrap();
+        control.breakpoints.iter().copied().collect()
+    }
+
+    /// Pauses the VM and swaps in a rebuilt program: `image` is written from
+    /// address 0 and the CPU reset to start it over. Breakpoints move to the
+    /// same symbols in `rebuilt`, as `DebugInfo::relocate` finds them, and
+    /// the ones that have nowhere to go are dropped and returned.
+    pub fn hot_load(&self, image: &[u8], built: &DebugInfo, rebuilt: &DebugInfo) -> Vec<u16> {
+        self.pause();
+        self.modify(|cpu| {
+            let memory = cpu.memory_mut();
+            for (address, byte) in image.iter().take(memory.byte_length()).enumerate() {
+                memory.set_byte(address, *byte);
+            }
+            cpu.reset();
+        });
+
+        let mut control = self.shared.control.lock().unwrap();
+        control.fault = None;
+        let mut dropped = Vec::new();
+        let breakpoints = std::mem::take(&mut control.breakpoints);
+        for address in breakpoints {
+            match built.relocate(address, rebuilt) {
+                Some(moved) => {
+                    control.breakpoints.insert(moved);
+                }
+                None => dropped.push(address),
+            }
+        }
+        dropped
+    }
+
+    /// `Cpu::patch`, refused unless the VM is paused
+    pub fn patch(&self, address: u16, bytes: &[u8]) -> Result<(), PatchError> {
+        let control = self.shared.control.lock().unwrap();
+        if !control.paused || control.busy {
+            return Err(PatchError::Running);
+        }
+        self.shared.cpu.lock().unwrap().patch(address, bytes)
+    }
+
+    /// Stops the VM thread and hands the CPU back.
+    pub fn stop(mut self) -> Cpu {
+        self.shutdown();
+        let shared = Arc::clone(&self.shared);
+        drop(self);
+        match Arc::try_unwrap(shared) {
+            Ok(shared) => shared.cpu.into_inner().unwrap(),
+            Err(_) => unreachable!("VM thread has been joined"),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        {
+            let mut control = self.shared.control.lock().unwrap();
+            control.stopped = true;
+            self.shared.wakeup.notify_all();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for CpuHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run(shared: Arc<Shared>) {
+    loop {
+        {
+            let mut control = shared.control.lock().unwrap();
+            loop {
+                if control.stopped {
+                    return;
+                }
+                if control.pending_steps > 0 {
+                    break;
+                }
+                if !control.paused {
+                    let resuming = std::mem::take(&mut control.resuming);
+                    if resuming || !at_breakpoint(&shared, &control) {
+                        break;
+                    }
+                    control.paused = true;
+                    shared.wakeup.notify_all();
+                }
+                control = shared.wakeup.wait(control).unwrap();
+            }
+            control.busy = true;
+        }
+
+        let (result, halted) = {
+            let mut cpu = shared.cpu.lock().unwrap();
+            (cpu.step(), cpu.is_halted())
+        };
+
+        let mut control = shared.control.lock().unwrap();
+        control.busy = false;
+        if let Err(fault) = result {
+            control.paused = true;
+            control.fault = Some(fault);
+        }
+        // Nothing left to run until the CPU is reset
+        if halted {
+            control.paused = true;
+        }
+        if control.pending_steps > 0 {
+            control.pending_steps -= 1;
+        }
+        if control.paused {
+            shared.wakeup.notify_all();
+        }
+    }
+}
+
+fn at_breakpoint(shared: &Shared, control: &Control) -> bool {
+    if control.breakpoints.is_empty() {
+        return false;
+    }
+    let cpu = shared.cpu.lock().unwrap();
+    let address = cpu.peek_register(Register::InstructionPointer);
+    control.breakpoints.contains(&address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuHandle;
+    use crate::cpu::{Cpu, Fault, Instruction, Register};
+    use crate::debug_info::DebugInfo;
+    use crate::memory::Memory;
+    use crate::patch::PatchError;
+
+    fn looping_program() -> Memory {
+        let mut memory = Memory::new(256);
+
+        // start:
+        //   mov 0x1234, r1
+        //   jne 0x0001, start:
+        let mut i = 0;
+        memory.set_byte(i, Instruction::MovLitReg as u8);
+        i += 1;
+        memory.set_byte(i, 0x12);
+        i += 1;
+        memory.set_byte(i, 0x34);
+        i += 1;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_byte(i, Instruction::JmpNotEq as u8);
+        i += 1;
+        memory.set_byte(i, 0x00);
+        i += 1;
+        memory.set_byte(i, 0x01);
+        i += 1;
+        memory.set_byte(i, 0x00);
+        i += 1;
+        memory.set_byte(i, 0x00);
+
+        memory
+    }
+
+    #[test]
+    fn single_steps_while_paused() {
+        let handle = CpuHandle::spawn(Cpu::new(looping_program()));
+        assert!(handle.is_paused());
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 0);
+
+        handle.step();
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 4);
+        assert_eq!(handle.peek_register(Register::Register1), 0x1234);
+
+        handle.step();
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 0);
+    }
+
+    #[test]
+    fn pauses_and_resumes() {
+        let handle = CpuHandle::spawn(Cpu::new(looping_program()));
+
+        handle.resume();
+        assert!(!handle.is_paused());
+        while handle.peek_register(Register::Register1) != 0x1234 {
+            std::thread::yield_now();
+        }
+        handle.pause();
+        assert!(handle.is_paused());
+
+        let ip = handle.peek_register(Register::InstructionPointer);
+        assert!(ip == 0 || ip == 4, "Stopped on an instruction boundary");
+
+        let cpu = handle.stop();
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), ip);
+    }
+
+    #[test]
+    fn pauses_at_breakpoints() {
+        let handle = CpuHandle::spawn(Cpu::new(looping_program()));
+        handle.add_breakpoint(4);
+        assert_eq!(handle.breakpoints(), [4]);
+
+        handle.resume();
+        while !handle.is_paused() {
+            std::thread::yield_now();
+        }
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 4);
+        assert_eq!(handle.inspect(|cpu| cpu.instruction_count()), 1);
+
+        // Runs off the breakpoint and round the loop back to it
+        handle.resume();
+        while handle.inspect(|cpu| cpu.instruction_count()) < 3 {
+            std::thread::yield_now();
+        }
+        handle.pause();
+        assert_eq!(handle.inspect(|cpu| cpu.instruction_count()), 3);
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 4);
+
+        handle.remove_breakpoint(4);
+        assert_eq!(handle.breakpoints(), []);
+    }
+
+    #[test]
+    fn pauses_on_fault() {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::MovLitReg as u8);
+        memory.set_byte(3, 0xee);
+
+        let handle = CpuHandle::spawn(Cpu::new(memory));
+        handle.resume();
+        while !handle.is_paused() {
+            std::thread::yield_now();
+        }
+
+        assert_eq!(
+            handle.fault(),
+            Some(Fault::IllegalOperand {
+                address: 3,
+                value: 0xee
+            })
+        );
+    }
+
+    #[test]
+    fn pauses_on_halt() {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::Halt as u8);
+
+        let handle = CpuHandle::spawn(Cpu::new(memory));
+        handle.resume();
+        while !handle.is_paused() {
+            std::thread::yield_now();
+        }
+
+        assert_eq!(handle.fault(), None);
+        assert!(handle.inspect(|cpu| cpu.is_halted()));
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 1);
+    }
+
+    #[test]
+    fn hot_loads_keeping_breakpoints_on_their_symbols() {
+        let handle = CpuHandle::spawn(Cpu::new(looping_program()));
+        handle.add_breakpoint(4);
+        handle.add_breakpoint(0x80);
+        handle.step();
+
+        // The same loop with a nop in front of it
+        let mut image = vec![Instruction::Noop as u8];
+        image.extend(looping_program().peek(0, 9));
+        image[9] = 0x01;
+        let mut built = DebugInfo::default();
+        built.add_scope("start", 0, 9);
+        let mut rebuilt = DebugInfo::default();
+        rebuilt.add_scope("start", 1, 10);
+
+        assert_eq!(handle.hot_load(&image, &built, &rebuilt), [0x80]);
+        assert_eq!(handle.breakpoints(), [5]);
+        assert!(handle.is_paused());
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 0);
+
+        handle.resume();
+        while !handle.is_paused() {
+            std::thread::yield_now();
+        }
+        assert_eq!(handle.peek_register(Register::InstructionPointer), 5);
+        assert_eq!(handle.peek_register(Register::Register1), 0x1234);
+    }
+
+    #[test]
+    fn patches_only_while_paused() {
+        let handle = CpuHandle::spawn(Cpu::new(looping_program()));
+        handle.resume();
+        assert_eq!(handle.patch(2, &[0xab]), Err(PatchError::Running));
+        handle.pause();
+        // The literal of the mov can't be patched while it is up next
+        if handle.peek_register(Register::InstructionPointer) == 0 {
+            handle.step();
+        }
+        handle.patch(1, &[0xab, 0xcd]).unwrap();
+        handle.step();
+        handle.step();
+        assert_eq!(handle.peek_register(Register::Register1), 0xabcd);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::mapper::Device;
+use std::collections::BTreeMap;
+
+/// Memory mapped allocator handing out blocks of a heap region, 4 bytes
+/// long. Writing a size to the first word allocates a block of at least
+/// that many bytes, reading the word back gives its address, or 0 when it
+/// does not fit. Writing a block's address to the second word frees it.
+/// Blocks are word aligned and the bookkeeping lives on the host, so all
+/// of the region is usable.
+pub struct Allocator {
+    registers: [u8; 4],
+    /// Free blocks, size by address, with no two adjacent
+    free: BTreeMap<u16, u16>,
+    /// Allocated blocks, size by address
+    allocated: BTreeMap<u16, u16>,
+}
+
+impl Allocator {
+    /// An allocator for the `size` bytes from `start`, all of them free
+    pub fn new(start: u16, size: u16) -> Allocator {
+        let mut free = BTreeMap::new();
+        if size >= 2 {
+            free.insert(start, size & !1);
+        }
+        Allocator {
+            registers: [0; 4],
+            free,
+            allocated: BTreeMap::new(),
+        }
+    }
+
+    /// First fit, 0 when no free block is big enough
+    pub fn allocate(&mut self, size: u16) -> u16 {
+        if size == 0 {
+            return 0;
+        }
+        let size = size.saturating_add(1) & !1;
+        let Some((&address, &free)) = self.free.iter().find(|(_, &free)| free >= size) else {
+            return 0;
+        };
+        self.free.remove(&address);
+        if free > size {
+            self.free.insert(address + size, free - size);
+        }
+        self.allocated.insert(address, size);
+        address
+    }
+
+    /// Gives a block back, merging it with free neighbours. Addresses that
+    /// are not the start of an allocated block are ignored.
+    pub fn free(&mut self, address: u16) {
+        let Some(mut size) = self.allocated.remove(&address) else {
+            return;
+        };
+        let mut address = address;
+        if let Some(next) = self.free.remove(&(address + size)) {
+            size += next;
+        }
+        if let Some((&previous, &previous_size)) = self.free.range(..address).next_back() {
+            if previous + previous_size == address {
+                self.free.remove(&previous);
+                address = previous;
+                size += previous_size;
+            }
+        }
+        self.free.insert(address, size);
+    }
+
+    /// Bytes in allocated blocks
+    pub fn allocated(&self) -> usize {
+        self.allocated.values().map(|&size| size as usize).sum()
+    }
+}
+
+impl Device for Allocator {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.registers[address] = value;
+        let word =
+            |high: usize| u16::from_be_bytes([self.registers[high], self.registers[high + 1]]);
+        match address {
+            1 => {
+                let block = self.allocate(word(0));
+                self.registers[0..2].copy_from_slice(&block.to_be_bytes());
+            }
+            3 => self.free(word(2)),
+            _ => {}
+        }
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.registers[address]
+    }
+
+    fn byte_length(&self) -> usize {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Allocator;
+    use crate::mapper::Device;
+
+    #[test]
+    fn allocates_first_fit_and_merges_freed_blocks() {
+        let mut heap = Allocator::new(0x1000, 0x100);
+        let first = heap.allocate(0x20);
+        let second = heap.allocate(0x0f);
+        let third = heap.allocate(0x20);
+        assert_eq!((first, second, third), (0x1000, 0x1020, 0x1030));
+        assert_eq!(heap.allocated(), 0x50, "Sizes round up to words");
+        assert_eq!(heap.allocate(0x100), 0, "Too big");
+        assert_eq!(heap.allocate(0), 0);
+
+        heap.free(first);
+        heap.free(second);
+        assert_eq!(heap.allocate(0x30), 0x1000, "Freed neighbours merge");
+        heap.free(0x1002);
+        assert_eq!(heap.allocated(), 0x50);
+
+        heap.free(0x1000);
+        heap.free(third);
+        assert_eq!(heap.allocate(0x100), 0x1000, "All free again");
+    }
+
+    #[test]
+    fn allocates_on_writes() {
+        let mut heap = Allocator::new(0x2000, 0x40);
+        heap.set_word(0, 0x10);
+        assert_eq!(heap.get_word(0), 0x2000);
+        heap.set_word(0, 0x10);
+        assert_eq!(heap.get_word(0), 0x2010);
+        heap.set_word(2, 0x2000);
+        assert_eq!(heap.allocated(), 0x10);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::cpu::{Cpu, Register};
+use crate::debugger::trace_line;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// Watches a CPU execute. Only built with the `instrument` feature, so
+/// without it the interpreter has no hooks to check.
+/// While any observer is attached, `run` and `run_jit` fall back to paths
+/// that see every instruction.
+pub trait Observer: Send {
+    /// Before the instruction at `address` executes
+    fn instruction(&mut self, _cpu: &Cpu, _address: u16) {}
+
+    /// After the guest read `value` from `address`. Instruction fetches
+    /// don't count.
+    fn read(&mut self, _cpu: &Cpu, _address: u16, _value: u16) {}
+
+    /// After the guest wrote `value` to `address`
+    fn write(&mut self, _cpu: &Cpu, _address: u16, _value: u16) {}
+
+    /// After the CPU entered the handler of interrupt `value`
+    fn interrupt(&mut self, _cpu: &Cpu, _value: u16) {}
+}
+
+/// Execution counters, see `Cpu::enable_metrics`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub instructions: u64,
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    /// Subroutine calls, not counting interrupts
+    pub calls: u64,
+    /// Most bytes on the stack at once
+    pub max_stack_depth: u16,
+    /// Guest reads and writes of data, in words. Instruction fetches don't
+    /// count.
+    pub reads: u64,
+    pub writes: u64,
+    /// Interrupts that got through the mask
+    pub interrupts: u64,
+    /// Cycles from a device raising an interrupt to the first instruction
+    /// of its handler, by interrupt bit
+    pub interrupt_latency: [LatencyStats; 16],
+}
+
+/// Smallest, largest and total of a series of latencies, in cycles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: u64) {
+        self.min = match self.count {
+            0 => latency,
+            _ => self.min.min(latency),
+        };
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.count += 1;
+    }
+
+    pub fn average(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total as f64 / self.count as f64)
+    }
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "instructions       {}", self.instructions)?;
+        writeln!(
+            f,
+            "branches           {} taken, {} not taken",
+            self.branches_taken, self.branches_not_taken
+        )?;
+        writeln!(f, "calls              {}", self.calls)?;
+        writeln!(f, "max stack depth    {:#06x}", self.max_stack_depth)?;
+        writeln!(f, "reads, writes      {}, {}", self.reads, self.writes)?;
+        writeln!(f, "interrupts         {}", self.interrupts)?;
+        for (bit, latency) in self.interrupt_latency.iter().enumerate() {
+            if let Some(average) = latency.average() {
+                writeln!(
+                    f,
+                    "  latency of {:<6} min {} avg {:.1} max {} cycles",
+                    format!("{:#x}", bit),
+                    latency.min,
+                    average,
+                    latency.max
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Cpu {
+    pub fn attach_observer(&mut self, observer: impl Observer + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Starts counting from zero. Like observers, counting makes `run` and
+    /// `run_jit` fall back to paths that see every instruction.
+    pub fn enable_metrics(&mut self) {
+        self.metrics = Some(Metrics::default());
+    }
+
+    /// The counters since `enable_metrics`, all zero if it wasn't called
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.unwrap_or_default()
+    }
+
+    pub(crate) fn count_branch(&mut self, taken: bool) {
+        self.count(|metrics| match taken {
+            true => metrics.branches_taken += 1,
+            false => metrics.branches_not_taken += 1,
+        });
+    }
+
+    pub(crate) fn count(&mut self, update: impl FnOnce(&mut Metrics)) {
+        if let Some(metrics) = &mut self.metrics {
+            update(metrics);
+        }
+    }
+
+    pub(crate) fn notify_instruction(&mut self) {
+        self.count(|metrics| metrics.instructions += 1);
+        let address = self.peek_register(Register::InstructionPointer);
+        self.notify(|observer, cpu| observer.instruction(cpu, address));
+    }
+
+    pub(crate) fn notify(&mut self, event: impl Fn(&mut dyn Observer, &Cpu)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in &mut observers {
+            event(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
+}
+
+/// Writes a `debugger::trace_line` before every instruction
+pub struct Tracer<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> Tracer<W> {
+    pub fn new(out: W) -> Tracer<W> {
+        Tracer { out }
+    }
+}
+
+impl<W: Write + Send> Observer for Tracer<W> {
+    fn instruction(&mut self, cpu: &Cpu, _address: u16) {
+        // Tracing is best effort, a full disk shouldn't stop the program
+        let _ = writeln!(self.out, "{}", trace_line(cpu));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A guest access to a watched word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Instructions executed before the one that made the access
+    pub instruction: u64,
+    pub address: u16,
+    pub value: u16,
+    pub access: Access,
+}
+
+/// Records every guest read and write of the watched addresses. Clones
+/// share the record, so keep one to look at the hits.
+#[derive(Clone, Default)]
+pub struct Watchpoints {
+    addresses: BTreeSet<u16>,
+    hits: Arc<Mutex<Vec<WatchHit>>>,
+}
+
+impl Watchpoints {
+    pub fn new(addresses: impl IntoIterator<Item = u16>) -> Watchpoints {
+        Watchpoints {
+            addresses: addresses.into_iter().collect(),
+            hits: Arc::default(),
+        }
+    }
+
+    /// Hands over the hits recorded since the last call
+    pub fn take_hits(&self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits.lock().unwrap())
+    }
+
+    fn record(&mut self, cpu: &Cpu, address: u16, value: u16, access: Access) {
+        // Words are watched by their first byte, but either byte counts
+        let watched =
+            self.addresses.contains(&address) || self.addresses.contains(&address.wrapping_add(1));
+        if watched {
+            self.hits.lock().unwrap().push(WatchHit {
+                instruction: cpu.instruction_count(),
+                address,
+                value,
+                access,
+            });
+        }
+    }
+}
+
+impl Observer for Watchpoints {
+    fn read(&mut self, cpu: &Cpu, address: u16, value: u16) {
+        self.record(cpu, address, value, Access::Read);
+    }
+
+    fn write(&mut self, cpu: &Cpu, address: u16, value: u16) {
+        self.record(cpu, address, value, Access::Write);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Metrics, Observer, Tracer, WatchHit, Watchpoints};
+    use crate::bench::standard_workload;
+    use crate::cpu::{Cpu, Instruction, Register, StopReason, INTERRUPT_VECTOR_ADDRESS};
+    use crate::memory::Memory;
+    use crate::timer::Timer;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn watches_reads_and_writes() {
+        // mov 0x1234, r1
+        // mov r1, #0080
+        // mov #007f, r2 ;; overlaps the watched word
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x00, Instruction::MovLitReg as u8);
+        memory.set_word(0x01, 0x1234);
+        memory.set_byte(0x03, Register::Register1 as u8);
+        memory.set_byte(0x04, Instruction::MovRegMem as u8);
+        memory.set_byte(0x05, Register::Register1 as u8);
+        memory.set_word(0x06, 0x0080);
+        memory.set_byte(0x08, Instruction::MovMemReg as u8);
+        memory.set_word(0x09, 0x007f);
+        memory.set_byte(0x0b, Register::Register2 as u8);
+
+        let watchpoints = Watchpoints::new([0x0080]);
+        let mut cpu = Cpu::new(memory);
+        cpu.attach_observer(watchpoints.clone());
+        assert_eq!(cpu.run(3), StopReason::FuelExhausted);
+
+        assert_eq!(
+            watchpoints.take_hits(),
+            [
+                WatchHit {
+                    instruction: 1,
+                    address: 0x0080,
+                    value: 0x1234,
+                    access: Access::Write
+                },
+                WatchHit {
+                    instruction: 2,
+                    address: 0x007f,
+                    value: 0x0012,
+                    access: Access::Read
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_what_the_program_does() {
+        let mut cpu = Cpu::new(standard_workload());
+        cpu.run(10);
+        assert_eq!(cpu.metrics(), Metrics::default(), "Off until enabled");
+
+        let mut cpu = Cpu::new(standard_workload());
+        cpu.enable_metrics();
+        // Into the loop, then two rounds of it, each calling the subroutine
+        // once
+        cpu.run(1 + 2 * 12);
+        let metrics = cpu.metrics();
+        assert_eq!(metrics.instructions, 25);
+        assert_eq!(metrics.calls, 2);
+        assert_eq!(metrics.branches_taken, 2);
+        assert_eq!(metrics.branches_not_taken, 0);
+        // Two pushes, the 10 words the call saves and the store each round
+        assert_eq!(metrics.writes, 2 * 13);
+        assert!(metrics.max_stack_depth > 0);
+        assert_eq!(metrics.interrupts, 0);
+    }
+
+    #[test]
+    fn measures_interrupt_latency() {
+        // nop ... ;; with a timer raising interrupt 2 every 3 cycles
+        // At 0x0100, the handler:
+        //   rti
+        let mut cpu = Cpu::builder()
+            .memory_size(0x1100)
+            .device("timer", Timer::new(), 0x1080, 0x1083)
+            .build()
+            .unwrap();
+        cpu.tick_devices_every(1);
+        cpu.memory_mut().set_byte(0x0100, Instruction::RetInt as u8);
+        cpu.memory_mut()
+            .set_word(INTERRUPT_VECTOR_ADDRESS + 2 * 2, 0x0100);
+        cpu.memory_mut().set_word(0x1082, 2);
+        cpu.memory_mut().set_word(0x1080, 3);
+        cpu.enable_metrics();
+
+        cpu.step_n(12).unwrap();
+        let metrics = cpu.metrics();
+        let latency = metrics.interrupt_latency[2];
+        assert_eq!(metrics.interrupts, 3);
+        assert_eq!((latency.count, latency.min, latency.max), (3, 1, 1));
+        assert_eq!(latency.average(), Some(1.0));
+        assert_eq!(metrics.interrupt_latency[0].average(), None);
+        assert!(
+            metrics
+                .to_string()
+                .contains("latency of 0x2    min 1 avg 1.0 max 1 cycles"),
+            "{}",
+            metrics
+        );
+    }
+
+    struct Counter(Arc<Mutex<u64>>);
+
+    impl Observer for Counter {
+        fn instruction(&mut self, _cpu: &Cpu, _address: u16) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn every_engine_reports_every_instruction() {
+        let mut cpu = Cpu::new(standard_workload());
+        let count = Arc::new(Mutex::new(0));
+        cpu.attach_observer(Counter(Arc::clone(&count)));
+
+        cpu.run(100);
+        cpu.run_cached(100);
+        assert_eq!(*count.lock().unwrap(), 200);
+    }
+
+    #[test]
+    fn traces_instructions() {
+        let mut cpu = Cpu::new(standard_workload());
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        cpu.attach_observer(Tracer::new(Shared(Arc::clone(&trace))));
+        cpu.step_n(3).unwrap();
+
+        let trace = String::from_utf8(trace.lock().unwrap().clone()).unwrap();
+        assert_eq!(trace.lines().count(), 3);
+        assert!(trace.starts_with("0x0000"), "{}", trace);
+    }
+}
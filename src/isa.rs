@@ -0,0 +1,205 @@
+use crate::cpu::Cpu;
+use std::fmt::Display;
+use std::ops::BitOr;
+
+/// Version of the instruction set the CPU runs. Versions only ever add to
+/// the instruction set, so images built for a version run on later ones.
+pub const ISA_VERSION: u8 = 1;
+
+/// Bytes `CpuBuilder::isa_info` maps: the ISA version as a word, then the
+/// feature word, see `Features`
+pub const ISA_INFO_SIZE: usize = 4;
+
+/// Optional extensions to the instruction set, the bits of the feature
+/// word guests read
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Features(u16);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Condition flags, and instructions that set and test them
+    pub const FLAGS: Features = Features(1 << 0);
+    /// Interrupts, raised by devices or `Int` and left with `RetInt`
+    pub const INTERRUPTS: Features = Features(1 << 1);
+    /// A floating point coprocessor, usually custom instructions, see
+    /// `Cpu::register_instruction`
+    pub const FLOATING_POINT: Features = Features(1 << 2);
+
+    /// What every CPU of this version has
+    pub const BUILT_IN: Features = Features::INTERRUPTS;
+
+    const NAMES: [(Features, &'static str); 3] = [
+        (Features::FLAGS, "flags"),
+        (Features::INTERRUPTS, "interrupts"),
+        (Features::FLOATING_POINT, "floating point"),
+    ];
+
+    pub const fn from_bits(bits: u16) -> Features {
+        Features(bits)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// What of `required` these features lack
+    pub fn missing(self, required: Features) -> Features {
+        Features(required.0 & !self.0)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl Display for Features {
+    /// Names the features, and bits no version has yet by number
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        let mut names: Vec<String> = Features::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| name.to_string())
+            .collect();
+        let known = Features::NAMES
+            .iter()
+            .fold(Features::NONE, |known, (feature, _)| known | *feature);
+        let unknown = known.missing(*self).bits();
+        names.extend(
+            (0..16)
+                .filter(|bit| unknown & 1 << bit != 0)
+                .map(|bit| format!("feature bit {}", bit)),
+        );
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// Why a machine can't run an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsaError {
+    /// The image needs a later version of the instruction set than
+    /// `ISA_VERSION`
+    Version(u8),
+    /// The image needs extensions the machine doesn't have
+    Missing(Features),
+}
+
+impl Display for IsaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IsaError::Version(version) => write!(
+                f,
+                "Needs version {} of the instruction set, this is version {}",
+                version, ISA_VERSION
+            ),
+            IsaError::Missing(features) => write!(f, "Needs {}, which the machine lacks", features),
+        }
+    }
+}
+
+impl std::error::Error for IsaError {}
+
+/// Whether a machine with `features` runs images built for ISA `version`
+/// that need `required`, for loaders to check before loading them
+pub fn check(features: Features, version: u8, required: Features) -> Result<(), IsaError> {
+    if version > ISA_VERSION {
+        return Err(IsaError::Version(version));
+    }
+    match features.missing(required) {
+        missing if missing.is_empty() => Ok(()),
+        missing => Err(IsaError::Missing(missing)),
+    }
+}
+
+impl Cpu {
+    /// Extensions the machine has, built in or declared with
+    /// `CpuBuilder::features`
+    pub fn features(&self) -> Features {
+        self.features
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Features, IsaError, ISA_VERSION};
+    use crate::cpu::{Cpu, Instruction, Register};
+
+    #[test]
+    fn refuses_what_the_machine_lacks() {
+        let features = Features::BUILT_IN | Features::FLOATING_POINT;
+        assert_eq!(
+            check(features, ISA_VERSION, Features::FLOATING_POINT),
+            Ok(())
+        );
+        assert_eq!(check(features, 0, Features::NONE), Ok(()));
+        assert_eq!(
+            check(features, ISA_VERSION + 1, Features::NONE),
+            Err(IsaError::Version(ISA_VERSION + 1))
+        );
+        let error = check(
+            features,
+            ISA_VERSION,
+            Features::FLAGS | Features::INTERRUPTS | Features::from_bits(0x8000),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            IsaError::Missing(Features::FLAGS | Features::from_bits(0x8000))
+        );
+        assert_eq!(
+            error.to_string(),
+            "Needs flags, feature bit 15, which the machine lacks"
+        );
+    }
+
+    #[test]
+    fn guests_read_the_feature_word() {
+        // mov [0x1ffc], r1
+        // mov [0x1ffe], r2
+        let code = [
+            Instruction::MovMemReg as u8,
+            0x1f,
+            0xfc,
+            Register::Register1 as u8,
+            Instruction::MovMemReg as u8,
+            0x1f,
+            0xfe,
+            Register::Register2 as u8,
+        ];
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .stack_top(0x1ff0)
+            .features(Features::FLOATING_POINT)
+            .isa_info(0x1ffc)
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+        assert_eq!(
+            cpu.features(),
+            Features::BUILT_IN | Features::FLOATING_POINT
+        );
+
+        cpu.step_n(2).unwrap();
+        assert_eq!(cpu.peek_register(Register::Register1), ISA_VERSION as u16);
+        assert_eq!(
+            cpu.peek_register(Register::Register2),
+            cpu.features().bits()
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use crate::block_cache::DecodedInstruction;
+use crate::cpu::{Cpu, Instruction, Register, StopReason};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, InstBuilder, MemFlagsData, Signature, UserFuncName, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use std::collections::HashMap;
+
+/// Times a block is entered before it is compiled
+const HOT_THRESHOLD: u32 = 16;
+
+/// Compiled block. Takes the register file and the CPU, returns the number
+/// of instructions retired.
+type NativeCode = unsafe extern "C" fn(registers: *mut u16, cpu: *mut Cpu) -> u64;
+
+#[derive(Clone, Copy)]
+struct NativeBlock {
+    code: NativeCode,
+    /// Most instructions a call can retire
+    length: usize,
+}
+
+enum Tier {
+    Interpreted(u32),
+    Native(NativeBlock),
+    /// The first instruction of the block cannot be compiled
+    Uncompilable,
+}
+
+/// Compiles hot basic blocks to native code with cranelift
+pub(crate) struct Jit {
+    module: JITModule,
+    context: Context,
+    builder_context: FunctionBuilderContext,
+    tiers: HashMap<usize, Tier>,
+    /// Block cache generation the tiers were built against
+    generation: u64,
+}
+
+impl Jit {
+    fn new() -> Jit {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").unwrap();
+        flags.set("is_pic", "false").unwrap();
+        let isa = cranelift_native::builder()
+            .expect("Host machine is not supported by cranelift")
+            .finish(settings::Flags::new(flags))
+            .unwrap();
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        Jit {
+            context: module.make_context(),
+            module,
+            builder_context: FunctionBuilderContext::new(),
+            tiers: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    fn native_block(&mut self, cpu: &mut Cpu, start: usize) -> Option<NativeBlock> {
+        // Code was overwritten since the tiers were built
+        if self.generation != cpu.block_cache.generation {
+            self.generation = cpu.block_cache.generation;
+            self.tiers.clear();
+        }
+
+        let tier = self.tiers.entry(start).or_insert(Tier::Interpreted(0));
+        match tier {
+            Tier::Native(native) => return Some(*native),
+            Tier::Uncompilable => return None,
+            Tier::Interpreted(hits) if *hits + 1 < HOT_THRESHOLD => {
+                *hits += 1;
+                return None;
+            }
+            Tier::Interpreted(_) => {}
+        }
+
+        let instructions = cpu.cached_block(start)?;
+        let tier = match self.compile(&instructions, |address| {
+            let address = address as usize;
+            cpu.in_bounds(address, 2)
+                && !cpu.in_register_window(address, 2)
+                && !cpu.in_counters(address, 2)
+                && !cpu.is_misaligned(address)
+                && cpu.memory.wait_states(address, false) == 0
+                && cpu.memory.wait_states(address, true) == 0
+        }) {
+            Some(native) => Tier::Native(native),
+            None => Tier::Uncompilable,
+        };
+        let native = match tier {
+            Tier::Native(native) => Some(native),
+            _ => None,
+        };
+        self.tiers.insert(start, tier);
+        native
+    }
+
+    fn compile(
+        &mut self,
+        instructions: &[DecodedInstruction],
+        in_memory: impl Fn(u16) -> bool,
+    ) -> Option<NativeBlock> {
+        let length = instructions
+            .iter()
+            .position(|instruction| !compilable(instruction, &in_memory))
+            .unwrap_or(instructions.len());
+        if length == 0 {
+            return None;
+        }
+        let instructions = &instructions[..length];
+
+        let pointer = self.module.target_config().pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I64));
+
+        let id = self
+            .module
+            .declare_anonymous_function(&signature)
+            .expect("Declaring a native block");
+        self.context.func.signature = signature;
+        self.context.func.name = UserFuncName::user(0, id.as_u32());
+
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let registers = builder.block_params(entry)[0];
+        let cpu = builder.block_params(entry)[1];
+
+        let mut emitter = Emitter {
+            builder,
+            registers,
+            cpu,
+            pointer,
+        };
+        for (index, instruction) in instructions.iter().enumerate() {
+            emitter.instruction(instruction, index);
+        }
+        if !matches!(
+            instructions[length - 1].info.instruction,
+            Instruction::JmpNotEq
+        ) {
+            // Leave the rest of the block to the interpreter
+            emitter.exit(instructions[length - 1].next, length as u64);
+        }
+        emitter.builder.seal_all_blocks();
+        emitter.builder.finalize(self.module.target_config());
+
+        self.module
+            .define_function(id, &mut self.context)
+            .expect("Compiling a native block");
+        self.module.clear_context(&mut self.context);
+        self.module
+            .finalize_definitions()
+            .expect("Linking a native block");
+
+        let code = self.module.get_finalized_function(id);
+        Some(NativeBlock {
+            // SAFETY: the function was built with the `NativeCode` signature
+            code: unsafe { std::mem::transmute::<*const u8, NativeCode>(code) },
+            length,
+        })
+    }
+}
+
+/// Whether `instruction` has a native form. Jumps through `mov` and memory
+/// accesses that may run off the end of memory, reach the register window
+/// or stall, as told by `in_memory`, stay in the interpreter.
+fn compilable(instruction: &DecodedInstruction, in_memory: impl Fn(u16) -> bool) -> bool {
+    let [first, second, _] = instruction.operands;
+    let writes_ip = |register: u16| register == Register::InstructionPointer as u16;
+
+    match instruction.info.instruction {
+        Instruction::Noop | Instruction::AddRegReg | Instruction::JmpNotEq => true,
+        Instruction::MovLitReg | Instruction::MovRegReg => !writes_ip(second),
+        Instruction::MovMemReg => in_memory(first) && !writes_ip(second),
+        Instruction::MovRegMem => in_memory(second),
+        _ => false,
+    }
+}
+
+/// Emits the native form of single instructions
+struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    registers: Value,
+    cpu: Value,
+    pointer: types::Type,
+}
+
+impl Emitter<'_> {
+    /// Emits `instruction`, the `index`th of the block
+    fn instruction(&mut self, instruction: &DecodedInstruction, index: usize) {
+        let [first, second, _] = instruction.operands;
+
+        match instruction.info.instruction {
+            Instruction::Noop => {}
+            Instruction::MovLitReg => {
+                let value = self.builder.ins().iconst(types::I16, first as i64);
+                self.set_register(second, value);
+            }
+            Instruction::MovRegReg => {
+                let value = self.register(first);
+                self.set_register(second, value);
+            }
+            Instruction::MovMemReg => {
+                let address = self.builder.ins().iconst(types::I32, first as i64);
+                let value = self.call(read_word as *const () as usize, &[address], types::I32);
+                let value = self.builder.ins().ireduce(types::I16, value);
+                self.set_register(second, value);
+            }
+            Instruction::MovRegMem => {
+                let value = self.register(first);
+                let value = self.builder.ins().uextend(types::I32, value);
+                let address = self.builder.ins().iconst(types::I32, second as i64);
+                let invalidated = self.call(
+                    write_word as *const () as usize,
+                    &[address, value],
+                    types::I32,
+                );
+
+                // The write hit cached code, which may include this block
+                let exit = self.builder.create_block();
+                let next = self.builder.create_block();
+                self.builder.ins().brif(invalidated, exit, &[], next, &[]);
+                self.builder.switch_to_block(exit);
+                self.exit(instruction.next, index as u64 + 1);
+                self.builder.switch_to_block(next);
+            }
+            Instruction::AddRegReg => {
+                let value1 = self.register(first);
+                let value1 = self.builder.ins().uextend(types::I32, value1);
+                let value2 = self.register(second);
+                let value2 = self.builder.ins().uextend(types::I32, value2);
+                // The runtime sets the flags like the interpreter
+                let sum = self.call(add as *const () as usize, &[value1, value2], types::I32);
+                let sum = self.builder.ins().ireduce(types::I16, sum);
+                self.set_register(Register::Accumulator as u16, sum);
+            }
+            Instruction::JmpNotEq => {
+                let accumulator = self.register(Register::Accumulator as u16);
+                let not_equal =
+                    self.builder
+                        .ins()
+                        .icmp_imm_u(IntCC::NotEqual, accumulator, first as i64);
+                let target = self.builder.ins().iconst(types::I16, second as i64);
+                let next = self
+                    .builder
+                    .ins()
+                    .iconst(types::I16, instruction.next as u16 as i64);
+                let ip = self.builder.ins().select(not_equal, target, next);
+                self.set_register(Register::InstructionPointer as u16, ip);
+                let retired = self.builder.ins().iconst(types::I64, index as i64 + 1);
+                self.builder.ins().return_(&[retired]);
+            }
+            _ => unreachable!("Instruction is not compilable"),
+        }
+    }
+
+    /// Returns to the interpreter with the instruction pointer at `ip`
+    fn exit(&mut self, ip: usize, result: u64) {
+        // Past 0xffff the instruction pointer wraps around
+        let ip = self.builder.ins().iconst(types::I16, ip as u16 as i64);
+        self.set_register(Register::InstructionPointer as u16, ip);
+        let result = self.builder.ins().iconst(types::I64, result as i64);
+        self.builder.ins().return_(&[result]);
+    }
+
+    fn register(&mut self, register: u16) -> Value {
+        self.builder.ins().load(
+            types::I16,
+            MemFlagsData::new().with_notrap(),
+            self.registers,
+            register as i32 * 2,
+        )
+    }
+
+    fn set_register(&mut self, register: u16, value: Value) {
+        self.builder.ins().store(
+            MemFlagsData::new().with_notrap(),
+            value,
+            self.registers,
+            register as i32 * 2,
+        );
+    }
+
+    /// Calls back into the runtime with the CPU as the first argument
+    fn call(&mut self, function: usize, arguments: &[Value], result: types::Type) -> Value {
+        let mut signature = Signature::new(self.builder.func.signature.call_conv);
+        signature.params.push(AbiParam::new(self.pointer));
+        for _ in arguments {
+            signature.params.push(AbiParam::new(types::I32));
+        }
+        signature.returns.push(AbiParam::new(result));
+        let signature = self.builder.import_signature(signature);
+
+        let callee = self.builder.ins().iconst(self.pointer, function as i64);
+        let mut values = vec![self.cpu];
+        values.extend_from_slice(arguments);
+        let call = self.builder.ins().call_indirect(signature, callee, &values);
+        self.builder.inst_results(call)[0]
+    }
+}
+
+/// # Safety
+/// `cpu` points to the CPU running the block
+unsafe extern "C" fn read_word(cpu: *mut Cpu, address: u32) -> u32 {
+    let cpu = unsafe { &mut *cpu };
+    cpu.memory.get_word(address as usize) as u32
+}
+
+/// Adds two words wrapping around, setting the flags
+///
+/// # Safety
+/// `cpu` points to the CPU running the block
+unsafe extern "C" fn add(cpu: *mut Cpu, value1: u32, value2: u32) -> u32 {
+    let cpu = unsafe { &mut *cpu };
+    cpu.add_with_flags(value1 as u16, value2 as u16) as u32
+}
+
+/// Returns 1 if the write dropped cached code
+///
+/// # Safety
+/// `cpu` points to the CPU running the block
+unsafe extern "C" fn write_word(cpu: *mut Cpu, address: u32, value: u32) -> u32 {
+    let cpu = unsafe { &mut *cpu };
+    let generation = cpu.block_cache.generation;
+    // Only addresses in bounds are compiled, so this can't fault
+    let _ = cpu.write_word(address as usize, value as u16);
+    (cpu.block_cache.generation != generation) as u32
+}
+
+impl Cpu {
+    /// Runs like `run_cached`, compiling blocks that are entered often to
+    /// native code. Falls back to the interpreter for instructions it cannot
+    /// compile and while something has to see every instruction, like a
+    /// watchdog or ticked devices.
+    pub fn run_jit(&mut self, fuel: usize) -> StopReason {
+        if self.needs_every_instruction() {
+            return self.run_cached(fuel);
+        }
+
+        let mut jit = self.jit.take().unwrap_or_else(|| Box::new(Jit::new()));
+        let mut executed = 0;
+        let reason = loop {
+            if self.halted {
+                break StopReason::Halted;
+            }
+            if executed >= fuel {
+                break StopReason::FuelExhausted;
+            }
+
+            let ip = self.peek_register(Register::InstructionPointer) as usize;
+            let native = jit
+                .native_block(self, ip)
+                .filter(|native| native.length <= fuel - executed);
+            let Some(native) = native else {
+                match self.run_block(fuel - executed) {
+                    Ok(count) => executed += count,
+                    Err(fault) => break self.stop_reason(fault),
+                }
+                continue;
+            };
+
+            let registers = self.register.as_mut_ptr();
+            // SAFETY: the block only touches the register file and goes
+            // through `read_word` and `write_word` for everything else
+            let retired = unsafe { (native.code)(registers, self) };
+            self.clock.advance_by(retired);
+            executed += retired as usize;
+        };
+
+        self.jit = Some(jit);
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tier;
+    use crate::cpu::{Cpu, Instruction, Register, StopReason};
+    use crate::memory::Memory;
+
+    // start:
+    //   mov 0x0001, r2
+    //   mov [0x0080], r3
+    //   add r3, r2
+    //   mov acc, [0x0080]
+    //   add r1, r2
+    //   mov acc, r1
+    //   jne 0x0100, start:
+    fn counting_loop() -> Memory {
+        let mut memory = Memory::new(256);
+        let program = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x01,
+            Register::Register2 as u8,
+            Instruction::MovMemReg as u8,
+            0x00,
+            0x80,
+            Register::Register3 as u8,
+            Instruction::AddRegReg as u8,
+            Register::Register3 as u8,
+            Register::Register2 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Accumulator as u8,
+            0x00,
+            0x80,
+            Instruction::AddRegReg as u8,
+            Register::Register1 as u8,
+            Register::Register2 as u8,
+            Instruction::MovRegReg as u8,
+            Register::Accumulator as u8,
+            Register::Register1 as u8,
+            Instruction::JmpNotEq as u8,
+            0x01,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.set_byte(i, *byte);
+        }
+        memory
+    }
+
+    #[test]
+    fn run_jit_matches_step_n() {
+        let mut stepped = Cpu::new(counting_loop());
+        let mut jitted = Cpu::new(counting_loop());
+
+        for fuel in [1, 5, 100, 333, 1000] {
+            stepped.step_n(fuel).unwrap();
+            assert_eq!(jitted.run_jit(fuel), StopReason::FuelExhausted);
+            assert_eq!(
+                stepped.registers().collect::<Vec<_>>(),
+                jitted.registers().collect::<Vec<_>>()
+            );
+            assert_eq!(stepped.peek(0x80), jitted.peek(0x80));
+        }
+        assert_eq!(jitted.instruction_count(), 1439);
+
+        let jit = jitted.jit.as_ref().unwrap();
+        assert!(jit
+            .tiers
+            .values()
+            .any(|tier| matches!(tier, Tier::Native(_))));
+    }
+
+    #[test]
+    fn sees_code_replaced_through_memory_mut() {
+        let mut cpu = Cpu::new(counting_loop());
+        cpu.run_jit(700);
+
+        // add r1, r2 becomes add r1, r3
+        cpu.memory_mut().set_byte(17, Register::Register3 as u8);
+        let mut stepped = Cpu::new(counting_loop());
+        stepped.step_n(700).unwrap();
+        stepped.memory_mut().set_byte(17, Register::Register3 as u8);
+
+        cpu.run_jit(700);
+        stepped.step_n(700).unwrap();
+        assert_eq!(
+            stepped.registers().collect::<Vec<_>>(),
+            cpu.registers().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn handles_self_modifying_code() {
+        // start:
+        //   mov 0x0000, r1
+        //   mov r2, start: + 1
+        //   jne 0x0001, start:
+        let mut memory = Memory::new(256);
+        let program = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x00,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register2 as u8,
+            0x00,
+            0x01,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.set_byte(i, *byte);
+        }
+
+        let mut cpu = Cpu::new(memory);
+        for value in 1..50 {
+            cpu.set_register(Register::Register2, value);
+            cpu.run_jit(3);
+            assert_eq!(cpu.peek_register(Register::Register1), value - 1);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::cpu::{Cpu, Register};
+use crate::disassembler::disassemble_one;
+use std::fmt::Write;
+use std::ops::Range;
+
+impl Cpu {
+    /// The machine state as a JSON object, for tools that can't link
+    /// against the crate: the registers by name, the flags, the words on the
+    /// stack innermost first, the bytes of each of `memory` and the
+    /// instruction at the instruction pointer. Reads memory without side
+    /// effects.
+    pub fn to_json(&self, memory: &[Range<usize>]) -> String {
+        let registers: Vec<String> = self
+            .registers()
+            .map(|(_, name, value)| format!("{}: {}", string(name), value))
+            .collect();
+
+        let stack_pointer = self.peek_register(Register::StackPointer) as usize;
+        let stack: Vec<String> = (stack_pointer + 2..=self.stack_top as usize)
+            .step_by(2)
+            .map(|address| self.peek(address).to_string())
+            .collect();
+
+        let ranges: Vec<String> = memory
+            .iter()
+            .map(|range| {
+                format!(
+                    "{{\"start\": {}, \"bytes\": {}}}",
+                    range.start,
+                    bytes(&self.peek_memory(range.start, range.len()))
+                )
+            })
+            .collect();
+
+        let instruction_pointer = self.peek_register(Register::InstructionPointer) as usize;
+        let instruction =
+            disassemble_one(&self.peek_tape(instruction_pointer), instruction_pointer);
+
+        let mut json = String::from("{\n");
+        let mut field = |name: &str, value: String| {
+            let separator = if json.len() > 2 { ",\n" } else { "" };
+            let _ = write!(json, "{}  {}: {}", separator, string(name), value);
+        };
+        field("registers", format!("{{{}}}", registers.join(", ")));
+        field(
+            "flags",
+            format!(
+                "{{\"in_interrupt_handler\": {}}}",
+                self.is_in_interrupt_handler
+            ),
+        );
+        field(
+            "stack",
+            format!(
+                "{{\"top\": {}, \"words\": [{}]}}",
+                self.stack_top,
+                stack.join(", ")
+            ),
+        );
+        field("memory", format!("[{}]", ranges.join(", ")));
+        field(
+            "instruction",
+            format!(
+                "{{\"address\": {}, \"bytes\": {}, \"text\": {}}}",
+                instruction.address,
+                bytes(&instruction.bytes),
+                string(&instruction.text)
+            ),
+        );
+        field(
+            "instructions_executed",
+            self.instruction_count().to_string(),
+        );
+        json.push_str("\n}");
+        json
+    }
+}
+
+pub(crate) fn bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(u8::to_string).collect();
+    format!("[{}]", bytes.join(", "))
+}
+
+pub(crate) fn string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::string;
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(string("mov r1, acc"), "\"mov r1, acc\"");
+        assert_eq!(string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+}
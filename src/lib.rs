@@ -0,0 +1,59 @@
+pub mod assembler;
+#[cfg(feature = "tokio")]
+pub mod async_runner;
+pub mod bench;
+mod block_cache;
+pub mod bus;
+pub mod cartridge;
+pub mod checkpoint;
+#[cfg(feature = "instrument")]
+pub mod chrome_trace;
+pub mod clock;
+pub mod config;
+pub mod console;
+pub mod control_flow;
+pub mod core_dump;
+pub mod counters;
+pub mod cpu;
+pub mod debug_info;
+pub mod debugger;
+pub mod device_schedule;
+pub mod differential;
+pub mod disassembler;
+pub mod extension;
+pub mod front_panel;
+pub mod generator;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod handle;
+pub mod heap;
+#[cfg(feature = "instrument")]
+pub mod instrument;
+pub mod isa;
+#[cfg(feature = "jit")]
+mod jit;
+mod json;
+pub mod mapper;
+pub mod memory;
+pub mod monitor;
+pub mod multicore;
+pub mod patch;
+pub mod printf;
+pub mod profiler;
+pub mod replay;
+pub mod ring;
+pub mod rng;
+pub mod scheduler;
+mod self_modifying;
+pub mod semihosting;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod snapshot;
+pub mod stream;
+pub mod test_runner;
+mod time_slice;
+pub mod timer;
+pub mod turtle;
+#[cfg(feature = "instrument")]
+pub mod vcd;
+pub mod watchdog;
@@ -1,11 +1,953 @@
-mod cpu;
-mod memory;
+use rsll16::assembler::Assembler;
+use rsll16::bench::{self, Engine};
+use rsll16::cartridge::{self, Cartridge};
+use rsll16::console::Console;
+use rsll16::control_flow::control_flow_graph;
+use rsll16::core_dump::{CoreDump, TraceHistory};
+use rsll16::cpu::{Cpu, Instruction, Register, StopReason};
+use rsll16::debug_info::DebugInfo;
+use rsll16::debugger::{self, FaultAt};
+use rsll16::differential::Trace;
+use rsll16::disassembler::{self, disassemble, Syntax};
+use rsll16::front_panel::FrontPanel;
+#[cfg(feature = "gui")]
+use rsll16::gui;
+use rsll16::handle::CpuHandle;
+use rsll16::isa::Features;
+use rsll16::memory::Memory;
+use rsll16::monitor::{monitor_machine, MONITOR_PARKED};
+use rsll16::profiler::Profiler;
+#[cfg(feature = "server")]
+use rsll16::server::Server;
+use rsll16::stream::{StreamInput, StreamOutput, STREAM_INPUT_ADDRESS, STREAM_OUTPUT_ADDRESS};
+use rsll16::test_runner::{run_test, Outcome, TrapPoints};
+use rsll16::turtle::Turtle;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, stdin};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
-use crate::cpu::{Cpu, Instruction, Register};
-use crate::memory::Memory;
-use std::io::stdin;
+/// Instructions `bench` runs unless told otherwise
+const BENCH_INSTRUCTIONS: usize = 50_000_000;
+
+/// Instructions between samples unless told otherwise
+const PROFILE_INTERVAL: usize = 997;
+
+/// Busiest addresses `profile` lists
+const PROFILE_TOP: usize = 10;
+
+/// Instructions `trace` prints unless told otherwise
+const TRACE_INSTRUCTIONS: usize = 20;
+
+/// Trace lines a core file keeps
+const CORE_TRACE: usize = 32;
+
+/// Instructions `step-line` and `next-line` run at most
+const LINE_FUEL: usize = 1_000_000;
+
+/// Frames `console` shows unless told otherwise
+const CONSOLE_FRAMES: usize = 60;
+
+/// Instructions `run` executes unless told otherwise
+const RUN_FUEL: usize = 10_000_000;
+
+/// Instructions a test program may run before it counts as stuck
+const TEST_FUEL: usize = 1_000_000;
+
+/// Where `turtle` maps the turtle
+const TURTLE_ADDRESS: usize = 0xff00;
+
+/// Instructions `turtle` runs unless told otherwise
+const TURTLE_INSTRUCTIONS: usize = 1_000_000;
+
+/// Instructions the monitor runs between checks for whether it parked
+const MONITOR_SLICE: usize = 100_000;
+
+/// How often `watch` looks for a new image
+const WATCH_POLL: Duration = Duration::from_millis(250);
+
+/// Where `serve` listens unless told otherwise
+#[cfg(feature = "server")]
+const SERVER_ADDRESS: &str = "127.0.0.1:1616";
 
 fn main() {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None => step_through_demo(None),
+        Some("step") => {
+            if let Err(message) = run_step(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 step [--debug-info FILE]");
+                process::exit(2);
+            }
+        }
+        Some("bench") => {
+            if let Err(message) = run_bench(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 bench [--engine step|run|cached|jit] [instructions]");
+                process::exit(2);
+            }
+        }
+        Some("profile") => {
+            if let Err(message) = run_profile(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 profile [--interval N] [--collapsed FILE] [instructions]");
+                process::exit(2);
+            }
+        }
+        Some("trace") => {
+            if let Err(message) = run_trace(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 trace [--debug-info FILE] [--core FILE] [instructions]");
+                process::exit(2);
+            }
+        }
+        Some("state") => {
+            if let Err(message) = run_state(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 state [instructions] [START:LENGTH ...]");
+                process::exit(2);
+            }
+        }
+        Some("asm") => {
+            if let Err(message) = run_assembler(args) {
+                eprintln!("{}", message);
+                eprintln!(
+                    "Usage: rsll16 asm <source> <image> [--base ADDR] [--define NAME[=VALUE] ...]"
+                );
+                process::exit(2);
+            }
+        }
+        Some("disasm") => {
+            if let Err(message) = run_disassembler(args) {
+                eprintln!("{}", message);
+                eprintln!(
+                    "Usage: rsll16 disasm <image> [--base ADDR] [--syntax native|intel|att|json]"
+                );
+                process::exit(2);
+            }
+        }
+        Some("cfg") => {
+            if let Err(message) = run_cfg(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 cfg <image> [--base ADDR] [--entry ADDR ...]");
+                process::exit(2);
+            }
+        }
+        #[cfg(feature = "server")]
+        Some("serve") => {
+            if let Err(message) = run_server(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 serve [ADDRESS:PORT]");
+                process::exit(2);
+            }
+        }
+        Some("core") => {
+            if let Err(message) = run_core(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 core <core file> [--debug-info FILE]");
+                process::exit(2);
+            }
+        }
+        Some("console") => {
+            if let Err(message) = run_console(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 console <game.rom> [frames]");
+                process::exit(2);
+            }
+        }
+        #[cfg(feature = "gui")]
+        Some("gui") => {
+            if let Err(message) = run_gui(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 gui <game.rom>");
+                process::exit(2);
+            }
+        }
+        Some("test") => match run_tests(args) {
+            Ok(true) => {}
+            Ok(false) => process::exit(1),
+            Err(message) => {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 test <directory>");
+                process::exit(2);
+            }
+        },
+        Some("turtle") => {
+            if let Err(message) = run_turtle(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 turtle <image> [instructions]");
+                process::exit(2);
+            }
+        }
+        Some("panel") => {
+            if let Err(message) = run_panel(args) {
+                eprintln!("{}", message);
+                eprintln!("Usage: rsll16 panel [image]");
+                process::exit(2);
+            }
+        }
+        Some("run") => {
+            if let Err(message) = run_image(args) {
+                eprintln!("{}", message);
+                eprintln!(
+                    "Usage: rsll16 run <image> [--fuel N] [--filter] [--debug] [-- ARGUMENTS...]"
+                );
+                process::exit(2);
+            }
+        }
+        Some("monitor") => {
+            if let Err(message) = run_monitor() {
+                eprintln!("{}", message);
+                process::exit(1);
+            }
+        }
+        Some("watch") => {
+            if let Err(message) = run_watch(args) {
+                eprintln!("{}", message);
+                eprintln!(
+                    "Usage: rsll16 watch <image> [--debug-info FILE] [--break SCOPE ...] [--run]"
+                );
+                process::exit(2);
+            }
+        }
+        Some("diff") => {
+            if let Err(message) = run_diff(args) {
+                eprintln!("{}", message);
+                process::exit(1);
+            }
+        }
+        Some(command) => {
+            eprintln!("Unknown command: {}", command);
+            process::exit(2);
+        }
+    }
+}
+
+/// Runs the standard workload and reports instructions per second
+fn run_bench(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut engines = Vec::new();
+    let mut instructions = BENCH_INSTRUCTIONS;
+    while let Some(arg) = args.next() {
+        if arg == "--engine" {
+            let name = args.next().ok_or("--engine needs a name")?;
+            engines.push(name.parse::<Engine>()?);
+        } else {
+            instructions = arg
+                .parse()
+                .map_err(|_| format!("Not an instruction count: {}", arg))?;
+        }
+    }
+    if engines.is_empty() {
+        engines.push(Engine::Run);
+    }
+
+    for engine in engines {
+        println!("{}", bench::measure(engine, instructions));
+    }
+    Ok(())
+}
+
+/// Replays a trace recorded by the JavaScript VM and reports where this VM
+/// disagrees with it
+fn run_diff(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or("Usage: rsll16 diff <trace>")?;
+    let source = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let trace: Trace = source.parse()?;
+    let steps = trace
+        .compare()
+        .map_err(|divergence| divergence.to_string())?;
+    println!("{} steps match", steps);
+    Ok(())
+}
+
+/// Profiles the standard workload, listing the busiest addresses and
+/// optionally writing collapsed stacks for a flame graph
+fn run_profile(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut interval = PROFILE_INTERVAL;
+    let mut collapsed = None;
+    let mut instructions = BENCH_INSTRUCTIONS;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => {
+                let value = args.next().ok_or("--interval needs a number")?;
+                interval = value
+                    .parse()
+                    .ok()
+                    .filter(|interval| *interval > 0)
+                    .ok_or(format!("Not an interval: {}", value))?;
+            }
+            "--collapsed" => collapsed = Some(args.next().ok_or("--collapsed needs a file")?),
+            _ => {
+                instructions = arg
+                    .parse()
+                    .map_err(|_| format!("Not an instruction count: {}", arg))?
+            }
+        }
+    }
+
+    let mut cpu = Cpu::new(bench::standard_workload());
+    let mut profiler = Profiler::new(interval);
+    cpu.run_profiled(instructions, &mut profiler);
+
+    let mut busiest: Vec<_> = profiler.histogram().iter().collect();
+    busiest.sort_by(|a, b| b.1.cmp(a.1));
+    let samples = profiler.samples();
+    for (address, count) in busiest.into_iter().take(PROFILE_TOP) {
+        println!(
+            "0x{:04x}  {:>6.2}%  {}",
+            address,
+            *count as f64 * 100.0 / samples as f64,
+            count
+        );
+    }
+
+    if let Some(path) = collapsed {
+        let mut file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+        profiler
+            .write_collapsed(&mut file)
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// Prints a trace line for every instruction of the demo program, with
+/// source locations if there is debug info. Can write a core file on fault.
+fn run_trace(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut instructions = TRACE_INSTRUCTIONS;
+    let mut debug_info = None;
+    let mut core = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug-info" => {
+                let path = args.next().ok_or("--debug-info needs a file")?;
+                debug_info = Some(read_debug_info(&path)?);
+            }
+            "--core" => core = Some(args.next().ok_or("--core needs a file")?),
+            _ => {
+                instructions = arg
+                    .parse()
+                    .map_err(|_| format!("Not an instruction count: {}", arg))?
+            }
+        }
+    }
+
+    let mut cpu = Cpu::new(demo_program());
+    let mut history = TraceHistory::new(if core.is_some() { CORE_TRACE } else { 0 });
+    let mut previous = registers(&cpu);
+    for _ in 0..instructions {
+        match &debug_info {
+            Some(info) => println!("{}", debugger::source_trace_line(&cpu, info)),
+            None => println!("{}", debugger::trace_line(&cpu)),
+        }
+        history.record(&cpu);
+        let before = registers(&cpu);
+        if let Err(fault) = debugger::step(&mut cpu) {
+            print_fault(&cpu, fault, &previous, debug_info.as_ref());
+            if let Some(path) = &core {
+                let dump = CoreDump::capture(&cpu, fault, &history);
+                fs::write(path, dump.to_string()).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            break;
+        }
+        previous = before;
+    }
+    Ok(())
+}
+
+/// Shows a core file: the fault, the instructions that led to it and the
+/// machine as it was
+fn run_core(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut core = None;
+    let mut debug_info = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug-info" => {
+                let path = args.next().ok_or("--debug-info needs a file")?;
+                debug_info = Some(read_debug_info(&path)?);
+            }
+            _ => core = Some(arg),
+        }
+    }
+    let path = core.ok_or("Missing the core file")?;
+    let source = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let dump: CoreDump = source.parse().map_err(|e| format!("{}: {}", path, e))?;
+
+    let cpu = dump.restore();
+    for line in &dump.trace {
+        println!("{}", line);
+    }
+    print_fault(&cpu, dump.fault, &[], debug_info.as_ref());
+    Ok(())
+}
+
+/// Runs the demo program, then prints the machine state as JSON with the
+/// given memory ranges, in hex
+fn run_state(args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut instructions = 0;
+    let mut ranges = Vec::new();
+    for arg in args {
+        match arg.split_once(':') {
+            Some((start, length)) => {
+                let parse = |field: &str| {
+                    usize::from_str_radix(field.trim_start_matches("0x"), 16)
+                        .map_err(|_| format!("Not a memory range: {}", arg))
+                };
+                let start = parse(start)?;
+                ranges.push(start..start + parse(length)?);
+            }
+            None => {
+                instructions = arg
+                    .parse()
+                    .map_err(|_| format!("Not an instruction count: {}", arg))?
+            }
+        }
+    }
+
+    let mut cpu = Cpu::new(demo_program());
+    let result = cpu.step_n(instructions);
+    println!("{}", cpu.to_json(&ranges));
+    result.map_err(|fault| fault.to_string())
+}
+
+/// Prints the control-flow graph of a raw image as Graphviz DOT. Addresses
+/// are in hex, the entry point defaults to the base.
+fn run_cfg(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut image = None;
+    let mut base = 0;
+    let mut entries = Vec::new();
+    let address = |arg: Option<String>, flag: &str| {
+        let arg = arg.ok_or(format!("{} needs an address", flag))?;
+        usize::from_str_radix(arg.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Not an address: {}", arg))
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => base = address(args.next(), "--base")?,
+            "--entry" => entries.push(address(args.next(), "--entry")?),
+            _ => image = Some(arg),
+        }
+    }
+    let path = image.ok_or("Missing the image")?;
+    let code = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    if entries.is_empty() {
+        entries.push(base);
+    }
+
+    print!("{}", control_flow_graph(&code, base, &entries).to_dot());
+    Ok(())
+}
+
+/// Boots a machine into the monitor ROM on stdin and stdout, see
+/// `monitor_rom` for the commands, until stdin runs dry or the machine
+/// halts or faults
+fn run_monitor() -> Result<(), String> {
+    let mut cpu = monitor_machine(io::stdin(), io::stdout()).map_err(|e| e.to_string())?;
+    loop {
+        match cpu.run(MONITOR_SLICE) {
+            StopReason::FuelExhausted => {}
+            StopReason::Halted => return Ok(()),
+            StopReason::Fault(fault) => return Err(fault.to_string()),
+            StopReason::GuestPanic { message, code } => return Err(guest_panic(&message, code)),
+        }
+        if cpu.peek_register(Register::InstructionPointer) as usize == MONITOR_PARKED {
+            return Ok(());
+        }
+    }
+}
+
+/// Assembles a source file into a raw image to load at `--base`, for the
+/// machine profile the `--define`s pick
+fn run_assembler(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut paths = Vec::new();
+    let mut assembler = Assembler::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => {
+                let arg = args.next().ok_or("--base needs an address")?;
+                let base = u16::from_str_radix(arg.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("Not an address: {}", arg))?;
+                assembler = assembler.origin(base);
+            }
+            "--define" => {
+                let arg = args.next().ok_or("--define needs a name")?;
+                let (name, value) = arg.split_once('=').unwrap_or((&arg, "1"));
+                let value = match value.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => value.parse(),
+                }
+                .map_err(|_| format!("Not a value: {}", value))?;
+                assembler = assembler.define(name, value);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [source, image] = paths.as_slice() else {
+        return Err("Needs the source and the image".to_string());
+    };
+    let text = fs::read_to_string(source).map_err(|e| format!("{}: {}", source, e))?;
+    let code = assembler
+        .assemble(&text)
+        .map_err(|e| format!("{}: {}", source, e))?;
+    fs::write(image, code).map_err(|e| format!("{}: {}", image, e))
+}
+
+/// Disassembles a raw image loaded at `--base`, as text in any syntax or
+/// as JSON
+fn run_disassembler(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut image = None;
+    let mut base = 0;
+    // `None` for JSON
+    let mut syntax = Some(Syntax::default());
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => {
+                let arg = args.next().ok_or("--base needs an address")?;
+                base = usize::from_str_radix(arg.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("Not an address: {}", arg))?;
+            }
+            "--syntax" => match args.next().as_deref() {
+                Some("json") => syntax = None,
+                Some(name) => syntax = Some(name.parse::<Syntax>()?),
+                None => return Err("--syntax needs a syntax".to_string()),
+            },
+            _ => image = Some(arg),
+        }
+    }
+    let path = image.ok_or("Missing the image")?;
+    let code = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let lines = disassemble(&code, base);
+    match syntax {
+        Some(syntax) => lines
+            .iter()
+            .for_each(|line| println!("{}", line.listing(syntax))),
+        None => println!("{}", disassembler::to_json(&lines)),
+    }
+    Ok(())
+}
+
+/// Powers on the console with the cartridge at `path`, or a bare image
+fn load_console(path: &str) -> Result<Console, String> {
+    let image = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let cartridge = match image.starts_with(cartridge::MAGIC) {
+        true => Cartridge::from_bytes(&image),
+        false => Cartridge::from_image(&image),
+    }
+    .map_err(|e| format!("{}: {}", path, e))?;
+    // The console has no extensions
+    cartridge
+        .check(Features::BUILT_IN)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Console::with_cartridge(cartridge.into_device()).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Plays a cartridge on the fantasy console and prints the screen after
+/// every frame. Files that aren't cartridges are taken as raw images.
+/// Nobody is holding the gamepad yet.
+fn run_console(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or("Missing the cartridge")?;
+    let frames = match args.next() {
+        Some(frames) => frames
+            .parse()
+            .map_err(|_| format!("Not a number of frames: {}", frames))?,
+        None => CONSOLE_FRAMES,
+    };
+    let mut console = load_console(&path)?;
+
+    for _ in 0..frames {
+        let error = match console.run_frame() {
+            StopReason::FuelExhausted => None,
+            StopReason::Halted => {
+                println!("{}", console.video.render());
+                return Ok(());
+            }
+            StopReason::Fault(fault) => Some(fault.to_string()),
+            StopReason::GuestPanic { message, code } => Some(guest_panic(&message, code)),
+        };
+        if let Some(error) = error {
+            println!("{}", console.video.render());
+            return Err(error);
+        }
+        let tone = console.sound.tone();
+        println!(
+            "Frame {}, tone {} Hz at volume {}",
+            console.video.frame(),
+            tone.frequency,
+            tone.volume
+        );
+        println!("{}", console.video.render());
+    }
+    Ok(())
+}
+
+/// Runs every test program in a directory: each `name.bin` image loaded at
+/// address 0, checked by the trap points in `name.traps`. Returns whether
+/// they all passed.
+fn run_tests(mut args: impl Iterator<Item = String>) -> Result<bool, String> {
+    let directory = args.next().ok_or("Missing the directory")?;
+    let mut images: Vec<_> = fs::read_dir(&directory)
+        .map_err(|e| format!("{}: {}", directory, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bin"))
+        .collect();
+    images.sort();
+
+    let mut failed = 0;
+    for image in &images {
+        let name = image.file_stem().unwrap_or_default().to_string_lossy();
+        let code = fs::read(image).map_err(|e| format!("{}: {}", image.display(), e))?;
+        let traps_path = image.with_extension("traps");
+        let traps: TrapPoints = fs::read_to_string(&traps_path)
+            .map_err(|e| format!("{}: {}", traps_path.display(), e))?
+            .parse()
+            .map_err(|e| format!("{}: {}", traps_path.display(), e))?;
+
+        let mut memory = Memory::new(0x1_0000);
+        for (address, byte) in code.iter().take(memory.byte_length()).enumerate() {
+            memory.set_byte(address, *byte);
+        }
+        match run_test(&mut Cpu::new(memory), &traps, TEST_FUEL) {
+            outcome @ Outcome::Passed { .. } => println!("PASS {}: {}", name, outcome),
+            outcome => {
+                failed += 1;
+                println!("FAIL {}: {}", name, outcome);
+            }
+        }
+    }
+    println!("{} passed, {} failed", images.len() - failed, failed);
+    Ok(failed == 0)
+}
+
+/// Runs a raw image loaded at address 0 with a turtle mapped at
+/// `TURTLE_ADDRESS`, then prints its drawing as SVG
+fn run_turtle(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or("Missing the image")?;
+    let instructions = match args.next() {
+        Some(count) => count
+            .parse()
+            .map_err(|_| format!("Not an instruction count: {}", count))?,
+        None => TURTLE_INSTRUCTIONS,
+    };
+    let image = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let turtle = Turtle::new();
+    let mut cpu = Cpu::builder()
+        .memory_size(TURTLE_ADDRESS)
+        .device("turtle", turtle.clone(), TURTLE_ADDRESS, TURTLE_ADDRESS + 3)
+        .build()
+        .map_err(|e| e.to_string())?;
+    for (address, byte) in image.iter().take(TURTLE_ADDRESS).enumerate() {
+        cpu.memory_mut().set_byte(address, *byte);
+    }
+
+    match cpu.run(instructions) {
+        StopReason::FuelExhausted | StopReason::Halted => {}
+        StopReason::Fault(fault) => eprintln!("{}", fault),
+        StopReason::GuestPanic { message, code } => eprintln!("{}", guest_panic(&message, code)),
+    }
+    print!("{}", turtle.to_svg());
+    Ok(())
+}
+
+/// Drives a CPU from a front panel, one command per line: `switches HEX`,
+/// `examine`, `examine-next`, `deposit`, `deposit-next`, `step` and
+/// `run N`. Memory starts empty unless an image is loaded at address 0.
+fn run_panel(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut memory = Memory::new(0x1_0000);
+    if let Some(path) = args.next() {
+        let image = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+        for (address, byte) in image.iter().take(memory.byte_length()).enumerate() {
+            memory.set_byte(address, *byte);
+        }
+    }
+    let mut cpu = Cpu::new(memory);
+    let mut panel = FrontPanel::new();
+
+    print!("{}", panel.render(&cpu));
+    for line in stdin().lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let mut words = line.split_whitespace();
+        let result = match (words.next(), words.next()) {
+            (Some("switches"), Some(value)) => {
+                match u16::from_str_radix(value.trim_start_matches("0x"), 16) {
+                    Ok(switches) => panel.set_switches(switches),
+                    Err(_) => println!("Not a switch setting: {}", value),
+                }
+                Ok(())
+            }
+            (Some("examine"), None) => {
+                panel.examine(&mut cpu);
+                Ok(())
+            }
+            (Some("examine-next"), None) => {
+                panel.examine_next(&mut cpu);
+                Ok(())
+            }
+            (Some("deposit"), None) => {
+                panel.deposit(&mut cpu);
+                Ok(())
+            }
+            (Some("deposit-next"), None) => {
+                panel.deposit_next(&mut cpu);
+                Ok(())
+            }
+            (Some("step"), None) => panel.step(&mut cpu),
+            (Some("run"), Some(count)) => match count.parse::<usize>() {
+                Ok(count) => (0..count).try_for_each(|_| panel.step(&mut cpu)),
+                Err(_) => {
+                    println!("Not an instruction count: {}", count);
+                    Ok(())
+                }
+            },
+            _ => {
+                println!("Unknown command: {}", line);
+                continue;
+            }
+        };
+        if let Err(fault) = result {
+            println!("{}", fault);
+        }
+        print!("{}", panel.render(&cpu));
+    }
+    Ok(())
+}
+
+/// Runs an image loaded at address 0 with the arguments after `--`, the
+/// image path first, passed as `Cpu::set_arguments` describes. Prints the
+/// registers when it stops, unless `--filter` maps stdin and stdout into
+/// the machine to make it a Unix filter, see `stream`. With `--debug` a
+/// fault opens the debugger at the faulting instruction instead.
+fn run_image(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or("Missing the image")?;
+    let mut fuel = RUN_FUEL;
+    let mut filter = false;
+    let mut debug_faults = false;
+    let mut arguments = vec![path.clone()];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--filter" => filter = true,
+            "--debug" => debug_faults = true,
+            "--fuel" => {
+                let value = args.next().ok_or("--fuel needs a number")?;
+                fuel = value
+                    .parse()
+                    .map_err(|_| format!("Not an instruction count: {}", value))?;
+            }
+            "--" => arguments.extend(args.by_ref()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let image = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let builder = match filter {
+        true => Cpu::builder()
+            .memory_size(STREAM_INPUT_ADDRESS)
+            .device(
+                "stdin",
+                StreamInput::new(io::stdin()),
+                STREAM_INPUT_ADDRESS,
+                STREAM_INPUT_ADDRESS + 1,
+            )
+            .device(
+                "stdout",
+                StreamOutput::new(io::stdout()),
+                STREAM_OUTPUT_ADDRESS,
+                STREAM_OUTPUT_ADDRESS + 1,
+            ),
+        false => Cpu::builder().memory_size(0x1_0000),
+    };
+    let mut cpu = builder.build().map_err(|e| e.to_string())?;
+    let memory = cpu.memory_mut();
+    for (address, byte) in image.iter().take(memory.byte_length()).enumerate() {
+        memory.set_byte(address, *byte);
+    }
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    cpu.set_arguments(&arguments)
+        .map_err(|fault| format!("The arguments don't fit: {}", fault))?;
+
+    if debug_faults {
+        for _ in 0..fuel {
+            let previous = registers(&cpu);
+            if let Err(fault) = debugger::step(&mut cpu) {
+                print_fault(&cpu, fault, &previous, None);
+                debugger::rewind(&mut cpu, fault);
+                println!("\nSuspended at the faulting instruction");
+                debug(cpu, None);
+                return Ok(());
+            }
+        }
+    } else {
+        match cpu.run(fuel) {
+            StopReason::FuelExhausted | StopReason::Halted => {}
+            StopReason::Fault(fault) => eprintln!("{}", fault),
+            StopReason::GuestPanic { message, code } => {
+                eprintln!("{}", guest_panic(&message, code))
+            }
+        }
+    }
+    if !filter {
+        print!("{}", debugger::register_pane(&cpu));
+    }
+    Ok(())
+}
+
+/// Runs an image, loading it again whenever it changes on disk, as when an
+/// assembler rebuilds it. The breakpoints on the scopes named with `--break`
+/// follow them into every new image. Stops at breakpoints and faults, and
+/// with `--run` starts every image that is loaded.
+fn run_watch(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut path = None;
+    let mut debug_info_path = None;
+    let mut scopes = Vec::new();
+    let mut run = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug-info" => {
+                debug_info_path = Some(args.next().ok_or("--debug-info needs a file")?);
+            }
+            "--break" => scopes.push(args.next().ok_or("--break needs a scope")?),
+            "--run" => run = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    let path = path.ok_or("Missing the image")?;
+
+    let handle = CpuHandle::spawn(Cpu::new(Memory::new(0x1_0000)));
+    let mut built = DebugInfo::default();
+    let mut loaded = None;
+    let mut reported = false;
+    loop {
+        // The image may be missing for a moment while it is rewritten
+        let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+        if let Some(modified) = modified.ok().filter(|modified| loaded != Some(*modified)) {
+            loaded = Some(modified);
+            let image = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let rebuilt = match &debug_info_path {
+                Some(debug_info_path) => read_debug_info(debug_info_path)?,
+                None => DebugInfo::default(),
+            };
+            for address in handle.hot_load(&image, &built, &rebuilt) {
+                eprintln!("Dropped the breakpoint at {}", built.symbolize(address));
+            }
+            // Breakpoints are set by name once, then moved along
+            for name in scopes.drain(..) {
+                match rebuilt.scope_named(&name) {
+                    Some(scope) => handle.add_breakpoint(scope.start),
+                    None => eprintln!("No scope named {}", name),
+                }
+            }
+            eprintln!("Loaded {} bytes from {}", image.len(), path);
+            built = rebuilt;
+            reported = false;
+            if run {
+                handle.resume();
+            }
+        }
+
+        if !handle.is_paused() {
+            reported = false;
+        } else if !reported {
+            reported = true;
+            if let Some(fault) = handle.fault() {
+                println!("{}", fault);
+            }
+            handle.inspect(|cpu| {
+                let address = cpu.peek_register(Register::InstructionPointer);
+                println!("Stopped at {}", built.symbolize(address));
+                print!("{}", debugger::register_pane(cpu));
+            });
+        }
+        thread::sleep(WATCH_POLL);
+    }
+}
+
+/// Lets clients drive the demo program over HTTP
+#[cfg(feature = "server")]
+fn run_server(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let address = args.next().unwrap_or_else(|| SERVER_ADDRESS.to_string());
+    let server = Server::bind(&address, Cpu::new(demo_program()))
+        .map_err(|e| format!("{}: {}", address, e))?;
+    eprintln!("Listening on {}", address);
+    server.serve().map_err(|e| e.to_string())
+}
+
+/// Debugs a cartridge in a window
+#[cfg(feature = "gui")]
+fn run_gui(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let path = args.next().ok_or("Missing the cartridge")?;
+    gui::run(load_console(&path)?)
+}
+
+fn read_debug_info(path: &str) -> Result<DebugInfo, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    source.parse().map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Steps through the demo program, by source line if there is debug info
+fn run_step(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let mut debug_info = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug-info" => {
+                let path = args.next().ok_or("--debug-info needs a file")?;
+                debug_info = Some(read_debug_info(&path)?);
+            }
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    step_through_demo(debug_info);
+    Ok(())
+}
+
+fn step_through_demo(debug_info: Option<DebugInfo>) {
+    debug(Cpu::new(demo_program()), debug_info);
+}
+
+/// Enter steps an instruction. With debug info, `step-line` and `next-line`
+/// step a source line, into or over calls. `run` runs until Ctrl-C breaks
+/// back in or the program halts. Faults suspend the program at the faulting instruction, with
+/// the crash report printed, until `quit` or the end of input.
+fn debug(mut cpu: Cpu, debug_info: Option<DebugInfo>) {
+    let mut sources = HashMap::new();
+
+    print_cpu(&cpu, debug_info.as_ref(), &mut sources);
+
+    loop {
+        let mut command = String::new();
+        if stdin().read_line(&mut command).unwrap_or(0) == 0 || command.trim() == "quit" {
+            break;
+        }
+        let previous = registers(&cpu);
+        let result = match (command.trim(), &debug_info) {
+            ("step-line", Some(info)) => debugger::step_line(&mut cpu, info, LINE_FUEL),
+            ("next-line", Some(info)) => debugger::next_line(&mut cpu, info, LINE_FUEL),
+            ("step-line" | "next-line", None) => {
+                println!("Needs --debug-info");
+                continue;
+            }
+            ("run", _) => break_on_ctrl_c(|stop| debugger::run_until_stopped(&mut cpu, stop)).map(
+                |executed| match cpu.is_halted() {
+                    true => println!("Halted after {} instructions", executed),
+                    false => println!("Interrupted after {} instructions", executed),
+                },
+            ),
+            _ => debugger::step(&mut cpu),
+        };
+        if let Err(fault) = result {
+            print_fault(&cpu, fault, &previous, debug_info.as_ref());
+            debugger::rewind(&mut cpu, fault);
+            println!("\nSuspended at the faulting instruction");
+        }
+        print_cpu(&cpu, debug_info.as_ref(), &mut sources);
+    }
+}
+
+fn demo_program() -> Memory {
     let mut memory = Memory::new(256 * 256);
 
     // psh 0x1111
@@ -133,66 +1075,79 @@ fn main() {
 
     memory.set_byte(i, Instruction::Ret as u8);
 
-    let mut cpu = Cpu::new(memory);
+    memory
+}
 
-    print_cpu(&cpu);
+/// Prints the crash report, marking registers that differ from `previous`.
+/// With debug info, also says where in the source the fault happened and
+/// which calls led there.
+/// Set by Ctrl-C while `break_on_ctrl_c` runs
+static CTRL_C: AtomicBool = AtomicBool::new(false);
 
-    loop {
-        stdin().read_line(&mut (String::new())).unwrap();
-        cpu.step();
-        print_cpu(&cpu);
+/// Runs `run` with Ctrl-C setting the flag it's given instead of killing
+/// the process
+#[cfg(unix)]
+fn break_on_ctrl_c<T>(run: impl FnOnce(&AtomicBool) -> T) -> T {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn signal(signal: i32, handler: usize) -> usize;
+    }
+    extern "C" fn on_ctrl_c(_signal: i32) {
+        CTRL_C.store(true, Ordering::Relaxed);
     }
-}
 
-fn print_cpu(cpu: &Cpu) {
-    print_register(cpu, Register::InstructionPointer);
-    print_register(cpu, Register::Accumulator);
-    print_register(cpu, Register::Register1);
-    print_register(cpu, Register::Register2);
-    print_register(cpu, Register::Register3);
-    print_register(cpu, Register::Register4);
-    print_register(cpu, Register::Register5);
-    print_register(cpu, Register::Register6);
-    print_register(cpu, Register::Register7);
-    print_register(cpu, Register::Register8);
+    CTRL_C.store(false, Ordering::Relaxed);
+    // SAFETY: the handler only stores to an atomic, which is signal safe
+    let previous = unsafe { signal(SIGINT, on_ctrl_c as *const () as usize) };
+    let result = run(&CTRL_C);
+    // SAFETY: puts back the handler that was there before
+    unsafe { signal(SIGINT, previous) };
+    result
+}
 
-    print_tape(cpu);
-    print_stack(cpu);
+/// Without signals Ctrl-C still ends the process
+#[cfg(not(unix))]
+fn break_on_ctrl_c<T>(run: impl FnOnce(&AtomicBool) -> T) -> T {
+    run(&CTRL_C)
 }
 
-fn print_register(cpu: &Cpu, register: Register) {
-    println!("0x{:04x}  :: {:?}", cpu.peek_register(register), register);
+/// How a `StopReason::GuestPanic` is reported
+fn guest_panic(message: &str, code: u16) -> String {
+    format!("Guest panicked with code {:#06x}: {}", code, message)
 }
 
-fn print_stack(cpu: &Cpu) {
-    let tape: Vec<u8> = cpu.peek_stack();
-    let mut formatted: Vec<String> = Vec::new();
-    for x in tape {
-        formatted.push(format!("0x{:02x?}", x));
+fn print_fault(
+    cpu: &Cpu,
+    fault: FaultAt,
+    previous: &[(Register, u16)],
+    debug_info: Option<&DebugInfo>,
+) {
+    print!("{}", debugger::crash_report(cpu, fault, previous));
+    if let Some(info) = debug_info {
+        print!("\n{}", debugger::fault_report(cpu, fault, info));
     }
-    let joined = formatted.join(" ");
-    println!(
-        "Stack 0x{:04x} :: {}",
-        cpu.peek_register(Register::StackPointer),
-        joined
-    );
 }
 
-fn print_tape(cpu: &Cpu) {
-    let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
-    let tape: Vec<u8> = cpu.peek_tape(instruction_pointer as usize);
-    let instruction: Instruction = if let Some(x) = tape.get(0) {
-        (*x).into()
-    } else {
-        Instruction::Noop
+fn registers(cpu: &Cpu) -> Vec<(Register, u16)> {
+    cpu.registers()
+        .map(|(register, _, value)| (register, value))
+        .collect()
+}
+
+/// Source files are read once and kept in `sources`, by path
+fn print_cpu(cpu: &Cpu, debug_info: Option<&DebugInfo>, sources: &mut HashMap<String, String>) {
+    print!("{}", debugger::register_pane(cpu));
+    print!("{}", debugger::tape_pane(cpu));
+    print!("{}", debugger::stack_pane(cpu));
+
+    let Some(info) = debug_info else {
+        return;
     };
-    let mut formatted: Vec<String> = Vec::new();
-    for x in tape {
-        formatted.push(format!("0x{:02x?}", x));
-    }
-    let joined = formatted.join(" ");
-    println!(
-        "Tape 0x{:04x} :: {} ::: {:?}",
-        instruction_pointer, joined, instruction
-    );
+    let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
+    if let Some(location) = info.location(instruction_pointer) {
+        let source = sources
+            .entry(location.file.to_string())
+            .or_insert_with(|| fs::read_to_string(location.file).unwrap_or_default());
+        print!("{}", debugger::source_pane(cpu, info, source));
+    }
 }
@@ -0,0 +1,477 @@
+/// Anything that can sit on the memory bus
+pub trait Device: Send {
+    fn get_byte(&mut self, address: usize) -> u8;
+
+    fn set_byte(&mut self, address: usize, value: u8);
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        u16::from_be_bytes([self.get_byte(address), self.get_byte(address + 1)])
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        let be_bytes = value.to_be_bytes();
+        self.set_byte(address, be_bytes[0]);
+        self.set_byte(address + 1, be_bytes[1]);
+    }
+
+    /// Reads a byte without any side effects, for debuggers and frontends
+    fn peek_byte(&self, address: usize) -> u8;
+
+    fn byte_length(&self) -> usize;
+
+    /// Whether `address` is backed by the device, so accessing it is safe
+    fn is_mapped(&self, address: usize) -> bool {
+        address < self.byte_length()
+    }
+
+    /// Advances the device by `cycles` instructions of the CPU driving it,
+    /// pushing the interrupts it raises onto `interrupts`
+    fn tick(&mut self, _cycles: u64, _interrupts: &mut Vec<u16>) {}
+
+    /// Cycles the CPU stalls for when it reads, or writes if `write` is
+    /// set, the word at `address`
+    fn wait_states(&self, _address: usize, _write: bool) -> u64 {
+        0
+    }
+
+    fn peek(&self, address: usize, length: usize) -> Vec<u8> {
+        let end = (address + length).min(self.byte_length());
+        (address.min(end)..end).map(|x| self.peek_byte(x)).collect()
+    }
+
+    /// Like `get_byte`, for callers that have checked the address already.
+    /// `AddressSpace` uses these for regions the device fully covers, so
+    /// devices that override them must never shrink their `byte_length`.
+    ///
+    /// # Safety
+    /// `address` must be less than `byte_length()`.
+    #[cfg(feature = "unchecked")]
+    unsafe fn get_byte_unchecked(&mut self, address: usize) -> u8 {
+        self.get_byte(address)
+    }
+
+    /// # Safety
+    /// `address` must be less than `byte_length()`.
+    #[cfg(feature = "unchecked")]
+    unsafe fn set_byte_unchecked(&mut self, address: usize, value: u8) {
+        self.set_byte(address, value);
+    }
+
+    /// # Safety
+    /// `address + 1` must be less than `byte_length()`.
+    #[cfg(feature = "unchecked")]
+    unsafe fn get_word_unchecked(&mut self, address: usize) -> u16 {
+        self.get_word(address)
+    }
+
+    /// # Safety
+    /// `address + 1` must be less than `byte_length()`.
+    #[cfg(feature = "unchecked")]
+    unsafe fn set_word_unchecked(&mut self, address: usize, value: u16) {
+        self.set_word(address, value);
+    }
+}
+
+impl<D: Device + ?Sized> Device for Box<D> {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        (**self).get_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        (**self).set_byte(address, value);
+    }
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        (**self).get_word(address)
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        (**self).set_word(address, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        (**self).peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        (**self).byte_length()
+    }
+
+    fn is_mapped(&self, address: usize) -> bool {
+        (**self).is_mapped(address)
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        (**self).tick(cycles, interrupts);
+    }
+
+    fn wait_states(&self, address: usize, write: bool) -> u64 {
+        (**self).wait_states(address, write)
+    }
+
+    fn peek(&self, address: usize, length: usize) -> Vec<u8> {
+        (**self).peek(address, length)
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn get_byte_unchecked(&mut self, address: usize) -> u8 {
+        unsafe { (**self).get_byte_unchecked(address) }
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn set_byte_unchecked(&mut self, address: usize, value: u8) {
+        unsafe { (**self).set_byte_unchecked(address, value) }
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn get_word_unchecked(&mut self, address: usize) -> u16 {
+        unsafe { (**self).get_word_unchecked(address) }
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn set_word_unchecked(&mut self, address: usize, value: u16) {
+        unsafe { (**self).set_word_unchecked(address, value) }
+    }
+}
+
+struct Region {
+    device: Box<dyn Device>,
+    start: usize,
+    end: usize,
+    remap: bool,
+    /// The device is large enough for every address of the region, so
+    /// accesses inside the region need no further bounds checks
+    #[cfg(feature = "unchecked")]
+    validated: bool,
+}
+
+/// Maps devices onto address ranges. Regions mapped later take precedence
+/// over the ones mapped earlier.
+#[derive(Default)]
+pub struct AddressSpace {
+    regions: Vec<Region>,
+}
+
+impl AddressSpace {
+    pub fn new() -> AddressSpace {
+        AddressSpace::default()
+    }
+
+    /// Maps `device` to the inclusive range `start..=end`. If `remap` is
+    /// set, the device sees addresses relative to `start`.
+    pub fn map(&mut self, device: impl Device + 'static, start: usize, end: usize, remap: bool) {
+        #[cfg(feature = "unchecked")]
+        let validated = {
+            let highest = if remap { end - start } else { end };
+            highest < device.byte_length()
+        };
+        self.regions.push(Region {
+            device: Box::new(device),
+            start,
+            end,
+            remap,
+            #[cfg(feature = "unchecked")]
+            validated,
+        });
+    }
+
+    fn position(&self, address: usize) -> Option<usize> {
+        self.regions
+            .iter()
+            .rposition(|region| region.start <= address && address <= region.end)
+    }
+
+    /// Whether the word at `address` crosses into another region, so its
+    /// bytes go to different devices
+    fn straddles(&self, address: usize) -> bool {
+        self.position(address) != self.position(address + 1)
+    }
+
+    fn find_region(&self, address: usize) -> usize {
+        match self.position(address) {
+            Some(index) => index,
+            None => panic!("No memory region found for address {:#06x}", address),
+        }
+    }
+
+    fn region(&mut self, address: usize) -> (&mut Box<dyn Device>, usize) {
+        let index = self.find_region(address);
+        let region = &mut self.regions[index];
+        let address = if region.remap {
+            address - region.start
+        } else {
+            address
+        };
+        (&mut region.device, address)
+    }
+
+    /// Like `region`, but only for accesses of `length` bytes that stay
+    /// inside a validated region
+    #[cfg(feature = "unchecked")]
+    fn validated_region(
+        &mut self,
+        address: usize,
+        length: usize,
+    ) -> Option<(&mut Box<dyn Device>, usize)> {
+        let index = self.find_region(address);
+        let region = &mut self.regions[index];
+        if !region.validated || address + length - 1 > region.end {
+            return None;
+        }
+        let address = if region.remap {
+            address - region.start
+        } else {
+            address
+        };
+        Some((&mut region.device, address))
+    }
+}
+
+impl Device for AddressSpace {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        #[cfg(feature = "unchecked")]
+        if let Some((device, address)) = self.validated_region(address, 1) {
+            // SAFETY: the device covers the whole region
+            return unsafe { device.get_byte_unchecked(address) };
+        }
+        let (device, address) = self.region(address);
+        device.get_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        #[cfg(feature = "unchecked")]
+        if let Some((device, address)) = self.validated_region(address, 1) {
+            // SAFETY: the device covers the whole region
+            return unsafe { device.set_byte_unchecked(address, value) };
+        }
+        let (device, address) = self.region(address);
+        device.set_byte(address, value);
+    }
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        if self.straddles(address) {
+            return u16::from_be_bytes([self.get_byte(address), self.get_byte(address + 1)]);
+        }
+        #[cfg(feature = "unchecked")]
+        if let Some((device, address)) = self.validated_region(address, 2) {
+            // SAFETY: the device covers the whole region, both bytes included
+            return unsafe { device.get_word_unchecked(address) };
+        }
+        let (device, address) = self.region(address);
+        device.get_word(address)
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        if self.straddles(address) {
+            let be_bytes = value.to_be_bytes();
+            self.set_byte(address, be_bytes[0]);
+            self.set_byte(address + 1, be_bytes[1]);
+            return;
+        }
+        #[cfg(feature = "unchecked")]
+        if let Some((device, address)) = self.validated_region(address, 2) {
+            // SAFETY: the device covers the whole region, both bytes included
+            return unsafe { device.set_word_unchecked(address, value) };
+        }
+        let (device, address) = self.region(address);
+        device.set_word(address, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        let region = &self.regions[self.find_region(address)];
+        if region.remap {
+            region.device.peek_byte(address - region.start)
+        } else {
+            region.device.peek_byte(address)
+        }
+    }
+
+    fn byte_length(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| region.end + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether a region covers `address` with its device large enough for it
+    fn is_mapped(&self, address: usize) -> bool {
+        let Some(index) = self.position(address) else {
+            return false;
+        };
+        let region = &self.regions[index];
+        match region.remap {
+            true => region.device.is_mapped(address - region.start),
+            false => region.device.is_mapped(address),
+        }
+    }
+
+    /// Ticks every mapped device, in the order they were mapped
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        for region in &mut self.regions {
+            region.device.tick(cycles, interrupts);
+        }
+    }
+
+    fn wait_states(&self, address: usize, write: bool) -> u64 {
+        let Some(region) = self
+            .regions
+            .iter()
+            .rfind(|region| region.start <= address && address <= region.end)
+        else {
+            return 0;
+        };
+        match region.remap {
+            true => region.device.wait_states(address - region.start, write),
+            false => region.device.wait_states(address, write),
+        }
+    }
+}
+
+/// Makes every access to `device` stall the CPU, for slow peripherals
+pub struct WaitStates<D> {
+    device: D,
+    read: u64,
+    write: u64,
+}
+
+impl<D: Device> WaitStates<D> {
+    /// Stalls `read` cycles on every read and `write` on every write
+    pub fn new(device: D, read: u64, write: u64) -> WaitStates<D> {
+        WaitStates {
+            device,
+            read,
+            write,
+        }
+    }
+}
+
+impl<D: Device> Device for WaitStates<D> {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.device.get_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.device.set_byte(address, value);
+    }
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        self.device.get_word(address)
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        self.device.set_word(address, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.device.peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        self.device.byte_length()
+    }
+
+    fn is_mapped(&self, address: usize) -> bool {
+        self.device.is_mapped(address)
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        self.device.tick(cycles, interrupts);
+    }
+
+    fn wait_states(&self, _address: usize, write: bool) -> u64 {
+        match write {
+            true => self.write,
+            false => self.read,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressSpace, Device, WaitStates};
+    use crate::memory::Memory;
+
+    #[test]
+    fn later_regions_take_precedence() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(256), 0x00, 0xff, false);
+        space.map(Memory::new(16), 0x40, 0x4f, true);
+
+        space.set_word(0x40, 0x4243);
+        space.set_word(0x50, 0x5253);
+
+        assert_eq!(space.get_word(0x40), 0x4243);
+        assert_eq!(space.get_word(0x50), 0x5253);
+        assert_eq!(space.byte_length(), 256);
+        assert_eq!(space.peek(0x3f, 3), [0x00, 0x42, 0x43]);
+    }
+
+    #[test]
+    fn slow_regions_declare_wait_states() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(256), 0x00, 0xff, false);
+        space.map(WaitStates::new(Memory::new(16), 3, 5), 0x40, 0x4f, true);
+
+        assert_eq!(space.wait_states(0x3e, false), 0);
+        assert_eq!(space.wait_states(0x40, false), 3);
+        assert_eq!(space.wait_states(0x4e, true), 5);
+        space.set_word(0x40, 0x4243);
+        assert_eq!(space.get_word(0x40), 0x4243);
+    }
+
+    #[cfg(feature = "unchecked")]
+    #[test]
+    fn validates_regions_the_device_covers() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(256), 0x00, 0xff, false);
+        space.map(Memory::new(16), 0x40, 0x4f, true);
+        space.map(Memory::new(16), 0x80, 0x8f, false);
+
+        let validated: Vec<_> = space
+            .regions
+            .iter()
+            .map(|region| region.validated)
+            .collect();
+        assert_eq!(validated, [true, true, false]);
+
+        assert!(space.validated_region(0x4e, 2).is_some());
+        // Straddles the end of the region, so goes the checked way
+        assert!(space.validated_region(0x4f, 2).is_none());
+    }
+
+    #[test]
+    fn knows_which_addresses_are_mapped() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(16), 0x00, 0x0f, false);
+        // Longer than the device backing it
+        space.map(Memory::new(4), 0x20, 0x2f, true);
+
+        assert!(space.is_mapped(0x0f));
+        assert!(!space.is_mapped(0x10));
+        assert!(space.is_mapped(0x23));
+        assert!(!space.is_mapped(0x24));
+    }
+
+    #[test]
+    fn splits_words_across_regions() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(256), 0x00, 0xff, false);
+        space.map(Memory::new(16), 0x40, 0x4f, true);
+
+        space.set_word(0x4f, 0x4f50);
+        space.set_word(0x3f, 0x3f40);
+        assert_eq!(space.get_word(0x4f), 0x4f50);
+        assert_eq!(space.get_word(0x3f), 0x3f40);
+        assert_eq!(space.peek(0x3f, 2), [0x3f, 0x40]);
+        assert_eq!(space.peek(0x4f, 2), [0x4f, 0x50]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No memory region found")]
+    fn panics_on_unmapped_address() {
+        let mut space = AddressSpace::new();
+        space.map(Memory::new(16), 0x00, 0x0f, false);
+        space.get_byte(0x10);
+    }
+}
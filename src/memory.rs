@@ -1,3 +1,4 @@
+use crate::mapper::Device;
 use std::fmt::Debug;
 
 pub struct Memory {
@@ -48,6 +49,31 @@ impl Memory {
         u16::from_be_bytes([slice[offset], slice[offset + 1]])
     }
 
+    /// # Safety
+    /// `offset + 1` must be less than `byte_length()`.
+    #[cfg(feature = "unchecked")]
+    pub unsafe fn get_word_unchecked(&self, offset: usize) -> u16 {
+        debug_assert!(offset + 1 < self.inner.len());
+        unsafe {
+            u16::from_be_bytes([
+                *self.inner.get_unchecked(offset),
+                *self.inner.get_unchecked(offset + 1),
+            ])
+        }
+    }
+
+    /// # Safety
+    /// `offset + 1` must be less than `byte_length()`.
+    #[cfg(feature = "unchecked")]
+    pub unsafe fn set_word_unchecked(&mut self, offset: usize, value: u16) {
+        debug_assert!(offset + 1 < self.inner.len());
+        let [high, low] = value.to_be_bytes();
+        unsafe {
+            *self.inner.get_unchecked_mut(offset) = high;
+            *self.inner.get_unchecked_mut(offset + 1) = low;
+        }
+    }
+
     pub fn peek(&self, offset: usize, length: usize) -> Vec<u8> {
         let slice = self.inner.as_slice();
         let end = if (offset + length) < slice.len() {
@@ -59,6 +85,147 @@ impl Memory {
     }
 }
 
+impl Device for Memory {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        Memory::get_byte(self, address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        Memory::set_byte(self, address, value);
+    }
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        Memory::get_word(self, address)
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        Memory::set_word(self, address, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.inner[address]
+    }
+
+    fn byte_length(&self) -> usize {
+        Memory::byte_length(self)
+    }
+
+    fn peek(&self, address: usize, length: usize) -> Vec<u8> {
+        Memory::peek(self, address, length)
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn get_byte_unchecked(&mut self, address: usize) -> u8 {
+        debug_assert!(address < self.inner.len());
+        unsafe { *self.inner.get_unchecked(address) }
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn set_byte_unchecked(&mut self, address: usize, value: u8) {
+        debug_assert!(address < self.inner.len());
+        unsafe { *self.inner.get_unchecked_mut(address) = value }
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn get_word_unchecked(&mut self, address: usize) -> u16 {
+        unsafe { Memory::get_word_unchecked(self, address) }
+    }
+
+    #[cfg(feature = "unchecked")]
+    unsafe fn set_word_unchecked(&mut self, address: usize, value: u16) {
+        unsafe { Memory::set_word_unchecked(self, address, value) }
+    }
+}
+
+/// Read-only memory, writes are ignored
+pub struct Rom {
+    inner: Memory,
+}
+
+impl Rom {
+    pub fn new(image: Vec<u8>) -> Rom {
+        Rom {
+            inner: Memory { inner: image },
+        }
+    }
+}
+
+impl Device for Rom {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.inner.get_byte(address)
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        self.inner.get_word(address)
+    }
+
+    fn set_word(&mut self, _address: usize, _value: u16) {}
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.inner.peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        self.inner.byte_length()
+    }
+}
+
+/// Bytes in a ROM bank
+pub const BANK_SIZE: usize = 0x4000;
+
+/// Banked read-only memory, for images bigger than the address space. It
+/// shows two banks: bank 0 always comes first, then whichever bank was
+/// selected last, bank 1 at power on. Writing a byte anywhere selects the
+/// bank with that number, wrapping around the banks there are.
+pub struct BankedRom {
+    banks: Vec<Vec<u8>>,
+    selected: usize,
+}
+
+impl BankedRom {
+    /// Panics without banks or with banks that aren't `BANK_SIZE` bytes
+    pub fn new(banks: Vec<Vec<u8>>) -> BankedRom {
+        assert!(!banks.is_empty(), "A banked ROM needs at least one bank");
+        assert!(
+            banks.iter().all(|bank| bank.len() == BANK_SIZE),
+            "Banks have to be {} bytes",
+            BANK_SIZE
+        );
+        BankedRom {
+            selected: 1 % banks.len(),
+            banks,
+        }
+    }
+
+    /// The bank shown after bank 0
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+}
+
+impl Device for BankedRom {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, _address: usize, value: u8) {
+        self.selected = value as usize % self.banks.len();
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        match address {
+            address if address < BANK_SIZE => self.banks[0][address],
+            address => self.banks[self.selected][address - BANK_SIZE],
+        }
+    }
+
+    fn byte_length(&self) -> usize {
+        2 * BANK_SIZE
+    }
+}
+
 impl Debug for Memory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut regs = Vec::new();
@@ -89,6 +256,22 @@ mod tests {
         assert_eq!(value, 0x43);
     }
 
+    #[cfg(feature = "unchecked")]
+    #[test]
+    fn unchecked_operations_match_checked_ones() {
+        use super::Memory;
+        use crate::mapper::Device;
+
+        let mut mem = Memory::new(10);
+        unsafe {
+            mem.set_word_unchecked(8, 0x4243);
+            Device::set_byte_unchecked(&mut mem, 0, 0x11);
+            assert_eq!(Device::get_byte_unchecked(&mut mem, 9), 0x43);
+        }
+        assert_eq!(mem.get_word(8), 0x4243);
+        assert_eq!(mem.get_byte(0), 0x11);
+    }
+
     #[test]
     fn test_byte_size_operations() {
         use super::Memory;
@@ -101,4 +284,21 @@ mod tests {
 
         assert_eq!(value, 0x42);
     }
+
+    #[test]
+    fn switches_banks_on_writes() {
+        use super::{BankedRom, BANK_SIZE};
+        use crate::mapper::Device;
+
+        let banks = (0..3).map(|bank| vec![bank as u8; BANK_SIZE]).collect();
+        let mut rom = BankedRom::new(banks);
+        assert_eq!(rom.get_byte(0), 0);
+        assert_eq!(rom.get_byte(BANK_SIZE), 1);
+
+        rom.set_byte(0x1234, 2);
+        assert_eq!(rom.get_byte(BANK_SIZE), 2);
+        assert_eq!(rom.get_byte(0), 0, "Bank 0 stays put");
+        rom.set_byte(BANK_SIZE, 4);
+        assert_eq!(rom.selected(), 1, "Wraps around");
+    }
 }
@@ -0,0 +1,499 @@
+use crate::config::ConfigError;
+use crate::cpu::{opcode_info, Cpu, Instruction, Operand, Register};
+use crate::memory::Rom;
+use crate::stream::{StreamInput, StreamOutput, STREAM_INPUT_ADDRESS, STREAM_OUTPUT_ADDRESS};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// Where the monitor ROM is mapped, it fills memory from there up to the
+/// stream devices
+pub const MONITOR_ADDRESS: usize = 0xf000;
+
+/// Bytes of monitor ROM
+pub const MONITOR_SIZE: usize = STREAM_INPUT_ADDRESS - MONITOR_ADDRESS;
+
+/// Where the monitor waits for good once its input has run dry
+pub const MONITOR_PARKED: usize = MONITOR_ADDRESS + 4;
+
+/// RAM the monitor keeps its trampolines and scratch bytes in, see
+/// `MONITOR_WORKSPACE_SIZE`
+pub const MONITOR_WORKSPACE: usize = 0xefe0;
+
+pub const MONITOR_WORKSPACE_SIZE: usize = 0x14;
+
+/// `mov [address], acc` followed by `mov r8, ip`, with the address patched
+/// in before calling it
+const LOAD: u16 = MONITOR_WORKSPACE as u16;
+/// `mov acc, [address]` followed by `mov r8, ip`
+const STORE: u16 = LOAD + 8;
+/// Four bytes from `SCRATCH - 2` on, for taking words apart
+const SCRATCH: u16 = LOAD + 0x12;
+
+/// A machine code monitor, the classic way to bring up a bare machine. It
+/// boots from ROM, reads commands from the stream device at
+/// `STREAM_INPUT_ADDRESS` and writes replies to the one at
+/// `STREAM_OUTPUT_ADDRESS`, addresses and bytes in hex:
+///
+/// - `eAAAA` examines the byte at `AAAA`, replying with it in hex
+/// - `dAAAAVV` deposits `VV` at `AAAA`, replying with an empty line
+/// - `jAAAA` jumps to `AAAA`, with the registers as the monitor left them
+///
+/// Anything else between commands, like whitespace, is skipped. A bad
+/// digit abandons the command with a `?`, and so does `ffef` or `ffff`,
+/// where the word the monitor reads or writes would run off the end of
+/// the bus. When the input ends it ends the output and parks at
+/// `MONITOR_PARKED`.
+///
+/// The monitor only needs RAM at `MONITOR_WORKSPACE`, no stack.
+pub fn monitor_rom() -> Rom {
+    let mut image = monitor_program();
+    assert!(image.len() <= MONITOR_SIZE, "The monitor outgrew its ROM");
+    image.resize(MONITOR_SIZE, 0);
+    Rom::new(image)
+}
+
+/// A 64 KiB machine booting into the monitor, talking over `input` and
+/// `output`. The stack starts below the monitor's workspace.
+pub fn monitor_machine(
+    input: impl Read + Send + 'static,
+    output: impl Write + Send + 'static,
+) -> Result<Cpu, ConfigError> {
+    Cpu::builder()
+        .stack_top(MONITOR_WORKSPACE - 2)
+        .entry_point(MONITOR_ADDRESS as u16)
+        .boot_rom(monitor_rom(), MONITOR_ADDRESS)
+        .device(
+            "input",
+            StreamInput::new(input),
+            STREAM_INPUT_ADDRESS,
+            STREAM_INPUT_ADDRESS + 1,
+        )
+        .device(
+            "output",
+            StreamOutput::new(output),
+            STREAM_OUTPUT_ADDRESS,
+            STREAM_OUTPUT_ADDRESS + 1,
+        )
+        .build()
+}
+
+/// An operand for `Program::emit`
+enum Arg {
+    Literal(u16),
+    Register(Register),
+    /// The address of a label, for a literal or address operand
+    Label(String),
+}
+
+use Arg::{Literal, Register as Reg};
+
+fn label(name: &str) -> Arg {
+    Arg::Label(name.to_string())
+}
+
+/// Assembles a program from instructions and labels, just enough
+/// assembler for the monitor
+struct Program {
+    origin: u16,
+    code: Vec<u8>,
+    labels: HashMap<String, u16>,
+    /// Where label addresses go once they are known
+    fixups: Vec<(usize, String)>,
+    /// For the labels `jump_if` and `load` make up
+    skips: usize,
+}
+
+impl Program {
+    fn new(origin: u16) -> Program {
+        Program {
+            origin,
+            code: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            skips: 0,
+        }
+    }
+
+    fn here(&self) -> u16 {
+        self.origin + self.code.len() as u16
+    }
+
+    fn label(&mut self, name: &str) {
+        let previous = self.labels.insert(name.to_string(), self.here());
+        assert!(previous.is_none(), "Label {} is defined twice", name);
+    }
+
+    /// Panics if the operands don't fit the instruction
+    fn emit(&mut self, instruction: Instruction, args: &[Arg]) {
+        let info = opcode_info(instruction as u8).unwrap();
+        assert_eq!(info.operands.len(), args.len(), "{:?}", instruction);
+        self.code.push(instruction as u8);
+        for (operand, arg) in info.operands.iter().zip(args) {
+            match (operand, arg) {
+                (
+                    Operand::Register | Operand::RegisterPointer | Operand::OffsetRegister,
+                    Reg(register),
+                ) => self.code.push(*register as u8),
+                (Operand::Literal | Operand::Address | Operand::FrameOffset, Literal(value)) => {
+                    self.code.extend(value.to_be_bytes())
+                }
+                (Operand::Literal | Operand::Address, Arg::Label(name)) => {
+                    self.fixups.push((self.code.len(), name.clone()));
+                    self.code.extend([0, 0]);
+                }
+                _ => panic!("Wrong operand for {:?}", instruction),
+            }
+        }
+    }
+
+    fn words(&mut self, words: impl IntoIterator<Item = u16>) {
+        for word in words {
+            self.code.extend(word.to_be_bytes());
+        }
+    }
+
+    /// `mov label, ip`
+    fn jump(&mut self, to: &str) {
+        self.emit(
+            Instruction::MovLitReg,
+            &[label(to), Reg(Register::InstructionPointer)],
+        );
+    }
+
+    /// Jumps to `to` if the accumulator holds `value`
+    fn jump_if(&mut self, value: u16, to: &str) {
+        self.skips += 1;
+        let skip = format!("skip {}", self.skips);
+        self.emit(Instruction::JmpNotEq, &[Literal(value), label(&skip)]);
+        self.jump(to);
+        self.label(&skip);
+    }
+
+    /// `mov value, r1` then `mov r1, [address]`
+    fn store(&mut self, value: u16, address: u16) {
+        self.emit(
+            Instruction::MovLitReg,
+            &[Literal(value), Reg(Register::Register1)],
+        );
+        self.emit(
+            Instruction::MovRegMem,
+            &[Reg(Register::Register1), Literal(address)],
+        );
+    }
+
+    /// Loads the word at the address in the accumulator into it, through
+    /// the `LOAD` trampoline
+    fn load(&mut self) {
+        self.skips += 1;
+        let back = format!("back {}", self.skips);
+        self.emit(
+            Instruction::MovRegMem,
+            &[Reg(Register::Accumulator), Literal(LOAD + 1)],
+        );
+        self.emit(
+            Instruction::MovLitReg,
+            &[label(&back), Reg(Register::Register8)],
+        );
+        self.emit(
+            Instruction::MovLitReg,
+            &[Literal(LOAD), Reg(Register::InstructionPointer)],
+        );
+        self.label(&back);
+    }
+
+    /// Loads the word at `table` indexed by the accumulator into it
+    fn lookup(&mut self, table: &str) {
+        self.emit(
+            Instruction::MovRegReg,
+            &[Reg(Register::Accumulator), Reg(Register::Register1)],
+        );
+        self.emit(
+            Instruction::AddRegReg,
+            &[Reg(Register::Register1), Reg(Register::Register1)],
+        );
+        self.emit(
+            Instruction::MovRegReg,
+            &[Reg(Register::Accumulator), Reg(Register::Register1)],
+        );
+        self.emit(
+            Instruction::MovLitReg,
+            &[label(table), Reg(Register::Register2)],
+        );
+        self.emit(
+            Instruction::AddRegReg,
+            &[Reg(Register::Register1), Reg(Register::Register2)],
+        );
+        self.load();
+    }
+
+    /// Leaves the high byte of the accumulator in it, as `0x00XX`. There's
+    /// no masking, so this writes the word to scratch, zeroes the byte
+    /// before it and reads it back from one byte earlier.
+    fn high_byte(&mut self) {
+        self.emit(
+            Instruction::MovRegMem,
+            &[Reg(Register::Accumulator), Literal(SCRATCH)],
+        );
+        self.store(0, SCRATCH - 2);
+        self.emit(
+            Instruction::MovMemReg,
+            &[Literal(SCRATCH - 1), Reg(Register::Accumulator)],
+        );
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (offset, name) in &self.fixups {
+            let Some(address) = self.labels.get(name) else {
+                panic!("Label {} is never defined", name);
+            };
+            self.code[*offset..*offset + 2].copy_from_slice(&address.to_be_bytes());
+        }
+        self.code
+    }
+}
+
+/// The monitor, at `MONITOR_ADDRESS`. Comments give the source in the
+/// disassembler's syntax, `jmp` standing for `mov label, ip`.
+fn monitor_program() -> Vec<u8> {
+    use Instruction::{AddRegReg, JmpNotEq, MovLitReg, MovMemReg, MovRegMem, MovRegReg};
+    use Register::{
+        Accumulator as ACC, InstructionPointer as IP, Register1 as R1, Register2 as R2,
+        Register3 as R3, Register4 as R4, Register5 as R5, Register7 as R7, Register8 as R8,
+    };
+    let input = STREAM_INPUT_ADDRESS as u16;
+    let output = STREAM_OUTPUT_ADDRESS as u16;
+    let mut program = Program::new(MONITOR_ADDRESS as u16);
+
+    program.jump("boot");
+    // parked: jmp parked
+    program.label("parked");
+    assert_eq!(program.here() as usize, MONITOR_PARKED);
+    program.jump("parked");
+
+    // Writes the trampolines a word at a time
+    program.label("boot");
+    let word = |high: u8, low: u8| u16::from_be_bytes([high, low]);
+    for (offset, word) in [
+        // mov [0x0000], acc
+        // mov r8, ip
+        (0, word(MovMemReg as u8, 0)),
+        (2, word(0, ACC as u8)),
+        (4, word(MovRegReg as u8, R8 as u8)),
+        (6, word(IP as u8, 0)),
+        // mov acc, [0x0000]
+        // mov r8, ip
+        (8, word(MovRegMem as u8, ACC as u8)),
+        (10, 0),
+        (12, word(MovRegReg as u8, R8 as u8)),
+        (14, word(IP as u8, 0)),
+    ] {
+        program.store(word, LOAD + offset);
+    }
+
+    // command:
+    //   mov [input], acc
+    //   jeq 0xffff, end ; jeq 'e', examine ; jeq 'd', deposit ; jeq 'j', jump
+    //   jmp command
+    program.label("command");
+    program.emit(MovMemReg, &[Literal(input), Reg(ACC)]);
+    program.jump_if(0xffff, "end");
+    program.jump_if(b'e' as u16, "examine");
+    program.jump_if(b'd' as u16, "deposit");
+    program.jump_if(b'j' as u16, "jump");
+    program.jump("command");
+
+    // examine:
+    //   mov 4, r5 ; mov examine_address, r7 ; jmp hex
+    // examine_address:
+    //   mov r3, acc ; jeq 0xffef, bad ; jeq 0xffff, bad
+    //   load acc ; high_byte ; lookup hex_out ; mov acc, r4
+    //   high_byte ; mov acc, [output] ; mov r4, [output]
+    //   jmp newline
+    program.label("examine");
+    program.emit(MovLitReg, &[Literal(4), Reg(R5)]);
+    program.emit(MovLitReg, &[label("examine address"), Reg(R7)]);
+    program.jump("hex");
+    program.label("examine address");
+    program.emit(MovRegReg, &[Reg(R3), Reg(ACC)]);
+    program.jump_if(0xffef, "bad");
+    program.jump_if(0xffff, "bad");
+    program.load();
+    program.high_byte();
+    program.lookup("hex out");
+    program.emit(MovRegReg, &[Reg(ACC), Reg(R4)]);
+    program.high_byte();
+    program.emit(MovRegMem, &[Reg(ACC), Literal(output)]);
+    program.emit(MovRegMem, &[Reg(R4), Literal(output)]);
+    program.jump("newline");
+
+    // deposit:
+    //   mov 4, r5 ; mov deposit_address, r7 ; jmp hex
+    // deposit_address:
+    //   mov r3, r4 ; mov r3, acc ; jeq 0xffef, bad ; jeq 0xffff, bad
+    //   mov 2, r5 ; mov deposit_byte, r7 ; jmp hex
+    // deposit_byte:
+    //   mov r4, acc ; load acc
+    //   mov acc, [scratch] ; mov r3, [scratch - 1] ; mov [scratch], acc
+    //   mov r4, [store + 2] ; mov newline, r8 ; jmp store
+    program.label("deposit");
+    program.emit(MovLitReg, &[Literal(4), Reg(R5)]);
+    program.emit(MovLitReg, &[label("deposit address"), Reg(R7)]);
+    program.jump("hex");
+    program.label("deposit address");
+    program.emit(MovRegReg, &[Reg(R3), Reg(R4)]);
+    program.emit(MovRegReg, &[Reg(R3), Reg(ACC)]);
+    program.jump_if(0xffef, "bad");
+    program.jump_if(0xffff, "bad");
+    program.emit(MovLitReg, &[Literal(2), Reg(R5)]);
+    program.emit(MovLitReg, &[label("deposit byte"), Reg(R7)]);
+    program.jump("hex");
+    program.label("deposit byte");
+    program.emit(MovRegReg, &[Reg(R4), Reg(ACC)]);
+    program.load();
+    // Swaps the high byte for the new one, keeping the byte after it
+    program.emit(MovRegMem, &[Reg(ACC), Literal(SCRATCH)]);
+    program.emit(MovRegMem, &[Reg(R3), Literal(SCRATCH - 1)]);
+    program.emit(MovMemReg, &[Literal(SCRATCH), Reg(ACC)]);
+    program.emit(MovRegMem, &[Reg(R4), Literal(STORE + 2)]);
+    program.emit(MovLitReg, &[label("newline"), Reg(R8)]);
+    program.emit(MovLitReg, &[Literal(STORE), Reg(IP)]);
+
+    // jump:
+    //   mov 4, r5 ; mov jump_address, r7 ; jmp hex
+    // jump_address:
+    //   mov r3, ip
+    program.label("jump");
+    program.emit(MovLitReg, &[Literal(4), Reg(R5)]);
+    program.emit(MovLitReg, &[label("jump address"), Reg(R7)]);
+    program.jump("hex");
+    program.label("jump address");
+    program.emit(MovRegReg, &[Reg(R3), Reg(IP)]);
+
+    // newline:
+    //   mov 0x000a, r1 ; mov r1, [output] ; jmp command
+    program.label("newline");
+    program.store(b'\n' as u16, output);
+    program.jump("command");
+
+    // bad:
+    //   mov '?', r1 ; mov r1, [output] ; jmp newline
+    program.label("bad");
+    program.store(b'?' as u16, output);
+    program.jump("newline");
+
+    // end:
+    //   mov 0xffff, r1 ; mov r1, [output] ; jmp parked
+    program.label("end");
+    program.store(0xffff, output);
+    program.jump("parked");
+
+    // Reads r5 hex digits into r3, returning through r7
+    // hex:
+    //   mov 0, r3
+    // hex_digit:
+    //   mov [input], acc ; jeq 0xffff, end
+    //   lookup hex_value ; jeq 0xffff, bad
+    //   mov acc, r1
+    //   add r3, r3 ; mov acc, r3  ;; four times
+    //   add r3, r1 ; mov acc, r3
+    //   mov 0xffff, r2 ; add r5, r2 ; mov acc, r5
+    //   jne 0x0000, hex_digit
+    //   mov r7, ip
+    program.label("hex");
+    program.emit(MovLitReg, &[Literal(0), Reg(R3)]);
+    program.label("hex digit");
+    program.emit(MovMemReg, &[Literal(input), Reg(ACC)]);
+    program.jump_if(0xffff, "end");
+    program.lookup("hex value");
+    program.jump_if(0xffff, "bad");
+    program.emit(MovRegReg, &[Reg(ACC), Reg(R1)]);
+    for _ in 0..4 {
+        program.emit(AddRegReg, &[Reg(R3), Reg(R3)]);
+        program.emit(MovRegReg, &[Reg(ACC), Reg(R3)]);
+    }
+    program.emit(AddRegReg, &[Reg(R3), Reg(R1)]);
+    program.emit(MovRegReg, &[Reg(ACC), Reg(R3)]);
+    program.emit(MovLitReg, &[Literal(0xffff), Reg(R2)]);
+    program.emit(AddRegReg, &[Reg(R5), Reg(R2)]);
+    program.emit(MovRegReg, &[Reg(ACC), Reg(R5)]);
+    program.emit(JmpNotEq, &[Literal(0), label("hex digit")]);
+    program.emit(MovRegReg, &[Reg(R7), Reg(IP)]);
+
+    // Each character's digit value, 0xffff if it isn't one
+    program.label("hex value");
+    program.words((0..=0xffu8).map(|c| match (c as char).to_digit(16) {
+        Some(digit) => digit as u16,
+        None => 0xffff,
+    }));
+    // Each byte as two lowercase hex digits
+    program.label("hex out");
+    program.words((0..=0xffu8).map(|byte| {
+        let [high, low] = format!("{:02x}", byte).into_bytes()[..] else {
+            unreachable!()
+        };
+        u16::from_be_bytes([high, low])
+    }));
+
+    program.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{monitor_machine, MONITOR_PARKED};
+    use crate::cpu::{Instruction, Register};
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn examines_deposits_and_jumps() {
+        // Deposits `mov 0x1234, r1` at 0x0100 and jumps to it
+        let [high, low] = 0x1234u16.to_be_bytes();
+        let mut commands = String::new();
+        for (offset, byte) in [
+            Instruction::MovLitReg as u8,
+            high,
+            low,
+            Register::Register1 as u8,
+        ]
+        .iter()
+        .enumerate()
+        {
+            commands += &format!("d{:04x}{:02x}\n", 0x100 + offset, byte);
+        }
+        commands += "e0101 E0102 eF000 e01zz effff\n";
+        let output = Shared::default();
+        let mut cpu = monitor_machine(Cursor::new(commands.into_bytes()), output.clone()).unwrap();
+
+        cpu.run(100_000);
+        assert_eq!(
+            cpu.peek_register(Register::InstructionPointer) as usize,
+            MONITOR_PARKED
+        );
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "\n\n\n\n12\n10\n?\n?\n",
+            "Commands are lower case, digits either"
+        );
+        assert_eq!(cpu.peek(0x0100), 0x1012);
+
+        let output = Shared::default();
+        let mut cpu = monitor_machine(Cursor::new(b"j0100".to_vec()), output).unwrap();
+        cpu.memory_mut().set_word(0x0100, 0x1012);
+        cpu.memory_mut().set_word(0x0102, 0x3402);
+        cpu.run(1000);
+        assert_eq!(cpu.peek_register(Register::Register1), 0x1234);
+    }
+}
@@ -0,0 +1,504 @@
+use crate::clock::Clock;
+use crate::config::{ConfigError, MachineConfig};
+use crate::cpu::{Cpu, Fault};
+use crate::mapper::{AddressSpace, Device};
+use crate::rng::Rng;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// An address space shared by several cores. Every access takes the bus lock.
+#[derive(Clone)]
+pub struct SharedBus {
+    space: Arc<Mutex<AddressSpace>>,
+}
+
+impl SharedBus {
+    pub fn new(space: AddressSpace) -> SharedBus {
+        SharedBus {
+            space: Arc::new(Mutex::new(space)),
+        }
+    }
+}
+
+impl Device for SharedBus {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.space.lock().unwrap().get_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.space.lock().unwrap().set_byte(address, value);
+    }
+
+    fn get_word(&mut self, address: usize) -> u16 {
+        self.space.lock().unwrap().get_word(address)
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        self.space.lock().unwrap().set_word(address, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.space.lock().unwrap().peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        self.space.lock().unwrap().byte_length()
+    }
+
+    fn is_mapped(&self, address: usize) -> bool {
+        self.space.lock().unwrap().is_mapped(address)
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        self.space.lock().unwrap().tick(cycles, interrupts);
+    }
+
+    fn wait_states(&self, address: usize, write: bool) -> u64 {
+        self.space.lock().unwrap().wait_states(address, write)
+    }
+}
+
+type Mailboxes = Arc<Mutex<Vec<VecDeque<u16>>>>;
+
+/// Memory mapped inter-core interrupt controller. Writing the word `value`
+/// at offset `core * 2` raises interrupt `value` on that core.
+pub struct InterCoreInterrupt {
+    mailboxes: Mailboxes,
+    cores: usize,
+}
+
+impl Device for InterCoreInterrupt {
+    fn get_byte(&mut self, _address: usize) -> u8 {
+        0
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        let core = address / 2;
+        if core < self.cores {
+            self.mailboxes.lock().unwrap()[core].push_back(value);
+        }
+    }
+
+    fn peek_byte(&self, _address: usize) -> u8 {
+        0
+    }
+
+    fn byte_length(&self) -> usize {
+        self.cores * 2
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    /// One instruction per core, in turn
+    Interleaved,
+    /// The given number of instructions per core, in turn
+    Quantum(usize),
+    /// Every core once per round, in a random order and for a random number
+    /// of instructions up to `max_quantum`. The same seed gives the same
+    /// schedule.
+    Random { seed: u64, max_quantum: usize },
+}
+
+/// Several cores executing over one shared address space
+pub struct Multicore {
+    bus: SharedBus,
+    cores: Vec<Cpu>,
+    mailboxes: Mailboxes,
+    policy: SchedulingPolicy,
+    rng: Rng,
+}
+
+impl Multicore {
+    /// Maps the inter-core interrupt controller at `interrupt_controller` and
+    /// creates `cores` cores, each starting at the matching entry point with
+    /// a stack of `stack_size` bytes carved out from the top of memory.
+    /// Pushes past its own stack fault, rather than run into the next core's.
+    /// Fails if the stacks don't fit in memory or a core would get no
+    /// instructions per turn.
+    pub fn new(
+        mut space: AddressSpace,
+        entry_points: &[u16],
+        stack_size: usize,
+        interrupt_controller: usize,
+        policy: SchedulingPolicy,
+    ) -> Result<Multicore, ConfigError> {
+        match policy {
+            SchedulingPolicy::Quantum(0) | SchedulingPolicy::Random { max_quantum: 0, .. } => {
+                return Err(ConfigError::Quantum(0));
+            }
+            _ => {}
+        }
+        let cores = entry_points.len();
+        let mailboxes: Mailboxes = Arc::new(Mutex::new(vec![VecDeque::new(); cores]));
+        space.map(
+            InterCoreInterrupt {
+                mailboxes: Arc::clone(&mailboxes),
+                cores,
+            },
+            interrupt_controller,
+            interrupt_controller + cores * 2 - 1,
+            true,
+        );
+
+        let bus = SharedBus::new(space);
+        let memory_size = bus.byte_length();
+        // Every stack has to fit between the one above it and address 0
+        let stacks = cores.checked_mul(stack_size);
+        if stack_size < 2
+            || !stack_size.is_multiple_of(2)
+            || stacks.is_none_or(|stacks| stacks > memory_size)
+        {
+            return Err(ConfigError::StackSize(stack_size));
+        }
+        let cores = entry_points
+            .iter()
+            .enumerate()
+            .map(|(index, entry_point)| {
+                let config = MachineConfig {
+                    stack_top: memory_size - 2 - index * stack_size,
+                    stack_size: Some(stack_size),
+                    entry_point: *entry_point,
+                    ..MachineConfig::new(memory_size)
+                };
+                Cpu::from_config(Box::new(bus.clone()), &config, Clock::new())
+            })
+            .collect();
+
+        let seed = match policy {
+            SchedulingPolicy::Random { seed, .. } => seed,
+            _ => 0,
+        };
+        Ok(Multicore {
+            bus,
+            cores,
+            mailboxes,
+            policy,
+            rng: Rng::new(seed),
+        })
+    }
+
+    pub fn core(&self, index: usize) -> &Cpu {
+        &self.cores[index]
+    }
+
+    pub fn core_count(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn bus(&self) -> &SharedBus {
+        &self.bus
+    }
+
+    /// Raises interrupt `value` on `core` before its next instruction
+    pub fn send_interrupt(&self, core: usize, value: u16) {
+        self.mailboxes.lock().unwrap()[core].push_back(value);
+    }
+
+    /// Gives every core one turn according to the scheduling policy. Stops
+    /// at the first fault, reporting which core raised it.
+    pub fn step_round(&mut self) -> Result<(), (usize, Fault)> {
+        let mut turns: Vec<(usize, usize)> = match self.policy {
+            SchedulingPolicy::Interleaved => (0..self.cores.len()).map(|i| (i, 1)).collect(),
+            SchedulingPolicy::Quantum(n) => (0..self.cores.len()).map(|i| (i, n)).collect(),
+            SchedulingPolicy::Random { max_quantum, .. } => (0..self.cores.len())
+                .map(|i| (i, 1 + self.rng.below(max_quantum as u64) as usize))
+                .collect(),
+        };
+        if let SchedulingPolicy::Random { .. } = self.policy {
+            // Fisher-Yates
+            for i in (1..turns.len()).rev() {
+                turns.swap(i, self.rng.below(i as u64 + 1) as usize);
+            }
+        }
+        for (index, quantum) in turns {
+            for _ in 0..quantum {
+                self.deliver_interrupts(index)
+                    .map_err(|fault| (index, fault))?;
+                self.cores[index].step().map_err(|fault| (index, fault))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn step_rounds(&mut self, n: usize) -> Result<(), (usize, Fault)> {
+        for _ in 0..n {
+            self.step_round()?;
+        }
+        Ok(())
+    }
+
+    fn deliver_interrupts(&mut self, index: usize) -> Result<(), Fault> {
+        let pending: Vec<u16> = self.mailboxes.lock().unwrap()[index].drain(..).collect();
+        for value in pending {
+            self.cores[index].handle_interrupt(value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Multicore, SchedulingPolicy};
+    use crate::config::ConfigError;
+    use crate::cpu::{Fault, Instruction, Register, INTERRUPT_VECTOR_ADDRESS};
+    use crate::mapper::{AddressSpace, Device};
+    use crate::memory::Memory;
+
+    const CONTROLLER: usize = 0xff00;
+
+    fn idle_loop(memory: &mut Memory, address: usize) {
+        // jne 0x0001, address
+        memory.set_byte(address, Instruction::JmpNotEq as u8);
+        memory.set_word(address + 1, 0x0001);
+        memory.set_word(address + 3, address as u16);
+    }
+
+    #[test]
+    fn cores_share_memory() {
+        let mut memory = Memory::new(256 * 256);
+
+        // core 0: mov 0x1234, r1 ; mov r1, #0100
+        let mut i = 0x0000;
+        memory.set_byte(i, Instruction::MovLitReg as u8);
+        i += 1;
+        memory.set_word(i, 0x1234);
+        i += 2;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_byte(i, Instruction::MovRegMem as u8);
+        i += 1;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_word(i, 0x0100);
+        i += 2;
+        idle_loop(&mut memory, i);
+
+        // core 1: mov #0100, r2
+        let mut i = 0x0200;
+        memory.set_byte(i, Instruction::MovMemReg as u8);
+        i += 1;
+        memory.set_word(i, 0x0100);
+        i += 2;
+        memory.set_byte(i, Register::Register2 as u8);
+        i += 1;
+        idle_loop(&mut memory, i);
+
+        let mut space = AddressSpace::new();
+        space.map(memory, 0x0000, 0xffff, false);
+        let mut machine = Multicore::new(
+            space,
+            &[0x0000, 0x0200],
+            0x100,
+            CONTROLLER,
+            SchedulingPolicy::Quantum(2),
+        )
+        .unwrap();
+
+        machine.step_round().unwrap();
+
+        assert_eq!(machine.core(1).peek_register(Register::Register2), 0x1234);
+        assert_ne!(
+            machine.core(0).peek_register(Register::StackPointer),
+            machine.core(1).peek_register(Register::StackPointer),
+            "Every core has its own stack"
+        );
+    }
+
+    #[test]
+    fn cores_overflow_their_own_stack() {
+        let mut memory = Memory::new(256 * 256);
+        idle_loop(&mut memory, 0x0000);
+
+        // core 1: start: psh 0x1234 ; jne 0x0001, start:
+        memory.set_byte(0x0200, Instruction::PushLit as u8);
+        memory.set_word(0x0201, 0x1234);
+        idle_loop(&mut memory, 0x0203);
+        memory.set_word(0x0206, 0x0200);
+
+        let mut space = AddressSpace::new();
+        space.map(memory, 0x0000, 0xffff, false);
+        let mut machine = Multicore::new(
+            space,
+            &[0x0000, 0x0200],
+            0x40,
+            CONTROLLER,
+            SchedulingPolicy::Quantum(2),
+        )
+        .unwrap();
+
+        // Core 1's stack is 0xff80..=0xffbf, right below core 0's
+        assert_eq!(
+            machine.step_rounds(0x40),
+            Err((1, Fault::StackOverflow { address: 0xff7e }))
+        );
+        assert_eq!(machine.bus().peek(0xffbe, 2), [0x12, 0x34]);
+        assert_eq!(machine.bus().peek(0xff7e, 2), [0, 0]);
+
+        machine.cores[1].reset();
+        assert_eq!(
+            machine.core(1).peek_register(Register::StackPointer),
+            0xffbe,
+            "Resets keep the core's stack"
+        );
+        assert_eq!(
+            machine.core(1).peek_register(Register::InstructionPointer),
+            0x0200
+        );
+    }
+
+    #[test]
+    fn interrupts_another_core() {
+        let mut memory = Memory::new(256 * 256);
+
+        // core 0: mov 0x0003, #ff02 ;; interrupt 3 on core 1
+        let mut i = 0x0000;
+        memory.set_byte(i, Instruction::MovLitReg as u8);
+        i += 1;
+        memory.set_word(i, 0x0003);
+        i += 2;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_byte(i, Instruction::MovRegMem as u8);
+        i += 1;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_word(i, CONTROLLER as u16 + 2);
+        i += 2;
+        idle_loop(&mut memory, i);
+
+        // core 1 idles until interrupted
+        idle_loop(&mut memory, 0x0200);
+
+        // handler: mov 0x4242, r3 ; rti
+        memory.set_word(INTERRUPT_VECTOR_ADDRESS + 3 * 2, 0x0300);
+        let mut i = 0x0300;
+        memory.set_byte(i, Instruction::MovLitReg as u8);
+        i += 1;
+        memory.set_word(i, 0x4242);
+        i += 2;
+        memory.set_byte(i, Register::Register3 as u8);
+        i += 1;
+        memory.set_byte(i, Instruction::RetInt as u8);
+
+        let mut space = AddressSpace::new();
+        space.map(memory, 0x0000, 0xffff, false);
+        let mut machine = Multicore::new(
+            space,
+            &[0x0000, 0x0200],
+            0x100,
+            CONTROLLER,
+            SchedulingPolicy::Interleaved,
+        )
+        .unwrap();
+
+        machine.step_rounds(2).unwrap();
+        assert_eq!(machine.core(1).peek_register(Register::Register3), 0x4242);
+        assert_eq!(machine.core(0).peek_register(Register::Register3), 0x0000);
+
+        machine.step_round().unwrap();
+        assert_eq!(
+            machine.core(1).peek_register(Register::InstructionPointer),
+            0x0200,
+            "Returned to the idle loop"
+        );
+        assert_eq!(
+            machine.core(1).peek_register(Register::Register3),
+            0x0000,
+            "Interrupted state is restored"
+        );
+        assert_eq!(machine.bus().peek(CONTROLLER, 2), [0, 0]);
+    }
+
+    #[test]
+    fn random_schedule_follows_the_seed() {
+        // Both cores count in a shared word:
+        //   mov #0100, r1 ; add r1, r2 ;; r2 holds 1
+        //   mov acc, #0100 ; jne 0x0000, start
+        let schedule = |seed| {
+            let mut memory = Memory::new(256 * 256);
+            for start in [0x0000, 0x0200] {
+                let code = [
+                    Instruction::MovMemReg as u8,
+                    0x01,
+                    0x00,
+                    Register::Register1 as u8,
+                    Instruction::AddRegReg as u8,
+                    Register::Register1 as u8,
+                    Register::Register2 as u8,
+                    Instruction::MovRegMem as u8,
+                    Register::Accumulator as u8,
+                    0x01,
+                    0x00,
+                    Instruction::JmpNotEq as u8,
+                    0x00,
+                    0x00,
+                    (start >> 8) as u8,
+                    start as u8,
+                ];
+                for (i, byte) in code.iter().enumerate() {
+                    memory.set_byte(start + i, *byte);
+                }
+            }
+            let mut space = AddressSpace::new();
+            space.map(memory, 0x0000, 0xffff, false);
+            let mut machine = Multicore::new(
+                space,
+                &[0x0000, 0x0200],
+                0x100,
+                CONTROLLER,
+                SchedulingPolicy::Random {
+                    seed,
+                    max_quantum: 7,
+                },
+            )
+            .unwrap();
+            for core in &mut machine.cores {
+                core.set_register(Register::Register2, 1);
+            }
+
+            // Lost updates depend on how the cores interleave
+            (0..50)
+                .map(|_| {
+                    machine.step_round().unwrap();
+                    machine.bus().peek(0x0100, 2)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(schedule(1), schedule(1));
+        assert_ne!(schedule(1), schedule(2));
+    }
+
+    #[test]
+    fn rejects_schedules_and_stacks_that_cant_work() {
+        let machine = |stack_size, policy| {
+            let mut space = AddressSpace::new();
+            space.map(Memory::new(0x1000), 0x0000, 0x0fff, false);
+            Multicore::new(space, &[0x0000, 0x0200], stack_size, 0x0f00, policy).err()
+        };
+
+        assert_eq!(
+            machine(
+                0x100,
+                SchedulingPolicy::Random {
+                    seed: 1,
+                    max_quantum: 0
+                }
+            ),
+            Some(ConfigError::Quantum(0))
+        );
+        assert_eq!(
+            machine(0x100, SchedulingPolicy::Quantum(0)),
+            Some(ConfigError::Quantum(0))
+        );
+        // The second core's stack would end below address 0
+        assert_eq!(
+            machine(0x802, SchedulingPolicy::Interleaved),
+            Some(ConfigError::StackSize(0x802))
+        );
+        assert_eq!(machine(0x800, SchedulingPolicy::Interleaved), None);
+    }
+}
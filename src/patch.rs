@@ -0,0 +1,156 @@
+use crate::cpu::{Cpu, Event, Register};
+use std::fmt::Display;
+
+/// Why a patch was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    /// The VM is running, so there is no knowing what it is executing
+    Running,
+    /// The patch at `address` runs past the end of memory
+    OutOfBounds { address: u16 },
+    /// The patch would change part of the instruction at `instruction`,
+    /// which the CPU is about to run, and leave the rest
+    SplitsInstruction { address: u16, instruction: u16 },
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::Running => write!(f, "Pause the VM before patching it"),
+            PatchError::OutOfBounds { address } => {
+                write!(f, "Patch at {:#06x} runs past the end of memory", address)
+            }
+            PatchError::SplitsInstruction {
+                address,
+                instruction,
+            } => write!(
+                f,
+                "Patch at {:#06x} splits the instruction at {:#06x}",
+                address, instruction
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl Cpu {
+    /// Replaces the code at `address` with `bytes` in between two
+    /// instructions, the way debuggers plant and remove breakpoint bytes.
+    /// Cached decodes of it are dropped and an `Event::Patched` is raised,
+    /// so a trace shows where the code changed. The instruction the CPU is
+    /// about to run may be replaced from its first byte, but not cut in two.
+    pub fn patch(&mut self, address: u16, bytes: &[u8]) -> Result<(), PatchError> {
+        let start = address as usize;
+        let end = start + bytes.len();
+        if end > self.code_length() {
+            return Err(PatchError::OutOfBounds { address });
+        }
+        let instruction = self.peek_register(Register::InstructionPointer);
+        let next = match self.decode_at(instruction as usize) {
+            Some((info, _)) => instruction as usize + info.length(),
+            None => instruction as usize + 1,
+        };
+        let inside = |address: usize| (instruction as usize) < address && address < next;
+        if !bytes.is_empty() && (inside(start) || inside(end)) {
+            return Err(PatchError::SplitsInstruction {
+                address,
+                instruction,
+            });
+        }
+
+        let code = self.code_mut();
+        for (offset, byte) in bytes.iter().enumerate() {
+            code.set_byte(start + offset, *byte);
+        }
+        self.block_cache.invalidate(start, bytes.len());
+        if !self.is_harvard() {
+            self.dirty_pages.mark(start, bytes.len());
+        }
+        // Patched bytes haven't run, so writing them isn't self-modifying
+        if let Some(executed_code) = &mut self.executed_code {
+            executed_code.overwrite(start, bytes.len());
+        }
+        self.events.push(Event::Patched {
+            address,
+            length: bytes.len() as u16,
+            instruction: self.instruction_count(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PatchError;
+    use crate::cpu::{Cpu, Event, Instruction, Register};
+    use crate::memory::Memory;
+
+    /// mov 0x1234, r1; mov 0x5678, r2; then zeros, which are nops
+    fn program() -> Cpu {
+        let mut memory = Memory::new(256);
+        for (i, byte) in [
+            Instruction::MovLitReg as u8,
+            0x12,
+            0x34,
+            Register::Register1 as u8,
+            Instruction::MovLitReg as u8,
+            0x56,
+            0x78,
+            Register::Register2 as u8,
+        ]
+        .iter()
+        .enumerate()
+        {
+            memory.set_byte(i, *byte);
+        }
+        Cpu::new(memory)
+    }
+
+    #[test]
+    fn patches_code_between_instructions() {
+        let mut cpu = program();
+        cpu.run_cached(1);
+        // The second mov is cached now, planting a nop over it must show
+        cpu.patch(4, &[Instruction::Noop as u8; 4]).unwrap();
+        cpu.run_cached(4);
+        assert_eq!(cpu.peek_register(Register::Register2), 0);
+        assert_eq!(
+            cpu.take_events(),
+            [Event::Patched {
+                address: 4,
+                length: 4,
+                instruction: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_the_next_instruction_whole() {
+        let mut cpu = program();
+        assert_eq!(
+            cpu.patch(2, &[0x00]),
+            Err(PatchError::SplitsInstruction {
+                address: 2,
+                instruction: 0
+            })
+        );
+        assert_eq!(
+            cpu.patch(0, &[0x00, 0x00]),
+            Err(PatchError::SplitsInstruction {
+                address: 0,
+                instruction: 0
+            }),
+            "Ends inside it"
+        );
+        assert_eq!(
+            cpu.patch(0xff, &[0x00, 0x00]),
+            Err(PatchError::OutOfBounds { address: 0xff })
+        );
+
+        cpu.patch(0, &[Instruction::MovLitReg as u8, 0xab, 0xcd, 0x02])
+            .unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.peek_register(Register::Register1), 0xabcd);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::cpu::{Cpu, Fault};
+use crate::extension::InstructionHandler;
+use std::sync::{Arc, Mutex};
+
+/// Formatted output rendered by the host, for a custom instruction without
+/// operands. It pops the address of a NUL terminated format string, then a
+/// word for every conversion in it, so guests push the arguments last to
+/// first and the format string last:
+///
+/// - `%d` signed decimal, `%u` unsigned decimal, `%x` hex
+/// - `%c` the low byte as a character
+/// - `%s` the NUL terminated string at the address
+/// - `%%` a percent sign
+///
+/// Numbers take a width, padded with zeros if it starts with `0`, like
+/// `%04x`. Register it with `Cpu::register_instruction`; clones share the
+/// output, so keep one to read it.
+#[derive(Clone, Default)]
+pub struct Printf {
+    output: Arc<Mutex<String>>,
+}
+
+impl Printf {
+    pub fn new() -> Printf {
+        Printf::default()
+    }
+
+    /// Everything printed so far
+    pub fn output(&self) -> String {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Everything printed since the last call
+    pub fn take_output(&self) -> String {
+        std::mem::take(&mut self.output.lock().unwrap())
+    }
+}
+
+/// The NUL terminated string at `address`, cut off at the end of memory
+fn string_at(cpu: &Cpu, address: u16) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut address = address as usize;
+    while let Some(&byte) = cpu.peek_memory(address, 1).first() {
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+        address += 1;
+    }
+    bytes
+}
+
+fn pad(text: String, width: usize, zeros: bool) -> String {
+    match zeros {
+        true => format!("{:0>width$}", text, width = width),
+        false => format!("{:>width$}", text, width = width),
+    }
+}
+
+impl InstructionHandler for Printf {
+    fn execute(&self, cpu: &mut Cpu) -> Result<(), Fault> {
+        let format = cpu.pop()?;
+        let format = string_at(cpu, format);
+        let mut text = String::new();
+        let mut bytes = format.iter().copied().peekable();
+        while let Some(byte) = bytes.next() {
+            if byte != b'%' {
+                text.push(byte as char);
+                continue;
+            }
+            let zeros = bytes.next_if_eq(&b'0').is_some();
+            let mut width = 0;
+            while let Some(digit) = bytes.next_if(u8::is_ascii_digit) {
+                width = width * 10 + (digit - b'0') as usize;
+            }
+            match bytes.next() {
+                Some(b'd') => text.push_str(&pad((cpu.pop()? as i16).to_string(), width, zeros)),
+                Some(b'u') => text.push_str(&pad(cpu.pop()?.to_string(), width, zeros)),
+                Some(b'x') => text.push_str(&pad(format!("{:x}", cpu.pop()?), width, zeros)),
+                Some(b'c') => text.push(cpu.pop()? as u8 as char),
+                Some(b's') => {
+                    let address = cpu.pop()?;
+                    text.extend(string_at(cpu, address).into_iter().map(char::from));
+                }
+                Some(b'%') => text.push('%'),
+                // Anything else is printed as it is
+                Some(other) => {
+                    text.push('%');
+                    text.push(other as char);
+                }
+                None => text.push('%'),
+            }
+        }
+        self.output.lock().unwrap().push_str(&text);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Printf;
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+
+    const PRINTF: u8 = 0xf0;
+
+    #[test]
+    fn renders_arguments_from_the_stack() {
+        // psh 0x00a0  ;; "ok"
+        // psh 0xfffe  ;; -2
+        // psh 0x1234
+        // psh 0x0080  ;; the format string
+        // printf
+        let mut memory = Memory::new(256);
+        for (i, argument) in [0x00a0u16, 0xfffe, 0x1234, 0x0080].iter().enumerate() {
+            memory.set_byte(i * 3, Instruction::PushLit as u8);
+            memory.set_word(i * 3 + 1, *argument);
+        }
+        memory.set_byte(12, PRINTF);
+        for (i, byte) in b"r1=%04x %d %s, 100%%\0".iter().enumerate() {
+            memory.set_byte(0x80 + i, *byte);
+        }
+        for (i, byte) in b"ok\0".iter().enumerate() {
+            memory.set_byte(0xa0 + i, *byte);
+        }
+        let mut cpu = Cpu::new(memory);
+        let printf = Printf::new();
+        cpu.register_instruction(PRINTF, printf.clone()).unwrap();
+
+        cpu.step_n(5).unwrap();
+        assert_eq!(printf.take_output(), "r1=1234 -2 ok, 100%");
+        assert_eq!(
+            cpu.peek_register(Register::StackPointer),
+            0x00fe,
+            "Everything popped"
+        );
+        assert_eq!(printf.output(), "");
+    }
+}
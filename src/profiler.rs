@@ -0,0 +1,141 @@
+use crate::cpu::{Cpu, Register, StopReason};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Deepest call stack `backtrace` follows, in case the chain is corrupt
+const MAX_DEPTH: usize = 256;
+
+/// Records the instruction pointer and the call stack every `interval`
+/// instructions
+pub struct Profiler {
+    interval: usize,
+    histogram: BTreeMap<u16, u64>,
+    /// Return addresses outermost first, then the instruction pointer
+    stacks: BTreeMap<Vec<u16>, u64>,
+}
+
+impl Profiler {
+    pub fn new(interval: usize) -> Profiler {
+        assert!(interval > 0, "Sampling interval has to be at least 1");
+        Profiler {
+            interval,
+            histogram: BTreeMap::new(),
+            stacks: BTreeMap::new(),
+        }
+    }
+
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    pub fn sample(&mut self, cpu: &Cpu) {
+        let instruction_pointer = cpu.peek_register(Register::InstructionPointer);
+        *self.histogram.entry(instruction_pointer).or_default() += 1;
+
+        let mut stack = cpu.backtrace();
+        stack.reverse();
+        stack.push(instruction_pointer);
+        *self.stacks.entry(stack).or_default() += 1;
+    }
+
+    /// Samples taken at each instruction pointer
+    pub fn histogram(&self) -> &BTreeMap<u16, u64> {
+        &self.histogram
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.histogram.values().sum()
+    }
+
+    /// Writes the samples in the collapsed stack format read by
+    /// flamegraph.pl and inferno, one stack per line, e.g. `0x000c;0x0107 12`.
+    /// Callers appear as their return addresses.
+    pub fn write_collapsed(&self, out: &mut impl Write) -> io::Result<()> {
+        for (stack, count) in &self.stacks {
+            let frames: Vec<String> = stack.iter().map(|ip| format!("{:#06x}", ip)).collect();
+            writeln!(out, "{} {}", frames.join(";"), count)?;
+        }
+        Ok(())
+    }
+}
+
+impl Cpu {
+    /// Return addresses of the active calls and interrupts, innermost
+    /// first, found by following the frame pointer chain
+    pub fn backtrace(&self) -> Vec<u16> {
+        let mut frames = Vec::new();
+        let mut frame_pointer = self.peek_register(Register::FramePointer) as usize;
+        while frame_pointer < self.stack_top as usize
+            && frame_pointer + 5 < self.memory.byte_length()
+            && frames.len() < MAX_DEPTH
+        {
+            let frame_size = self.peek(frame_pointer + 2) as usize;
+            if frame_size == 0 {
+                break;
+            }
+            frames.push(self.peek(frame_pointer + 4));
+            frame_pointer += frame_size;
+        }
+        frames
+    }
+
+    /// Runs like `run`, handing the CPU to `profiler` every
+    /// `profiler.interval()` instructions
+    pub fn run_profiled(&mut self, fuel: usize, profiler: &mut Profiler) -> StopReason {
+        let mut executed = 0;
+        while executed < fuel {
+            let chunk = profiler.interval().min(fuel - executed);
+            let stop = self.run(chunk);
+            executed += chunk;
+            profiler.sample(self);
+            if stop != StopReason::FuelExhausted {
+                return stop;
+            }
+        }
+        StopReason::FuelExhausted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profiler;
+    use crate::bench::standard_workload;
+    use crate::cpu::{Cpu, StopReason};
+
+    #[test]
+    fn backtrace_follows_the_frame_pointers() {
+        let mut cpu = Cpu::new(standard_workload());
+        assert_eq!(cpu.backtrace(), vec![]);
+
+        // Inside the subroutine, called from 0x0009
+        cpu.step_n(5).unwrap();
+        assert_eq!(cpu.backtrace(), vec![0x000c]);
+
+        // Back in the loop
+        cpu.step_n(2).unwrap();
+        assert_eq!(cpu.backtrace(), vec![]);
+    }
+
+    #[test]
+    fn samples_every_interval() {
+        let mut cpu = Cpu::new(standard_workload());
+        let mut profiler = Profiler::new(7);
+        assert_eq!(
+            cpu.run_profiled(7 * 100, &mut profiler),
+            StopReason::FuelExhausted
+        );
+        assert_eq!(profiler.samples(), 100);
+
+        let mut collapsed = Vec::new();
+        profiler.write_collapsed(&mut collapsed).unwrap();
+        let collapsed = String::from_utf8(collapsed).unwrap();
+        assert!(collapsed
+            .lines()
+            .any(|line| line.starts_with("0x000c;0x01")));
+        let total: u64 = collapsed
+            .lines()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, 100);
+    }
+}
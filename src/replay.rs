@@ -0,0 +1,469 @@
+use crate::clock::Clock;
+use crate::mapper::Device;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// A byte read from a nondeterministic device (keyboard, RNG, timer),
+/// stamped with the number of instructions executed before the read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub instruction: u64,
+    pub address: usize,
+    pub value: u8,
+}
+
+/// An interrupt raised by a nondeterministic device (like a timer) when it
+/// was ticked, stamped like `InputEvent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptEvent {
+    pub instruction: u64,
+    pub interrupt: u16,
+}
+
+/// Ordered lists of inputs and interrupts, one event per line in its text
+/// form: `<instruction> <address> <value>` for a read and
+/// `<instruction> irq <interrupt>` for an interrupt
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputLog {
+    events: Vec<InputEvent>,
+    interrupts: Vec<InterruptEvent>,
+}
+
+impl InputLog {
+    pub fn new() -> InputLog {
+        InputLog::default()
+    }
+
+    pub fn push(&mut self, event: InputEvent) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    pub fn push_interrupt(&mut self, event: InterruptEvent) {
+        self.interrupts.push(event);
+    }
+
+    pub fn interrupts(&self) -> &[InterruptEvent] {
+        &self.interrupts
+    }
+}
+
+impl Display for InputLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for event in &self.events {
+            writeln!(
+                f,
+                "{} {:#06x} {:#04x}",
+                event.instruction, event.address, event.value
+            )?;
+        }
+        for event in &self.interrupts {
+            writeln!(f, "{} irq {:#06x}", event.instruction, event.interrupt)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InputLog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse_hex(field: Option<&str>, line: usize) -> Result<usize, String> {
+            let field = field.ok_or(format!("Line {}: missing field", line))?;
+            usize::from_str_radix(field.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("Line {}: {}", line, e))
+        }
+
+        let mut log = InputLog::new();
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let instruction = fields
+                .next()
+                .unwrap()
+                .parse()
+                .map_err(|e| format!("Line {}: {}", line_number, e))?;
+            let address = fields.next();
+            if address == Some("irq") {
+                let interrupt = parse_hex(fields.next(), line_number)?;
+                log.push_interrupt(InterruptEvent {
+                    instruction,
+                    interrupt: u16::try_from(interrupt).map_err(|_| {
+                        format!(
+                            "Line {}: interrupt {:#x} is too large",
+                            line_number, interrupt
+                        )
+                    })?,
+                });
+                continue;
+            }
+            let address = parse_hex(address, line_number)?;
+            let value = parse_hex(fields.next(), line_number)?;
+            log.push(InputEvent {
+                instruction,
+                address,
+                value: u8::try_from(value).map_err(|_| {
+                    format!(
+                        "Line {}: value {:#x} is more than a byte",
+                        line_number, value
+                    )
+                })?,
+            });
+        }
+        Ok(log)
+    }
+}
+
+/// Wraps a nondeterministic device and logs every byte read from it and
+/// every interrupt it raises
+pub struct Recorder<D: Device> {
+    device: D,
+    clock: Clock,
+    log: Arc<Mutex<InputLog>>,
+}
+
+impl<D: Device> Recorder<D> {
+    pub fn new(device: D, clock: Clock) -> Recorder<D> {
+        Recorder {
+            device,
+            clock,
+            log: Arc::new(Mutex::new(InputLog::new())),
+        }
+    }
+
+    /// Shared view of the log, still readable after the recorder is mapped
+    pub fn log(&self) -> Arc<Mutex<InputLog>> {
+        Arc::clone(&self.log)
+    }
+}
+
+impl<D: Device> Device for Recorder<D> {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        let value = self.device.get_byte(address);
+        self.log.lock().unwrap().push(InputEvent {
+            instruction: self.clock.now(),
+            address,
+            value,
+        });
+        value
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.device.set_byte(address, value);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.device.peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        self.device.byte_length()
+    }
+
+    fn is_mapped(&self, address: usize) -> bool {
+        self.device.is_mapped(address)
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        let raised = interrupts.len();
+        self.device.tick(cycles, interrupts);
+        let mut log = self.log.lock().unwrap();
+        for interrupt in &interrupts[raised..] {
+            log.push_interrupt(InterruptEvent {
+                instruction: self.clock.now(),
+                interrupt: *interrupt,
+            });
+        }
+    }
+
+    fn wait_states(&self, address: usize, write: bool) -> u64 {
+        self.device.wait_states(address, write)
+    }
+}
+
+/// Where a replay first read at a different point than during recording,
+/// which means the runs diverged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub instruction: u64,
+    pub address: usize,
+    /// What the recording read instead, `None` once the log ended
+    pub recorded: Option<InputEvent>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.recorded {
+            Some(event) => write!(
+                f,
+                "Replay diverged: read {:#06x} at instruction {}, recorded {:#06x} at instruction {}",
+                self.address, self.instruction, event.address, event.instruction
+            ),
+            None => write!(
+                f,
+                "Replay diverged: read {:#06x} at instruction {} after the log ended",
+                self.address, self.instruction
+            ),
+        }
+    }
+}
+
+/// Stands in for a recorded device and plays back its inputs, and its
+/// interrupts when ticked at or after the instruction they were raised at,
+/// like the CPU ticks devices at the same points of every run. When the
+/// guest reads at a different point than during recording, the first such
+/// read is kept as the `divergence` and the replay goes on with the next
+/// recorded byte, or 0 once the log ended, so check it after the run.
+pub struct Replayer {
+    events: VecDeque<InputEvent>,
+    interrupts: VecDeque<InterruptEvent>,
+    clock: Clock,
+    byte_length: usize,
+    divergence: Arc<Mutex<Option<Divergence>>>,
+}
+
+impl Replayer {
+    pub fn new(log: &InputLog, clock: Clock, byte_length: usize) -> Replayer {
+        Replayer {
+            events: log.events().iter().copied().collect(),
+            interrupts: log.interrupts().iter().copied().collect(),
+            clock,
+            byte_length,
+            divergence: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Shared view of the first divergence, still readable after the
+    /// replayer is mapped
+    pub fn divergence(&self) -> Arc<Mutex<Option<Divergence>>> {
+        Arc::clone(&self.divergence)
+    }
+}
+
+impl Device for Replayer {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        let now = self.clock.now();
+        let recorded = self.events.pop_front();
+        if let Some(event) = recorded {
+            if event.instruction == now && event.address == address {
+                return event.value;
+            }
+        }
+        self.divergence.lock().unwrap().get_or_insert(Divergence {
+            instruction: now,
+            address,
+            recorded,
+        });
+        recorded.map(|event| event.value).unwrap_or(0)
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.events
+            .iter()
+            .find(|event| event.address == address)
+            .map(|event| event.value)
+            .unwrap_or(0)
+    }
+
+    fn byte_length(&self) -> usize {
+        self.byte_length
+    }
+
+    fn tick(&mut self, _cycles: u64, interrupts: &mut Vec<u16>) {
+        let now = self.clock.now();
+        while let Some(event) = self.interrupts.front() {
+            if event.instruction > now {
+                break;
+            }
+            interrupts.push(event.interrupt);
+            self.interrupts.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Divergence, InputLog, InterruptEvent, Recorder, Replayer};
+    use crate::clock::Clock;
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::mapper::{AddressSpace, Device, WaitStates};
+    use crate::memory::Memory;
+
+    /// Stands in for a keyboard or RNG: every read yields a new value
+    struct Noise {
+        state: u8,
+    }
+
+    impl Device for Noise {
+        fn get_byte(&mut self, _address: usize) -> u8 {
+            self.state = self.state.wrapping_mul(37).wrapping_add(11);
+            self.state
+        }
+
+        fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+        fn peek_byte(&self, _address: usize) -> u8 {
+            self.state
+        }
+
+        fn byte_length(&self) -> usize {
+            2
+        }
+
+        /// Fires like a timer with a jittery period
+        fn tick(&mut self, _cycles: u64, interrupts: &mut Vec<u16>) {
+            self.state = self.state.wrapping_mul(37).wrapping_add(11);
+            if self.state & 1 == 1 {
+                interrupts.push(self.state as u16 & 0x07);
+            }
+        }
+    }
+
+    fn program() -> Memory {
+        let mut memory = Memory::new(0x1000);
+
+        // mov #1000, r1
+        // mov #1000, r2
+        let mut i = 0;
+        memory.set_byte(i, Instruction::MovMemReg as u8);
+        i += 1;
+        memory.set_byte(i, 0x10);
+        i += 1;
+        memory.set_byte(i, 0x00);
+        i += 1;
+        memory.set_byte(i, Register::Register1 as u8);
+        i += 1;
+        memory.set_byte(i, Instruction::MovMemReg as u8);
+        i += 1;
+        memory.set_byte(i, 0x10);
+        i += 1;
+        memory.set_byte(i, 0x00);
+        i += 1;
+        memory.set_byte(i, Register::Register2 as u8);
+
+        memory
+    }
+
+    fn machine(input: impl Device + 'static) -> AddressSpace {
+        let mut space = AddressSpace::new();
+        space.map(program(), 0x0000, 0x0fff, false);
+        space.map(input, 0x1000, 0x1001, true);
+        space.map(Memory::new(0x100), 0x1002, 0x1101, true);
+        space
+    }
+
+    #[test]
+    fn reproduces_a_run() {
+        let clock = Clock::new();
+        let recorder = Recorder::new(Noise { state: 1 }, clock.clone());
+        let log = recorder.log();
+        let mut recorded = Cpu::with_clock(machine(recorder), clock);
+        recorded.step_n(2).unwrap();
+
+        let log: InputLog = log.lock().unwrap().to_string().parse().unwrap();
+        assert_eq!(log.events().len(), 4);
+        assert_eq!(log.events()[2].instruction, 1);
+
+        let clock = Clock::new();
+        let replayer = Replayer::new(&log, clock.clone(), 2);
+        let mut replayed = Cpu::with_clock(machine(replayer), clock);
+        replayed.step_n(2).unwrap();
+
+        for register in [Register::Register1, Register::Register2] {
+            assert_eq!(
+                replayed.peek_register(register),
+                recorded.peek_register(register)
+            );
+        }
+        assert_ne!(replayed.peek_register(Register::Register1), 0);
+    }
+
+    #[test]
+    fn detects_divergence() {
+        let log: InputLog = "5 0x0000 0x2a\n".parse().unwrap();
+        let clock = Clock::new();
+        let replayer = Replayer::new(&log, clock.clone(), 2);
+        let divergence = replayer.divergence();
+        let mut cpu = Cpu::with_clock(machine(replayer), clock);
+        cpu.step().unwrap();
+
+        // The first divergence is kept, and the run goes on with what was
+        // recorded
+        let first = Divergence {
+            instruction: 0,
+            address: 0,
+            recorded: Some(log.events()[0]),
+        };
+        assert_eq!(*divergence.lock().unwrap(), Some(first));
+        assert_eq!(cpu.peek_register(Register::Register1), 0x2a00);
+        assert_eq!(
+            first.to_string(),
+            "Replay diverged: read 0x0000 at instruction 0, recorded 0x0000 at instruction 5"
+        );
+
+        cpu.step().unwrap();
+        assert_eq!(*divergence.lock().unwrap(), Some(first));
+        assert_eq!(cpu.peek_register(Register::Register2), 0);
+    }
+
+    #[test]
+    fn replays_interrupts() {
+        let clock = Clock::new();
+        let mut recorder = Recorder::new(Noise { state: 1 }, clock.clone());
+        let log = recorder.log();
+        let mut raised = Vec::new();
+        for _ in 0..4 {
+            clock.advance_by(3);
+            recorder.tick(3, &mut raised);
+        }
+
+        let log: InputLog = log.lock().unwrap().to_string().parse().unwrap();
+        assert_eq!(log.interrupts().len(), raised.len());
+        assert_eq!(
+            log.interrupts()[0],
+            InterruptEvent {
+                instruction: 6,
+                interrupt: raised[0]
+            }
+        );
+
+        let clock = Clock::new();
+        let mut replayer = Replayer::new(&log, clock.clone(), 2);
+        let mut replayed = Vec::new();
+        clock.advance_by(5);
+        replayer.tick(5, &mut replayed);
+        assert!(replayed.is_empty());
+        clock.advance_by(7);
+        replayer.tick(7, &mut replayed);
+        assert_eq!(replayed, raised);
+    }
+
+    #[test]
+    fn forwards_wait_states() {
+        let recorder = Recorder::new(WaitStates::new(Noise { state: 1 }, 2, 3), Clock::new());
+        assert_eq!(recorder.wait_states(0, false), 2);
+        assert_eq!(recorder.wait_states(0, true), 3);
+    }
+
+    #[test]
+    fn rejects_values_wider_than_a_byte() {
+        assert_eq!(
+            "5 0x0000 0x12a\n".parse::<InputLog>(),
+            Err("Line 1: value 0x12a is more than a byte".to_string())
+        );
+    }
+}
@@ -0,0 +1,393 @@
+use crate::mapper::Device;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Bytes of index registers in front of the buffer of a ring
+pub const RING_HEADER_SIZE: usize = 4;
+
+/// A ring buffer shared between the host and the guest, memory mapped as
+/// `RING_HEADER_SIZE + capacity` bytes:
+///
+/// - `0x00` head: offset of the buffer the producer writes next
+/// - `0x02` tail: offset of the buffer the consumer reads next
+/// - `0x04` the buffer
+///
+/// The ring is empty when head and tail are equal, so it holds up to
+/// `capacity - 1` bytes. Offsets are taken modulo the capacity. Whoever
+/// produces fills the buffer first and then moves the head past what it
+/// wrote, so a batch of bytes costs one store to publish.
+struct Ring {
+    buffer: Vec<u8>,
+    head: u16,
+    tail: u16,
+    /// Raised on the next tick if the host moved its index since the last
+    interrupt: Option<u16>,
+    moved: bool,
+    /// Host tasks waiting for the guest to move its index
+    wakers: Vec<Waker>,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Ring {
+        assert!(
+            (2..=0x8000).contains(&capacity),
+            "Rings hold between 2 and 0x8000 bytes"
+        );
+        Ring {
+            buffer: vec![0; capacity],
+            head: 0,
+            tail: 0,
+            interrupt: None,
+            moved: false,
+            wakers: Vec::new(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn head(&self) -> usize {
+        self.head as usize % self.capacity()
+    }
+
+    fn tail(&self) -> usize {
+        self.tail as usize % self.capacity()
+    }
+
+    fn len(&self) -> usize {
+        (self.head() + self.capacity() - self.tail()) % self.capacity()
+    }
+
+    /// Puts as many of `bytes` in the buffer as fit and moves the head past
+    /// them, for a host that produces
+    fn write(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(self.capacity() - 1 - self.len());
+        let capacity = self.capacity();
+        let mut head = self.head();
+        for byte in &bytes[..count] {
+            self.buffer[head] = *byte;
+            head = (head + 1) % capacity;
+        }
+        if count > 0 {
+            self.head = head as u16;
+            self.moved = true;
+        }
+        count
+    }
+
+    /// Takes everything published and moves the tail past it, for a host
+    /// that consumes
+    fn read(&mut self) -> Vec<u8> {
+        let capacity = self.capacity();
+        let tail = self.tail();
+        let bytes: Vec<u8> = (0..self.len())
+            .map(|i| self.buffer[(tail + i) % capacity])
+            .collect();
+        if !bytes.is_empty() {
+            self.tail = self.head() as u16;
+            self.moved = true;
+        }
+        bytes
+    }
+
+    /// Has `cx` woken up the next time the guest moves its index
+    fn wait(&mut self, cx: &mut Context<'_>) {
+        if !self.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            self.wakers.push(cx.waker().clone());
+        }
+    }
+
+    fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn word(&self, address: usize) -> u16 {
+        match address / 2 {
+            0 => self.head,
+            _ => self.tail,
+        }
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        match address.checked_sub(RING_HEADER_SIZE) {
+            // Reads as 0 past the buffer, for word reads straddling the end
+            Some(offset) => self.buffer.get(offset).copied().unwrap_or(0),
+            None => self.word(address).to_be_bytes()[address % 2],
+        }
+    }
+
+    /// Guest writes, to the buffer or to the index the guest owns, `head`
+    /// if it produces and `tail` if it consumes
+    fn set_byte(&mut self, address: usize, value: u8, guest_produces: bool) {
+        match address.checked_sub(RING_HEADER_SIZE) {
+            Some(offset) => {
+                if let Some(byte) = self.buffer.get_mut(offset) {
+                    *byte = value;
+                }
+            }
+            None => {
+                let mut bytes = self.word(address).to_be_bytes();
+                bytes[address % 2] = value;
+                self.set_word(
+                    address - address % 2,
+                    u16::from_be_bytes(bytes),
+                    guest_produces,
+                );
+            }
+        }
+    }
+
+    fn set_word(&mut self, address: usize, value: u16, guest_produces: bool) {
+        match (address, guest_produces) {
+            (0, true) => {
+                self.head = value;
+                self.wake();
+            }
+            (2, false) => {
+                self.tail = value;
+                self.wake();
+            }
+            (0..RING_HEADER_SIZE, _) => {}
+            _ => {
+                let [high, low] = value.to_be_bytes();
+                self.set_byte(address, high, guest_produces);
+                self.set_byte(address + 1, low, guest_produces);
+            }
+        }
+    }
+
+    fn tick(&mut self, interrupts: &mut Vec<u16>) {
+        if let (Some(interrupt), true) = (self.interrupt, self.moved) {
+            interrupts.push(interrupt);
+        }
+        self.moved = false;
+    }
+}
+
+/// A ring the host fills and the guest drains, for data headed into the
+/// guest like received packets or a file being streamed in. Clones share
+/// the ring, so the host keeps one to write to it.
+#[derive(Clone)]
+pub struct InputRing {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl InputRing {
+    pub fn new(capacity: usize) -> InputRing {
+        InputRing {
+            ring: Arc::new(Mutex::new(Ring::new(capacity))),
+        }
+    }
+
+    /// Raises `interrupt` on the tick after the host wrote, once however
+    /// many writes there were
+    pub fn notify(self, interrupt: u16) -> InputRing {
+        self.ring.lock().unwrap().interrupt = Some(interrupt);
+        self
+    }
+
+    /// Puts as many of `bytes` in the ring as fit and returns how many
+    pub fn write(&self, bytes: &[u8]) -> usize {
+        self.ring.lock().unwrap().write(bytes)
+    }
+
+    /// Puts all of `bytes` in the ring, waiting for the guest to make room
+    /// whenever it's full
+    pub async fn write_all(&self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let count = poll_fn(|cx| {
+                let mut ring = self.ring.lock().unwrap();
+                match ring.write(bytes) {
+                    0 => {
+                        ring.wait(cx);
+                        Poll::Pending
+                    }
+                    count => Poll::Ready(count),
+                }
+            })
+            .await;
+            bytes = &bytes[count..];
+        }
+    }
+
+    /// Bytes the guest hasn't read yet
+    pub fn len(&self) -> usize {
+        self.ring.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Device for InputRing {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.ring.lock().unwrap().set_byte(address, value, false);
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        self.ring.lock().unwrap().set_word(address, value, false);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.ring.lock().unwrap().peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        RING_HEADER_SIZE + self.ring.lock().unwrap().capacity()
+    }
+
+    fn tick(&mut self, _cycles: u64, interrupts: &mut Vec<u16>) {
+        self.ring.lock().unwrap().tick(interrupts);
+    }
+}
+
+/// A ring the guest fills and the host drains, for data headed out of the
+/// guest like packets to send or a file being written. Clones share the
+/// ring, so the host keeps one to read from it.
+#[derive(Clone)]
+pub struct OutputRing {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl OutputRing {
+    pub fn new(capacity: usize) -> OutputRing {
+        OutputRing {
+            ring: Arc::new(Mutex::new(Ring::new(capacity))),
+        }
+    }
+
+    /// Raises `interrupt` on the tick after the host read, once however
+    /// many reads there were, so the guest knows there's room again
+    pub fn notify(self, interrupt: u16) -> OutputRing {
+        self.ring.lock().unwrap().interrupt = Some(interrupt);
+        self
+    }
+
+    /// Takes everything the guest has published so far
+    pub fn read(&self) -> Vec<u8> {
+        self.ring.lock().unwrap().read()
+    }
+
+    /// Like `read`, but waits for the guest to publish something first
+    pub async fn read_async(&self) -> Vec<u8> {
+        poll_fn(|cx| {
+            let mut ring = self.ring.lock().unwrap();
+            let bytes = ring.read();
+            if bytes.is_empty() {
+                ring.wait(cx);
+                return Poll::Pending;
+            }
+            Poll::Ready(bytes)
+        })
+        .await
+    }
+}
+
+impl Device for OutputRing {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        self.ring.lock().unwrap().set_byte(address, value, true);
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        self.ring.lock().unwrap().set_word(address, value, true);
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.ring.lock().unwrap().peek_byte(address)
+    }
+
+    fn byte_length(&self) -> usize {
+        RING_HEADER_SIZE + self.ring.lock().unwrap().capacity()
+    }
+
+    fn tick(&mut self, _cycles: u64, interrupts: &mut Vec<u16>) {
+        self.ring.lock().unwrap().tick(interrupts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputRing, OutputRing, RING_HEADER_SIZE};
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::mapper::Device;
+
+    #[test]
+    fn guests_drain_what_the_host_writes() {
+        let host = InputRing::new(4).notify(3);
+        let mut ring = host.clone();
+        assert_eq!(host.write(b"abcde"), 3, "One slot stays free");
+        assert_eq!(host.write(b"d"), 0);
+
+        let mut interrupts = Vec::new();
+        ring.tick(1, &mut interrupts);
+        ring.tick(1, &mut interrupts);
+        assert_eq!(interrupts, [3], "One interrupt for the batch");
+
+        assert_eq!(ring.get_word(0), 3);
+        assert_eq!(ring.get_byte(RING_HEADER_SIZE), b'a');
+        ring.set_word(0, 0);
+        assert_eq!(ring.get_word(0), 3, "The head is the host's");
+        ring.set_word(2, 2);
+        assert_eq!(host.len(), 1);
+        assert_eq!(host.write(b"de"), 2);
+        assert_eq!(ring.get_word(0), 1, "Wraps around");
+        assert_eq!(ring.get_byte(RING_HEADER_SIZE), b'e');
+    }
+
+    #[test]
+    fn hosts_drain_what_the_guest_publishes() {
+        // mov 0x6162, r1
+        // mov r1, [buffer]
+        // mov 0x0002, r1
+        // mov r1, [head]
+        let ring = OutputRing::new(8).notify(4);
+        let start = 0x1800_u16;
+        let mut code = Vec::new();
+        for (value, address) in [(0x6162, start + RING_HEADER_SIZE as u16), (2, start)] {
+            let [value_high, value_low] = u16::to_be_bytes(value);
+            let [address_high, address_low] = address.to_be_bytes();
+            code.extend([
+                Instruction::MovLitReg as u8,
+                value_high,
+                value_low,
+                Register::Register1 as u8,
+                Instruction::MovRegMem as u8,
+                Register::Register1 as u8,
+                address_high,
+                address_low,
+            ]);
+        }
+        let mut cpu = Cpu::builder()
+            .memory_size(0x2000)
+            .device("ring", ring.clone(), start as usize, start as usize + 11)
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+
+        cpu.step_n(2).unwrap();
+        assert!(ring.read().is_empty(), "Not published yet");
+        cpu.step_n(2).unwrap();
+        assert_eq!(ring.read(), b"ab");
+        assert!(ring.read().is_empty());
+        assert_eq!(cpu.peek(start as usize + 2), 2);
+
+        let mut interrupts = Vec::new();
+        cpu.memory_mut().tick(1, &mut interrupts);
+        assert_eq!(interrupts, [4], "Room again");
+    }
+}
@@ -0,0 +1,93 @@
+use crate::mapper::Device;
+
+/// Small xorshift generator behind everything random in the machine. The
+/// same seed always gives the same numbers, on every platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            // xorshift gets stuck at zero
+            state: seed ^ 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number in `0..limit`
+    pub fn below(&mut self, limit: u64) -> u64 {
+        self.next_u64() % limit
+    }
+}
+
+/// Memory mapped random number generator, 2 bytes long. Every byte read
+/// gives a fresh random byte, writes are ignored.
+pub struct RngDevice {
+    rng: Rng,
+    /// What the next read returns
+    next: u8,
+}
+
+impl RngDevice {
+    pub fn new(seed: u64) -> RngDevice {
+        let mut rng = Rng::new(seed);
+        let next = rng.next_u64() as u8;
+        RngDevice { rng, next }
+    }
+}
+
+impl Device for RngDevice {
+    fn get_byte(&mut self, _address: usize) -> u8 {
+        let value = self.next;
+        self.next = self.rng.next_u64() as u8;
+        value
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    fn peek_byte(&self, _address: usize) -> u8 {
+        self.next
+    }
+
+    fn byte_length(&self) -> usize {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rng, RngDevice};
+    use crate::mapper::Device;
+
+    #[test]
+    fn same_seed_gives_the_same_numbers() {
+        let numbers = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..8).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(0), numbers(0));
+        assert_ne!(numbers(0), numbers(1));
+    }
+
+    #[test]
+    fn device_reads_advance_but_peeks_do_not() {
+        let mut device = RngDevice::new(7);
+        let peeked = device.peek_byte(0);
+        assert_eq!(device.peek_byte(1), peeked);
+        assert_eq!(device.get_byte(0), peeked);
+
+        let mut again = RngDevice::new(7);
+        let words: Vec<u16> = (0..4).map(|_| device.get_word(0)).collect();
+        again.get_byte(0);
+        let replayed: Vec<u16> = (0..4).map(|_| again.get_word(0)).collect();
+        assert_eq!(words, replayed);
+    }
+}
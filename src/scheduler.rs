@@ -0,0 +1,215 @@
+use crate::cpu::{Cpu, Fault, StopReason};
+
+/// Identifies a VM owned by a `Scheduler`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VmId(usize);
+
+struct Slot {
+    cpu: Cpu,
+    quantum: usize,
+    failure: Option<StopReason>,
+}
+
+/// Owns several independent VMs and runs them round-robin, giving each one
+/// its own quantum of instructions per round.
+#[derive(Default)]
+pub struct Scheduler {
+    slots: Vec<Option<Slot>>,
+    rounds: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    pub fn spawn(&mut self, cpu: Cpu, quantum: usize) -> VmId {
+        self.slots.push(Some(Slot {
+            cpu,
+            quantum,
+            failure: None,
+        }));
+        VmId(self.slots.len() - 1)
+    }
+
+    /// Takes a VM out of the rotation and hands it back
+    pub fn remove(&mut self, id: VmId) -> Option<Cpu> {
+        self.slots
+            .get_mut(id.0)
+            .and_then(|slot| slot.take())
+            .map(|slot| slot.cpu)
+    }
+
+    pub fn set_quantum(&mut self, id: VmId, quantum: usize) {
+        if let Some(slot) = self.slot_mut(id) {
+            slot.quantum = quantum;
+        }
+    }
+
+    pub fn quantum(&self, id: VmId) -> Option<usize> {
+        self.slot(id).map(|slot| slot.quantum)
+    }
+
+    /// The fault that took a VM out of the rotation
+    pub fn fault(&self, id: VmId) -> Option<Fault> {
+        match self.failure(id)? {
+            StopReason::Fault(fault) => Some(*fault),
+            _ => None,
+        }
+    }
+
+    /// The fault or guest panic that took a VM out of the rotation
+    pub fn failure(&self, id: VmId) -> Option<&StopReason> {
+        self.slot(id).and_then(|slot| slot.failure.as_ref())
+    }
+
+    pub fn vm(&self, id: VmId) -> Option<&Cpu> {
+        self.slot(id).map(|slot| &slot.cpu)
+    }
+
+    pub fn vm_mut(&mut self, id: VmId) -> Option<&mut Cpu> {
+        self.slot_mut(id).map(|slot| &mut slot.cpu)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = VmId> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_some())
+            .map(|(index, _)| VmId(index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of completed rounds
+    pub fn rounds(&self) -> u64 {
+        self.rounds
+    }
+
+    /// Runs every VM for its quantum, in the order they were spawned. VMs
+    /// that fault or panic are skipped from then on.
+    pub fn run_round(&mut self) {
+        for slot in self.slots.iter_mut().flatten() {
+            if slot.failure.is_none() {
+                match slot.cpu.run(slot.quantum) {
+                    StopReason::FuelExhausted | StopReason::Halted => {}
+                    failure @ (StopReason::Fault(_) | StopReason::GuestPanic { .. }) => {
+                        slot.failure = Some(failure)
+                    }
+                }
+            }
+        }
+        self.rounds += 1;
+    }
+
+    pub fn run_rounds(&mut self, n: usize) {
+        for _ in 0..n {
+            self.run_round();
+        }
+    }
+
+    fn slot(&self, id: VmId) -> Option<&Slot> {
+        self.slots.get(id.0).and_then(|slot| slot.as_ref())
+    }
+
+    fn slot_mut(&mut self, id: VmId) -> Option<&mut Slot> {
+        self.slots.get_mut(id.0).and_then(|slot| slot.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scheduler;
+    use crate::cpu::{Cpu, Instruction, Register, StopReason};
+    use crate::memory::Memory;
+
+    #[test]
+    fn runs_each_vm_for_its_quantum() {
+        let mut scheduler = Scheduler::new();
+        let fast = scheduler.spawn(Cpu::new(Memory::new(256)), 3);
+        let slow = scheduler.spawn(Cpu::new(Memory::new(256)), 1);
+
+        scheduler.run_rounds(2);
+
+        let ip = |id| {
+            scheduler
+                .vm(id)
+                .unwrap()
+                .peek_register(Register::InstructionPointer)
+        };
+        assert_eq!(ip(fast), 6);
+        assert_eq!(ip(slow), 2);
+        assert_eq!(scheduler.rounds(), 2);
+    }
+
+    #[test]
+    fn faulted_vms_stop_running() {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::PushReg as u8);
+        memory.set_byte(1, 0xee);
+
+        let mut scheduler = Scheduler::new();
+        let broken = scheduler.spawn(Cpu::new(memory), 4);
+        scheduler.run_rounds(2);
+
+        assert!(scheduler.fault(broken).is_some());
+        assert_eq!(
+            scheduler
+                .vm(broken)
+                .unwrap()
+                .peek_register(Register::InstructionPointer),
+            2
+        );
+    }
+
+    #[test]
+    fn panicked_vms_stop_running() {
+        // pnc 0x0080, 0x0007
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::Panic as u8);
+        memory.set_word(1, 0x0080);
+        memory.set_word(3, 0x0007);
+        memory.set_byte(0x80, b'!');
+
+        let mut scheduler = Scheduler::new();
+        let broken = scheduler.spawn(Cpu::new(memory), 4);
+        scheduler.run_rounds(2);
+
+        assert_eq!(
+            scheduler.failure(broken),
+            Some(&StopReason::GuestPanic {
+                message: "!".to_string(),
+                code: 7
+            })
+        );
+        assert_eq!(scheduler.fault(broken), None);
+        assert_eq!(
+            scheduler
+                .vm(broken)
+                .unwrap()
+                .peek_register(Register::InstructionPointer),
+            5
+        );
+    }
+
+    #[test]
+    fn removed_vms_leave_the_rotation() {
+        let mut scheduler = Scheduler::new();
+        let first = scheduler.spawn(Cpu::new(Memory::new(256)), 1);
+        let second = scheduler.spawn(Cpu::new(Memory::new(256)), 1);
+
+        let cpu = scheduler.remove(first).unwrap();
+        scheduler.run_round();
+
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0);
+        assert!(scheduler.vm(first).is_none());
+        assert_eq!(scheduler.ids().collect::<Vec<_>>(), [second]);
+        assert_eq!(scheduler.len(), 1);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::cpu::{Cpu, Event};
+
+const ADDRESS_SPACE_SIZE: usize = 0x10000;
+
+/// One bit per byte that has been fetched as part of an instruction since it
+/// was last written
+pub(crate) struct ExecutedCode {
+    bits: Vec<u64>,
+}
+
+impl ExecutedCode {
+    fn new() -> ExecutedCode {
+        ExecutedCode {
+            bits: vec![0; ADDRESS_SPACE_SIZE / 64],
+        }
+    }
+
+    pub(crate) fn mark(&mut self, address: usize, length: usize) {
+        for address in address..(address + length).min(ADDRESS_SPACE_SIZE) {
+            self.bits[address / 64] |= 1 << (address % 64);
+        }
+    }
+
+    /// Clears `address..address + length`, returning the first address in
+    /// there that had been executed
+    pub(crate) fn overwrite(&mut self, address: usize, length: usize) -> Option<usize> {
+        let mut executed = None;
+        for address in address..(address + length).min(ADDRESS_SPACE_SIZE) {
+            let bit = 1 << (address % 64);
+            if self.bits[address / 64] & bit != 0 {
+                self.bits[address / 64] &= !bit;
+                executed.get_or_insert(address);
+            }
+        }
+        executed
+    }
+}
+
+impl Cpu {
+    /// Raises `Event::SelfModifyingCode` whenever the guest writes over bytes
+    /// it has executed. Writes still drop any cached decodes as usual.
+    /// Turning this on makes `run` and `run_jit` fall back to slower paths
+    /// that see every instruction.
+    pub fn detect_self_modifying_code(&mut self, detect: bool) {
+        self.executed_code = detect.then(|| Box::new(ExecutedCode::new()));
+    }
+
+    /// Records that `address..address + length` was fetched for execution
+    pub(crate) fn mark_executed(&mut self, address: usize, length: usize) {
+        if let Some(executed_code) = &mut self.executed_code {
+            executed_code.mark(address, length);
+        }
+    }
+
+    /// Raises an event if a guest write to `address..address + length` lands
+    /// on executed code
+    pub(crate) fn check_code_write(&mut self, address: usize, length: usize) {
+        let Some(executed_code) = &mut self.executed_code else {
+            return;
+        };
+        if let Some(address) = executed_code.overwrite(address, length) {
+            self.events.push(Event::SelfModifyingCode {
+                address: address as u16,
+                instruction: self.clock.now() + 1,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Cpu, Event, Instruction, Register, StopReason};
+    use crate::memory::Memory;
+
+    /// mov 0x0000, r1
+    /// mov r1, #0001 ;; overwrites the literal of the first instruction
+    /// jne 0x0001, 0x0000
+    fn patching_loop() -> Memory {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x00, Instruction::MovLitReg as u8);
+        memory.set_word(0x01, 0x0000);
+        memory.set_byte(0x03, Register::Register1 as u8);
+        memory.set_byte(0x04, Instruction::MovRegMem as u8);
+        memory.set_byte(0x05, Register::Register1 as u8);
+        memory.set_word(0x06, 0x0001);
+        memory.set_byte(0x08, Instruction::JmpNotEq as u8);
+        memory.set_word(0x09, 0x0001);
+        memory.set_word(0x0b, 0x0000);
+        memory
+    }
+
+    #[test]
+    fn reports_writes_over_executed_code() {
+        let run_with: [fn(&mut Cpu, usize) -> StopReason; 3] =
+            [Cpu::run, Cpu::run_cached, |cpu, fuel| {
+                match cpu.step_n(fuel) {
+                    Ok(()) => StopReason::FuelExhausted,
+                    Err(fault) => StopReason::Fault(fault),
+                }
+            }];
+        for run in run_with {
+            let mut cpu = Cpu::new(patching_loop());
+            cpu.detect_self_modifying_code(true);
+            assert_eq!(run(&mut cpu, 6), StopReason::FuelExhausted);
+            assert_eq!(
+                cpu.take_events(),
+                vec![
+                    Event::SelfModifyingCode {
+                        address: 0x0001,
+                        instruction: 2
+                    },
+                    Event::SelfModifyingCode {
+                        address: 0x0001,
+                        instruction: 5
+                    }
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_writes_over_code_that_has_not_run() {
+        let mut memory = patching_loop();
+        // Write past the end of the loop instead
+        memory.set_word(0x06, 0x0080);
+        let mut cpu = Cpu::new(memory);
+        cpu.detect_self_modifying_code(true);
+        cpu.run(30);
+        assert_eq!(cpu.take_events(), vec![]);
+
+        let mut cpu = Cpu::new(patching_loop());
+        cpu.run(30);
+        assert_eq!(cpu.take_events(), vec![], "Detection is off by default");
+    }
+}
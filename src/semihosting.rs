@@ -0,0 +1,220 @@
+use crate::cpu::{Cpu, Fault, Register};
+use crate::extension::InstructionHandler;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// What `Semihosting` leaves in the accumulator when a service fails
+pub const SEMIHOSTING_ERROR: u16 = 0xffff;
+
+/// Files guests can have open at once
+const MAX_OPEN_FILES: usize = 16;
+
+/// Service numbers, popped first
+const OPEN: u16 = 1;
+const READ: u16 = 2;
+const WRITE: u16 = 3;
+const CLOSE: u16 = 4;
+
+/// Modes `open` takes
+const MODE_READ: u16 = 0;
+const MODE_WRITE: u16 = 1;
+const MODE_APPEND: u16 = 2;
+
+/// File services run by the host, for a custom instruction without
+/// operands. It pops a service number, then its arguments, and leaves the
+/// result in the accumulator, `SEMIHOSTING_ERROR` if it failed. Guests push
+/// the arguments last to first and the service number last:
+///
+/// - `1 open path mode`: opens the NUL terminated path for reading (mode
+///   0), writing over it (1) or appending to it (2), giving a handle
+/// - `2 read handle buffer length`: reads up to `length` bytes into
+///   `buffer`, giving how many, 0 at the end of the file
+/// - `3 write handle buffer length`: writes `length` bytes from `buffer`,
+///   giving how many
+/// - `4 close handle`: gives 0
+///
+/// Paths are relative to a root directory and can't climb out of it.
+/// Clones share the open files.
+#[derive(Clone)]
+pub struct Semihosting {
+    root: PathBuf,
+    files: Arc<Mutex<HashMap<u16, File>>>,
+}
+
+impl Semihosting {
+    /// Services for the files under `root`
+    pub fn new(root: impl Into<PathBuf>) -> Semihosting {
+        Semihosting {
+            root: root.into(),
+            files: Arc::default(),
+        }
+    }
+
+    /// `path` under the root, `None` if it would leave it
+    fn sandboxed(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        inside.then(|| self.root.join(path))
+    }
+
+    fn open(&self, cpu: &mut Cpu) -> Result<Option<u16>, Fault> {
+        let path = cpu.pop()?;
+        let mode = cpu.pop()?;
+        let path = String::from_utf8_lossy(&string_at(cpu, path)).into_owned();
+        let Some(path) = self.sandboxed(&path) else {
+            return Ok(None);
+        };
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            _ => return Ok(None),
+        };
+        let mut files = self.files.lock().unwrap();
+        let Some(handle) = (0..MAX_OPEN_FILES as u16).find(|handle| !files.contains_key(handle))
+        else {
+            return Ok(None);
+        };
+        Ok(options.open(path).ok().map(|file| {
+            files.insert(handle, file);
+            handle
+        }))
+    }
+
+    fn read(&self, cpu: &mut Cpu) -> Result<Option<u16>, Fault> {
+        let handle = cpu.pop()?;
+        let buffer = cpu.pop()?;
+        let length = cpu.pop()?;
+        let mut files = self.files.lock().unwrap();
+        let Some(file) = files.get_mut(&handle) else {
+            return Ok(None);
+        };
+        let mut bytes = vec![0; length as usize];
+        let Ok(read) = file.read(&mut bytes) else {
+            return Ok(None);
+        };
+        let memory = cpu.memory_mut();
+        let end = (buffer as usize + read).min(memory.byte_length());
+        for (address, byte) in (buffer as usize..end).zip(bytes) {
+            memory.set_byte(address, byte);
+        }
+        Ok(Some((end - buffer as usize) as u16))
+    }
+
+    fn write(&self, cpu: &mut Cpu) -> Result<Option<u16>, Fault> {
+        let handle = cpu.pop()?;
+        let buffer = cpu.pop()?;
+        let length = cpu.pop()?;
+        let mut files = self.files.lock().unwrap();
+        let Some(file) = files.get_mut(&handle) else {
+            return Ok(None);
+        };
+        let bytes = cpu.peek_memory(buffer as usize, length as usize);
+        Ok(file.write_all(&bytes).ok().map(|()| bytes.len() as u16))
+    }
+
+    fn close(&self, cpu: &mut Cpu) -> Result<Option<u16>, Fault> {
+        let handle = cpu.pop()?;
+        let closed = self.files.lock().unwrap().remove(&handle);
+        Ok(closed.map(|_| 0))
+    }
+}
+
+/// The NUL terminated string at `address`, cut off at the end of memory
+fn string_at(cpu: &Cpu, address: u16) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut address = address as usize;
+    while let Some(&byte) = cpu.peek_memory(address, 1).first() {
+        if byte == 0 {
+            break;
+        }
+        bytes.push(byte);
+        address += 1;
+    }
+    bytes
+}
+
+impl InstructionHandler for Semihosting {
+    fn execute(&self, cpu: &mut Cpu) -> Result<(), Fault> {
+        let result = match cpu.pop()? {
+            OPEN => self.open(cpu)?,
+            READ => self.read(cpu)?,
+            WRITE => self.write(cpu)?,
+            CLOSE => self.close(cpu)?,
+            _ => None,
+        };
+        cpu.set_register(Register::Accumulator, result.unwrap_or(SEMIHOSTING_ERROR));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Semihosting, SEMIHOSTING_ERROR};
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+    use std::fs;
+
+    const SEMIHOST: u8 = 0xf1;
+
+    /// Pushes `arguments` last to first, then runs the service
+    fn call(cpu: &mut Cpu, arguments: &[u16]) -> u16 {
+        let start = 0x80;
+        let mut address = start;
+        for argument in arguments.iter().rev() {
+            cpu.memory_mut()
+                .set_byte(address, Instruction::PushLit as u8);
+            cpu.memory_mut().set_word(address + 1, *argument);
+            address += 3;
+        }
+        cpu.memory_mut().set_byte(address, SEMIHOST);
+        cpu.set_register(Register::InstructionPointer, start as u16);
+        cpu.step_n(arguments.len() + 1).unwrap();
+        cpu.peek_register(Register::Accumulator)
+    }
+
+    fn set_string(cpu: &mut Cpu, address: usize, text: &[u8]) {
+        for (i, byte) in text.iter().chain(&[0]).enumerate() {
+            cpu.memory_mut().set_byte(address + i, *byte);
+        }
+    }
+
+    #[test]
+    fn guests_copy_files_in_the_sandbox() {
+        let root = std::env::temp_dir().join(format!("rsll16-semihosting-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("in.txt"), b"hello, files").unwrap();
+
+        let mut cpu = Cpu::new(Memory::new(0x1000));
+        cpu.register_instruction(SEMIHOST, Semihosting::new(&root))
+            .unwrap();
+        set_string(&mut cpu, 0x200, b"in.txt");
+        set_string(&mut cpu, 0x210, b"./out.txt");
+        set_string(&mut cpu, 0x220, b"../escape.txt");
+
+        let input = call(&mut cpu, &[1, 0x200, 0]);
+        let output = call(&mut cpu, &[1, 0x210, 1]);
+        assert_ne!(input, output);
+        assert_eq!(call(&mut cpu, &[2, input, 0x300, 0x40]), 12);
+        assert_eq!(call(&mut cpu, &[2, input, 0x300, 0x40]), 0, "End of file");
+        assert_eq!(call(&mut cpu, &[3, output, 0x300, 5]), 5);
+        assert_eq!(call(&mut cpu, &[4, input]), 0);
+        assert_eq!(call(&mut cpu, &[4, output]), 0);
+        assert_eq!(fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+        assert_eq!(
+            call(&mut cpu, &[1, 0x220, 1]),
+            SEMIHOSTING_ERROR,
+            "Sandboxed"
+        );
+        assert_eq!(call(&mut cpu, &[4, input]), SEMIHOSTING_ERROR, "Closed");
+        assert_eq!(call(&mut cpu, &[9]), SEMIHOSTING_ERROR);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use crate::cpu::{Cpu, Register};
+use crate::handle::CpuHandle;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often `/stream` looks for a change in the machine
+const STREAM_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Largest program `/load` accepts
+const MAX_BODY: usize = 0x1_0000;
+
+/// Lets a remote client drive a CPU over HTTP. Only built with the `server`
+/// feature.
+///
+/// - `POST /load?address=ADDR` writes the body to memory at `ADDR`, then
+///   resets the CPU and points it at the program
+/// - `POST /run`, `POST /pause` and `POST /step`
+/// - `GET /state` gives `Cpu::to_json`, with whether the CPU is paused and
+///   the fault that stopped it
+/// - `GET /breakpoints`, `PUT /breakpoints/ADDR` and
+///   `DELETE /breakpoints/ADDR`
+/// - `GET /stream` upgrades to a WebSocket and sends the state every time it
+///   changes
+///
+/// Addresses are in hex. Every request gets its own connection.
+pub struct Server {
+    listener: TcpListener,
+    handle: Arc<CpuHandle>,
+}
+
+impl Server {
+    /// Listens on `address`. The CPU starts paused.
+    pub fn bind(address: impl ToSocketAddrs, cpu: Cpu) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(address)?,
+            handle: Arc::new(CpuHandle::spawn(cpu)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answers requests until the listener fails, each connection on its own
+    /// thread
+    pub fn serve(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let handle = Arc::clone(&self.handle);
+            thread::spawn(move || {
+                // A client hanging up early is its own problem
+                let _ = answer(stream, &handle);
+            });
+        }
+        Ok(())
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    websocket_key: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", message),
+        }
+    }
+}
+
+fn answer(stream: TcpStream, handle: &CpuHandle) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    let request = match read_request(&mut reader)? {
+        Ok(request) => request,
+        Err(message) => return respond(&mut stream, Response::error("400 Bad Request", message)),
+    };
+
+    if request.path == "/stream" {
+        return match &request.websocket_key {
+            Some(key) => stream_state(stream, key, handle),
+            None => respond(
+                &mut stream,
+                Response::error("426 Upgrade Required", "Needs a WebSocket"),
+            ),
+        };
+    }
+    respond(&mut stream, route(&request, handle))
+}
+
+fn read_request(reader: &mut impl BufRead) -> io::Result<Result<Request, &'static str>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Ok(Err("Not an HTTP request")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    let mut length = 0;
+    let mut websocket_key = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => return Ok(Err("Not a header")),
+        };
+        match name.as_str() {
+            "content-length" => match value.parse() {
+                Ok(value) if value <= MAX_BODY => length = value,
+                _ => return Ok(Err("Bad content length")),
+            },
+            "sec-websocket-key" => websocket_key = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Ok(Request {
+        method,
+        path,
+        query,
+        websocket_key,
+        body,
+    }))
+}
+
+fn route(request: &Request, handle: &CpuHandle) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["state"]) => Response::json(state(handle)),
+        ("POST", ["run"]) => {
+            handle.resume();
+            Response::json(state(handle))
+        }
+        ("POST", ["pause"]) => {
+            handle.pause();
+            Response::json(state(handle))
+        }
+        ("POST", ["step"]) => {
+            handle.step();
+            Response::json(state(handle))
+        }
+        ("POST", ["load"]) => {
+            let address = request
+                .query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("address="))
+                .unwrap_or("0");
+            match parse_address(address) {
+                Some(address) => match load(handle, address, &request.body) {
+                    Ok(()) => Response::json(state(handle)),
+                    Err(message) => Response::error("400 Bad Request", message),
+                },
+                None => Response::error("400 Bad Request", "Not an address"),
+            }
+        }
+        ("GET", ["breakpoints"]) => Response::json(breakpoints(handle)),
+        (method @ ("PUT" | "DELETE"), ["breakpoints", address]) => match parse_address(address) {
+            Some(address) => {
+                if method == "PUT" {
+                    handle.add_breakpoint(address);
+                } else {
+                    handle.remove_breakpoint(address);
+                }
+                Response::json(breakpoints(handle))
+            }
+            None => Response::error("400 Bad Request", "Not an address"),
+        },
+        _ => Response::error("404 Not Found", "No such endpoint"),
+    }
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+/// Writes `program` to memory at `address` and points the CPU at it, unless
+/// part of it would land past the end of memory or on nothing
+fn load(handle: &CpuHandle, address: u16, program: &[u8]) -> Result<(), &'static str> {
+    let start = address as usize;
+    let end = start + program.len();
+    let fits = handle.modify(|cpu| {
+        let memory = cpu.memory_mut();
+        end <= memory.byte_length() && (start..end).all(|address| memory.is_mapped(address))
+    });
+    if !fits {
+        return Err("The program doesn't fit in memory");
+    }
+    handle.pause();
+    handle.modify(|cpu| {
+        let memory = cpu.memory_mut();
+        for (offset, byte) in program.iter().enumerate() {
+            memory.set_byte(address as usize + offset, *byte);
+        }
+        cpu.reset();
+        cpu.set_register(Register::InstructionPointer, address);
+    });
+    Ok(())
+}
+
+fn state(handle: &CpuHandle) -> String {
+    let fault = match handle.fault() {
+        Some(fault) => format!("\"{}\"", fault),
+        None => "null".to_string(),
+    };
+    let cpu = handle.inspect(|cpu| cpu.to_json(&[]));
+    format!(
+        "{{\"paused\": {}, \"fault\": {}, \"cpu\": {}}}",
+        handle.is_paused(),
+        fault,
+        cpu
+    )
+}
+
+fn breakpoints(handle: &CpuHandle) -> String {
+    let addresses: Vec<String> = handle
+        .breakpoints()
+        .iter()
+        .map(|address| address.to_string())
+        .collect();
+    format!("[{}]", addresses.join(", "))
+}
+
+fn respond(stream: &mut TcpStream, response: Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+/// Sends the state as a text message whenever it changes, until the client
+/// goes away
+fn stream_state(mut stream: TcpStream, key: &str, handle: &CpuHandle) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        websocket_accept(key)
+    )?;
+
+    let mut last = None;
+    loop {
+        let state = state(handle);
+        if last.as_ref() != Some(&state) {
+            write_text_frame(&mut stream, &state)?;
+            last = Some(state);
+        }
+        thread::sleep(STREAM_INTERVAL);
+    }
+}
+
+fn write_text_frame(out: &mut impl Write, text: &str) -> io::Result<()> {
+    // Final fragment of a text message, servers don't mask
+    let mut frame = vec![0x81];
+    match text.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(text.as_bytes());
+    out.write_all(&frame)
+}
+
+/// What the server answers to a `Sec-WebSocket-Key`, see RFC 6455
+fn websocket_accept(key: &str) -> String {
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+    for chunk in padded.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, websocket_accept, Server};
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+
+    fn request(address: SocketAddr, method: &str, path: &str, body: &[u8]) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            method,
+            path,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn encodes_the_websocket_handshake() {
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man"), "TWFu");
+        // The example from RFC 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn loads_and_steps_a_program_over_http() {
+        let server = Server::bind("127.0.0.1:0", Cpu::new(Memory::new(256))).unwrap();
+        let address = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve());
+
+        // mov 0x1234, r1
+        let program = [
+            Instruction::MovLitReg as u8,
+            0x12,
+            0x34,
+            Register::Register1 as u8,
+        ];
+        let response = request(address, "POST", "/load?address=0x40", &program);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("\"ip\": 64"), "{}", response);
+
+        // Past the end of memory, which leaves the CPU as it was
+        let response = request(address, "POST", "/load?address=0xfe", &program);
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+        let response = request(address, "GET", "/state", b"");
+        assert!(response.contains("\"ip\": 64"), "{}", response);
+
+        let response = request(address, "PUT", "/breakpoints/44", b"");
+        assert!(response.ends_with("\r\n\r\n[68]"), "{}", response);
+
+        let response = request(address, "POST", "/step", b"");
+        assert!(response.contains("\"r1\": 4660"), "{}", response);
+        assert!(response.contains("\"paused\": true"), "{}", response);
+
+        let response = request(address, "GET", "/nowhere", b"");
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+
+    #[test]
+    fn streams_the_state_over_a_websocket() {
+        let server = Server::bind("127.0.0.1:0", Cpu::new(Memory::new(256))).unwrap();
+        let address = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET /stream HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        )
+        .unwrap();
+
+        let mut handshake = Vec::new();
+        while !handshake.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            handshake.push(byte[0]);
+        }
+        let handshake = String::from_utf8(handshake).unwrap();
+        assert!(handshake.starts_with("HTTP/1.1 101"), "{}", handshake);
+        assert!(handshake.contains("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81, "A whole text message");
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut message = vec![0; length];
+        stream.read_exact(&mut message).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.starts_with("{\"paused\": true"), "{}", message);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::counters::COUNTERS;
+use crate::cpu::{Cpu, Word, REGISTER_FILE_SIZE};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Bytes of memory tracked, and copied, together
+pub const PAGE_SIZE: usize = 0x100;
+
+const PAGES: usize = 0x10000 / PAGE_SIZE;
+
+/// A page of memory as of a snapshot, shared by the snapshots it didn't
+/// change between
+pub(crate) type Page = Arc<[u8]>;
+
+/// One bit per page written since the last snapshot
+pub(crate) struct DirtyPages {
+    bits: [u64; PAGES / 64],
+}
+
+impl Default for DirtyPages {
+    /// Everything is dirty until the first snapshot
+    fn default() -> Self {
+        DirtyPages {
+            bits: [u64::MAX; PAGES / 64],
+        }
+    }
+}
+
+impl DirtyPages {
+    pub(crate) fn mark(&mut self, address: usize, length: usize) {
+        let first = address / PAGE_SIZE;
+        let last = ((address + length.max(1) - 1) / PAGE_SIZE).min(PAGES - 1);
+        for page in first..=last {
+            self.bits[page / 64] |= 1 << (page % 64);
+        }
+    }
+
+    pub(crate) fn mark_all(&mut self) {
+        self.bits = [u64::MAX; PAGES / 64];
+    }
+
+    fn is_dirty(&self, page: usize) -> bool {
+        page >= PAGES || self.bits[page / 64] & 1 << (page % 64) != 0
+    }
+
+    fn clear(&mut self) {
+        self.bits = [0; PAGES / 64];
+    }
+}
+
+/// The state of a machine at one instruction, to go back to with
+/// `Cpu::restore`. Snapshots share the pages of memory that didn't change
+/// between them, so taking one often only copies what was written since the
+/// one before.
+///
+/// Only what is on the bus is saved, so devices keep their internal state
+/// and bytes they change on their own, without the CPU writing them, are
+/// only picked up by the first snapshot.
+#[derive(Clone)]
+pub struct Snapshot {
+    instructions: u64,
+    registers: [Word; REGISTER_FILE_SIZE],
+    stack_frame_size: usize,
+    is_in_interrupt_handler: bool,
+    halted: bool,
+    call_depth: usize,
+    shadow_stack: Option<Vec<u16>>,
+    parked_shadow_stacks: HashMap<u16, Vec<u16>>,
+    code_bank: u16,
+    pending_interrupts: Vec<u16>,
+    interrupts_raised_at: u64,
+    cycles_since_tick: u64,
+    stalled_cycles: u64,
+    interrupt_count: u64,
+    counter_latches: [u64; COUNTERS],
+    pages: Vec<Page>,
+    copied_pages: usize,
+}
+
+impl Snapshot {
+    /// Instructions executed when the snapshot was taken
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Pages copied from memory for this snapshot, the rest are shared with
+    /// the snapshot before
+    pub fn copied_pages(&self) -> usize {
+        self.copied_pages
+    }
+
+    /// Memory as it was, all of it
+    pub fn memory(&self) -> Vec<u8> {
+        self.pages.concat()
+    }
+}
+
+impl Cpu {
+    /// Saves the state of the machine, copying only the pages written since
+    /// the last snapshot taken or restored
+    pub fn snapshot(&mut self) -> Snapshot {
+        let length = self.memory.byte_length();
+        let previous = self.snapshot_pages.take();
+        let mut copied_pages = 0;
+        let pages: Vec<Page> = (0..length.div_ceil(PAGE_SIZE))
+            .map(|page| match &previous {
+                Some(previous) if !self.dirty_pages.is_dirty(page) => previous[page].clone(),
+                _ => {
+                    copied_pages += 1;
+                    self.memory.peek(page * PAGE_SIZE, PAGE_SIZE).into()
+                }
+            })
+            .collect();
+        self.dirty_pages.clear();
+        self.snapshot_pages = Some(pages.clone());
+
+        Snapshot {
+            instructions: self.clock.now(),
+            registers: self.register,
+            stack_frame_size: self.stack_frame_size,
+            is_in_interrupt_handler: self.is_in_interrupt_handler,
+            halted: self.halted,
+            call_depth: self.call_depth,
+            shadow_stack: self.shadow_stack.clone(),
+            parked_shadow_stacks: self.parked_shadow_stacks.clone(),
+            code_bank: self.code_bank,
+            pending_interrupts: self.pending_interrupts.clone(),
+            interrupts_raised_at: self.interrupts_raised_at,
+            cycles_since_tick: self.cycles_since_tick,
+            stalled_cycles: self.stalled_cycles,
+            interrupt_count: self.interrupt_count,
+            counter_latches: self.counter_latches,
+            pages,
+            copied_pages,
+        }
+    }
+
+    /// Puts the machine back the way it was when `snapshot` was taken,
+    /// writing back only the pages that differ from memory now. The clock,
+    /// shared with devices, is set back too.
+    ///
+    /// Panics if the snapshot was taken of a machine with a different
+    /// amount of memory.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        let length = self.memory.byte_length();
+        assert_eq!(
+            length.div_ceil(PAGE_SIZE),
+            snapshot.pages.len(),
+            "The snapshot is of a machine with a different amount of memory"
+        );
+        for (page, bytes) in snapshot.pages.iter().enumerate() {
+            let unchanged = !self.dirty_pages.is_dirty(page)
+                && self
+                    .snapshot_pages
+                    .as_ref()
+                    .is_some_and(|current| Arc::ptr_eq(&current[page], bytes));
+            if unchanged {
+                continue;
+            }
+            let start = page * PAGE_SIZE;
+            for (offset, byte) in bytes.iter().enumerate() {
+                self.memory.set_byte(start + offset, *byte);
+            }
+        }
+        self.dirty_pages.clear();
+        self.snapshot_pages = Some(snapshot.pages.clone());
+        self.block_cache.clear();
+
+        self.clock.set(snapshot.instructions);
+        self.register = snapshot.registers;
+        self.stack_frame_size = snapshot.stack_frame_size;
+        self.is_in_interrupt_handler = snapshot.is_in_interrupt_handler;
+        self.halted = snapshot.halted;
+        self.call_depth = snapshot.call_depth;
+        self.shadow_stack = snapshot.shadow_stack.clone();
+        self.parked_shadow_stacks = snapshot.parked_shadow_stacks.clone();
+        self.code_bank = snapshot.code_bank;
+        self.pending_interrupts = snapshot.pending_interrupts.clone();
+        self.interrupts_raised_at = snapshot.interrupts_raised_at;
+        self.cycles_since_tick = snapshot.cycles_since_tick;
+        self.stalled_cycles = snapshot.stalled_cycles;
+        self.interrupt_count = snapshot.interrupt_count;
+        self.counter_latches = snapshot.counter_latches;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+
+    /// Counts r1 up, storing it at 0x0800 every time round
+    fn counter() -> Cpu {
+        // start:
+        // mov 0x0001, r2
+        // add r1, r2
+        // mov acc, r1
+        // mov r1, [0x0800]
+        // jne 0x0000, start  ;; always taken
+        let code = [
+            Instruction::MovLitReg as u8,
+            0x00,
+            0x01,
+            Register::Register2 as u8,
+            Instruction::AddRegReg as u8,
+            Register::Register1 as u8,
+            Register::Register2 as u8,
+            Instruction::MovRegReg as u8,
+            Register::Accumulator as u8,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            0x08,
+            0x00,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        let mut memory = Memory::new(0x1000);
+        for (i, byte) in code.iter().enumerate() {
+            memory.set_byte(i, *byte);
+        }
+        Cpu::new(memory)
+    }
+
+    #[test]
+    fn copies_only_dirty_pages() {
+        let mut cpu = counter();
+        let first = cpu.snapshot();
+        assert_eq!(
+            first.copied_pages(),
+            16,
+            "The first snapshot copies everything"
+        );
+
+        cpu.step_n(5).unwrap();
+        let second = cpu.snapshot();
+        assert_eq!(
+            second.copied_pages(),
+            1,
+            "Only the counter's page was written"
+        );
+        assert_eq!(second.instruction_count(), 5);
+
+        cpu.step_n(3).unwrap();
+        assert_eq!(cpu.snapshot().copied_pages(), 0, "No store yet");
+    }
+
+    #[test]
+    fn restores_earlier_states() {
+        let mut cpu = counter();
+        cpu.step_n(10).unwrap();
+        let hash = cpu.state_hash();
+        let snapshot = cpu.snapshot();
+
+        cpu.step_n(23).unwrap();
+        cpu.memory_mut().set_byte(0x0f00, 0xff);
+        assert_ne!(cpu.state_hash(), hash);
+
+        cpu.restore(&snapshot);
+        assert_eq!(cpu.state_hash(), hash);
+        assert_eq!(cpu.instruction_count(), 10);
+        assert_eq!(cpu.peek(0x0800), 2);
+        assert_eq!(snapshot.memory()[0x0f00], 0);
+
+        cpu.step_n(5).unwrap();
+        assert_eq!(cpu.peek(0x0800), 3, "Runs on from the snapshot");
+    }
+}
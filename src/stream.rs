@@ -0,0 +1,225 @@
+use crate::mapper::Device;
+use std::io::{ErrorKind, Read, Write};
+
+/// Where filter mode maps a `StreamInput`
+pub const STREAM_INPUT_ADDRESS: usize = 0xfff0;
+
+/// Where filter mode maps a `StreamOutput`
+pub const STREAM_OUTPUT_ADDRESS: usize = 0xfff2;
+
+/// What a `StreamInput` reads as at the end of its stream, and what ends
+/// the stream of a `StreamOutput`
+pub const END_OF_STREAM: u16 = 0xffff;
+
+/// A host byte stream, like stdin, memory mapped as a word. Reading the
+/// word takes the next byte off the stream, as `0x00XX`, or
+/// `END_OF_STREAM` once it has run dry.
+pub struct StreamInput<R> {
+    reader: R,
+    /// The byte read last, `None` at the end of the stream
+    latched: Option<u8>,
+}
+
+impl<R: Read + Send> StreamInput<R> {
+    pub fn new(reader: R) -> StreamInput<R> {
+        StreamInput {
+            reader,
+            latched: None,
+        }
+    }
+
+    /// Read errors count as the end of the stream
+    fn next_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(1) => return Some(byte[0]),
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    fn word(&self) -> u16 {
+        self.latched.map_or(END_OF_STREAM, u16::from)
+    }
+}
+
+impl<R: Read + Send> Device for StreamInput<R> {
+    /// The high byte comes first in a word read, so reading it takes the
+    /// next byte off the stream
+    fn get_byte(&mut self, address: usize) -> u8 {
+        if address == 0 {
+            self.latched = self.next_byte();
+        }
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, _address: usize, _value: u8) {}
+
+    /// Reads as 0 past the word, for word reads straddling the end
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.word().to_be_bytes().get(address).copied().unwrap_or(0)
+    }
+
+    fn byte_length(&self) -> usize {
+        2
+    }
+}
+
+/// A host byte stream, like stdout, memory mapped as a word. Writing the
+/// word puts its low byte on the stream, writing `END_OF_STREAM` flushes
+/// the stream and drops any later writes.
+pub struct StreamOutput<W: Write> {
+    writer: W,
+    ended: bool,
+}
+
+impl<W: Write + Send> StreamOutput<W> {
+    pub fn new(writer: W) -> StreamOutput<W> {
+        StreamOutput {
+            writer,
+            ended: false,
+        }
+    }
+
+    fn write(&mut self, byte: u8) {
+        // A closed pipe ends the stream, like it ends a Unix filter
+        if !self.ended && self.writer.write_all(&[byte]).is_err() {
+            self.ended = true;
+        }
+    }
+}
+
+impl<W: Write + Send> Device for StreamOutput<W> {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    /// The low byte comes last in a word write, so writing it writes
+    fn set_byte(&mut self, address: usize, value: u8) {
+        if address == 1 {
+            self.write(value);
+        }
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        match (address, value) {
+            (0, END_OF_STREAM) => {
+                let _ = self.writer.flush();
+                self.ended = true;
+            }
+            (0, value) => self.write(value as u8),
+            // Straddles the end of the device
+            (_, value) => self.set_byte(address, (value >> 8) as u8),
+        }
+    }
+
+    fn peek_byte(&self, _address: usize) -> u8 {
+        0
+    }
+
+    fn byte_length(&self) -> usize {
+        2
+    }
+}
+
+impl<W: Write> Drop for StreamOutput<W> {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamInput, StreamOutput, STREAM_INPUT_ADDRESS, STREAM_OUTPUT_ADDRESS};
+    use crate::cpu::{Cpu, Instruction, Register};
+    use std::io::{Cursor, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn guests_filter_streams() {
+        // loop:
+        // mov [0xfff0], acc
+        // jne 0xffff, copy
+        // mov 0xffff, r1
+        // mov r1, [0xfff2]
+        // end:
+        // jne 0x0000, end
+        // copy:
+        // mov acc, [0xfff2]
+        // jne 0xffff, loop
+        let [input_high, input_low] = (STREAM_INPUT_ADDRESS as u16).to_be_bytes();
+        let [output_high, output_low] = (STREAM_OUTPUT_ADDRESS as u16).to_be_bytes();
+        let code = [
+            Instruction::MovMemReg as u8,
+            input_high,
+            input_low,
+            Register::Accumulator as u8,
+            Instruction::JmpNotEq as u8,
+            0xff,
+            0xff,
+            0x00,
+            0x16,
+            Instruction::MovLitReg as u8,
+            0xff,
+            0xff,
+            Register::Register1 as u8,
+            Instruction::MovRegMem as u8,
+            Register::Register1 as u8,
+            output_high,
+            output_low,
+            Instruction::JmpNotEq as u8,
+            0x00,
+            0x00,
+            0x00,
+            0x11,
+            Instruction::MovRegMem as u8,
+            Register::Accumulator as u8,
+            output_high,
+            output_low,
+            Instruction::JmpNotEq as u8,
+            0xff,
+            0xff,
+            0x00,
+            0x00,
+        ];
+        let output = Shared::default();
+        let mut cpu = Cpu::builder()
+            .memory_size(STREAM_INPUT_ADDRESS)
+            .device(
+                "stdin",
+                StreamInput::new(Cursor::new(b"a\xffb".to_vec())),
+                STREAM_INPUT_ADDRESS,
+                STREAM_INPUT_ADDRESS + 1,
+            )
+            .device(
+                "stdout",
+                StreamOutput::new(output.clone()),
+                STREAM_OUTPUT_ADDRESS,
+                STREAM_OUTPUT_ADDRESS + 1,
+            )
+            .build()
+            .unwrap();
+        for (i, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(i, *byte);
+        }
+
+        cpu.step_n(40).unwrap();
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x11);
+        assert_eq!(*output.0.lock().unwrap(), b"a\xffb", "0xff is just a byte");
+    }
+}
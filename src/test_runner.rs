@@ -0,0 +1,363 @@
+use crate::cpu::{Cpu, Fault, Register};
+use crate::debugger::{self, FaultAt};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// What an assertion looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(Register),
+    /// `[address]`, the byte there
+    Byte(u16),
+    /// `[address]:w`, the word there
+    Word(u16),
+}
+
+impl Operand {
+    fn value(&self, cpu: &Cpu) -> u16 {
+        match *self {
+            Operand::Register(register) => cpu.peek_register(register),
+            Operand::Byte(address) => cpu.peek_memory(address as usize, 1)[0] as u16,
+            Operand::Word(address) => cpu.peek(address as usize),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{}", register.name()),
+            Operand::Byte(address) => write!(f, "[{:#06x}]", address),
+            Operand::Word(address) => write!(f, "[{:#06x}]:w", address),
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = |text: &str| {
+            let hex = text
+                .strip_prefix("0x")
+                .ok_or(format!("Not a hex address: {}", text))?;
+            u16::from_str_radix(hex, 16).map_err(|e| e.to_string())
+        };
+        if let Some(inner) = s.strip_prefix('[') {
+            return match inner.split_once(']') {
+                Some((at, "")) => Ok(Operand::Byte(address(at)?)),
+                Some((at, ":w")) => Ok(Operand::Word(address(at)?)),
+                _ => Err(format!("Not an operand: {}", s)),
+            };
+        }
+        Ok(Operand::Register(s.parse()?))
+    }
+}
+
+/// `.assert <operand> == <value>`, or `!=`, checked by the host every time
+/// execution reaches it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assertion {
+    pub operand: Operand,
+    pub equal: bool,
+    pub value: u16,
+}
+
+impl Assertion {
+    /// `Err` holds the value the operand had instead
+    pub fn check(&self, cpu: &Cpu) -> Result<(), u16> {
+        let actual = self.operand.value(cpu);
+        match (actual == self.value) == self.equal {
+            true => Ok(()),
+            false => Err(actual),
+        }
+    }
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = if self.equal { "==" } else { "!=" };
+        write!(f, ".assert {} {} {:#06x}", self.operand, op, self.value)
+    }
+}
+
+impl FromStr for Assertion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [".assert", operand, op, value] = fields[..] else {
+            return Err(format!("Not an assertion: {}", s));
+        };
+        let equal = match op {
+            "==" => true,
+            "!=" => false,
+            op => return Err(format!("Unknown comparison: {}", op)),
+        };
+        let value = match value.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|e| format!("{}: {}", value, e))?;
+        Ok(Assertion {
+            operand: operand.parse()?,
+            equal,
+            value,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Assert(Assertion),
+    /// `.end`, the test is over
+    End,
+}
+
+/// Where an assembler put the checks of a test program, which take no
+/// space in the image. The text form has one trap per line, addresses in
+/// hex:
+///
+/// ```text
+/// <address> .assert r1 == 0x1234
+/// <address> .assert [0x1000] == 0x42
+/// <address> .end
+/// ```
+///
+/// Traps at an address are checked in order, before the instruction
+/// there runs. `#` starts a comment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrapPoints {
+    traps: BTreeMap<u16, Vec<Trap>>,
+}
+
+impl TrapPoints {
+    pub fn add(&mut self, address: u16, trap: Trap) {
+        self.traps.entry(address).or_default().push(trap);
+    }
+
+    pub fn at(&self, address: u16) -> &[Trap] {
+        self.traps.get(&address).map_or(&[], Vec::as_slice)
+    }
+}
+
+impl Display for TrapPoints {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (address, traps) in &self.traps {
+            for trap in traps {
+                match trap {
+                    Trap::Assert(assertion) => writeln!(f, "{:04x} {}", address, assertion)?,
+                    Trap::End => writeln!(f, "{:04x} .end", address)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TrapPoints {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut points = TrapPoints::default();
+        for (index, line) in s.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (address, trap) = line
+                .split_once(char::is_whitespace)
+                .ok_or(format!("Line {}: missing field", line_number))?;
+            let address = u16::from_str_radix(address.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("Line {}: {}", line_number, e))?;
+            let trap = match trap.trim() {
+                ".end" => Trap::End,
+                assertion => Trap::Assert(
+                    assertion
+                        .parse()
+                        .map_err(|e| format!("Line {}: {}", line_number, e))?,
+                ),
+            };
+            points.add(address, trap);
+        }
+        Ok(points)
+    }
+}
+
+/// How a test program did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Reached `.end` with every assertion on the way holding
+    Passed {
+        assertions: usize,
+    },
+    Failed {
+        address: u16,
+        assertion: Assertion,
+        actual: u16,
+    },
+    Faulted(FaultAt),
+    /// Gave up with `Instruction::Panic` at `address`
+    Panicked {
+        address: u16,
+        message: String,
+        code: u16,
+    },
+    /// Never reached `.end`
+    OutOfFuel,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Passed { assertions } => write!(f, "{} assertions held", assertions),
+            Outcome::Failed {
+                address,
+                assertion,
+                actual,
+            } => write!(
+                f,
+                "{} failed at {:#06x}, found {:#06x}",
+                assertion, address, actual
+            ),
+            Outcome::Faulted(fault) => write!(f, "{}", fault),
+            Outcome::Panicked {
+                address,
+                message,
+                code,
+            } => write!(
+                f,
+                "panicked at {:#06x} with code {:#06x}: {}",
+                address, code, message
+            ),
+            Outcome::OutOfFuel => write!(f, "never reached .end"),
+        }
+    }
+}
+
+/// Runs a test program for at most `fuel` instructions, checking its traps
+pub fn run_test(cpu: &mut Cpu, traps: &TrapPoints, fuel: usize) -> Outcome {
+    let mut assertions = 0;
+    for _ in 0..fuel {
+        let address = cpu.peek_register(Register::InstructionPointer);
+        for trap in traps.at(address) {
+            match trap {
+                Trap::Assert(assertion) => {
+                    if let Err(actual) = assertion.check(cpu) {
+                        return Outcome::Failed {
+                            address,
+                            assertion: *assertion,
+                            actual,
+                        };
+                    }
+                    assertions += 1;
+                }
+                Trap::End => return Outcome::Passed { assertions },
+            }
+        }
+        match debugger::step(cpu) {
+            Ok(()) => {}
+            Err(FaultAt {
+                fault: Fault::GuestPanic { message, code },
+                address,
+            }) => {
+                return Outcome::Panicked {
+                    address,
+                    message: cpu.peek_string(message as usize),
+                    code,
+                }
+            }
+            Err(fault) => return Outcome::Faulted(fault),
+        }
+    }
+    Outcome::OutOfFuel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_test, Assertion, Operand, Outcome, TrapPoints};
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+
+    /// mov 0x1234, r1
+    /// mov r1, [0x0080]
+    fn program() -> Cpu {
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x00, Instruction::MovLitReg as u8);
+        memory.set_word(0x01, 0x1234);
+        memory.set_byte(0x03, Register::Register1 as u8);
+        memory.set_byte(0x04, Instruction::MovRegMem as u8);
+        memory.set_byte(0x05, Register::Register1 as u8);
+        memory.set_word(0x06, 0x0080);
+        Cpu::new(memory)
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let traps: TrapPoints = "\
+0004 .assert r1 == 0x1234  # after the first mov
+0008 .assert [0x0080]:w != 0
+0008 .end
+"
+        .parse()
+        .unwrap();
+        assert_eq!(traps.at(0x0008).len(), 2);
+        assert_eq!(traps.to_string().parse::<TrapPoints>(), Ok(traps));
+        assert_eq!(
+            "0 .assert [0x80] == 0x42".parse::<Assertion>(),
+            Err("Not an assertion: 0 .assert [0x80] == 0x42".to_string())
+        );
+        assert_eq!(
+            ".assert [0x80] == 0x42"
+                .parse::<Assertion>()
+                .unwrap()
+                .operand,
+            Operand::Byte(0x80)
+        );
+        assert_eq!(
+            "0 .assert r1 < 2".parse::<TrapPoints>(),
+            Err("Line 1: Unknown comparison: <".to_string())
+        );
+    }
+
+    #[test]
+    fn checks_assertions_where_they_are() {
+        let passing: TrapPoints = "\
+0004 .assert r1 == 0x1234
+0008 .assert [0x0080] == 0x12
+0008 .end
+"
+        .parse()
+        .unwrap();
+        assert_eq!(
+            run_test(&mut program(), &passing, 100),
+            Outcome::Passed { assertions: 2 }
+        );
+
+        let failing: TrapPoints = "0008 .assert [0x0080]:w == 0x1235".parse().unwrap();
+        let outcome = run_test(&mut program(), &failing, 100);
+        assert_eq!(
+            outcome.to_string(),
+            ".assert [0x0080]:w == 0x1235 failed at 0x0008, found 0x1234"
+        );
+
+        // pnc message, 0x0007
+        let mut cpu = program();
+        let panicking = [Instruction::Panic as u8, 0x00, 0xa0, 0x00, 0x07];
+        for (i, byte) in panicking.iter().enumerate() {
+            cpu.memory_mut().set_byte(0x08 + i, *byte);
+        }
+        for (i, byte) in b"r1 is off\0".iter().enumerate() {
+            cpu.memory_mut().set_byte(0xa0 + i, *byte);
+        }
+        assert_eq!(
+            run_test(&mut cpu, &TrapPoints::default(), 100).to_string(),
+            "panicked at 0x0008 with code 0x0007: r1 is off"
+        );
+
+        let endless: TrapPoints = "00f0 .end".parse().unwrap();
+        assert_eq!(run_test(&mut program(), &endless, 4), Outcome::OutOfFuel);
+    }
+}
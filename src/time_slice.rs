@@ -0,0 +1,86 @@
+use crate::cpu::{Cpu, StopReason};
+use std::time::{Duration, Instant};
+
+/// How long a chunk of instructions should take between two looks at the time
+const CHUNK_TIME: Duration = Duration::from_micros(500);
+pub(crate) const INITIAL_CHUNK: usize = 1024;
+const MAX_CHUNK: usize = 1 << 24;
+
+impl Cpu {
+    /// Executes for about `duration` of wall-clock time, then hands control
+    /// back, e.g. to render a frame. Runs in chunks sized from how fast the
+    /// previous ones went, so it overshoots by about one chunk at most.
+    /// The chunk size carries over to the next call.
+    /// Returns `FuelExhausted` when the time is up.
+    pub fn run_for(&mut self, duration: Duration) -> StopReason {
+        let start = Instant::now();
+        let deadline = start + duration;
+        loop {
+            let chunk_start = Instant::now();
+            let remaining = deadline.saturating_duration_since(chunk_start);
+            // The last chunk of the slice shouldn't run much past the deadline
+            let chunk = self
+                .time_slice_chunk
+                .min(scale(self.time_slice_chunk, remaining, CHUNK_TIME))
+                .max(1);
+
+            let stop = self.run(chunk);
+            if stop != StopReason::FuelExhausted {
+                return stop;
+            }
+
+            let now = Instant::now();
+            self.time_slice_chunk = scale(chunk, CHUNK_TIME, now - chunk_start).clamp(1, MAX_CHUNK);
+            if now >= deadline {
+                return StopReason::FuelExhausted;
+            }
+        }
+    }
+}
+
+/// `instructions` that took `elapsed` scaled to how many fit in `target`
+fn scale(instructions: usize, target: Duration, elapsed: Duration) -> usize {
+    if elapsed.is_zero() {
+        return instructions.saturating_mul(2);
+    }
+    (instructions as f64 * target.as_secs_f64() / elapsed.as_secs_f64()) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bench::standard_workload;
+    use crate::cpu::{Cpu, Fault, Instruction, StopReason};
+    use crate::memory::Memory;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn runs_for_about_the_given_time() {
+        let mut cpu = Cpu::new(standard_workload());
+        let start = Instant::now();
+        assert_eq!(
+            cpu.run_for(Duration::from_millis(20)),
+            StopReason::FuelExhausted
+        );
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(20));
+        assert!(elapsed < Duration::from_secs(1), "Took {:?}", elapsed);
+        assert!(cpu.instruction_count() > 0);
+
+        let executed = cpu.instruction_count();
+        cpu.run_for(Duration::ZERO);
+        assert!(cpu.instruction_count() > executed, "Always makes progress");
+    }
+
+    #[test]
+    fn stops_at_a_fault() {
+        // pop r1, with nothing on the stack
+        let mut memory = Memory::new(256);
+        memory.set_byte(0, Instruction::Pop as u8);
+        memory.set_byte(1, 0x02);
+        let mut cpu = Cpu::new(memory);
+        assert_eq!(
+            cpu.run_for(Duration::from_secs(10)),
+            StopReason::Fault(Fault::StackUnderflow { address: 0xfe })
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::mapper::Device;
+
+/// Programmable interval timer, counting CPU cycles handed to it by `tick`.
+/// It is memory mapped, 4 bytes long:
+///
+/// - `0x00` period: cycles between two interrupts, 0 stops the timer.
+///   Writing it restarts the count.
+/// - `0x02` interrupt: the interrupt raised when the count runs out
+///
+/// Periods that run out more than once in a single tick raise one interrupt.
+#[derive(Debug, Default)]
+pub struct Timer {
+    period: u16,
+    interrupt: u16,
+    /// Cycles until the next interrupt
+    remaining: u64,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    fn word(&self, address: usize) -> u16 {
+        match address / 2 {
+            0 => self.period,
+            _ => self.interrupt,
+        }
+    }
+}
+
+impl Device for Timer {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        let mut bytes = self.word(address).to_be_bytes();
+        bytes[address % 2] = value;
+        self.set_word(address - address % 2, u16::from_be_bytes(bytes));
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        match address / 2 {
+            0 => {
+                self.period = value;
+                self.remaining = value as u64;
+            }
+            _ => self.interrupt = value,
+        }
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.word(address).to_be_bytes()[address % 2]
+    }
+
+    fn byte_length(&self) -> usize {
+        4
+    }
+
+    fn tick(&mut self, cycles: u64, interrupts: &mut Vec<u16>) {
+        if self.period == 0 {
+            return;
+        }
+        if cycles < self.remaining {
+            self.remaining -= cycles;
+            return;
+        }
+        let overshoot = (cycles - self.remaining) % self.period as u64;
+        self.remaining = self.period as u64 - overshoot;
+        interrupts.push(self.interrupt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::mapper::Device;
+
+    const TIMER: usize = 0x3000;
+
+    #[test]
+    fn counts_down_and_reloads() {
+        let mut timer = Timer::new();
+        let mut interrupts = Vec::new();
+        timer.tick(100, &mut interrupts);
+        assert!(interrupts.is_empty(), "Stopped until given a period");
+
+        timer.set_word(2, 5);
+        timer.set_word(0, 10);
+        timer.tick(9, &mut interrupts);
+        assert!(interrupts.is_empty());
+        timer.tick(1, &mut interrupts);
+        assert_eq!(interrupts, [5]);
+        timer.tick(25, &mut interrupts);
+        assert_eq!(interrupts, [5, 5], "Missed periods are coalesced");
+        timer.tick(4, &mut interrupts);
+        assert_eq!(interrupts, [5, 5]);
+        timer.tick(1, &mut interrupts);
+        assert_eq!(interrupts, [5, 5, 5]);
+    }
+
+    #[test]
+    fn interrupts_at_the_same_instruction_every_run() {
+        let run = |tick_interval| {
+            let mut cpu = Cpu::builder()
+                .device("timer", Timer::new(), TIMER, TIMER + 3)
+                .build()
+                .unwrap();
+            cpu.tick_devices_every(tick_interval);
+
+            // Interrupt 1 is handled at 0x0100
+            //   mov 0x0001, r1
+            //   mov r1, #3002 ;; interrupt 1
+            //   mov 0x0008, r1
+            //   mov r1, #3000 ;; every 8 cycles
+            // loop:
+            //   noop
+            //   jne 0x0001, loop:
+            let memory = cpu.memory_mut();
+            memory.set_word(0x1002, 0x0100);
+            let code = [
+                Instruction::MovLitReg as u8,
+                0x00,
+                0x01,
+                Register::Register1 as u8,
+                Instruction::MovRegMem as u8,
+                Register::Register1 as u8,
+                0x30,
+                0x02,
+                Instruction::MovLitReg as u8,
+                0x00,
+                0x08,
+                Register::Register1 as u8,
+                Instruction::MovRegMem as u8,
+                Register::Register1 as u8,
+                0x30,
+                0x00,
+                Instruction::Noop as u8,
+                Instruction::JmpNotEq as u8,
+                0x00,
+                0x01,
+                0x00,
+                0x10,
+            ];
+            for (i, byte) in code.iter().enumerate() {
+                memory.set_byte(i, *byte);
+            }
+
+            let mut handled_at = None;
+            for instruction in 1..=20 {
+                cpu.step().unwrap();
+                if cpu.peek_register(Register::InstructionPointer) > 0x0100 {
+                    handled_at = Some(instruction);
+                    break;
+                }
+            }
+            handled_at
+        };
+
+        // Armed by the 4th instruction and runs out after the 11th, so the
+        // 12th is the first one in the handler
+        assert_eq!(run(1), Some(12));
+        assert_eq!(run(1), run(1));
+        // Coarser ticks charge whole intervals, so it runs out after the 8th
+        assert_eq!(run(4), Some(9));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::mapper::Device;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Commands, written to the command register
+pub mod commands {
+    /// Moves the argument's worth of pixels ahead, backwards if negative
+    pub const FORWARD: u8 = 1;
+    /// Turns the argument's worth of degrees clockwise
+    pub const TURN: u8 = 2;
+    pub const PEN_UP: u8 = 3;
+    pub const PEN_DOWN: u8 = 4;
+    /// Draws in the argument's color from then on, as `0x0rgb`
+    pub const COLOR: u8 = 5;
+}
+
+/// Width and height of the canvas in pixels. The turtle starts in the
+/// middle, heading up with its pen down, drawing in black.
+pub const CANVAS_SIZE: u16 = 256;
+
+/// A stroke of the pen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Line {
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+    /// `0x0rgb`
+    pub color: u16,
+}
+
+struct State {
+    registers: [u8; 4],
+    position: (f64, f64),
+    /// Degrees clockwise from up
+    heading: f64,
+    pen_down: bool,
+    color: u16,
+    lines: Vec<Line>,
+}
+
+impl State {
+    fn execute(&mut self, command: u8) {
+        let argument = u16::from_be_bytes([self.registers[0], self.registers[1]]);
+        match command {
+            commands::FORWARD => {
+                let distance = argument as i16 as f64;
+                let radians = self.heading.to_radians();
+                let (x, y) = self.position;
+                let to = (x + distance * radians.sin(), y - distance * radians.cos());
+                if self.pen_down {
+                    self.lines.push(Line {
+                        from: self.position,
+                        to,
+                        color: self.color,
+                    });
+                }
+                self.position = to;
+            }
+            commands::TURN => {
+                self.heading = (self.heading + argument as i16 as f64).rem_euclid(360.0);
+            }
+            commands::PEN_UP => self.pen_down = false,
+            commands::PEN_DOWN => self.pen_down = true,
+            commands::COLOR => self.color = argument & 0x0fff,
+            _ => {}
+        }
+    }
+}
+
+/// A turtle that draws on a canvas, memory mapped as 2 words: the
+/// argument, then the command in the low byte of the second word. Writing
+/// a command carries it out, so guest code writes the argument first.
+/// Clones share the drawing, so keep one to look at it.
+#[derive(Clone)]
+pub struct Turtle {
+    state: Arc<Mutex<State>>,
+}
+
+impl Turtle {
+    pub fn new() -> Turtle {
+        let middle = CANVAS_SIZE as f64 / 2.0;
+        Turtle {
+            state: Arc::new(Mutex::new(State {
+                registers: [0; 4],
+                position: (middle, middle),
+                heading: 0.0,
+                pen_down: true,
+                color: 0x000,
+                lines: Vec::new(),
+            })),
+        }
+    }
+
+    /// Everything drawn so far, oldest first
+    pub fn lines(&self) -> Vec<Line> {
+        self.state.lock().unwrap().lines.clone()
+    }
+
+    /// The drawing as an SVG image on a white canvas
+    pub fn to_svg(&self) -> String {
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{0}\" \
+             viewBox=\"0 0 {0} {0}\">\n<rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\n",
+            CANVAS_SIZE
+        );
+        for line in self.state.lock().unwrap().lines.iter() {
+            let _ = writeln!(
+                svg,
+                "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#{:03x}\"/>",
+                line.from.0, line.from.1, line.to.0, line.to.1, line.color
+            );
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+impl Default for Turtle {
+    fn default() -> Self {
+        Turtle::new()
+    }
+}
+
+impl Device for Turtle {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.registers[address] = value;
+        if address == 3 {
+            state.execute(value);
+        }
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.state.lock().unwrap().registers[address]
+    }
+
+    fn byte_length(&self) -> usize {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{commands, Turtle};
+    use crate::mapper::Device;
+
+    fn command(turtle: &mut Turtle, command: u8, argument: u16) {
+        turtle.set_word(0, argument);
+        turtle.set_word(2, command as u16);
+    }
+
+    #[test]
+    fn draws_a_corner() {
+        let mut turtle = Turtle::new();
+        command(&mut turtle, commands::FORWARD, 10);
+        command(&mut turtle, commands::TURN, 90);
+        command(&mut turtle, commands::COLOR, 0x0f00);
+        command(&mut turtle, commands::FORWARD, 20);
+        command(&mut turtle, commands::PEN_UP, 0);
+        command(&mut turtle, commands::FORWARD, -5i16 as u16);
+
+        let lines = turtle.lines();
+        assert_eq!(lines.len(), 2, "Nothing drawn with the pen up");
+        assert_eq!(lines[0].from, (128.0, 128.0));
+        assert_eq!(lines[0].to, (128.0, 118.0), "Heading up");
+        assert!((lines[1].to.0 - 148.0).abs() < 1e-9, "{:?}", lines[1]);
+        assert_eq!(lines[1].color, 0xf00);
+        assert!(turtle.to_svg().contains(
+            "<line x1=\"128.0\" y1=\"118.0\" x2=\"148.0\" y2=\"118.0\" stroke=\"#f00\"/>"
+        ));
+    }
+}
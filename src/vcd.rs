@@ -0,0 +1,182 @@
+use crate::cpu::Cpu;
+use crate::instrument::Observer;
+use std::io::{self, Write};
+
+/// Writes registers, flags and bus accesses as a Value Change Dump, for
+/// waveform viewers like GTKWave. Time is counted in instructions, one
+/// nanosecond each. Attach it with `Cpu::attach_observer`.
+pub struct Vcd<W: Write + Send> {
+    out: W,
+    /// Last value dumped for every register, then the interrupt flag
+    values: Vec<u16>,
+    time: Option<u64>,
+    /// Whether the bus strobes are up and need to be pulled down
+    bus_active: bool,
+}
+
+const IN_INTERRUPT_HANDLER: &str = "i";
+const BUS_ADDRESS: &str = "a";
+const BUS_DATA: &str = "d";
+const BUS_READ: &str = "r";
+const BUS_WRITE: &str = "w";
+
+impl<W: Write + Send> Vcd<W> {
+    /// Writes the header for the registers of `cpu`
+    pub fn new(mut out: W, cpu: &Cpu) -> io::Result<Vcd<W>> {
+        writeln!(out, "$timescale 1 ns $end")?;
+        writeln!(out, "$scope module cpu $end")?;
+        for (index, (_, name, _)) in cpu.registers().enumerate() {
+            writeln!(out, "$var wire 16 {} {} $end", identifier(index), name)?;
+        }
+        writeln!(
+            out,
+            "$var wire 1 {} in_interrupt_handler $end",
+            IN_INTERRUPT_HANDLER
+        )?;
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$scope module bus $end")?;
+        writeln!(out, "$var wire 16 {} address $end", BUS_ADDRESS)?;
+        writeln!(out, "$var wire 16 {} data $end", BUS_DATA)?;
+        writeln!(out, "$var wire 1 {} read $end", BUS_READ)?;
+        writeln!(out, "$var wire 1 {} write $end", BUS_WRITE)?;
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        Ok(Vcd {
+            out,
+            values: Vec::new(),
+            time: None,
+            bus_active: false,
+        })
+    }
+
+    fn advance(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let time = cpu.instruction_count();
+        if self.time == Some(time) {
+            return Ok(());
+        }
+        writeln!(self.out, "#{}", time)?;
+
+        let first = self.time.is_none();
+        if first {
+            writeln!(self.out, "$dumpvars")?;
+            writeln!(self.out, "b0 {}", BUS_ADDRESS)?;
+            writeln!(self.out, "b0 {}", BUS_DATA)?;
+        }
+        if first || self.bus_active {
+            writeln!(self.out, "0{}", BUS_READ)?;
+            writeln!(self.out, "0{}", BUS_WRITE)?;
+            self.bus_active = false;
+        }
+
+        let values: Vec<u16> = cpu
+            .registers()
+            .map(|(_, _, value)| value)
+            .chain([cpu.is_in_interrupt_handler as u16])
+            .collect();
+        let flag = values.len() - 1;
+        for (index, value) in values.iter().enumerate() {
+            if !first && self.values[index] == *value {
+                continue;
+            }
+            if index == flag {
+                writeln!(self.out, "{}{}", value, IN_INTERRUPT_HANDLER)?;
+            } else {
+                writeln!(self.out, "b{:b} {}", value, identifier(index))?;
+            }
+        }
+        if first {
+            writeln!(self.out, "$end")?;
+        }
+
+        self.values = values;
+        self.time = Some(time);
+        Ok(())
+    }
+
+    fn access(&mut self, cpu: &Cpu, address: u16, value: u16, strobe: &str) -> io::Result<()> {
+        self.advance(cpu)?;
+        writeln!(self.out, "b{:b} {}", address, BUS_ADDRESS)?;
+        writeln!(self.out, "b{:b} {}", value, BUS_DATA)?;
+        writeln!(self.out, "1{}", strobe)?;
+        self.bus_active = true;
+        Ok(())
+    }
+}
+
+/// Short printable name of the `index`th register in the dump
+fn identifier(index: usize) -> String {
+    // Capitals, so they can't clash with the flag and the bus
+    char::from(b'A' + index as u8).to_string()
+}
+
+// Dumping is best effort, a full disk shouldn't stop the program
+impl<W: Write + Send> Observer for Vcd<W> {
+    fn instruction(&mut self, cpu: &Cpu, _address: u16) {
+        let _ = self.advance(cpu);
+    }
+
+    fn read(&mut self, cpu: &Cpu, address: u16, value: u16) {
+        let _ = self.access(cpu, address, value, BUS_READ);
+    }
+
+    fn write(&mut self, cpu: &Cpu, address: u16, value: u16) {
+        let _ = self.access(cpu, address, value, BUS_WRITE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vcd;
+    use crate::cpu::{Cpu, Instruction, Register};
+    use crate::memory::Memory;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dumps_register_changes_and_bus_accesses() {
+        // mov 0x0005, r1
+        // mov r1, #0080
+        // noop
+        let mut memory = Memory::new(256);
+        memory.set_byte(0x00, Instruction::MovLitReg as u8);
+        memory.set_word(0x01, 0x0005);
+        memory.set_byte(0x03, Register::Register1 as u8);
+        memory.set_byte(0x04, Instruction::MovRegMem as u8);
+        memory.set_byte(0x05, Register::Register1 as u8);
+        memory.set_word(0x06, 0x0080);
+        memory.set_byte(0x08, Instruction::Noop as u8);
+        let mut cpu = Cpu::new(memory);
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let vcd = Vcd::new(Shared(Arc::clone(&out)), &cpu).unwrap();
+        cpu.attach_observer(vcd);
+        cpu.step_n(3).unwrap();
+
+        let dump = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        let (header, changes) = dump.split_once("$enddefinitions $end\n").unwrap();
+        assert!(header.contains("$var wire 16 A ip $end"));
+        assert!(header.contains("$var wire 16 C r1 $end"));
+        assert!(header.contains("$var wire 1 w write $end"));
+
+        let after_dumpvars = changes.split_once("$end\n").unwrap().1;
+        assert_eq!(
+            after_dumpvars,
+            "#1\nb100 A\nb101 C\n\
+             b10000000 a\nb101 d\n1w\n\
+             #2\n0r\n0w\nb1000 A\n"
+        );
+    }
+}
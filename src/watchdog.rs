@@ -0,0 +1,180 @@
+use crate::clock::Clock;
+use crate::mapper::Device;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+struct State {
+    clock: Clock,
+    timeout: AtomicU64,
+    last_kick: AtomicU64,
+}
+
+/// Resets the CPU unless guest code kicks it at least every `timeout`
+/// instructions. A timeout of 0 disables it.
+#[derive(Clone)]
+pub struct Watchdog {
+    state: Arc<State>,
+}
+
+impl Watchdog {
+    /// `clock` has to be the clock of the CPU the watchdog is attached to
+    pub fn new(timeout: u64, clock: Clock) -> Watchdog {
+        let last_kick = clock.now();
+        Watchdog {
+            state: Arc::new(State {
+                clock,
+                timeout: AtomicU64::new(timeout),
+                last_kick: AtomicU64::new(last_kick),
+            }),
+        }
+    }
+
+    /// The memory mapped side of the watchdog, 4 bytes long:
+    ///
+    /// - `0x00` kick: writing anything kicks, reading gives the instructions left
+    /// - `0x02` timeout: reading and writing set the timeout, writing also kicks
+    pub fn registers(&self) -> WatchdogRegisters {
+        WatchdogRegisters {
+            watchdog: self.clone(),
+        }
+    }
+
+    pub fn kick(&self) {
+        self.state
+            .last_kick
+            .store(self.state.clock.now(), Ordering::Relaxed);
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.state.timeout.load(Ordering::Relaxed)
+    }
+
+    pub fn set_timeout(&self, timeout: u64) {
+        self.state.timeout.store(timeout, Ordering::Relaxed);
+        self.kick();
+    }
+
+    /// Instructions left until the watchdog fires
+    pub fn remaining(&self) -> u64 {
+        let elapsed = self.state.clock.now() - self.state.last_kick.load(Ordering::Relaxed);
+        self.timeout().saturating_sub(elapsed)
+    }
+
+    pub fn expired(&self) -> bool {
+        self.timeout() != 0 && self.remaining() == 0
+    }
+}
+
+pub struct WatchdogRegisters {
+    watchdog: Watchdog,
+}
+
+impl WatchdogRegisters {
+    fn word(&self, address: usize) -> u16 {
+        match address / 2 {
+            0 => self.watchdog.remaining().min(0xffff) as u16,
+            _ => self.watchdog.timeout().min(0xffff) as u16,
+        }
+    }
+}
+
+impl Device for WatchdogRegisters {
+    fn get_byte(&mut self, address: usize) -> u8 {
+        self.peek_byte(address)
+    }
+
+    fn set_byte(&mut self, address: usize, value: u8) {
+        match address {
+            0 | 1 => self.watchdog.kick(),
+            _ => {
+                let mut bytes = self.word(2).to_be_bytes();
+                bytes[address - 2] = value;
+                self.watchdog.set_timeout(u16::from_be_bytes(bytes) as u64);
+            }
+        }
+    }
+
+    fn set_word(&mut self, address: usize, value: u16) {
+        match address {
+            0 => self.watchdog.kick(),
+            _ => self.watchdog.set_timeout(value as u64),
+        }
+    }
+
+    fn peek_byte(&self, address: usize) -> u8 {
+        self.word(address).to_be_bytes()[address % 2]
+    }
+
+    fn byte_length(&self) -> usize {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchdog;
+    use crate::clock::Clock;
+    use crate::cpu::{Cpu, Event, Instruction, Register};
+
+    const WATCHDOG: usize = 0x3000;
+
+    #[test]
+    fn resets_hung_program() {
+        let clock = Clock::new();
+        let watchdog = Watchdog::new(4, clock.clone());
+        let mut cpu = Cpu::builder()
+            .clock(clock)
+            .device("watchdog", watchdog.registers(), WATCHDOG, WATCHDOG + 3)
+            .build()
+            .unwrap();
+        cpu.attach_watchdog(watchdog);
+
+        // start:
+        //   mov 0x1234, r1
+        //   jne 0x0001, start: ;; never kicks the watchdog
+        let memory = cpu.memory_mut();
+        memory.set_byte(0, Instruction::MovLitReg as u8);
+        memory.set_word(1, 0x1234);
+        memory.set_byte(3, Register::Register1 as u8);
+        memory.set_byte(4, Instruction::JmpNotEq as u8);
+        memory.set_word(5, 0x0001);
+        memory.set_word(7, 0x0000);
+
+        cpu.step_n(3).unwrap();
+        assert!(cpu.take_events().is_empty());
+        assert_eq!(cpu.peek_register(Register::Register1), 0x1234);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.take_events(), [Event::WatchdogReset { instruction: 4 }]);
+        assert_eq!(cpu.peek_register(Register::Register1), 0x0000);
+        assert_eq!(cpu.peek_register(Register::InstructionPointer), 0x0000);
+    }
+
+    #[test]
+    fn kicking_keeps_the_program_running() {
+        let clock = Clock::new();
+        let watchdog = Watchdog::new(4, clock.clone());
+        let mut cpu = Cpu::builder()
+            .clock(clock)
+            .device("watchdog", watchdog.registers(), WATCHDOG, WATCHDOG + 3)
+            .build()
+            .unwrap();
+        cpu.attach_watchdog(watchdog.clone());
+
+        // start:
+        //   mov r1, #3000 ;; kick
+        //   jne 0x0001, start:
+        let memory = cpu.memory_mut();
+        memory.set_byte(0, Instruction::MovRegMem as u8);
+        memory.set_byte(1, Register::Register1 as u8);
+        memory.set_word(2, WATCHDOG as u16);
+        memory.set_byte(4, Instruction::JmpNotEq as u8);
+        memory.set_word(5, 0x0001);
+        memory.set_word(7, 0x0000);
+
+        cpu.step_n(20).unwrap();
+        assert!(cpu.take_events().is_empty());
+        assert_eq!(watchdog.remaining(), 2);
+        assert_eq!(cpu.peek(WATCHDOG + 2), 4, "Guest can read the timeout");
+    }
+}
@@ -0,0 +1,194 @@
+// Runs the instruction test vectors in tests/conformance.
+//
+// A vector starts with `test <name>` and lists, one per line:
+//
+//   memory <size>              memory size in bytes, 256 by default
+//   code <bytes>               bytes loaded at address 0
+//   mem <address> <bytes>      bytes loaded at <address>
+//   set <register> <value>     initial register value
+//   steps <count>              instructions to execute, 1 by default
+//   expect <register> <value>  final register value
+//   expect mem <address> <bytes>
+//   expect fault <message>     the last step faults with <message>
+//
+// Registers without an expectation must keep their initial value, and
+// memory without one must stay unchanged. `#` starts a comment.
+
+use rsll16::cpu::{opcode_info, Cpu, Register};
+use rsll16::memory::Memory;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+#[derive(Default)]
+struct Vector {
+    name: String,
+    memory_size: Option<usize>,
+    memory: Vec<(usize, Vec<u8>)>,
+    registers: Vec<(Register, u16)>,
+    steps: Option<usize>,
+    expected_registers: Vec<(Register, u16)>,
+    expected_memory: Vec<(usize, Vec<u8>)>,
+    expected_fault: Option<String>,
+}
+
+fn number(text: &str) -> usize {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.unwrap_or_else(|_| panic!("Not a number: {}", text))
+}
+
+fn bytes<'a>(words: impl Iterator<Item = &'a str>) -> Vec<u8> {
+    words.map(|word| number(word) as u8).collect()
+}
+
+fn register(name: &str) -> Register {
+    name.parse().unwrap()
+}
+
+fn parse(source: &str) -> Vec<Vector> {
+    let mut vectors: Vec<Vector> = Vec::new();
+    for line in source.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let Some(directive) = words.next() else {
+            continue;
+        };
+        if directive == "test" {
+            vectors.push(Vector {
+                name: words.next().expect("Test name").to_string(),
+                ..Vector::default()
+            });
+            continue;
+        }
+
+        let vector = vectors.last_mut().expect("Directive before the first test");
+        match directive {
+            "memory" => vector.memory_size = Some(number(words.next().unwrap())),
+            "code" => vector.memory.push((0, bytes(words))),
+            "mem" => {
+                let address = number(words.next().unwrap());
+                vector.memory.push((address, bytes(words)));
+            }
+            "set" => {
+                let register = register(words.next().unwrap());
+                vector
+                    .registers
+                    .push((register, number(words.next().unwrap()) as u16));
+            }
+            "steps" => vector.steps = Some(number(words.next().unwrap())),
+            "expect" => match words.next().unwrap() {
+                "mem" => {
+                    let address = number(words.next().unwrap());
+                    vector.expected_memory.push((address, bytes(words)));
+                }
+                "fault" => {
+                    vector.expected_fault = Some(words.collect::<Vec<_>>().join(" "));
+                }
+                name => {
+                    let register = register(name);
+                    vector
+                        .expected_registers
+                        .push((register, number(words.next().unwrap()) as u16));
+                }
+            },
+            _ => panic!("Unknown directive in {}: {}", vector.name, line),
+        }
+    }
+    vectors
+}
+
+/// Runs `vector` and returns the opcodes it executed
+fn run(vector: &Vector) -> HashSet<u8> {
+    let memory_size = vector.memory_size.unwrap_or(256);
+    let mut image = vec![0; memory_size];
+    for (address, bytes) in &vector.memory {
+        image[*address..address + bytes.len()].copy_from_slice(bytes);
+    }
+    let mut memory = Memory::new(memory_size);
+    for (address, byte) in image.iter().enumerate() {
+        memory.set_byte(address, *byte);
+    }
+
+    let mut cpu = Cpu::new(memory);
+    for (register, value) in &vector.registers {
+        cpu.set_register(*register, *value);
+    }
+    let initial: Vec<_> = cpu.registers().collect();
+
+    let mut executed = HashSet::new();
+    let fault = (0..vector.steps.unwrap_or(1)).find_map(|_| {
+        let ip = cpu.peek_register(Register::InstructionPointer) as usize;
+        executed.extend(cpu.peek_memory(ip, 1));
+        cpu.step().err()
+    });
+    assert_eq!(
+        fault.map(|fault| fault.to_string()),
+        vector.expected_fault,
+        "{}: fault",
+        vector.name
+    );
+
+    for (register, name, value) in initial {
+        let expected = vector
+            .expected_registers
+            .iter()
+            .find(|(expected, _)| *expected == register)
+            .map_or(value, |(_, value)| *value);
+        assert_eq!(
+            cpu.peek_register(register),
+            expected,
+            "{}: register {}",
+            vector.name,
+            name
+        );
+    }
+
+    let mut expected = image;
+    for (address, bytes) in &vector.expected_memory {
+        expected[*address..address + bytes.len()].copy_from_slice(bytes);
+    }
+    for (address, (actual, expected)) in cpu
+        .peek_memory(0, memory_size)
+        .iter()
+        .zip(&expected)
+        .enumerate()
+    {
+        assert_eq!(
+            actual, expected,
+            "{}: memory at {:#06x}",
+            vector.name, address
+        );
+    }
+    executed
+}
+
+fn vectors() -> Vec<Vector> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut paths: Vec<_> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .flat_map(|path| parse(&fs::read_to_string(path).unwrap()))
+        .collect()
+}
+
+#[test]
+fn instructions_conform_to_their_vectors() {
+    let executed: HashSet<u8> = vectors().iter().flat_map(run).collect();
+
+    for opcode in 0..=u8::MAX {
+        if let Some(info) = opcode_info(opcode) {
+            assert!(
+                executed.contains(&opcode),
+                "No test vector executes {:?}",
+                info.instruction
+            );
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// Replays the traces in tests/traces, recorded from the Low Level
+// JavaScript VM with tools/record-trace.js, against this VM.
+
+use rsll16::differential::Trace;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn matches_the_reference_traces() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/traces");
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        let trace: Trace = fs::read_to_string(&path).unwrap().parse().unwrap();
+        if let Err(divergence) = trace.compare() {
+            panic!("{}: {}", path.display(), divergence);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Snapshots of what the debugger shows for fixed programs. After an
+// intended formatting change, review the new output with `cargo insta`.
+
+use insta::assert_snapshot;
+use rsll16::bench;
+use rsll16::control_flow::control_flow_graph;
+use rsll16::cpu::Cpu;
+use rsll16::debugger;
+use rsll16::disassembler::disassemble;
+
+fn listing(code: &[u8], start: usize) -> String {
+    disassemble(code, start)
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[test]
+fn disassembles_the_standard_workload() {
+    let cpu = Cpu::new(bench::standard_workload());
+    let main = listing(&cpu.peek_memory(0x0000, 0x26), 0x0000);
+    let subroutine = listing(&cpu.peek_memory(0x0100, 0x08), 0x0100);
+    assert_snapshot!(format!("{}\n{}", main, subroutine));
+}
+
+#[test]
+fn draws_the_control_flow_of_the_standard_workload() {
+    // Just the loop, the subroutine it calls is outside
+    let cpu = Cpu::new(bench::standard_workload());
+    let code = cpu.peek_memory(0x0000, 0x28);
+    assert_snapshot!(control_flow_graph(&code, 0x0000, &[0x0000]).to_dot());
+}
+
+#[test]
+fn disassembles_every_opcode_and_bad_bytes() {
+    #[rustfmt::skip]
+    let code = [
+        0x00,
+        0x10, 0x12, 0x34, 0x02,
+        0x11, 0x02, 0x0b,
+        0x12, 0x03, 0x80, 0x00,
+        0x13, 0x80, 0x00, 0x0c,
+        0x14, 0x02, 0x13,
+        0x15, 0xff, 0xff, 0x00, 0x10,
+        0x17, 0xbe, 0xef,
+        0x18, 0x0a,
+        0x1a, 0x01,
+        0x5e, 0x01, 0x00,
+        0x5f, 0x09,
+        0x60,
+        0xfc,
+        0xfd, 0x00, 0x03,
+        // Unknown opcode, unknown register, cut off at the end
+        0xee,
+        0x18, 0xee,
+        0x10, 0x12,
+    ];
+    assert_snapshot!(listing(&code, 0x0200));
+}
+
+#[test]
+fn traces_the_standard_workload() {
+    let mut cpu = Cpu::new(bench::standard_workload());
+    let mut trace = String::new();
+    for _ in 0..24 {
+        trace += &debugger::trace_line(&cpu);
+        trace += "\n";
+        cpu.step().unwrap();
+    }
+    assert_snapshot!(trace);
+}
+
+#[test]
+fn renders_the_panes_inside_a_subroutine() {
+    let mut cpu = Cpu::new(bench::standard_workload());
+    cpu.step_n(6).unwrap();
+    let panes =
+        debugger::register_pane(&cpu) + &debugger::tape_pane(&cpu) + &debugger::stack_pane(&cpu);
+    assert_snapshot!(panes);
+}
+
+#[test]
+fn exports_the_state_inside_a_subroutine_as_json() {
+    let mut cpu = Cpu::new(bench::standard_workload());
+    cpu.step_n(6).unwrap();
+    assert_snapshot!(cpu.to_json(&[0x0100..0x0108, 0x8000..0x8004]));
+}
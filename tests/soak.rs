@@ -0,0 +1,89 @@
+// Runs generated programs for millions of instructions on every engine and
+// checks that they stay in lockstep, down to the state hash. The data region
+// is a separate device behind the mapper.
+
+use rsll16::bench::Engine;
+use rsll16::cpu::{Cpu, StopReason};
+use rsll16::generator::{self, DATA_SIZE, DATA_START};
+use rsll16::memory::Memory;
+
+const CHUNK: usize = 10_000;
+
+fn machine(seed: u64) -> Cpu {
+    let mut cpu = Cpu::builder()
+        .memory_size(0x10000)
+        .device(
+            "data",
+            Memory::new(DATA_SIZE),
+            DATA_START,
+            DATA_START + DATA_SIZE - 1,
+        )
+        .build()
+        .unwrap();
+    generator::generate(seed).load(cpu.memory_mut());
+    cpu
+}
+
+fn soak(seeds: std::ops::Range<u64>, instructions: usize) {
+    let engines = [
+        Engine::Step,
+        Engine::Run,
+        Engine::Cached,
+        #[cfg(feature = "jit")]
+        Engine::Jit,
+    ];
+
+    for seed in seeds {
+        let mut cpus: Vec<Cpu> = engines.iter().map(|_| machine(seed)).collect();
+        for _ in 0..instructions / CHUNK {
+            for (engine, cpu) in engines.iter().zip(&mut cpus) {
+                assert_eq!(
+                    engine.execute(cpu, CHUNK),
+                    StopReason::FuelExhausted,
+                    "seed {} on {:?}",
+                    seed,
+                    engine
+                );
+            }
+
+            let (reference, others) = cpus.split_first().unwrap();
+            for (engine, cpu) in engines[1..].iter().zip(others) {
+                assert_eq!(
+                    cpu.registers().collect::<Vec<_>>(),
+                    reference.registers().collect::<Vec<_>>(),
+                    "seed {} on {:?} after {} instructions",
+                    seed,
+                    engine,
+                    reference.instruction_count()
+                );
+                assert_eq!(
+                    cpu.peek_memory(DATA_START, DATA_SIZE),
+                    reference.peek_memory(DATA_START, DATA_SIZE),
+                    "seed {} on {:?} after {} instructions",
+                    seed,
+                    engine,
+                    reference.instruction_count()
+                );
+                assert_eq!(
+                    cpu.state_hash(),
+                    reference.state_hash(),
+                    "seed {} on {:?} after {} instructions",
+                    seed,
+                    engine,
+                    reference.instruction_count()
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn generated_programs_agree_across_engines() {
+    soak(0..4, 1_000_000);
+}
+
+#[test]
+#[ignore = "takes minutes, run with --ignored"]
+fn generated_programs_agree_across_engines_for_long() {
+    soak(0..64, 20_000_000);
+}
@@ -0,0 +1,43 @@
+// Records a trace of the Low Level JavaScript VM for `rsll16 diff`.
+//
+//   node tools/record-trace.js <VM directory> <image> <steps> > program.trace
+//
+// <VM directory> holds the reference `cpu.js` and `create-memory.js`, and
+// <image> is a raw binary that gets loaded at address 0.
+
+const fs = require('fs');
+const path = require('path');
+
+const [vmDirectory, imagePath, steps] = process.argv.slice(2);
+if (steps === undefined) {
+  console.error('Usage: node tools/record-trace.js <VM directory> <image> <steps>');
+  process.exit(2);
+}
+
+const createMemory = require(path.resolve(vmDirectory, 'create-memory'));
+const CPU = require(path.resolve(vmDirectory, 'cpu'));
+
+const MEMORY_SIZE = 256 * 256;
+const REGISTERS = ['ip', 'acc', 'r1', 'r2', 'r3', 'r4', 'r5', 'r6', 'r7', 'r8', 'sp', 'fp'];
+
+const hex = (value, digits) => '0x' + value.toString(16).padStart(digits, '0');
+
+const memory = createMemory(MEMORY_SIZE);
+const bytes = new Uint8Array(memory.buffer);
+const image = fs.readFileSync(imagePath);
+bytes.set(image);
+const cpu = new CPU(memory);
+
+console.log(`memory ${MEMORY_SIZE}`);
+console.log(`load 0x0000 ${Array.from(image, byte => hex(byte, 2)).join(' ')}`);
+for (let step = 0; step < Number(steps); step++) {
+  const before = bytes.slice();
+  cpu.step();
+  const registers = REGISTERS.map(name => `${name}=${hex(cpu.getRegister(name), 4)}`);
+  console.log(`step ${registers.join(' ')}`);
+  for (let address = 0; address < bytes.length; address++) {
+    if (bytes[address] !== before[address]) {
+      console.log(`write ${hex(address, 4)} ${hex(bytes[address], 2)}`);
+    }
+  }
+}